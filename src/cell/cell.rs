@@ -122,7 +122,7 @@ pub struct CellList {
     box_len: Vec3,
     // cutoff and cell geometry
     cutoff: f64,
-    cell_size: f64, // typically >= cutoff
+    cell_size: Vec3, // L / n along each axis, always >= cutoff
     nx: usize,
     ny: usize,
     nz: usize,
@@ -133,14 +133,18 @@ pub struct CellList {
 }
 
 impl CellList {
-    /// Create a cell list. We set cell_size = cutoff (common choice),
-    /// and number of cells along each axis = floor(L / cell_size).
+    /// Create a cell list. The number of cells along each axis is floor(L / cutoff)
+    /// and the cells evenly tile the box, so every cell edge is >= cutoff.
     /// Ensure nx,ny,nz >= 1.
     pub fn new(box_len: Vec3, cutoff: f64) -> Self {
-        let cell_size = cutoff;
-        let nx = (box_len.x / cell_size).floor().max(1.0) as usize;
-        let ny = (box_len.y / cell_size).floor().max(1.0) as usize;
-        let nz = (box_len.z / cell_size).floor().max(1.0) as usize;
+        let nx = (box_len.x / cutoff).floor().max(1.0) as usize;
+        let ny = (box_len.y / cutoff).floor().max(1.0) as usize;
+        let nz = (box_len.z / cutoff).floor().max(1.0) as usize;
+        let cell_size = Vec3::new(
+            box_len.x / nx as f64,
+            box_len.y / ny as f64,
+            box_len.z / nz as f64,
+        );
 
         let ncell = nx * ny * nz;
 
@@ -188,9 +192,9 @@ impl CellList {
         let y = wrap_0_l(p.y, self.box_len.y);
         let z = wrap_0_l(p.z, self.box_len.z);
 
-        let cx = (x / self.cell_size).floor() as usize % self.nx;
-        let cy = (y / self.cell_size).floor() as usize % self.ny;
-        let cz = (z / self.cell_size).floor() as usize % self.nz;
+        let cx = (x / self.cell_size.x).floor() as usize % self.nx;
+        let cy = (y / self.cell_size.y).floor() as usize % self.ny;
+        let cz = (z / self.cell_size.z).floor() as usize % self.nz;
         (cx, cy, cz)
     }
 
@@ -198,16 +202,25 @@ impl CellList {
     ///
     /// This is the "what is wrong with rebuilding the cell list?" part:
     /// it's cheap and common to rebuild every step (or every few steps with a skin).
-    pub fn rebuild(&mut self, positions: &Vec<Particle>) {
+    pub fn rebuild(&mut self, positions: &[Particle]) {
+        self.rebuild_with(positions.len(), |i| positions[i].position);
+    }
+
+    /// Rebuild from coordinate arrays, for structure-of-arrays particle storage.
+    pub fn rebuild_from_coordinates(&mut self, x: &[f64], y: &[f64], z: &[f64]) {
+        self.rebuild_with(x.len(), |i| Vector3::new(x[i], y[i], z[i]));
+    }
+
+    fn rebuild_with(&mut self, n: usize, position: impl Fn(usize) -> Vector3<f64>) {
         // clear heads
         self.head.fill(None);
 
         // reset next pointers for each particle
         self.next.clear();
-        self.next.resize(positions.len(), None); // resize using the length of the particle vec
+        self.next.resize(n, None); // resize using the number of particles
 
-        for (i, p) in positions.iter().enumerate() {
-            let (cx, cy, cz) = self.pos_to_cell(p.position); // bookmark
+        for i in 0..n {
+            let (cx, cy, cz) = self.pos_to_cell(position(i)); // bookmark
             let c = self.cell_id(cx, cy, cz);
 
             // Insert particle i at the head of the cell's linked list:
@@ -226,69 +239,98 @@ impl CellList {
         }
     }
 
+    /// Distinct neighbouring cells of (cx,cy,cz), including the cell itself.
+    ///
+    /// With fewer than 3 cells along an axis the periodic wrap maps several of the
+    /// 27 offsets onto the same cell, which would visit the same pair more than once.
+    fn neighbor_cells(&self, cx: usize, cy: usize, cz: usize) -> Vec<usize> {
+        let mut cells = Vec::with_capacity(27);
+        for dz in -1isize..=1 {
+            for dy in -1isize..=1 {
+                for dx in -1isize..=1 {
+                    let nx = self.wrap_c(cx as isize + dx, self.nx);
+                    let ny = self.wrap_c(cy as isize + dy, self.ny);
+                    let nz = self.wrap_c(cz as isize + dz, self.nz);
+                    let c1 = self.cell_id(nx, ny, nz);
+                    if !cells.contains(&c1) {
+                        cells.push(c1);
+                    }
+                }
+            }
+        }
+        cells
+    }
+
+    /// Total number of cells; the linear cell ids run over 0..n_cells().
+    #[inline]
+    pub fn n_cells(&self) -> usize {
+        self.ncell()
+    }
+
     /// Neighbor traversal: for each particle i, visit candidates j in the 27 neighboring cells.
     ///
     /// This yields pairs (i,j) with j>i (no double-counting),
     /// and you can do your distance check + force calc inside the callback.
-    pub fn for_each_neighbor_pair<F>(&self, positions: &Vec<Particle>, mut f: F)
+    pub fn for_each_neighbor_pair<F>(&self, positions: &[Particle], f: F)
     where
         F: FnMut(usize, usize, Vec3, f64), // (i, j, dr, r2)
+    {
+        self.for_each_neighbor_pair_in_cells(positions, 0..self.ncell(), f);
+    }
+
+    /// Same as `for_each_neighbor_pair`, restricted to the pairs whose particle i lies in
+    /// one of the given cells. Disjoint cell ranges visit disjoint pairs, so the ranges
+    /// can be handed to different threads.
+    pub fn for_each_neighbor_pair_in_cells<F>(
+        &self,
+        positions: &[Particle],
+        cells: std::ops::Range<usize>,
+        mut f: F,
+    ) where
+        F: FnMut(usize, usize, Vec3, f64), // (i, j, dr, r2)
     {
         let rc2 = self.cutoff * self.cutoff;
 
-        // Loop over all cells
-        for cz in 0..self.nz {
-            for cy in 0..self.ny {
-                for cx in 0..self.nx {
-                    let c0 = self.cell_id(cx, cy, cz);
-
-                    // For each particle i in this cell
-                    let mut it_i = self.iter_cell(c0);
-                    while let Some(i) = it_i.next() {
-                        let pi = &positions[i]; // why do we have to refernce this?
-
-                        // Check 27 neighboring cells (including itself)
-                        for dz in -1isize..=1 {
-                            for dy in -1isize..=1 {
-                                for dx in -1isize..=1 {
-                                    let nx = self.wrap_c(cx as isize + dx, self.nx);
-                                    let ny = self.wrap_c(cy as isize + dy, self.ny);
-                                    let nz = self.wrap_c(cz as isize + dz, self.nz);
-                                    let c1 = self.cell_id(nx, ny, nz);
-
-                                    // Walk candidates j in neighbor cell
-                                    let mut it_j = self.iter_cell(c1);
-                                    while let Some(j) = it_j.next() {
-                                        // avoid double-count and self-pair
-                                        if j <= i {
-                                            continue;
-                                        }
-
-                                        let pj = &positions[j];
-
-                                        // Minimum-image displacement
-                                        let dr = Vec3::new(
-                                            min_image(
-                                                pj.position[0] - pi.position[0],
-                                                self.box_len.x,
-                                            ),
-                                            min_image(
-                                                pj.position[1] - pi.position[1],
-                                                self.box_len.y,
-                                            ),
-                                            min_image(
-                                                pj.position[2] - pi.position[2],
-                                                self.box_len.z,
-                                            ),
-                                        );
-                                        let r2 = dr.x * dr.x + dr.y * dr.y + dr.z * dr.z;
-
-                                        if r2 <= rc2 {
-                                            f(i, j, dr, r2);
-                                        }
-                                    }
-                                }
-                            }
+        self.for_each_candidate_pair_in_cells(cells, |i, j| {
+            let (pi, pj) = (&positions[i], &positions[j]);
+
+            // Minimum-image displacement
+            let dr = Vec3::new(
+                min_image(pj.position[0] - pi.position[0], self.box_len.x),
+                min_image(pj.position[1] - pi.position[1], self.box_len.y),
+                min_image(pj.position[2] - pi.position[2], self.box_len.z),
+            );
+            let r2 = dr.x * dr.x + dr.y * dr.y + dr.z * dr.z;
+
+            if r2 <= rc2 {
+                f(i, j, dr, r2);
+            }
+        });
+    }
+
+    /// Candidate pairs (i, j), j > i, of particles in neighbouring cells, with particle i
+    /// in one of the given cells. No distance check is made, so the caller can work on
+    /// its own coordinate storage.
+    pub fn for_each_candidate_pair_in_cells<F>(&self, cells: std::ops::Range<usize>, mut f: F)
+    where
+        F: FnMut(usize, usize),
+    {
+        // Loop over the cells (linear id, x fastest)
+        for c0 in cells {
+            let cx = c0 % self.nx;
+            let cy = (c0 / self.nx) % self.ny;
+            let cz = c0 / (self.nx * self.ny);
+            let neighbors = self.neighbor_cells(cx, cy, cz);
+
+            // For each particle i in this cell
+            for i in self.iter_cell(c0) {
+                // Check the neighboring cells (including itself)
+                for &c1 in neighbors.iter() {
+                    // Walk candidates j in neighbor cell
+                    for j in self.iter_cell(c1) {
+                        // avoid double-count and self-pair
+                        if j > i {
+                            f(i, j);
                         }
                     }
                 }
@@ -0,0 +1,143 @@
+/*
+Bias potentials on collective variables
+
+    harmonic   E = 1/2 k (s - s_0(t))^2,   s_0(t) = s_0 + v t
+    linear     E = c s
+
+A harmonic bias with v = 0 is an umbrella window; with v != 0 the centre is pulled at
+constant velocity (steered MD, constant-velocity pulling) and the work done on the
+system, W = -k v \int (s - s_0(t)) dt, is what Jarzynski's equality post-processes.
+A linear bias applies a constant generalised force -c (constant-force pulling).
+
+The force on atom i is F_i = -dE/ds ds/dx_i. Differences of periodic CVs are wrapped
+to the nearest image of the centre.
+ */
+
+use crate::colvar::colvar::Colvar;
+use crate::lennard_jones_simulations::Particle;
+
+#[derive(Clone, Debug)]
+pub enum BiasPotential {
+    Harmonic { k: f64, center: f64, rate: f64 },
+    Linear { slope: f64 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Bias {
+    pub colvar: Colvar,
+    pub potential: BiasPotential,
+}
+
+impl Bias {
+    pub fn harmonic(colvar: Colvar, k: f64, center: f64) -> Self {
+        Bias {
+            colvar,
+            potential: BiasPotential::Harmonic {
+                k,
+                center,
+                rate: 0.0,
+            },
+        }
+    }
+
+    pub fn center(&self, time: f64) -> Option<f64> {
+        match self.potential {
+            BiasPotential::Harmonic { center, rate, .. } => Some(center + rate * time),
+            BiasPotential::Linear { .. } => None,
+        }
+    }
+
+    pub fn energy_and_derivative(&self, value: f64, time: f64) -> (f64, f64) {
+        /*
+        Bias energy and dE/ds at CV value s
+         */
+        match self.potential {
+            BiasPotential::Harmonic { k, .. } => {
+                let mut diff = value - self.center(time).unwrap_or(0.0);
+                if let Some(period) = self.colvar.period() {
+                    diff -= period * (diff / period).round();
+                }
+                (0.5 * k * diff * diff, k * diff)
+            }
+            BiasPotential::Linear { slope } => (slope * value, slope),
+        }
+    }
+}
+
+pub fn apply_biases(
+    atoms: &mut [Particle],
+    biases: &[Bias],
+    box_length: f64,
+    time: f64,
+) -> (f64, Vec<f64>) {
+    /*
+    Add the bias forces to the atoms; returns the total bias energy and the value of
+    every biased CV
+     */
+    let mut energy = 0.0;
+    let mut values = Vec::with_capacity(biases.len());
+    for bias in biases {
+        let cv = bias.colvar.evaluate(atoms, box_length);
+        let (en, de_ds) = bias.energy_and_derivative(cv.value, time);
+        for (n, g) in cv.gradient {
+            atoms[n].force -= de_ds * g;
+        }
+        energy += en;
+        values.push(cv.value);
+    }
+    (energy, values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_forces_match_energy, particles, Tolerance};
+    use nalgebra::Vector3;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn periodic_harmonic_bias_forces_match_energy() {
+        let positions = [
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::new(-0.9, 1.2, -0.4),
+        ];
+        let mut atoms = particles(&positions, 1.0);
+
+        // a centre just below +pi is close to a torsion just above -pi
+        let bias = Bias {
+            colvar: Colvar::Dihedral {
+                atoms: [0, 1, 2, 3],
+            },
+            potential: BiasPotential::Harmonic {
+                k: 40.0,
+                center: 0.5 * PI,
+                rate: 0.4 * PI,
+            },
+        };
+        let time = 1.0;
+        let energy_at = |atoms: &[Particle]| {
+            let value = bias.colvar.evaluate(atoms, 10.0).value;
+            bias.energy_and_derivative(value, time).0
+        };
+
+        let (energy, values) = apply_biases(&mut atoms, std::slice::from_ref(&bias), 10.0, time);
+        let phi = values[0];
+        assert!(phi < -0.5 * PI, "torsion {phi}");
+        let mut diff = phi - 0.9 * PI;
+        diff -= 2.0 * PI * (diff / (2.0 * PI)).round();
+        assert!((energy - 20.0 * diff * diff).abs() < 1e-12);
+        assert!(diff.abs() < 0.5 * PI);
+
+        let forces: Vec<Vector3<f64>> = atoms.iter().map(|a| a.force).collect();
+        assert_forces_match_energy(
+            &atoms,
+            &forces,
+            energy_at,
+            1e-6,
+            Tolerance::Absolute(1e-5),
+            "dihedral bias",
+        );
+    }
+}
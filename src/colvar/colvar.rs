@@ -0,0 +1,318 @@
+/*
+Collective variables (CVs): scalar functions s(x) of the atom positions, with their
+gradient ds/dx_i, used to bias and monitor a simulation
+
+    distance       s = |r_ij|
+    angle          s = angle(r_ji, r_jk) in [0, pi]
+    dihedral       s = torsion i-j-k-l in (-pi, pi], periodic
+    COM distance   s = |R_B - R_A|, mass-weighted centres of two groups
+    RMSD           s = sqrt(1/N sum_i |x_i - R y_i|^2) after optimal superposition
+                   (Kabsch) of the reference y on the current positions x
+    coordination   s = sum_{i in A, j in B, i != j} (1 - (r_ij/r0)^n) / (1 - (r_ij/r0)^m)
+    volume         s = L^3 of the cubic box
+
+Indices refer to the slice of atoms the CV is evaluated on; for System runs this is
+every atom of every system, concatenated in order. Groups are made whole with the
+minimum image relative to their first atom. The RMSD gradient needs no rotation term
+because the fit is optimal. The box volume does not depend on atom positions, so its
+gradient is empty and a bias on it only adds energy.
+
+References: Fiorin, Klein and Henin, Mol. Phys. 111, 3345 (2013) (Colvars module);
+Kabsch, Acta Cryst. A32, 922 (1976); Iannuzzi, Laio and Parrinello, PRL 90, 238302
+(2003) for the switching function of the coordination number.
+ */
+
+use crate::lennard_jones_simulations::{minimum_image_convention, Particle};
+use crate::molecule::molecule::dihedral_gradient;
+use nalgebra::{Matrix3, Vector3};
+use std::f64::consts::PI;
+
+#[derive(Clone, Debug)]
+pub enum Colvar {
+    Distance {
+        atoms: [usize; 2],
+    },
+    Angle {
+        atoms: [usize; 3],
+    },
+    Dihedral {
+        atoms: [usize; 4],
+    },
+    ComDistance {
+        groups: [Vec<usize>; 2],
+    },
+    Rmsd {
+        atoms: Vec<usize>,
+        reference: Vec<Vector3<f64>>,
+    },
+    CoordinationNumber {
+        groups: [Vec<usize>; 2],
+        r0: f64,
+        n: i32, // 6 and 12 are the usual exponents
+        m: i32,
+    },
+    Volume,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ColvarValue {
+    pub value: f64,
+    pub gradient: Vec<(usize, Vector3<f64>)>, // ds/dx for every atom the CV depends on
+}
+
+impl Colvar {
+    pub fn period(&self) -> Option<f64> {
+        match self {
+            Colvar::Dihedral { .. } => Some(2.0 * PI),
+            _ => None,
+        }
+    }
+
+    pub fn evaluate(&self, atoms: &[Particle], box_length: f64) -> ColvarValue {
+        match self {
+            Colvar::Distance { atoms: [i, j] } => {
+                let d =
+                    minimum_image_convention(atoms[*j].position - atoms[*i].position, box_length);
+                let r = d.norm();
+                let u = if r > 0.0 { d / r } else { Vector3::zeros() };
+                ColvarValue {
+                    value: r,
+                    gradient: vec![(*i, -u), (*j, u)],
+                }
+            }
+            Colvar::Angle { atoms: [i, j, k] } => {
+                let a =
+                    minimum_image_convention(atoms[*i].position - atoms[*j].position, box_length);
+                let b =
+                    minimum_image_convention(atoms[*k].position - atoms[*j].position, box_length);
+                let (a_norm, b_norm) = (a.norm(), b.norm());
+                let cos = (a.dot(&b) / (a_norm * b_norm)).clamp(-1.0, 1.0);
+                let theta = a.cross(&b).norm().atan2(a.dot(&b));
+                let sin = theta.sin().max(1e-12);
+                // d(theta)/dr = -d(cos)/dr / sin
+                let gi = -(b / b_norm - cos * a / a_norm) / (a_norm * sin);
+                let gk = -(a / a_norm - cos * b / b_norm) / (b_norm * sin);
+                ColvarValue {
+                    value: theta,
+                    gradient: vec![(*i, gi), (*j, -gi - gk), (*k, gk)],
+                }
+            }
+            Colvar::Dihedral { atoms: idx } => {
+                let r = idx.map(|n| atoms[n].position);
+                match dihedral_gradient(&r, box_length) {
+                    Some((phi, g)) => ColvarValue {
+                        value: phi,
+                        gradient: idx.iter().copied().zip(g).collect(),
+                    },
+                    None => ColvarValue::default(),
+                }
+            }
+            Colvar::ComDistance { groups } => {
+                let (com_a, mass_a) = centre_of_mass(atoms, &groups[0], box_length);
+                let (com_b, mass_b) = centre_of_mass(atoms, &groups[1], box_length);
+                let d = minimum_image_convention(com_b - com_a, box_length);
+                let r = d.norm();
+                let u = if r > 0.0 { d / r } else { Vector3::zeros() };
+                let gradient = groups[0]
+                    .iter()
+                    .map(|&n| (n, -atoms[n].mass / mass_a * u))
+                    .chain(groups[1].iter().map(|&n| (n, atoms[n].mass / mass_b * u)))
+                    .collect();
+                ColvarValue { value: r, gradient }
+            }
+            Colvar::Rmsd {
+                atoms: idx,
+                reference,
+            } => rmsd(atoms, idx, reference, box_length),
+            Colvar::CoordinationNumber { groups, r0, n, m } => {
+                let mut value = 0.0;
+                let mut gradient = Vec::new();
+                for &i in &groups[0] {
+                    for &j in &groups[1] {
+                        if i == j {
+                            continue;
+                        }
+                        let d = minimum_image_convention(
+                            atoms[j].position - atoms[i].position,
+                            box_length,
+                        );
+                        let r = d.norm();
+                        let (s, ds_dr) = switching(r / r0, *n, *m);
+                        value += s;
+                        let g = ds_dr / r0 * d / r;
+                        gradient.push((i, -g));
+                        gradient.push((j, g));
+                    }
+                }
+                ColvarValue { value, gradient }
+            }
+            Colvar::Volume => ColvarValue {
+                value: box_length.powi(3),
+                gradient: Vec::new(),
+            },
+        }
+    }
+}
+
+fn centre_of_mass(atoms: &[Particle], group: &[usize], box_length: f64) -> (Vector3<f64>, f64) {
+    /*
+    Mass-weighted centre of a group made whole around its first atom
+     */
+    let origin = atoms[group[0]].position;
+    let mut weighted = Vector3::zeros();
+    let mut total = 0.0;
+    for &n in group {
+        let x = origin + minimum_image_convention(atoms[n].position - origin, box_length);
+        weighted += atoms[n].mass * x;
+        total += atoms[n].mass;
+    }
+    (weighted / total, total)
+}
+
+fn switching(x: f64, n: i32, m: i32) -> (f64, f64) {
+    /*
+    (1 - x^n) / (1 - x^m) and its derivative; the removable singularity at x = 1 is
+    stepped around
+     */
+    let x = if (x - 1.0).abs() < 1e-6 {
+        1.0 + 1e-6
+    } else {
+        x
+    };
+    let (xn, xm) = (x.powi(n), x.powi(m));
+    let (num, den) = (1.0 - xn, 1.0 - xm);
+    let d_num = -(n as f64) * xn / x;
+    let d_den = -(m as f64) * xm / x;
+    (num / den, (d_num * den - num * d_den) / (den * den))
+}
+
+fn rmsd(
+    atoms: &[Particle],
+    idx: &[usize],
+    reference: &[Vector3<f64>],
+    box_length: f64,
+) -> ColvarValue {
+    let count = idx.len() as f64;
+    let origin = atoms[idx[0]].position;
+    let x: Vec<Vector3<f64>> = idx
+        .iter()
+        .map(|&n| origin + minimum_image_convention(atoms[n].position - origin, box_length))
+        .collect();
+    let x_centre = x.iter().sum::<Vector3<f64>>() / count;
+    let y_centre = reference.iter().sum::<Vector3<f64>>() / count;
+
+    // Kabsch: the rotation R minimising sum |x_c - R y_c|^2
+    let mut h = Matrix3::zeros();
+    for (xi, yi) in x.iter().zip(reference) {
+        h += (yi - y_centre) * (xi - x_centre).transpose();
+    }
+    let svd = h.svd(true, true);
+    let (u, v_t) = (svd.u.unwrap(), svd.v_t.unwrap());
+    let v = v_t.transpose();
+    let sign = (v * u.transpose()).determinant().signum();
+    let rotation = v * Matrix3::from_diagonal(&Vector3::new(1.0, 1.0, sign)) * u.transpose();
+
+    let residuals: Vec<Vector3<f64>> = x
+        .iter()
+        .zip(reference)
+        .map(|(xi, yi)| (xi - x_centre) - rotation * (yi - y_centre))
+        .collect();
+    let value = (residuals.iter().map(|r| r.norm_squared()).sum::<f64>() / count).sqrt();
+    let scale = if value > 0.0 {
+        1.0 / (count * value)
+    } else {
+        0.0
+    };
+    ColvarValue {
+        value,
+        gradient: idx
+            .iter()
+            .zip(residuals)
+            .map(|(&n, r)| (n, scale * r))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_forces_match_energy, particles, Tolerance};
+    use nalgebra::Rotation3;
+
+    #[test]
+    fn colvar_gradients_match_finite_differences() {
+        let box_length = 6.0;
+        let positions = vec![
+            Vector3::new(0.2, 0.1, 0.3),
+            Vector3::new(1.1, 0.4, 0.2),
+            Vector3::new(1.5, 1.3, 0.6),
+            Vector3::new(2.4, 1.5, 1.4),
+            Vector3::new(5.7, 0.8, 0.1), // across the boundary from atom 0
+        ];
+        let rotation = Rotation3::from_euler_angles(0.3, -0.2, 0.5);
+        let reference: Vec<Vector3<f64>> = positions[..4]
+            .iter()
+            .enumerate()
+            .map(|(n, p)| rotation * p + Vector3::new(0.05 * n as f64, -0.04, 0.02))
+            .collect();
+        let colvars = vec![
+            Colvar::Distance { atoms: [0, 4] },
+            Colvar::Angle { atoms: [0, 1, 2] },
+            Colvar::Dihedral {
+                atoms: [0, 1, 2, 3],
+            },
+            Colvar::ComDistance {
+                groups: [vec![0, 4], vec![2, 3]],
+            },
+            Colvar::Rmsd {
+                atoms: vec![0, 1, 2, 3],
+                reference,
+            },
+            Colvar::CoordinationNumber {
+                groups: [vec![0, 1], vec![1, 2, 3, 4]],
+                r0: 1.2,
+                n: 6,
+                m: 12,
+            },
+        ];
+
+        // unequal masses, so the centres of mass differ from the centroids
+        let weighted = |positions: &[Vector3<f64>]| {
+            let mut atoms = particles(positions, 1.0);
+            for (id, a) in atoms.iter_mut().enumerate() {
+                a.mass = 1.0 + id as f64;
+            }
+            atoms
+        };
+        let atoms = weighted(&positions);
+        for colvar in &colvars {
+            let value = colvar.evaluate(&atoms, box_length);
+            assert!(value.value.is_finite() && value.value != 0.0, "{colvar:?}");
+            let mut gradient = vec![Vector3::zeros(); atoms.len()];
+            for (atom, g) in &value.gradient {
+                gradient[*atom] += g;
+            }
+            // the gradient of s is the force of the energy -s
+            assert_forces_match_energy(
+                &atoms,
+                &gradient,
+                |a| -colvar.evaluate(a, box_length).value,
+                1e-6,
+                Tolerance::Absolute(1e-6),
+                &format!("{colvar:?}"),
+            );
+        }
+
+        // a rigidly moved copy of the reference has zero RMSD
+        let moved: Vec<Vector3<f64>> = positions[..4]
+            .iter()
+            .map(|p| rotation * p + Vector3::new(0.5, 0.5, 0.5))
+            .collect();
+        let rmsd = Colvar::Rmsd {
+            atoms: vec![0, 1, 2, 3],
+            reference: positions[..4].to_vec(),
+        };
+        assert!(rmsd.evaluate(&weighted(&moved), box_length).value < 1e-10);
+        assert!((Colvar::Volume.evaluate(&atoms, box_length).value - 216.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,2 @@
+pub mod bias;
+pub mod colvar;
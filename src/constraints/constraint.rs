@@ -0,0 +1,129 @@
+/*
+Holonomic bond-length constraints shared by the constraint solvers
+
+Constraints are built from the harmonic bonds of a `System`: either every bond or
+only those involving a hydrogen. Hydrogens are recognised by the element the topology
+readers record in `System::elements` (the at.num of a GROMACS atom type, the element
+of a CHARMM MASS record, else an atom name starting with H), never by their mass, so
+the selection holds with hydrogen mass repartitioning and in reduced units. Each
+constraint removes one degree of freedom and each rigid (SETTLE) water three, which
+`constrained_dof` accounts for when temperatures are computed.
+
+The solver is chosen per run through `ConstraintOptions::algorithm`; SHAKE/RATTLE
+and LINCS take the same constraint lists and can be swapped freely.
+ */
+
+use crate::constraints::lincs::{lincs_positions, lincs_velocities};
+use crate::constraints::shake::{rattle_velocities, shake_positions};
+use crate::lennard_jones_simulations::Particle;
+use crate::molecule::molecule::{BondForm, Settle, System};
+use nalgebra::Vector3;
+
+#[derive(Clone, Debug)]
+pub struct Constraint {
+    pub atom1: usize,
+    pub atom2: usize,
+    pub length: f64,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ConstraintSelection {
+    #[default]
+    None,
+    HBonds, // bonds with a hydrogen (System::is_hydrogen)
+    AllBonds,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ConstraintAlgorithm {
+    #[default]
+    Shake, // SHAKE for positions, RATTLE for velocities
+    Lincs,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ConstraintOptions {
+    pub bonds: ConstraintSelection,
+    pub algorithm: ConstraintAlgorithm,
+    pub tolerance: f64, // SHAKE/RATTLE: relative tolerance on the constrained lengths
+    pub max_iterations: usize, // SHAKE/RATTLE
+    pub lincs_order: usize, // LINCS: terms of the matrix expansion
+    pub lincs_iterations: usize, // LINCS: rotational correction sweeps
+}
+
+impl Default for ConstraintOptions {
+    fn default() -> Self {
+        ConstraintOptions {
+            bonds: ConstraintSelection::None,
+            algorithm: ConstraintAlgorithm::Shake,
+            tolerance: 1e-8,
+            max_iterations: 1000,
+            lincs_order: 4,
+            lincs_iterations: 1,
+        }
+    }
+}
+
+pub fn constraints_from_bonds(system: &System, selection: ConstraintSelection) -> Vec<Constraint> {
+    /*
+    Only harmonic bonds are constrained: the other forms (FENE, tabulated, ...) have
+    no rest length to hold (r0 is 0) and keep their forces
+     */
+    system
+        .bonds
+        .iter()
+        .filter(|b| matches!(b.form, BondForm::Harmonic))
+        .filter(|b| match selection {
+            ConstraintSelection::None => false,
+            ConstraintSelection::HBonds => {
+                system.is_hydrogen(b.atom1) || system.is_hydrogen(b.atom2)
+            }
+            ConstraintSelection::AllBonds => true,
+        })
+        .map(|b| Constraint {
+            atom1: b.atom1,
+            atom2: b.atom2,
+            length: b.r0,
+        })
+        .collect()
+}
+
+pub fn constrained_dof(n_atoms: usize, constraints: &[Constraint], settles: &[Settle]) -> usize {
+    (3 * n_atoms).saturating_sub(constraints.len() + 3 * settles.len())
+}
+
+pub fn constrain_positions(
+    atoms: &mut [Particle],
+    reference: &[Vector3<f64>],
+    constraints: &[Constraint],
+    dt: f64,
+    box_length: f64,
+    options: &ConstraintOptions,
+) -> Result<(), String> {
+    /*
+    Constrain the positions after the drift with the selected algorithm; the
+    half-step velocities pick up the constraint displacement / dt
+     */
+    match options.algorithm {
+        ConstraintAlgorithm::Shake => {
+            shake_positions(atoms, reference, constraints, dt, box_length, options).map(|_| ())
+        }
+        ConstraintAlgorithm::Lincs => {
+            lincs_positions(atoms, reference, constraints, dt, box_length, options)
+        }
+    }
+}
+
+pub fn constrain_velocities(
+    atoms: &mut [Particle],
+    constraints: &[Constraint],
+    box_length: f64,
+    options: &ConstraintOptions,
+) -> Result<(), String> {
+    match options.algorithm {
+        ConstraintAlgorithm::Shake => {
+            rattle_velocities(atoms, constraints, box_length, options).map(|_| ())
+        }
+        ConstraintAlgorithm::Lincs => lincs_velocities(atoms, constraints, box_length, options),
+    }
+}
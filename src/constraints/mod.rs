@@ -0,0 +1,4 @@
+pub mod constraint;
+pub mod lincs;
+pub mod settle;
+pub mod shake;
@@ -0,0 +1,255 @@
+/*
+SETTLE analytical constraints for rigid 3-site water (S. Miyamoto and P. A. Kollman,
+J. Comput. Chem. 13, 952 (1992))
+
+Positions: the unconstrained water is expressed in a frame with z normal to the
+water plane at the start of the step and x/y chosen from the new oxygen position.
+The canonical rigid triangle (O at distance ra from the centre of mass along the
+bisector, hydrogens at rb and +-rc) is tilted by phi and psi to match the new
+out-of-plane displacements and rotated by theta about z; all three angles follow
+in closed form, so no iteration is needed and the result is exact to round-off.
+
+Velocities: the three constraint impulses that cancel the relative velocities
+along O-H1, O-H2 and H1-H2 follow from a 3x3 linear system, solved directly.
+ */
+
+use crate::lennard_jones_simulations::{minimum_image_convention, Particle};
+use crate::molecule::molecule::Settle;
+use nalgebra::{Matrix3, Vector3};
+
+pub fn settle_positions(
+    atoms: &mut [Particle],
+    reference: &[Vector3<f64>],
+    settles: &[Settle],
+    dt: f64,
+    box_length: f64,
+) -> Result<(), String> {
+    /*
+    Constrain every water after the drift. `reference` holds the positions at the
+    start of the step; the half-step velocities pick up displacement / dt.
+     */
+    for settle in settles {
+        let [o, h1, h2] = settle.atoms();
+        let (m_o, m_h) = (atoms[o].mass, atoms[h1].mass);
+        let m_tot = m_o + 2.0 * m_h;
+
+        // start-of-step geometry relative to the oxygen
+        let b0 = minimum_image_convention(reference[h1] - reference[o], box_length);
+        let c0 = minimum_image_convention(reference[h2] - reference[o], box_length);
+
+        // new positions, unwrapped around the oxygen, relative to their centre of mass
+        let o_pos = atoms[o].position;
+        let unwrapped = [
+            o_pos,
+            o_pos + minimum_image_convention(atoms[h1].position - o_pos, box_length),
+            o_pos + minimum_image_convention(atoms[h2].position - o_pos, box_length),
+        ];
+        let com = (m_o * unwrapped[0] + m_h * (unwrapped[1] + unwrapped[2])) / m_tot;
+        let (a1, b1, c1) = (unwrapped[0] - com, unwrapped[1] - com, unwrapped[2] - com);
+
+        // canonical triangle
+        let rc = 0.5 * settle.d_hh;
+        let height = (settle.d_oh * settle.d_oh - rc * rc).sqrt();
+        let ra = 2.0 * m_h * height / m_tot;
+        let rb = height - ra;
+
+        let z_axis = b0.cross(&c0);
+        let x_axis = a1.cross(&z_axis);
+        let y_axis = z_axis.cross(&x_axis);
+        let (x_axis, y_axis, z_axis) = (x_axis.normalize(), y_axis.normalize(), z_axis.normalize());
+        let to_frame = |v: &Vector3<f64>| Vector3::new(x_axis.dot(v), y_axis.dot(v), z_axis.dot(v));
+
+        let (b0d, c0d) = (to_frame(&b0), to_frame(&c0));
+        let (a1d, b1d, c1d) = (to_frame(&a1), to_frame(&b1), to_frame(&c1));
+
+        let sin_phi = a1d.z / ra;
+        let cos_phi_sq = 1.0 - sin_phi * sin_phi;
+        if cos_phi_sq <= 0.0 {
+            return Err(format!("SETTLE: water {o} is too distorted"));
+        }
+        let cos_phi = cos_phi_sq.sqrt();
+        let sin_psi = (b1d.z - c1d.z) / (2.0 * rc * cos_phi);
+        let cos_psi_sq = 1.0 - sin_psi * sin_psi;
+        if cos_psi_sq <= 0.0 {
+            return Err(format!("SETTLE: water {o} is too distorted"));
+        }
+        let cos_psi = cos_psi_sq.sqrt();
+
+        let ya2 = ra * cos_phi;
+        let xb2 = -rc * cos_psi;
+        let yb2 = -rb * cos_phi - rc * sin_psi * sin_phi;
+        let yc2 = -rb * cos_phi + rc * sin_psi * sin_phi;
+
+        let alpha = xb2 * (b0d.x - c0d.x) + b0d.y * yb2 + c0d.y * yc2;
+        let beta = xb2 * (c0d.y - b0d.y) + b0d.x * yb2 + c0d.x * yc2;
+        let gamma = b0d.x * b1d.y - b1d.x * b0d.y + c0d.x * c1d.y - c1d.x * c0d.y;
+        let al2be2 = alpha * alpha + beta * beta;
+        let disc = al2be2 - gamma * gamma;
+        if disc < 0.0 {
+            return Err(format!("SETTLE: water {o} rotated too far during the step"));
+        }
+        let sin_theta = (alpha * gamma - beta * disc.sqrt()) / al2be2;
+        let cos_theta = (1.0 - sin_theta * sin_theta).sqrt();
+
+        let a3d = Vector3::new(-ya2 * sin_theta, ya2 * cos_theta, a1d.z);
+        let b3d = Vector3::new(
+            xb2 * cos_theta - yb2 * sin_theta,
+            xb2 * sin_theta + yb2 * cos_theta,
+            b1d.z,
+        );
+        let c3d = Vector3::new(
+            -xb2 * cos_theta - yc2 * sin_theta,
+            -xb2 * sin_theta + yc2 * cos_theta,
+            c1d.z,
+        );
+        let from_frame = |v: Vector3<f64>| com + x_axis * v.x + y_axis * v.y + z_axis * v.z;
+
+        for ((idx, new), old) in [(o, a3d), (h1, b3d), (h2, c3d)].into_iter().zip(unwrapped) {
+            // displacement applied to the atom in whichever periodic image it sits
+            let displacement = from_frame(new) - old;
+            atoms[idx].position += displacement;
+            if dt > 0.0 {
+                atoms[idx].velocity += displacement / dt;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn settle_velocities(
+    atoms: &mut [Particle],
+    settles: &[Settle],
+    box_length: f64,
+) -> Result<(), String> {
+    /*
+    Remove the relative velocities along the three distances of every water. With
+    e_k the unit vector of constraint k = (i, j) and g_k its impulse,
+
+        v_i -= g_k e_k / m_i,  v_j += g_k e_k / m_j,  e_k . (v_j - v_i) = 0
+     */
+    for settle in settles {
+        let [o, h1, h2] = settle.atoms();
+        let pairs = [(o, h1), (o, h2), (h1, h2)];
+        let e: Vec<Vector3<f64>> = pairs
+            .iter()
+            .map(|&(i, j)| {
+                minimum_image_convention(atoms[j].position - atoms[i].position, box_length)
+                    .normalize()
+            })
+            .collect();
+
+        // velocity change of atom `a` per unit impulse of constraint l
+        let response = |a: usize, l: usize| -> Vector3<f64> {
+            let (i, j) = pairs[l];
+            if a == i {
+                -e[l] / atoms[a].mass
+            } else if a == j {
+                e[l] / atoms[a].mass
+            } else {
+                Vector3::zeros()
+            }
+        };
+
+        let mut matrix = Matrix3::zeros();
+        let mut rhs = Vector3::zeros();
+        for (k, &(i, j)) in pairs.iter().enumerate() {
+            for l in 0..3 {
+                matrix[(k, l)] = e[k].dot(&(response(j, l) - response(i, l)));
+            }
+            rhs[k] = -e[k].dot(&(atoms[j].velocity - atoms[i].velocity));
+        }
+        let impulses = matrix
+            .try_inverse()
+            .ok_or_else(|| format!("SETTLE: water {o} is degenerate"))?
+            * rhs;
+
+        for (l, g) in impulses.iter().enumerate() {
+            let (i, j) = pairs[l];
+            atoms[i].velocity -= *g * e[l] / atoms[i].mass;
+            atoms[j].velocity += *g * e[l] / atoms[j].mass;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constraints::constraint::{Constraint, ConstraintOptions};
+    use crate::constraints::shake::{rattle_velocities, shake_positions};
+    use crate::test_support::particle;
+
+    #[test]
+    fn settle_matches_converged_shake() {
+        // TIP3P geometry (nm), with the water straddling the box boundary
+        let settle = Settle {
+            oxygen: 0,
+            d_oh: 0.09572,
+            d_hh: 0.15139,
+        };
+        let reference = vec![
+            Vector3::new(2.99, 1.0, 1.0),
+            Vector3::new(3.0465, 1.0773, 1.0),
+            Vector3::new(2.9335 + 3.0, 1.0773, 1.0),
+        ];
+        let kicks = [
+            Vector3::new(0.0011, -0.0008, 0.0005),
+            Vector3::new(0.004, 0.006, -0.007),
+            Vector3::new(-0.009, 0.002, 0.005),
+        ];
+        let velocities = [
+            Vector3::new(0.3, 0.1, -0.2),
+            Vector3::new(2.0, -1.0, 1.5),
+            Vector3::new(-1.5, 2.5, -0.5),
+        ];
+        let masses = [15.9994, 1.008, 1.008];
+        let atoms: Vec<Particle> = (0..3)
+            .map(|i| Particle {
+                velocity: velocities[i],
+                ..particle(reference[i] + kicks[i], masses[i])
+            })
+            .collect();
+        let (dt, box_length) = (0.002, 3.0);
+
+        let mut settled = atoms.clone();
+        settle_positions(&mut settled, &reference, &[settle.clone()], dt, box_length).unwrap();
+
+        let constraints: Vec<Constraint> = [
+            (0, 1, settle.d_oh),
+            (0, 2, settle.d_oh),
+            (1, 2, settle.d_hh),
+        ]
+        .into_iter()
+        .map(|(atom1, atom2, length)| Constraint {
+            atom1,
+            atom2,
+            length,
+        })
+        .collect();
+        let options = ConstraintOptions {
+            tolerance: 1e-14,
+            ..Default::default()
+        };
+        let mut shaken = atoms.clone();
+        shake_positions(
+            &mut shaken,
+            &reference,
+            &constraints,
+            dt,
+            box_length,
+            &options,
+        )
+        .unwrap();
+
+        for (a, b) in settled.iter().zip(shaken.iter()) {
+            assert!((a.position - b.position).norm() < 1e-10);
+            assert!((a.velocity - b.velocity).norm() < 1e-7);
+        }
+
+        settle_velocities(&mut settled, &[settle], box_length).unwrap();
+        rattle_velocities(&mut shaken, &constraints, box_length, &options).unwrap();
+        for (a, b) in settled.iter().zip(shaken.iter()) {
+            assert!((a.velocity - b.velocity).norm() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+/*
+SHAKE (Ryckaert, Ciccotti and Berendsen, J. Comput. Phys. 23, 327 (1977)) and RATTLE
+(Andersen, J. Comput. Phys. 52, 24 (1983)) for velocity Verlet
+
+After the drift, SHAKE moves every constrained pair along its bond vector from the
+start of the step until |r_ij|^2 = d^2,
+
+    g = (d^2 - |r_ij|^2) / (2 r_ij . r_ij(t) (1/m_i + 1/m_j))
+    r_i += g r_ij(t) / m_i,  r_j -= g r_ij(t) / m_j
+
+and the half-step velocities receive the same displacement divided by dt. After the
+second half kick RATTLE removes the velocity component along each constraint,
+
+    k = r_ij . v_ij / (d^2 (1/m_i + 1/m_j))
+    v_i -= k r_ij / m_i,  v_j += k r_ij / m_j
+
+Both sweeps are repeated until every constraint meets the relative tolerance.
+ */
+
+use crate::constraints::constraint::{Constraint, ConstraintOptions};
+use crate::lennard_jones_simulations::{minimum_image_convention, Particle};
+use nalgebra::Vector3;
+
+fn inverse_masses(atoms: &[Particle], c: &Constraint) -> (f64, f64) {
+    (1.0 / atoms[c.atom1].mass, 1.0 / atoms[c.atom2].mass)
+}
+
+pub fn shake_positions(
+    atoms: &mut [Particle],
+    reference: &[Vector3<f64>],
+    constraints: &[Constraint],
+    dt: f64,
+    box_length: f64,
+    options: &ConstraintOptions,
+) -> Result<usize, String> {
+    /*
+    Constrain the positions after the drift. `reference` holds the (constrained)
+    positions at the start of the step, which fix the direction of the corrections.
+    Returns the number of iterations used.
+     */
+    if constraints.is_empty() {
+        return Ok(0);
+    }
+    let unconstrained: Vec<Vector3<f64>> = atoms.iter().map(|a| a.position).collect();
+
+    for iteration in 1..=options.max_iterations {
+        let mut converged = true;
+        for c in constraints {
+            let (i, j) = (c.atom1, c.atom2);
+            let r_ij = minimum_image_convention(atoms[i].position - atoms[j].position, box_length);
+            let d2 = c.length * c.length;
+            let diff = d2 - r_ij.norm_squared();
+            if diff.abs() <= 2.0 * options.tolerance * d2 {
+                continue;
+            }
+            converged = false;
+
+            let r_ref = minimum_image_convention(reference[i] - reference[j], box_length);
+            let (inv_mi, inv_mj) = inverse_masses(atoms, c);
+            let denom = 2.0 * r_ij.dot(&r_ref) * (inv_mi + inv_mj);
+            if denom.abs() <= 1e-12 * d2 {
+                return Err(format!(
+                    "SHAKE: constraint {i}-{j} rotated too far during the step"
+                ));
+            }
+            let g = diff / denom;
+            atoms[i].position += g * inv_mi * r_ref;
+            atoms[j].position -= g * inv_mj * r_ref;
+        }
+
+        if converged {
+            if dt > 0.0 {
+                for (a, r0) in atoms.iter_mut().zip(unconstrained.iter()) {
+                    a.velocity += (a.position - r0) / dt;
+                }
+            }
+            return Ok(iteration);
+        }
+    }
+
+    Err(format!(
+        "SHAKE did not converge in {} iterations",
+        options.max_iterations
+    ))
+}
+
+pub fn rattle_velocities(
+    atoms: &mut [Particle],
+    constraints: &[Constraint],
+    box_length: f64,
+    options: &ConstraintOptions,
+) -> Result<usize, String> {
+    /*
+    Remove the velocity components along the constraints after the second half kick
+     */
+    if constraints.is_empty() {
+        return Ok(0);
+    }
+
+    for iteration in 1..=options.max_iterations {
+        let mut converged = true;
+        for c in constraints {
+            let (i, j) = (c.atom1, c.atom2);
+            let r_ij = minimum_image_convention(atoms[i].position - atoms[j].position, box_length);
+            let rv = r_ij.dot(&(atoms[i].velocity - atoms[j].velocity));
+            let d2 = c.length * c.length;
+            // relative to the bond length times a unit velocity
+            if rv.abs() <= options.tolerance * d2 {
+                continue;
+            }
+            converged = false;
+
+            let (inv_mi, inv_mj) = inverse_masses(atoms, c);
+            let k = rv / (d2 * (inv_mi + inv_mj));
+            atoms[i].velocity -= k * inv_mi * r_ij;
+            atoms[j].velocity += k * inv_mj * r_ij;
+        }
+        if converged {
+            return Ok(iteration);
+        }
+    }
+
+    Err(format!(
+        "RATTLE did not converge in {} iterations",
+        options.max_iterations
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constraints::constraint::{
+        constrained_dof, constraints_from_bonds, ConstraintSelection,
+    };
+    use crate::molecule::molecule::{Bond, BondForm, System};
+    use crate::test_support::particle;
+
+    fn water() -> System {
+        // rigid-bond water: O-H bonds constrained, free H-O-H angle
+        let bond = |atom2| Bond {
+            atom1: 0,
+            atom2,
+            k: 1000.0,
+            r0: 0.9572,
+            form: BondForm::Harmonic,
+        };
+        System {
+            atoms: vec![
+                Particle {
+                    velocity: Vector3::new(0.3, -0.2, 0.1),
+                    ..particle(Vector3::new(5.0, 5.0, 5.0), 15.999)
+                },
+                Particle {
+                    velocity: Vector3::new(-2.0, 1.5, 0.5),
+                    ..particle(Vector3::new(5.9572, 5.0, 5.0), 1.008)
+                },
+                Particle {
+                    velocity: Vector3::new(1.0, 2.5, -1.5),
+                    ..particle(Vector3::new(4.76, 5.927, 5.0), 1.008)
+                },
+            ],
+            bonds: vec![bond(1), bond(2)],
+            elements: vec![8, 1, 1],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_harmonic_bonds_become_constraints() {
+        let mut system = water();
+        system.bonds[1].r0 = 0.0;
+        system.bonds[1].form = BondForm::Fene {
+            r_max: 1.5,
+            epsilon: 0.0,
+            sigma: 0.0,
+        };
+        let constraints = constraints_from_bonds(&system, ConstraintSelection::AllBonds);
+        assert_eq!(constraints.len(), 1);
+        assert_eq!(constraints[0].atom2, 1);
+        assert!((constraints[0].length - 0.9572).abs() < 1e-12);
+    }
+
+    #[test]
+    fn hydrogen_bonds_are_selected_by_element() {
+        // hydrogen mass repartitioning moves mass from the oxygen onto the hydrogens
+        let mut system = water();
+        system.atoms[0].mass = 11.967;
+        system.atoms[1].mass = 3.024;
+        system.atoms[2].mass = 3.024;
+        let constraints = constraints_from_bonds(&system, ConstraintSelection::HBonds);
+        assert_eq!(constraints.len(), 2);
+
+        // reduced units: unit masses, but no hydrogens
+        system.elements = vec![0; 3];
+        for a in system.atoms.iter_mut() {
+            a.mass = 1.0;
+        }
+        assert!(constraints_from_bonds(&system, ConstraintSelection::HBonds).is_empty());
+    }
+
+    #[test]
+    fn shake_and_rattle_keep_bond_lengths_during_free_flight() {
+        let mut system = water();
+        let constraints = constraints_from_bonds(&system, ConstraintSelection::HBonds);
+        assert_eq!(constraints.len(), 2);
+        assert_eq!(constrained_dof(system.atoms.len(), &constraints, &[]), 7);
+
+        let options = ConstraintOptions::default();
+        let (dt, box_length) = (0.01, 20.0);
+        rattle_velocities(&mut system.atoms, &constraints, box_length, &options).unwrap();
+        let momentum = |a: &[Particle]| a.iter().map(|p| p.mass * p.velocity).sum::<Vector3<f64>>();
+        let p0 = momentum(&system.atoms);
+
+        for _ in 0..200 {
+            let reference: Vec<Vector3<f64>> = system.atoms.iter().map(|a| a.position).collect();
+            for a in system.atoms.iter_mut() {
+                a.position += a.velocity * dt;
+            }
+            shake_positions(
+                &mut system.atoms,
+                &reference,
+                &constraints,
+                dt,
+                box_length,
+                &options,
+            )
+            .unwrap();
+            rattle_velocities(&mut system.atoms, &constraints, box_length, &options).unwrap();
+        }
+
+        for c in &constraints {
+            let r_ij = system.atoms[c.atom1].position - system.atoms[c.atom2].position;
+            let v_ij = system.atoms[c.atom1].velocity - system.atoms[c.atom2].velocity;
+            assert!((r_ij.norm() - c.length).abs() < 1e-6);
+            assert!(r_ij.dot(&v_ij).abs() < 1e-6);
+        }
+        assert!((momentum(&system.atoms) - p0).norm() < 1e-9);
+    }
+}
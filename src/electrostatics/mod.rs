@@ -0,0 +1,6 @@
+pub mod damped;
+pub mod external_field;
+pub mod reaction_field;
+pub mod slab;
+pub mod spme;
+pub mod tuning;
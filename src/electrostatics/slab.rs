@@ -0,0 +1,95 @@
+/*
+Yeh-Berkowitz slab correction (I.-C. Yeh and M. L. Berkowitz, J. Chem. Phys. 111,
+3155 (1999)) for systems that are periodic in x and y only
+
+The 3D Ewald sum is used with the box extended along z by a vacuum factor (typically
+3), and the spurious interaction between the periodic slab images is removed by the
+dipole term
+
+    E = (2 pi k_e / V) [M_z^2 - Q sum_i q_i z_i^2 - Q^2 L_z^2 / 12]
+    F_iz = -(4 pi k_e / V) q_i [M_z - Q z_i]
+
+with M_z = sum_i q_i z_i, Q the net charge (the last two terms vanish for neutral
+systems, Ballenegger et al., J. Chem. Phys. 131, 094107 (2009)) and V the volume of
+the extended box.
+
+Under a strain along z every term of E scales with L_z^2 / V, so the virial tensor
+W_ab = -dE/d(eps_ab) is diag(E, E, -E); compute_pressure_systems adds it.
+
+Only the reciprocal box is extended. Real-space Ewald and LJ pairs still use the cubic
+minimum image over L, so the box handed to the driver must already contain a vacuum
+gap of at least the cutoff between the slab and its periodic image along z.
+ */
+
+use crate::lennard_jones_simulations::Particle;
+use nalgebra::{Matrix3, Vector3};
+use std::f64::consts::PI;
+
+pub struct SlabCorrection {
+    pub energy: f64,
+    pub forces: Vec<Vector3<f64>>,
+    pub virial: Matrix3<f64>,
+}
+
+pub fn slab_correction(particles: &[Particle], box_dims: Vector3<f64>, k_e: f64) -> SlabCorrection {
+    let volume = box_dims.x * box_dims.y * box_dims.z;
+    let pref = 2.0 * PI * k_e / volume;
+
+    let mut m_z = 0.0;
+    let mut q_tot = 0.0;
+    let mut q_z2 = 0.0;
+    for p in particles {
+        m_z += p.charge * p.position.z;
+        q_tot += p.charge;
+        q_z2 += p.charge * p.position.z * p.position.z;
+    }
+
+    let energy = pref * (m_z * m_z - q_tot * q_z2 - q_tot * q_tot * box_dims.z * box_dims.z / 12.0);
+    let forces = particles
+        .iter()
+        .map(|p| {
+            Vector3::new(
+                0.0,
+                0.0,
+                -2.0 * pref * p.charge * (m_z - q_tot * p.position.z),
+            )
+        })
+        .collect();
+    let virial = Matrix3::from_diagonal(&Vector3::new(energy, energy, -energy));
+
+    SlabCorrection {
+        energy,
+        forces,
+        virial,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_forces_match_energy, particle, Tolerance};
+
+    fn ion(z: f64, charge: f64) -> Particle {
+        Particle {
+            charge,
+            ..particle(Vector3::new(1.0, 2.0, z), 1.0)
+        }
+    }
+
+    #[test]
+    fn slab_forces_are_energy_gradients() {
+        let dims = Vector3::new(10.0, 10.0, 30.0);
+        let ions = vec![ion(2.0, 1.0), ion(5.5, -0.6), ion(7.0, -0.2)];
+        let corr = slab_correction(&ions, dims, 1.0);
+
+        assert_forces_match_energy(
+            &ions,
+            &corr.forces,
+            |a| slab_correction(a, dims, 1.0).energy,
+            1e-6,
+            Tolerance::Absolute(1e-6),
+            "slab",
+        );
+        assert!((corr.virial.trace() - corr.energy).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,328 @@
+/*
+Smooth particle-mesh Ewald (Essmann et al., J. Chem. Phys. 103, 8577 (1995))
+
+The reciprocal part of the Ewald sum is evaluated on a K x K x K grid instead of
+explicitly over k-vectors:
+
+1. every charge is spread onto the n^3 grid points around it with cardinal B-spline
+   weights of order n (n = 4 is cubic, as in GROMACS' default pme-order)
+2. the charge grid Q is Fourier transformed
+3. F(Q) is multiplied by the influence function B(m) C(m), where C(m) is the Ewald
+   Green's function and B(m) corrects for the B-spline interpolation
+4. the inverse transform gives the potential grid phi, and E = sum_k Q(k) phi(k)
+5. forces are interpolated back with the B-spline derivatives
+
+The cost is O(N n^3 + K^3 log K) instead of O(N kmax^3) for the direct sum.
+The same prefactor convention as the direct k-space sum is used, so the two agree
+to within the interpolation error.
+ */
+
+use crate::lennard_jones_simulations::Particle;
+use crate::parallel::threads::{chunk_bounds, parallel_force_reduce};
+use nalgebra::Vector3;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftDirection, FftPlanner};
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+pub fn grid_size(box_length: f64, grid_spacing: f64, spline_order: usize) -> usize {
+    /*
+    Number of grid points per axis: at least L / spacing, and never fewer than the
+    spline order (a charge must not wrap onto itself)
+     */
+    ((box_length / grid_spacing).ceil() as usize).max(spline_order)
+}
+
+fn bspline_weights(w: f64, order: usize) -> (Vec<f64>, Vec<f64>) {
+    /*
+    M_n(w + j) and M_n'(w + j) for j = 0..n, with w in [0, 1) the fractional part of
+    the scaled coordinate. Grid point floor(u) - j receives weight M_n(w + j).
+
+    Recursion: M_n(x) = [x M_{n-1}(x) + (n - x) M_{n-1}(x - 1)] / (n - 1),
+               M_n'(x) = M_{n-1}(x) - M_{n-1}(x - 1)
+     */
+    let mut m = vec![0.0; order];
+    m[0] = w;
+    m[1] = 1.0 - w;
+    let mut dm = vec![0.0; order];
+
+    for n in 3..=order {
+        let prev = m.clone();
+        if n == order {
+            dm[0] = prev[0];
+            for j in 1..n {
+                dm[j] = prev[j] - prev[j - 1];
+            }
+        }
+        let inv = 1.0 / (n as f64 - 1.0);
+        for j in 0..n {
+            let x = w + j as f64;
+            let left = if j < n - 1 { prev[j] } else { 0.0 };
+            let right = if j > 0 { prev[j - 1] } else { 0.0 };
+            m[j] = inv * (x * left + (n as f64 - x) * right);
+        }
+    }
+    if order == 2 {
+        dm[0] = 1.0;
+        dm[1] = -1.0;
+    }
+
+    (m, dm)
+}
+
+fn bspline_moduli(k: usize, order: usize) -> Vec<f64> {
+    /*
+    |b(m)|^2 = 1 / |sum_{j=0}^{n-2} M_n(j + 1) exp(2 pi i m j / K)|^2 for m = 0..K.
+    For odd orders the sum vanishes at m = K/2; those entries are replaced by the
+    average of their neighbours.
+     */
+    // M_n at the integers 1..n-1 are the weights for w = 0 (M_n(0) = 0)
+    let (m_int, _) = bspline_weights(0.0, order);
+
+    let mut moduli = vec![0.0; k];
+    for (m, modulus) in moduli.iter_mut().enumerate() {
+        let mut re = 0.0;
+        let mut im = 0.0;
+        for j in 0..order - 1 {
+            let arg = 2.0 * PI * (m * j) as f64 / k as f64;
+            re += m_int[j + 1] * arg.cos();
+            im += m_int[j + 1] * arg.sin();
+        }
+        *modulus = re * re + im * im;
+    }
+    for m in 0..k {
+        if moduli[m] < 1e-7 {
+            let prev = moduli[(m + k - 1) % k];
+            let next = moduli[(m + 1) % k];
+            moduli[m] = 0.5 * (prev + next);
+        }
+    }
+    moduli.iter().map(|&d| 1.0 / d).collect()
+}
+
+struct Fft3d {
+    dims: [usize; 3],
+    forward: [Arc<dyn Fft<f64>>; 3],
+    inverse: [Arc<dyn Fft<f64>>; 3],
+}
+
+impl Fft3d {
+    fn new(dims: [usize; 3]) -> Self {
+        let mut planner = FftPlanner::new();
+        Self {
+            dims,
+            forward: dims.map(|k| planner.plan_fft(k, FftDirection::Forward)),
+            inverse: dims.map(|k| planner.plan_fft(k, FftDirection::Inverse)),
+        }
+    }
+
+    fn transform(&self, grid: &mut [Complex<f64>], direction: FftDirection) {
+        /*
+        Unnormalised 3D transform as three passes of 1D transforms (z, y, x)
+         */
+        let [kx, ky, kz] = self.dims;
+        let fft = match direction {
+            FftDirection::Forward => &self.forward,
+            FftDirection::Inverse => &self.inverse,
+        };
+
+        // z lines are contiguous
+        fft[2].process(grid);
+
+        let mut line = vec![Complex::new(0.0, 0.0); ky];
+        for x in 0..kx {
+            for z in 0..kz {
+                for y in 0..ky {
+                    line[y] = grid[(x * ky + y) * kz + z];
+                }
+                fft[1].process(&mut line);
+                for y in 0..ky {
+                    grid[(x * ky + y) * kz + z] = line[y];
+                }
+            }
+        }
+        let mut line = vec![Complex::new(0.0, 0.0); kx];
+        for y in 0..ky {
+            for z in 0..kz {
+                for x in 0..kx {
+                    line[x] = grid[(x * ky + y) * kz + z];
+                }
+                fft[0].process(&mut line);
+                for x in 0..kx {
+                    grid[(x * ky + y) * kz + z] = line[x];
+                }
+            }
+        }
+    }
+}
+
+struct SplineData {
+    base: [isize; 3],
+    theta: [Vec<f64>; 3],
+    dtheta: [Vec<f64>; 3],
+}
+
+fn spline_data(
+    position: Vector3<f64>,
+    box_dims: Vector3<f64>,
+    dims: [usize; 3],
+    order: usize,
+) -> SplineData {
+    let mut base = [0isize; 3];
+    let mut theta: [Vec<f64>; 3] = Default::default();
+    let mut dtheta: [Vec<f64>; 3] = Default::default();
+    for d in 0..3 {
+        let frac = position[d] / box_dims[d];
+        let u = dims[d] as f64 * (frac - frac.floor());
+        let fl = u.floor();
+        base[d] = fl as isize;
+        let (m, dm) = bspline_weights(u - fl, order);
+        theta[d] = m;
+        dtheta[d] = dm;
+    }
+    SplineData {
+        base,
+        theta,
+        dtheta,
+    }
+}
+
+#[inline]
+fn wrap(i: isize, k: usize) -> usize {
+    i.rem_euclid(k as isize) as usize
+}
+
+pub fn spme_reciprocal(
+    particles: &[Particle],
+    box_dims: Vector3<f64>,
+    alpha: f64,
+    k_e: f64,
+    grid_spacing: f64,
+    spline_order: usize,
+) -> (Vec<Vector3<f64>>, f64) {
+    /*
+    Reciprocal-space Ewald forces and energy by smooth PME (no self term), for an
+    orthorhombic box with edges `box_dims`
+     */
+    let n = particles.len();
+    let order = spline_order.max(2);
+    let dims = [0, 1, 2].map(|d| grid_size(box_dims[d], grid_spacing, order));
+    let [kx, ky, kz] = dims;
+    let volume = box_dims.x * box_dims.y * box_dims.z;
+
+    // 1) B-spline coefficients and charge spreading
+    let splines: Vec<SplineData> = particles
+        .iter()
+        .map(|p| spline_data(p.position, box_dims, dims, order))
+        .collect();
+
+    let mut grid = vec![Complex::new(0.0, 0.0); kx * ky * kz];
+    for (p, s) in particles.iter().zip(splines.iter()) {
+        if p.charge == 0.0 {
+            continue;
+        }
+        for (jx, tx) in s.theta[0].iter().enumerate() {
+            let gx = wrap(s.base[0] - jx as isize, kx);
+            for (jy, ty) in s.theta[1].iter().enumerate() {
+                let gy = wrap(s.base[1] - jy as isize, ky);
+                let qxy = p.charge * tx * ty;
+                for (jz, tz) in s.theta[2].iter().enumerate() {
+                    let gz = wrap(s.base[2] - jz as isize, kz);
+                    grid[(gx * ky + gy) * kz + gz].re += qxy * tz;
+                }
+            }
+        }
+    }
+    let charges: Vec<f64> = grid.iter().map(|c| c.re).collect();
+
+    // 2) forward FFT
+    let fft = Fft3d::new(dims);
+    fft.transform(&mut grid, FftDirection::Forward);
+
+    // 3) influence function B(m) C(m)
+    let moduli = dims.map(|k| bspline_moduli(k, order));
+    let wave = |m: usize, d: usize| {
+        let k = dims[d];
+        let signed = if m <= k / 2 {
+            m as f64
+        } else {
+            m as f64 - k as f64
+        };
+        2.0 * PI * signed / box_dims[d]
+    };
+    for mx in 0..kx {
+        let wx = wave(mx, 0);
+        for my in 0..ky {
+            let wy = wave(my, 1);
+            for mz in 0..kz {
+                let idx = (mx * ky + my) * kz + mz;
+                if mx == 0 && my == 0 && mz == 0 {
+                    grid[idx] = Complex::new(0.0, 0.0);
+                    continue;
+                }
+                let wz = wave(mz, 2);
+                let k2 = wx * wx + wy * wy + wz * wz;
+                let c = (2.0 * PI * k_e / volume) * (-k2 / (4.0 * alpha * alpha)).exp() / k2;
+                grid[idx] *= c * moduli[0][mx] * moduli[1][my] * moduli[2][mz];
+            }
+        }
+    }
+
+    // 4) potential grid and energy
+    fft.transform(&mut grid, FftDirection::Inverse);
+    let phi: Vec<f64> = grid.iter().map(|c| c.re).collect();
+    let energy: f64 = charges.iter().zip(phi.iter()).map(|(q, p)| q * p).sum();
+
+    // 5) forces: F_i = -2 q_i sum_k phi(k) grad_i theta(k), grad = (K / L) d/du
+    let scale = Vector3::new(
+        -2.0 * kx as f64 / box_dims.x,
+        -2.0 * ky as f64 / box_dims.y,
+        -2.0 * kz as f64 / box_dims.z,
+    );
+    let (forces, _) =
+        parallel_force_reduce(n, n * order * order * order, |w, n_workers, forces| {
+            let (start, end) = chunk_bounds(n, w, n_workers);
+            for i in start..end {
+                let q = particles[i].charge;
+                if q == 0.0 {
+                    continue;
+                }
+                let s = &splines[i];
+                let mut g = Vector3::<f64>::zeros();
+                for jx in 0..order {
+                    let gx = wrap(s.base[0] - jx as isize, kx);
+                    let (tx, dtx) = (s.theta[0][jx], s.dtheta[0][jx]);
+                    for jy in 0..order {
+                        let gy = wrap(s.base[1] - jy as isize, ky);
+                        let (ty, dty) = (s.theta[1][jy], s.dtheta[1][jy]);
+                        for jz in 0..order {
+                            let gz = wrap(s.base[2] - jz as isize, kz);
+                            let (tz, dtz) = (s.theta[2][jz], s.dtheta[2][jz]);
+                            let p = phi[(gx * ky + gy) * kz + gz];
+                            g.x += p * dtx * ty * tz;
+                            g.y += p * tx * dty * tz;
+                            g.z += p * tx * ty * dtz;
+                        }
+                    }
+                }
+                forces[i] = g.component_mul(&scale) * q;
+            }
+            0.0
+        });
+
+    (forces, energy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bspline_weights_partition_unity() {
+        for &order in &[3usize, 4, 6] {
+            let (m, dm) = bspline_weights(0.37, order);
+            assert!((m.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+            assert!(dm.iter().sum::<f64>().abs() < 1e-12);
+        }
+    }
+}
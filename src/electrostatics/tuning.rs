@@ -0,0 +1,295 @@
+/*
+Ewald / PME parameter tuning from a target relative accuracy
+
+For a requested relative error eps:
+
+- real space: alpha is chosen so that erfc(alpha rc) = eps, i.e. the screened pair
+  potential at the cutoff is eps of the bare Coulomb one (GROMACS' ewald-rtol rule)
+- reciprocal space: the damping exp(-k^2 / 4 alpha^2) of the highest wave vector kept
+  must be below eps. For the direct sum that is k = 2 pi kmax / L, for SPME the grid
+  keeps modes up to K/2.
+- SPME additionally aliases mode m onto m +- K with a relative amplitude of about
+  2 (m / (K - m))^p for B-splines of order p; the grid is refined until the damped
+  aliasing error is below eps as well.
+
+- the real-space cutoff itself is picked from candidates up to half the box (minimum
+  image) to minimise the estimated operation count for N charges in a box of volume V:
+
+    real space   N^2 (2 pi / 3) rc^3 / V      pair terms within rc
+    direct sum   N (2 kmax + 1)^3             structure-factor terms
+    SPME         N p^3 + K^3 log2 K           spreading / interpolation and the FFTs
+
+The reported RMS force errors are the Kolafa-Perram estimates (J. Kolafa and
+J. W. Perram, Mol. Sim. 9, 351 (1992)) for N charges with sum of squares Q2:
+
+    dF_real ~ 2 Q2 / sqrt(N rc L^3) exp(-alpha^2 rc^2)
+    dF_k    ~ 2 Q2 alpha / L sqrt(1 / (pi kmax N)) exp(-(pi kmax / (alpha L))^2)
+
+They assume randomly placed charges and are order-of-magnitude figures.
+ */
+
+use crate::{coulomb_prefactor, PmeConfig, ReciprocalMethod};
+use std::f64::consts::PI;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EwaldErrorEstimate {
+    pub real_space_relative: f64,
+    pub reciprocal_relative: f64,
+    pub real_space_force_rms: f64,
+    pub reciprocal_force_rms: f64,
+}
+
+fn erfc_tail(x: f64) -> f64 {
+    // Abramowitz and Stegun 7.1.26 written as poly * exp(-x^2), so it keeps its
+    // relative accuracy for large x (x >= 0)
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let poly = (((((1.061405429 * t - 1.453152027) * t) + 1.421413741) * t - 0.284496736) * t
+        + 0.254829592)
+        * t;
+    poly * (-x * x).exp()
+}
+
+fn alpha_for_real_space(real_cutoff: f64, rel_error: f64) -> f64 {
+    // erfc is monotonic: bisect erfc(x) = eps on x = alpha rc
+    let (mut lo, mut hi) = (0.0, 30.0);
+    for _ in 0..200 {
+        let mid = 0.5 * (lo + hi);
+        if erfc_tail(mid) > rel_error {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    hi / real_cutoff
+}
+
+fn kspace_damping(m: f64, alpha: f64, box_length: f64) -> f64 {
+    // exp(-k^2 / 4 alpha^2) with k = 2 pi m / L
+    (-(PI * m / (alpha * box_length)).powi(2)).exp()
+}
+
+fn spme_aliasing(k: usize, order: usize, alpha: f64, box_length: f64) -> f64 {
+    (1..=k / 2)
+        .map(|m| {
+            let m = m as f64;
+            let ratio = m / (k as f64 - m);
+            2.0 * ratio.powi(order as i32) * kspace_damping(m, alpha, box_length)
+        })
+        .fold(0.0, f64::max)
+}
+
+impl PmeConfig {
+    pub fn tune(
+        box_length: f64,
+        charges: &[f64],
+        rel_error: f64,
+        method: ReciprocalMethod,
+    ) -> Result<(PmeConfig, EwaldErrorEstimate), String> {
+        /*
+        Choose the real-space cutoff, alpha and kmax (direct sum) or the grid (SPME) for
+        a relative error `rel_error`, at the lowest estimated cost for these charges in
+        the box. The cutoff is at most half the box (minimum image). The spline order of
+        the default configuration is kept. Returns the configuration with its error
+        estimate.
+         */
+        if !(rel_error > 0.0 && rel_error < 1.0) {
+            return Err(format!("relative error must be in (0, 1), got {rel_error}"));
+        }
+        if box_length <= 0.0 {
+            return Err("box length must be positive".to_string());
+        }
+
+        // candidate cutoffs 0.05 L, 0.06 L, ..., 0.5 L
+        let n = charges.len().max(1) as f64;
+        let config = (5..=50)
+            .map(|i| {
+                let config = Self::tuned_for_cutoff(
+                    i as f64 * box_length / 100.0,
+                    box_length,
+                    rel_error,
+                    method,
+                );
+                (config.cost(box_length, n), config)
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, config)| config)
+            .unwrap_or_default();
+
+        let estimate = config.estimate_errors(box_length, charges);
+        Ok((config, estimate))
+    }
+
+    fn tuned_for_cutoff(
+        rc: f64,
+        box_length: f64,
+        rel_error: f64,
+        method: ReciprocalMethod,
+    ) -> PmeConfig {
+        let alpha = alpha_for_real_space(rc, rel_error);
+        let mut config = PmeConfig {
+            alpha,
+            real_cutoff: rc,
+            method,
+            ..Default::default()
+        };
+
+        match method {
+            ReciprocalMethod::Ewald => {
+                let mut kmax = 1;
+                while kspace_damping(kmax as f64, alpha, box_length) > rel_error {
+                    kmax += 1;
+                }
+                config.kmax = kmax;
+            }
+            ReciprocalMethod::Spme => {
+                let order = config.spline_order;
+                let mut k = order.max(2);
+                while kspace_damping((k / 2) as f64, alpha, box_length) > rel_error
+                    || spme_aliasing(k, order, alpha, box_length) > rel_error
+                {
+                    k += 1;
+                }
+                config.grid_spacing = box_length / k as f64;
+            }
+        }
+        config
+    }
+
+    fn cost(&self, box_length: f64, n: f64) -> f64 {
+        // estimated operation count of one force evaluation, see the module notes
+        let real = n * n * (2.0 * PI / 3.0) * (self.real_cutoff / box_length).powi(3);
+        let reciprocal = match self.method {
+            ReciprocalMethod::Ewald => n * (2.0 * self.kmax as f64 + 1.0).powi(3),
+            ReciprocalMethod::Spme => {
+                let k = crate::electrostatics::spme::grid_size(
+                    box_length,
+                    self.grid_spacing,
+                    self.spline_order,
+                ) as f64;
+                n * (self.spline_order as f64).powi(3) + k.powi(3) * k.log2()
+            }
+        };
+        real + reciprocal
+    }
+
+    pub fn fitted_to_box(&self, box_length: f64) -> PmeConfig {
+        /*
+        The configuration with its real-space cutoff capped at half the box (minimum
+        image). alpha is re-chosen so the capped cutoff keeps the real-space accuracy
+        erfc(alpha rc) of the original one. The reciprocal error depends on kmax / alpha
+        (direct sum) and on alpha times the grid spacing (SPME), so both are scaled with
+        alpha to keep the reciprocal accuracy as well.
+         */
+        let half_box = 0.5 * box_length;
+        if box_length <= 0.0 || self.real_cutoff <= half_box {
+            return *self;
+        }
+        let rel_error = erfc_tail(self.alpha * self.real_cutoff).clamp(1e-15, 0.5);
+        let alpha = alpha_for_real_space(half_box, rel_error);
+        let scale = alpha / self.alpha;
+        PmeConfig {
+            alpha,
+            real_cutoff: half_box,
+            kmax: (self.kmax as f64 * scale).ceil() as i32,
+            grid_spacing: self.grid_spacing / scale,
+            ..*self
+        }
+    }
+
+    pub fn estimate_errors(&self, box_length: f64, charges: &[f64]) -> EwaldErrorEstimate {
+        let n = charges.len().max(1) as f64;
+        let q2: f64 = coulomb_prefactor() * charges.iter().map(|q| q * q).sum::<f64>();
+        let (alpha, rc) = (self.alpha, self.real_cutoff);
+
+        let (kmax, aliasing) = match self.method {
+            ReciprocalMethod::Ewald => (self.kmax.max(1) as f64, 0.0),
+            ReciprocalMethod::Spme => {
+                let k = crate::electrostatics::spme::grid_size(
+                    box_length,
+                    self.grid_spacing,
+                    self.spline_order,
+                );
+                (
+                    (k / 2).max(1) as f64,
+                    spme_aliasing(k, self.spline_order, alpha, box_length),
+                )
+            }
+        };
+
+        let real_space_relative = erfc_tail(alpha * rc);
+        let reciprocal_relative = kspace_damping(kmax, alpha, box_length) + aliasing;
+
+        let real_space_force_rms =
+            2.0 * q2 / (n * rc * box_length.powi(3)).sqrt() * (-(alpha * rc).powi(2)).exp();
+        let reciprocal_force_rms = 2.0 * q2 * alpha / box_length
+            * (1.0 / (PI * kmax * n)).sqrt()
+            * kspace_damping(kmax, alpha, box_length);
+
+        EwaldErrorEstimate {
+            real_space_relative,
+            reciprocal_relative,
+            real_space_force_rms,
+            reciprocal_force_rms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tuned_parameters_meet_the_requested_accuracy() {
+        let charges: Vec<f64> = (0..200)
+            .map(|i| if i % 2 == 0 { 0.5 } else { -0.5 })
+            .collect();
+        for method in [ReciprocalMethod::Ewald, ReciprocalMethod::Spme] {
+            let (loose, _) = PmeConfig::tune(20.0, &charges, 1e-3, method).unwrap();
+            let (tight, est) = PmeConfig::tune(20.0, &charges, 1e-5, method).unwrap();
+
+            assert!(est.real_space_relative <= 1.01e-5);
+            assert!(est.reciprocal_relative <= 1e-5);
+            assert!(tight.real_cutoff <= 10.0 && loose.real_cutoff <= 10.0);
+            assert!(
+                tight.alpha * tight.real_cutoff > loose.alpha * loose.real_cutoff,
+                "tighter real-space accuracy needs a larger alpha rc"
+            );
+        }
+    }
+
+    #[test]
+    fn tuned_cutoff_shrinks_as_the_charge_count_grows() {
+        let (few, _) = PmeConfig::tune(20.0, &[0.5, -0.5], 1e-5, ReciprocalMethod::Ewald).unwrap();
+        // the reciprocal sum dominates: the cutoff goes to (about) half the box
+        assert!(few.real_cutoff > 9.0 && few.real_cutoff <= 10.0);
+
+        // with many charges the real-space pair count dominates at L/2
+        let many: Vec<f64> = (0..100_000)
+            .map(|i| if i % 2 == 0 { 0.5 } else { -0.5 })
+            .collect();
+        let (dense, est) = PmeConfig::tune(20.0, &many, 1e-5, ReciprocalMethod::Ewald).unwrap();
+        assert!(dense.real_cutoff < few.real_cutoff);
+        assert!(est.real_space_relative <= 1.01e-5 && est.reciprocal_relative <= 1e-5);
+
+        let at_half_box = PmeConfig::tuned_for_cutoff(10.0, 20.0, 1e-5, ReciprocalMethod::Ewald);
+        assert!(dense.cost(20.0, 1e5) <= at_half_box.cost(20.0, 1e5));
+    }
+
+    #[test]
+    fn fitting_to_a_small_box_keeps_the_real_space_accuracy() {
+        let config = PmeConfig::default();
+        assert_eq!(config.fitted_to_box(30.0).alpha, config.alpha);
+
+        // the 9.0 default cutoff in a 10-unit box
+        let fitted = config.fitted_to_box(10.0);
+        assert_eq!(fitted.real_cutoff, 5.0);
+        let before = erfc_tail(config.alpha * config.real_cutoff);
+        let after = erfc_tail(fitted.alpha * fitted.real_cutoff);
+        assert!((after - before).abs() < 1e-3 * before);
+        assert!(fitted.alpha > config.alpha);
+        assert!(
+            (fitted.alpha * fitted.grid_spacing - config.alpha * config.grid_spacing).abs() < 1e-12
+        );
+        assert!(fitted.kmax as f64 / fitted.alpha >= config.kmax as f64 / config.alpha);
+    }
+}
@@ -73,15 +73,24 @@ extern crate assert_type_eq;
 // src/molcule.rs
 // src/parameters.rs
 pub mod cell;
+pub mod colvar;
+pub mod constraints;
+pub mod electrostatics;
 pub mod error;
 pub mod molecule;
+pub mod parallel;
 pub mod parameters;
 #[cfg(feature = "python")]
 mod python;
 #[path = "quantum/quantum_chem.rs"]
 pub mod quantum_chemistry;
+pub mod soa;
+#[cfg(test)]
+mod test_support;
 pub mod thermostat_barostat;
 
+use crate::electrostatics::damped::DampedCoulomb;
+use crate::electrostatics::reaction_field::ReactionField;
 use std::collections::HashSet;
 
 // Use when importing the finished minimization modulexo
@@ -102,17 +111,33 @@ pub fn lennard_jones_force_scalar(r: f64, sigma: f64, epsilon: f64) -> f64 {
 }
 
 #[inline]
-fn coulomb_prefactor() -> f64 {
+pub(crate) fn coulomb_prefactor() -> f64 {
     // Reduced-unit Coulomb prefactor.
     // In SI units this would be 1/(4*pi*epsilon_0).
     1.0
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReciprocalMethod {
+    // explicit sum over k-vectors up to kmax, O(N kmax^3)
+    Ewald,
+    // smooth particle-mesh Ewald on a grid, O(N log N)
+    Spme,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct PmeConfig {
     pub alpha: f64,
     pub real_cutoff: f64,
     pub kmax: i32,
+    pub method: ReciprocalMethod,
+    pub grid_spacing: f64, // SPME: target grid spacing, the grid has ceil(L / spacing) points
+    pub spline_order: usize, // SPME: B-spline order (4 = cubic)
+    // Some(f): 2D-periodic slab, reciprocal box extended to f L along z plus the
+    // Yeh-Berkowitz dipole correction (f = 3 is the usual choice). Real-space pairs
+    // still use the cubic minimum image, so the box L must already hold a vacuum gap
+    // of at least the cutoff above the slab; the systems driver warns when it does not
+    pub slab_vacuum_factor: Option<f64>,
 }
 
 impl Default for PmeConfig {
@@ -121,6 +146,87 @@ impl Default for PmeConfig {
             alpha: 0.35,
             real_cutoff: 9.0,
             kmax: 4,
+            method: ReciprocalMethod::Spme,
+            grid_spacing: 1.0,
+            spline_order: 4,
+            slab_vacuum_factor: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Electrostatics {
+    // Ewald split: erfc-screened real space + reciprocal sum
+    Ewald(PmeConfig),
+    // cutoff Coulomb with a dielectric continuum beyond the cutoff (Martini, CG models)
+    ReactionField(ReactionField),
+    // erfc-damped Coulomb shifted to zero at the cutoff, no k-space sum
+    Wolf(DampedCoulomb),
+    // as Wolf, with the force shifted to zero at the cutoff as well
+    DampedShiftedForce(DampedCoulomb),
+}
+
+impl Default for Electrostatics {
+    fn default() -> Self {
+        Electrostatics::Ewald(PmeConfig::default())
+    }
+}
+
+impl Electrostatics {
+    pub fn fitted_to_box(&self, box_length: f64) -> Electrostatics {
+        /*
+        Ewald with its real-space cutoff capped at L/2 and alpha re-tuned for it (see
+        PmeConfig::fitted_to_box); the cutoff methods are returned unchanged
+         */
+        match self {
+            Electrostatics::Ewald(pme) => Electrostatics::Ewald(pme.fitted_to_box(box_length)),
+            other => *other,
+        }
+    }
+
+    pub fn cutoff(&self) -> f64 {
+        match self {
+            Electrostatics::Ewald(pme) => pme.real_cutoff,
+            Electrostatics::ReactionField(rf) => rf.cutoff,
+            Electrostatics::Wolf(d) | Electrostatics::DampedShiftedForce(d) => d.cutoff,
+        }
+    }
+
+    #[inline]
+    pub fn pair(&self, q_i: f64, q_j: f64, r: f64) -> (f64, f64) {
+        /*
+        Short-range pair term (energy, force magnitude, positive = repulsive); zero
+        beyond the cutoff. For Ewald this is the erfc-screened real-space part.
+         */
+        match self {
+            Electrostatics::Ewald(pme) => {
+                if r > pme.real_cutoff || r <= 1e-12 {
+                    return (0.0, 0.0);
+                }
+                let alpha = pme.alpha;
+                let ar = alpha * r;
+                let erfc_ar = lennard_jones_simulations::erfc_approx(ar);
+                let qq = coulomb_prefactor() * q_i * q_j;
+                let energy = qq * erfc_ar / r;
+                let f_mag = qq
+                    * (erfc_ar / (r * r)
+                        + (2.0 * alpha / std::f64::consts::PI.sqrt()) * (-(ar * ar)).exp() / r);
+                (energy, f_mag)
+            }
+            Electrostatics::ReactionField(rf) => rf.pair(q_i, q_j, r),
+            Electrostatics::Wolf(d) => d.wolf_pair(q_i, q_j, r),
+            Electrostatics::DampedShiftedForce(d) => d.dsf_pair(q_i, q_j, r),
+        }
+    }
+
+    pub fn coulomb_14_factor(&self) -> f64 {
+        /*
+        Prefactor of the plain Coulomb term of 1-4 pairs
+         */
+        match self {
+            Electrostatics::Ewald(_) => coulomb_prefactor(),
+            Electrostatics::ReactionField(rf) => rf.coulomb_constant / rf.epsilon_r,
+            Electrostatics::Wolf(d) | Electrostatics::DampedShiftedForce(d) => d.coulomb_constant,
         }
     }
 }
@@ -291,11 +397,14 @@ pub mod cell_subdivision {
 
             let box_size = Vector3::new(self.x_dimension, self.y_dimension, self.z_dimension);
 
-            for (i, system) in systems.iter_mut().enumerate() {
+            // atoms are indexed in the flattened order: system 0 atoms first, then system 1, ...
+            let mut i = 0usize;
+            for system in systems.iter_mut() {
                 for particle in system.atoms.iter() {
                     let (ix, iy, iz) = position_to_cell_3d(&particle.position, &box_size, n_cells);
                     let cid = cell_id(ix, iy, iz, n_cells);
                     cells[cid].atom_index.push(i);
+                    i += 1;
                 }
             }
         }
@@ -307,28 +416,48 @@ pub mod lennard_jones_simulations {
     use super::*; //
 
     use crate::cell::cell::{CellList, Vec3};
+    use crate::constraints::constraint::{
+        constrain_positions, constrain_velocities, constrained_dof, constraints_from_bonds,
+        Constraint, ConstraintOptions,
+    };
+    use crate::constraints::settle::{settle_positions, settle_velocities};
+    use crate::electrostatics::external_field::{charge_current, ExternalField};
+    use crate::electrostatics::slab::slab_correction;
+    use crate::electrostatics::spme::spme_reciprocal;
     use crate::error::error::compute_average_val;
+    use crate::parallel::threads::{chunk_bounds, parallel_force_reduce, WorkerPool};
     use crate::parameters::lj_parameters::lennard_jones_potential;
     use crate::thermostat_barostat::andersen::andersen::apply_andersen_collisions;
-    use crate::thermostat_barostat::nose_hoover::nose_hoover::apply_thermostat_nose_hoover_particles;
+    use crate::thermostat_barostat::drude::{
+        apply_dual_thermostat_drude, DrudeThermostat, DrudeThermostatState,
+    };
+    use crate::thermostat_barostat::nose_hoover::nose_hoover::{
+        apply_thermostat_nose_hoover_particles, apply_thermostat_nose_hoover_particles_with_dof,
+    };
 
-    use log::{debug, info};
+    use log::{debug, info, warn};
     #[cfg(feature = "mpi")]
     use mpi::collective::SystemOperation;
     #[cfg(feature = "mpi")]
     use mpi::traits::*;
-    use nalgebra::{zero, Vector3};
+    use nalgebra::{zero, Matrix3, Vector3};
     use rand::Rng;
     use rand_distr::{Distribution, Normal};
 
     // importing bonds
+    use crate::colvar::bias::{apply_biases, Bias};
+    use crate::molecule::drude::{apply_drude_hard_wall, compute_drude_forces};
     use crate::molecule::molecule::make_h2_system;
     use crate::molecule::molecule::Bond;
     use crate::molecule::molecule::System;
     use crate::molecule::molecule::{
-        apply_all_bonded_forces_and_energy, apply_bonded_forces_and_energy, Angle, Dihedral,
-        Improper,
+        apply_all_bonded_forces_and_energy, apply_bonded_forces_and_energy,
+        apply_extended_bonded_forces_and_energy, bond_virial, pair_key, Angle, Dihedral, Improper,
+        Pair14,
     };
+    use crate::molecule::restraint::apply_position_restraints;
+    use crate::molecule::virtual_site::{construct_virtual_sites, spread_virtual_site_forces};
+    use crate::soa::particle_store::ParticleStore;
 
     use crate::lennard_jones_simulations::cell_subdivision::MolecularCoordinates;
 
@@ -353,9 +482,45 @@ pub mod lennard_jones_simulations {
         pub charge: f64,
     }
 
-    #[derive(Clone)]
+    #[derive(Clone, Default)]
     pub struct SimulationSummary {
         pub energy: f64,
+        pub current: Vec<Vector3<f64>>, // charge current J = sum q v, one entry per step
+        pub restraint_energy: f64,      // position restraint part of `energy`
+        pub bias_energy: f64,           // collective-variable bias part of `energy`
+        pub colvars: Vec<Vec<f64>>,     // value of every biased CV, one entry per step
+        pub pressure: f64,              // instantaneous virial pressure after the last step
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct ForceEvaluation {
+        potential: f64, // restraints and biases included
+        restraint: f64,
+        bias: f64,
+        colvars: Vec<f64>,
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct MinimizationSummary {
+        pub energy: f64,
+        pub restraint_energy: f64,
+        pub max_force: f64,
+        pub iterations: usize,
+        pub converged: bool,
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct MdOptions {
+        // per-run settings that are not part of the system itself
+        pub electrostatics: Electrostatics,
+        pub external_field: Option<ExternalField>,
+        pub drude_thermostat: DrudeThermostat, // used with thermostat = "drude"
+        pub constraints: ConstraintOptions,
+        pub position_restraints: bool, // apply System::position_restraints in this run phase
+        // CV biases; System atoms are indexed over all systems concatenated in order
+        pub biases: Vec<Bias>,
+        // worker threads for the force kernels; 0 = SANG_MD_NUM_THREADS or all cores
+        pub num_threads: usize,
     }
 
     pub enum InitOutput {
@@ -369,6 +534,15 @@ pub mod lennard_jones_simulations {
     }
 
     impl Particle {
+        pub fn acceleration(&self) -> Vector3<f64> {
+            // massless virtual sites carry no inertia of their own
+            if self.mass > 0.0 {
+                self.force / self.mass
+            } else {
+                Vector3::zeros()
+            }
+        }
+
         pub fn distance(&self, other: &Particle) -> f64 {
             // Compute the distance between two particles
             (self.position - other.position).norm()
@@ -561,8 +735,6 @@ pub mod lennard_jones_simulations {
         }
     }
 
-    pub fn implement_shake() -> () {}
-
     //pub fn run_verlet_update_nve(state: &mut InitOutput, dt: f64, box_length: f64) -> () {
     //    /*
     //    Update the position and velocity of the particle using the verlet scheme
@@ -648,14 +820,14 @@ pub mod lennard_jones_simulations {
         let rij = particles[b.atom1].position - particles[b.atom2].position;
         let rij_mic = minimum_image_convention(rij, box_length);
         let r = safe_norm(rij_mic.norm());
-        let dr = r - b.r0;
-        let f_mag = -b.k * dr; // along r̂, attractive if r>r0
+        let (energy, de_dr) = b.energy_and_derivative(r);
+        let f_mag = -de_dr; // along r̂, attractive if r>r0
         let f_vec = (rij_mic / r) * f_mag; // vector force on i
 
         particles[b.atom1].force += f_vec;
         particles[b.atom2].force -= f_vec;
 
-        0.5 * b.k * dr * dr
+        energy
     }
 
     pub fn compute_pair_forces_vector(dr: Vec3, r2: f64, sigma: f64, epsilon: f64) -> Vec3 {
@@ -748,7 +920,7 @@ pub mod lennard_jones_simulations {
     }
 
     fn compute_bonded_forces(
-        atoms: &mut Vec<Particle>,
+        atoms: &mut [Particle],
         bonds: &[Bond],
         angles: &[Angle],
         dihedrals: &[Dihedral],
@@ -758,34 +930,13 @@ pub mod lennard_jones_simulations {
         apply_all_bonded_forces_and_energy(atoms, bonds, angles, dihedrals, impropers, box_length)
     }
 
-    fn compute_bonded_energy(
-        atoms: &mut Vec<Particle>,
-        bonds: &[Bond],
-        angles: &[Angle],
-        dihedrals: &[Dihedral],
-        impropers: &[Improper],
-        box_length: f64,
-    ) -> f64 {
-        // NOTE:
-        // `apply_all_bonded_forces_and_energy` updates forces as a side effect.
-        // During MD we call this helper for diagnostics only (energy reporting),
-        // so we must avoid mutating the active force buffers, otherwise bonded
-        // forces are effectively applied twice and the integrator can blow up.
-        let mut atoms_for_energy = atoms.clone();
-        apply_all_bonded_forces_and_energy(
-            &mut atoms_for_energy,
-            bonds,
-            angles,
-            dihedrals,
-            impropers,
-            box_length,
-        )
-    }
-
+    #[deprecated(note = "all-pairs O(N^2) loop; use compute_nonbonded_forces_systems")]
     pub fn compute_intermolecular_forces_systems(systems: &mut [System], box_length: f64) -> f64 {
         /*
         Compute Lennard-Jones interactions between atoms belonging to different systems.
         Intra-molecular interactions are omitted here and handled by bonded terms.
+
+        All-pairs reference for compute_nonbonded_forces_systems, kept for existing callers.
          */
         let mut total_energy = 0.0;
 
@@ -820,6 +971,7 @@ pub mod lennard_jones_simulations {
         total_energy
     }
 
+    #[deprecated(note = "all-pairs O(N^2) loop; use compute_nonbonded_forces_systems")]
     pub fn intermolecular_site_site_energy_systems(systems: &[System], box_length: f64) -> f64 {
         /*
         Compute Lennard-Jones potential energy between atoms in different systems.
@@ -850,8 +1002,230 @@ pub mod lennard_jones_simulations {
         total_energy
     }
 
+    fn compute_pair_14_force(
+        atoms: &mut [Particle],
+        pair: &Pair14,
+        box_length: f64,
+        coulomb_factor: f64,
+    ) -> f64 {
+        let r_vec = atoms[pair.atom2].position - atoms[pair.atom1].position;
+        let r_mic = minimum_image_convention(r_vec, box_length);
+        let r = safe_norm(r_mic.norm());
+
+        let qq = pair.coulomb_scale
+            * coulomb_factor
+            * atoms[pair.atom1].charge
+            * atoms[pair.atom2].charge;
+        let f_mag = lennard_jones_force_scalar(r, pair.sigma, pair.epsilon) + qq / (r * r);
+        let f_vec = (r_mic / r) * f_mag;
+
+        atoms[pair.atom1].force -= f_vec;
+        atoms[pair.atom2].force += f_vec;
+
+        lennard_jones_potential(r, pair.sigma, pair.epsilon) + qq / r
+    }
+
+    fn pair_14_energy(atoms: &[Particle], pair: &Pair14, box_length: f64) -> f64 {
+        let r_vec = atoms[pair.atom2].position - atoms[pair.atom1].position;
+        let r = safe_norm(minimum_image_convention(r_vec, box_length).norm());
+        let qq = pair.coulomb_scale
+            * coulomb_prefactor()
+            * atoms[pair.atom1].charge
+            * atoms[pair.atom2].charge;
+        lennard_jones_potential(r, pair.sigma, pair.epsilon) + qq / r
+    }
+
+    #[deprecated(note = "all-pairs O(N^2) loop; use compute_nonbonded_forces_systems")]
+    pub fn compute_intramolecular_forces_systems(systems: &mut [System], box_length: f64) -> f64 {
+        /*
+        Compute the nonbonded interactions inside each system:
+
+        - Lennard-Jones between atom pairs that are not excluded through the bond graph (nrexcl)
+        - the 1-4 pairs, with their own (scaled or force-field specific) LJ parameters and
+          scaled plain Coulomb
+
+        Coulomb between non-excluded intramolecular pairs is handled by the Ewald sum.
+        All-pairs reference for compute_nonbonded_forces_systems, kept for existing callers.
+         */
+        let mut total_energy = 0.0;
+
+        for sys in systems.iter_mut() {
+            let n = sys.atoms.len();
+            for i in 0..n {
+                for j in (i + 1)..n {
+                    if sys.is_excluded(i, j) {
+                        continue;
+                    }
+
+                    let r_vec = sys.atoms[j].position - sys.atoms[i].position;
+                    let r_mic = minimum_image_convention(r_vec, box_length);
+                    let r = safe_norm(r_mic.norm());
+
+                    let (lj_i, lj_j) = (&sys.atoms[i].lj_parameters, &sys.atoms[j].lj_parameters);
+                    let sigma = 0.5 * (lj_i.sigma + lj_j.sigma);
+                    let epsilon = (lj_i.epsilon * lj_j.epsilon).sqrt();
+
+                    let f_mag = lennard_jones_force_scalar(r, sigma, epsilon);
+                    let f_vec = (r_mic / r) * f_mag;
+
+                    sys.atoms[i].force -= f_vec;
+                    sys.atoms[j].force += f_vec;
+
+                    total_energy += lennard_jones_potential(r, sigma, epsilon);
+                }
+            }
+
+            for pair in sys.pairs.iter() {
+                total_energy +=
+                    compute_pair_14_force(&mut sys.atoms, pair, box_length, coulomb_prefactor());
+            }
+        }
+
+        total_energy
+    }
+
+    #[deprecated(note = "all-pairs O(N^2) loop; use compute_nonbonded_forces_systems")]
+    pub fn intramolecular_site_site_energy_systems(systems: &[System], box_length: f64) -> f64 {
+        /*
+        Energy counterpart of `compute_intramolecular_forces_systems`.
+         */
+        let mut total_energy = 0.0;
+
+        for sys in systems.iter() {
+            let n = sys.atoms.len();
+            for i in 0..n {
+                for j in (i + 1)..n {
+                    if sys.is_excluded(i, j) {
+                        continue;
+                    }
+
+                    let r_vec = sys.atoms[j].position - sys.atoms[i].position;
+                    let r = safe_norm(minimum_image_convention(r_vec, box_length).norm());
+
+                    let (lj_i, lj_j) = (&sys.atoms[i].lj_parameters, &sys.atoms[j].lj_parameters);
+                    let sigma = 0.5 * (lj_i.sigma + lj_j.sigma);
+                    let epsilon = (lj_i.epsilon * lj_j.epsilon).sqrt();
+
+                    total_energy += lennard_jones_potential(r, sigma, epsilon);
+                }
+            }
+
+            for pair in sys.pairs.iter() {
+                total_energy += pair_14_energy(&sys.atoms, pair, box_length);
+            }
+        }
+
+        total_energy
+    }
+
+    pub fn compute_nonbonded_forces_systems(
+        systems: &mut [System],
+        box_length: f64,
+        cutoff: f64,
+        electrostatics: &Electrostatics,
+    ) -> f64 {
+        /*
+        Nonbonded forces and energy of all systems through the cell list:
+
+        - Lennard-Jones (truncated at `cutoff`) between every pair of atoms that is not
+          excluded, whether both atoms belong to the same system or not
+        - short-range Coulomb (real-space Ewald, reaction field or Wolf/DSF, truncated at
+          the electrostatics cutoff) for the same pairs
+        - 1-4 pairs with their own parameters
+        - the reciprocal Ewald sum over all atoms (Ewald) or the Wolf/DSF self term
+        - the Ewald correction that removes excluded pairs from the reciprocal sum
+
+        Atoms are flattened into one list so a single cell list covers the box, which keeps
+        the pair search O(N). Cutoffs beyond half the box are capped at L/2, as the minimum
+        image only sees one copy of each pair; a capped Ewald cutoff gets a re-tuned alpha.
+         */
+        let fitted = electrostatics.fitted_to_box(box_length);
+        let electrostatics = &fitted;
+        let mut all_atoms: Vec<Particle> = systems
+            .iter()
+            .flat_map(|s| s.atoms.iter().cloned())
+            .collect();
+        for a in all_atoms.iter_mut() {
+            a.force = Vector3::zeros();
+        }
+
+        let excluded = global_exclusions_systems(systems);
+        let half_box = 0.5 * box_length;
+        let list_cutoff = cutoff.max(electrostatics.cutoff()).min(half_box);
+        let mut cl = CellList::new(Vec3::new(box_length, box_length, box_length), list_cutoff);
+        cl.rebuild(&all_atoms);
+
+        let rc2_lj = cutoff.min(half_box).powi(2);
+        let shared: &[Particle] = &all_atoms;
+
+        // the cells are split into one contiguous block per worker thread
+        let (forces, mut energy) =
+            parallel_force_reduce(shared.len(), shared.len(), |w, n_workers, forces| {
+                let mut energy = 0.0;
+                let (start, end) = chunk_bounds(cl.n_cells(), w, n_workers);
+
+                cl.for_each_neighbor_pair_in_cells(shared, start..end, |i, j, dr, r2| {
+                    if r2 <= 1e-24 || excluded.contains(&(i, j)) {
+                        return;
+                    }
+                    let r = r2.sqrt();
+                    let r_vec = Vector3::new(dr.x, dr.y, dr.z);
+                    let (pi, pj) = (&shared[i], &shared[j]);
+
+                    // positive f_mag is repulsive: pushes j away from i
+                    let mut f_mag = 0.0;
+                    if r2 <= rc2_lj {
+                        let sigma = 0.5 * (pi.lj_parameters.sigma + pj.lj_parameters.sigma);
+                        let epsilon = (pi.lj_parameters.epsilon * pj.lj_parameters.epsilon).sqrt();
+                        f_mag += lennard_jones_force_scalar(r, sigma, epsilon);
+                        energy += lennard_jones_potential(r, sigma, epsilon);
+                    }
+                    if pi.charge != 0.0 && pj.charge != 0.0 {
+                        let (e_coul, f_coul) = electrostatics.pair(pi.charge, pj.charge, r);
+                        energy += e_coul;
+                        f_mag += f_coul;
+                    }
+
+                    let f_vec = (r_vec / r) * f_mag;
+                    forces[i] -= f_vec;
+                    forces[j] += f_vec;
+                });
+                energy
+            });
+
+        for (a, f) in all_atoms.iter_mut().zip(forces) {
+            a.force = f;
+        }
+        energy +=
+            add_electrostatic_long_range_particles(&mut all_atoms, box_length, electrostatics);
+        energy += add_exclusion_correction_particles(
+            &mut all_atoms,
+            box_length,
+            electrostatics,
+            &excluded,
+        );
+
+        let mut idx = 0usize;
+        for sys in systems.iter_mut() {
+            for atom in sys.atoms.iter_mut() {
+                atom.force += all_atoms[idx].force;
+                idx += 1;
+            }
+            for pair in sys.pairs.iter() {
+                energy += compute_pair_14_force(
+                    &mut sys.atoms,
+                    pair,
+                    box_length,
+                    electrostatics.coulomb_14_factor(),
+                );
+            }
+        }
+
+        energy
+    }
+
     pub fn compute_bonded_forces_system(
-        atoms: &mut Vec<Particle>,
+        atoms: &mut [Particle],
         bonds: &[Bond],
         box_length: f64,
     ) -> f64 {
@@ -985,6 +1359,72 @@ pub mod lennard_jones_simulations {
         (n as f64 * temperature) / volume + virial / (3.0 * volume)
     }
 
+    pub fn compute_pressure_systems(
+        systems: &[System],
+        box_length: f64,
+        cutoff: f64,
+        electrostatics: &Electrostatics,
+    ) -> f64 {
+        /*
+        Instantaneous pressure of the systems, P = N T / V + tr(W) / (3V) as in
+        compute_pressure_particles, with the virial tensor W = sum r_ij (x) F_j of
+
+        - the truncated Lennard-Jones pairs that are not excluded, within and between
+          systems (cutoffs beyond half the box are capped at L/2)
+        - the bonds of each system, including those kept out of the exclusion graph
+        - the Yeh-Berkowitz slab correction, for Ewald with a slab vacuum factor
+
+        The angle, dihedral, Coulomb pair and reciprocal-space virials are not included.
+         */
+        let atoms: Vec<Particle> = systems
+            .iter()
+            .flat_map(|s| s.atoms.iter().cloned())
+            .collect();
+        let n = atoms.len();
+        if n == 0 || box_length <= 0.0 {
+            return 0.0;
+        }
+        let volume = box_length.powi(3);
+        let temperature = compute_temperature_particles(&atoms, 3 * n);
+
+        let excluded = global_exclusions_systems(systems);
+        let rc = cutoff.min(0.5 * box_length);
+        let mut cl = CellList::new(Vec3::new(box_length, box_length, box_length), rc);
+        cl.rebuild(&atoms);
+
+        let mut virial = Matrix3::zeros();
+        cl.for_each_neighbor_pair(&atoms, |i, j, dr, r2| {
+            if r2 <= 1e-24 || excluded.contains(&(i, j)) {
+                return;
+            }
+            let r = r2.sqrt();
+            let r_vec = Vector3::new(dr.x, dr.y, dr.z);
+            let (pi, pj) = (&atoms[i].lj_parameters, &atoms[j].lj_parameters);
+            let sigma = 0.5 * (pi.sigma + pj.sigma);
+            let epsilon = (pi.epsilon * pj.epsilon).sqrt();
+            let f_j = (r_vec / r) * lennard_jones_force_scalar(r, sigma, epsilon);
+            virial += r_vec * f_j.transpose();
+        });
+
+        for sys in systems {
+            virial += bond_virial(&sys.atoms, &sys.bonds, box_length);
+            virial += bond_virial(
+                &sys.atoms,
+                &sys.extended.bonds_without_exclusions,
+                box_length,
+            );
+        }
+
+        if let Electrostatics::Ewald(pme) = electrostatics {
+            if let Some(factor) = pme.slab_vacuum_factor {
+                let box_dims = Vector3::new(box_length, box_length, box_length * factor);
+                virial += slab_correction(&atoms, box_dims, coulomb_prefactor()).virial;
+            }
+        }
+
+        (n as f64 * temperature) / volume + virial.trace() / (3.0 * volume)
+    }
+
     pub fn apply_thermostat(state: &mut InitOutput, target_temperature: f64) {
         match state {
             InitOutput::Particles(particles) => {
@@ -1048,7 +1488,7 @@ pub mod lennard_jones_simulations {
     }
 
     pub fn apply_thermostat_berendsen_particles(
-        particles: &mut Vec<Particle>,
+        particles: &mut [Particle],
         target_temperature: f64,
         tau: f64,
         dt: f64,
@@ -1061,6 +1501,17 @@ pub mod lennard_jones_simulations {
 
          */
         let dof = 3 * particles.len();
+        apply_thermostat_berendsen_particles_with_dof(particles, dof, target_temperature, tau, dt);
+    }
+
+    pub fn apply_thermostat_berendsen_particles_with_dof(
+        particles: &mut [Particle],
+        dof: usize,
+        target_temperature: f64,
+        tau: f64,
+        dt: f64,
+    ) {
+        // as above, with the degrees of freedom reduced by e.g. constraints
         let current_temperature = compute_temperature_particles(particles, dof);
         // Bail if parameters are nonsense or temperature is zero/negative
         if tau <= 0.0 || dt <= 0.0 || current_temperature <= 0.0 || target_temperature <= 0.0 {
@@ -1201,7 +1652,7 @@ pub mod lennard_jones_simulations {
         )
     }
 
-    fn erfc_approx(x: f64) -> f64 {
+    pub(crate) fn erfc_approx(x: f64) -> f64 {
         // Abramowitz and Stegun 7.1.26
         let z = x.abs();
         let t = 1.0 / (1.0 + 0.3275911 * z);
@@ -1216,137 +1667,321 @@ pub mod lennard_jones_simulations {
         1.0 - erf
     }
 
-    fn add_electrostatic_real_space_particles(
+    fn add_electrostatic_short_range_particles(
         particles: &mut [Particle],
         box_length: f64,
-        pme: &PmeConfig,
+        electrostatics: &Electrostatics,
+        excluded: &HashSet<(usize, usize)>,
     ) -> f64 {
-        let mut energy = 0.0;
-        let alpha = pme.alpha;
-        let rc = pme.real_cutoff;
-        let k_e = coulomb_prefactor();
+        /*
+        Short-range Coulomb (real-space Ewald, reaction field or Wolf/DSF) over all pairs.
+        Rows i are dealt out round-robin to the worker threads so the triangular j loop
+        is evenly shared.
+         */
+        let n = particles.len();
+        let shared: &[Particle] = particles;
+
+        let (forces, energy) = parallel_force_reduce(n, n * n / 2, |w, n_workers, forces| {
+            let mut energy = 0.0;
+            for i in (w..n).step_by(n_workers) {
+                for j in (i + 1)..n {
+                    let qi = shared[i].charge;
+                    let qj = shared[j].charge;
+                    if qi == 0.0 && qj == 0.0 {
+                        continue;
+                    }
+                    if excluded.contains(&(i, j)) {
+                        continue;
+                    }
 
-        for i in 0..particles.len() {
-            for j in (i + 1)..particles.len() {
-                let qi = particles[i].charge;
-                let qj = particles[j].charge;
-                if qi == 0.0 && qj == 0.0 {
-                    continue;
-                }
+                    let rij = minimum_image_convention(
+                        shared[j].position - shared[i].position,
+                        box_length,
+                    );
+                    let r = rij.norm();
+                    let (e_pair, f_mag) = electrostatics.pair(qi, qj, r);
+                    if f_mag == 0.0 && e_pair == 0.0 {
+                        continue;
+                    }
 
-                let rij = minimum_image_convention(
-                    particles[j].position - particles[i].position,
-                    box_length,
-                );
-                let r = rij.norm();
-                if r <= 1e-12 || r > rc {
-                    continue;
+                    energy += e_pair;
+                    let f_vec = -(rij / r) * f_mag;
+                    forces[i] += f_vec;
+                    forces[j] -= f_vec;
                 }
-
-                let ar = alpha * r;
-                let erfc_ar = erfc_approx(ar);
-                let exp_term = (-(ar * ar)).exp();
-                let qq = k_e * qi * qj;
-
-                energy += qq * erfc_ar / r;
-
-                let scalar = qq
-                    * (erfc_ar / (r * r)
-                        + (2.0 * alpha / std::f64::consts::PI.sqrt()) * exp_term / r);
-                let f_vec = -(rij / r) * scalar;
-                particles[i].force += f_vec;
-                particles[j].force -= f_vec;
             }
-        }
+            energy
+        });
 
+        for (p, f) in particles.iter_mut().zip(forces) {
+            p.force += f;
+        }
         energy
     }
 
-    fn add_electrostatic_reciprocal_particles(
-        particles: &mut [Particle],
-        box_length: f64,
+    fn ewald_reciprocal_direct(
+        particles: &[Particle],
+        box_dims: Vector3<f64>,
         pme: &PmeConfig,
-    ) -> f64 {
-        if particles.is_empty() {
-            return 0.0;
-        }
+    ) -> (Vec<Vector3<f64>>, f64) {
+        /*
+        Reciprocal-space Ewald sum over explicit k-vectors for an orthorhombic box. The
+        k-vectors are split into one contiguous chunk per worker thread; every k-vector
+        needs the structure factor over all particles.
 
-        let volume = box_length.powi(3);
+        kmax applies to the shortest edge; longer edges get proportionally more
+        k-vectors so the cutoff in |k| is the same along every axis.
+         */
+
+        let volume = box_dims.x * box_dims.y * box_dims.z;
         let alpha = pme.alpha;
-        let kmax = pme.kmax;
         let k_e = coulomb_prefactor();
-        let two_pi_over_l = 2.0 * std::f64::consts::PI / box_length;
-        let mut energy = 0.0;
-
-        for nx in -kmax..=kmax {
-            for ny in -kmax..=kmax {
-                for nz in -kmax..=kmax {
+        let two_pi = 2.0 * std::f64::consts::PI;
+        let l_min = box_dims.x.min(box_dims.y).min(box_dims.z);
+        let kmax = box_dims.map(|l| (pme.kmax as f64 * l / l_min).ceil() as i32);
+
+        let mut kvectors = Vec::new();
+        for nx in -kmax.x..=kmax.x {
+            for ny in -kmax.y..=kmax.y {
+                for nz in -kmax.z..=kmax.z {
                     if nx == 0 && ny == 0 && nz == 0 {
                         continue;
                     }
 
                     let kvec = Vector3::new(
-                        nx as f64 * two_pi_over_l,
-                        ny as f64 * two_pi_over_l,
-                        nz as f64 * two_pi_over_l,
+                        nx as f64 * two_pi / box_dims.x,
+                        ny as f64 * two_pi / box_dims.y,
+                        nz as f64 * two_pi / box_dims.z,
                     );
-                    let k2 = kvec.norm_squared();
-                    if k2 <= 1e-12 {
-                        continue;
-                    }
-
-                    let damp = (-k2 / (4.0 * alpha * alpha)).exp();
-                    let coef = (2.0 * std::f64::consts::PI * k_e / volume) * damp / k2;
-
-                    let mut s_cos = 0.0;
-                    let mut s_sin = 0.0;
-                    for p in particles.iter() {
-                        let phase = kvec.dot(&p.position);
-                        s_cos += p.charge * phase.cos();
-                        s_sin += p.charge * phase.sin();
-                    }
-
-                    energy += coef * (s_cos * s_cos + s_sin * s_sin);
-
-                    for p in particles.iter_mut() {
-                        let phase = kvec.dot(&p.position);
-                        let sin_i = phase.sin();
-                        let cos_i = phase.cos();
-                        let force_coeff =
-                            -(4.0 * std::f64::consts::PI * k_e * p.charge / volume) * damp / k2;
-                        let proj = s_cos * sin_i - s_sin * cos_i;
-                        p.force += kvec * (force_coeff * proj);
+                    if kvec.norm_squared() > 1e-12 {
+                        kvectors.push(kvec);
                     }
                 }
             }
         }
 
-        let self_energy: f64 = particles.iter().map(|p| p.charge * p.charge).sum::<f64>()
-            * (-k_e * alpha / std::f64::consts::PI.sqrt());
+        let shared = particles;
+        let work = kvectors.len() * shared.len();
+        parallel_force_reduce(shared.len(), work, |w, n_workers, forces| {
+            let mut energy = 0.0;
+            let (start, end) = chunk_bounds(kvectors.len(), w, n_workers);
+
+            for kvec in &kvectors[start..end] {
+                let k2 = kvec.norm_squared();
+                let damp = (-k2 / (4.0 * alpha * alpha)).exp();
+                let coef = (2.0 * std::f64::consts::PI * k_e / volume) * damp / k2;
+
+                let mut s_cos = 0.0;
+                let mut s_sin = 0.0;
+                for p in shared.iter() {
+                    let phase = kvec.dot(&p.position);
+                    s_cos += p.charge * phase.cos();
+                    s_sin += p.charge * phase.sin();
+                }
 
-        energy + self_energy
+                energy += coef * (s_cos * s_cos + s_sin * s_sin);
+
+                for (p, f) in shared.iter().zip(forces.iter_mut()) {
+                    let phase = kvec.dot(&p.position);
+                    let sin_i = phase.sin();
+                    let cos_i = phase.cos();
+                    // F_i = -dE/dr_i = 2 C q_i k (S_cos sin_i - S_sin cos_i)
+                    let force_coeff =
+                        (4.0 * std::f64::consts::PI * k_e * p.charge / volume) * damp / k2;
+                    let proj = s_cos * sin_i - s_sin * cos_i;
+                    *f += kvec * (force_coeff * proj);
+                }
+            }
+            energy
+        })
     }
 
-    fn add_electrostatic_forces_particles(
+    fn add_electrostatic_reciprocal_particles(
         particles: &mut [Particle],
         box_length: f64,
         pme: &PmeConfig,
     ) -> f64 {
-        add_electrostatic_real_space_particles(particles, box_length, pme)
-            + add_electrostatic_reciprocal_particles(particles, box_length, pme)
-    }
+        /*
+        Reciprocal-space Ewald forces and energy, either by the direct k-space sum or by
+        smooth PME depending on `pme.method`, plus the slab correction when enabled. The
+        self term is not included; add_electrostatic_long_range_particles adds it.
+         */
+        if particles.is_empty() {
+            return 0.0;
+        }
+        let k_e = coulomb_prefactor();
+        let alpha = pme.alpha;
 
-    fn add_electrostatic_forces_systems(
-        systems: &mut [System],
-        box_length: f64,
-        pme: &PmeConfig,
-    ) -> f64 {
+        // slab geometry: the reciprocal box is extended along z by the vacuum factor
+        let box_dims = Vector3::new(
+            box_length,
+            box_length,
+            box_length * pme.slab_vacuum_factor.unwrap_or(1.0),
+        );
+
+        let (forces, mut energy) = match pme.method {
+            ReciprocalMethod::Ewald => ewald_reciprocal_direct(particles, box_dims, pme),
+            ReciprocalMethod::Spme => spme_reciprocal(
+                particles,
+                box_dims,
+                alpha,
+                k_e,
+                pme.grid_spacing,
+                pme.spline_order,
+            ),
+        };
+
+        for (p, f) in particles.iter_mut().zip(forces) {
+            p.force += f;
+        }
+
+        if pme.slab_vacuum_factor.is_some() {
+            let slab = slab_correction(particles, box_dims, k_e);
+            for (p, f) in particles.iter_mut().zip(slab.forces) {
+                p.force += f;
+            }
+            energy += slab.energy;
+        }
+
+        energy
+    }
+
+    fn add_electrostatic_long_range_particles(
+        particles: &mut [Particle],
+        box_length: f64,
+        electrostatics: &Electrostatics,
+    ) -> f64 {
+        /*
+        Everything beyond the short-range pair sum: the reciprocal sum and the self term
+        -k_e alpha / sqrt(pi) sum q^2 for Ewald, the self term for Wolf/DSF, nothing for
+        reaction field
+         */
+        match electrostatics {
+            Electrostatics::Ewald(pme) => {
+                let sum_q2: f64 = particles.iter().map(|p| p.charge * p.charge).sum();
+                let self_energy =
+                    -coulomb_prefactor() * pme.alpha / std::f64::consts::PI.sqrt() * sum_q2;
+                add_electrostatic_reciprocal_particles(particles, box_length, pme) + self_energy
+            }
+            Electrostatics::Wolf(damped) | Electrostatics::DampedShiftedForce(damped) => {
+                damped.self_energy(particles.iter().map(|p| p.charge * p.charge).sum())
+            }
+            Electrostatics::ReactionField(_) => 0.0,
+        }
+    }
+
+    fn add_electrostatic_forces_particles(
+        particles: &mut [Particle],
+        box_length: f64,
+        electrostatics: &Electrostatics,
+    ) -> f64 {
+        let mut energy = add_electrostatic_short_range_particles(
+            particles,
+            box_length,
+            electrostatics,
+            &HashSet::new(),
+        );
+        energy += add_electrostatic_long_range_particles(particles, box_length, electrostatics);
+        energy
+    }
+
+    fn add_exclusion_correction_particles(
+        particles: &mut [Particle],
+        box_length: f64,
+        electrostatics: &Electrostatics,
+        excluded: &HashSet<(usize, usize)>,
+    ) -> f64 {
+        /*
+        The reciprocal Ewald sum couples every pair of charges, excluded ones included.
+        For each excluded pair the reciprocal-space part of its interaction,
+
+            E_ij = k_e q_i q_j erf(alpha r) / r,
+
+        is subtracted again so excluded pairs do not interact at all. Only needed for
+        Ewald; the cutoff-only methods simply skip excluded pairs.
+         */
+        let pme = match electrostatics {
+            Electrostatics::Ewald(pme) => pme,
+            _ => return 0.0,
+        };
+        let alpha = pme.alpha;
+        let k_e = coulomb_prefactor();
+        let two_alpha_over_sqrt_pi = 2.0 * alpha / std::f64::consts::PI.sqrt();
+        let mut energy = 0.0;
+
+        for &(i, j) in excluded.iter() {
+            let qq = k_e * particles[i].charge * particles[j].charge;
+            if qq == 0.0 {
+                continue;
+            }
+            let rij =
+                minimum_image_convention(particles[j].position - particles[i].position, box_length);
+            let r = rij.norm();
+            if r <= 1e-12 {
+                // r -> 0 limit of erf(alpha r) / r
+                energy -= qq * two_alpha_over_sqrt_pi;
+                continue;
+            }
+
+            let ar = alpha * r;
+            let erf_ar = 1.0 - erfc_approx(ar);
+            energy -= qq * erf_ar / r;
+
+            // f_mag = -dE_corr/dr, positive = repulsive
+            let f_mag = qq * (two_alpha_over_sqrt_pi * (-(ar * ar)).exp() / r - erf_ar / (r * r));
+            let f_vec = (rij / r) * f_mag;
+            particles[i].force -= f_vec;
+            particles[j].force += f_vec;
+        }
+
+        energy
+    }
+
+    fn global_exclusions_systems(systems: &[System]) -> HashSet<(usize, usize)> {
+        /*
+        Map the per-system exclusions onto indices of the flattened atom list
+         */
+        let mut excluded = HashSet::new();
+        let mut offset = 0usize;
+        for sys in systems.iter() {
+            for &(i, j) in sys.exclusions.iter() {
+                excluded.insert(pair_key(offset + i, offset + j));
+            }
+            offset += sys.atoms.len();
+        }
+        excluded
+    }
+
+    pub fn add_electrostatic_forces_systems(
+        systems: &mut [System],
+        box_length: f64,
+        electrostatics: &Electrostatics,
+    ) -> f64 {
         let mut all_atoms: Vec<Particle> = systems
             .iter()
             .flat_map(|s| s.atoms.iter().cloned())
             .collect();
+        // only the electrostatic contribution is added back below
+        for a in all_atoms.iter_mut() {
+            a.force = Vector3::zeros();
+        }
 
-        let energy = add_electrostatic_forces_particles(&mut all_atoms, box_length, pme);
+        // excluded (bonded) pairs are left out of the short-range sum
+        let excluded = global_exclusions_systems(systems);
+        let mut energy = add_electrostatic_short_range_particles(
+            &mut all_atoms,
+            box_length,
+            electrostatics,
+            &excluded,
+        );
+        energy +=
+            add_electrostatic_long_range_particles(&mut all_atoms, box_length, electrostatics);
+        energy += add_exclusion_correction_particles(
+            &mut all_atoms,
+            box_length,
+            electrostatics,
+            &excluded,
+        );
 
         let mut idx = 0usize;
         for sys in systems.iter_mut() {
@@ -1359,6 +1994,80 @@ pub mod lennard_jones_simulations {
         energy
     }
 
+    pub fn compute_lj_forces_cell_list_particles(
+        particles: &[Particle],
+        cl: &CellList,
+    ) -> Vec<Vector3<f64>> {
+        /*
+        Lennard-Jones forces of all neighbour pairs in the (rebuilt) cell list. The cells
+        are split into one contiguous block per worker thread.
+         */
+        let (forces, _) =
+            parallel_force_reduce(particles.len(), particles.len(), |w, n_workers, forces| {
+                let (start, end) = chunk_bounds(cl.n_cells(), w, n_workers);
+                cl.for_each_neighbor_pair_in_cells(particles, start..end, |i, j, dr, r2| {
+                    let si = particles[i].lj_parameters.sigma;
+                    let ei = particles[i].lj_parameters.epsilon;
+                    let sj = particles[j].lj_parameters.sigma;
+                    let ej = particles[j].lj_parameters.epsilon;
+                    let sigma = 0.5 * (si + sj);
+                    let epsilon = (ei * ej).sqrt();
+
+                    let f_vec = compute_pair_forces_vector(dr, r2, sigma, epsilon);
+                    let fv = Vector3::new(f_vec.x, f_vec.y, f_vec.z);
+
+                    forces[i] -= fv;
+                    forces[j] += fv;
+                });
+                0.0
+            });
+        forces
+    }
+
+    fn log_electrostatics<'a>(
+        electrostatics: &Electrostatics,
+        box_length: f64,
+        atoms: impl Iterator<Item = &'a Particle>,
+    ) {
+        /*
+        Report the electrostatics settings of a run, with the estimated Ewald errors
+         */
+        match electrostatics {
+            Electrostatics::Ewald(pme) => {
+                let charges: Vec<f64> = atoms.map(|a| a.charge).collect();
+                let est = pme.estimate_errors(box_length, &charges);
+                info!(
+                    "Ewald ({:?}) | alpha={:.4} rc={:.3} kmax={} grid_spacing={:.4} order={} | est. rel. error real={:.2e} recip={:.2e} | est. rms force error real={:.2e} recip={:.2e}",
+                    pme.method,
+                    pme.alpha,
+                    pme.real_cutoff,
+                    pme.kmax,
+                    pme.grid_spacing,
+                    pme.spline_order,
+                    est.real_space_relative,
+                    est.reciprocal_relative,
+                    est.real_space_force_rms,
+                    est.reciprocal_force_rms
+                );
+            }
+            Electrostatics::ReactionField(rf) => {
+                info!(
+                    "Reaction field | eps_r={} eps_rf={} rc={:.3}",
+                    rf.epsilon_r, rf.epsilon_rf, rf.cutoff
+                );
+            }
+            Electrostatics::Wolf(d) => {
+                info!("Wolf sum | alpha={:.4} rc={:.3}", d.alpha, d.cutoff);
+            }
+            Electrostatics::DampedShiftedForce(d) => {
+                info!(
+                    "Damped shifted force | alpha={:.4} rc={:.3}",
+                    d.alpha, d.cutoff
+                );
+            }
+        }
+    }
+
     pub fn run_md_andersen_particles(
         particles: &mut Vec<Particle>,
         dt: f64,
@@ -1435,34 +2144,49 @@ pub mod lennard_jones_simulations {
         thermostat: &str,
         cutoff: f64,
     ) {
+        run_md_nve_particles_with_options(
+            particles,
+            number_of_steps,
+            dt,
+            box_length,
+            thermostat,
+            cutoff,
+            &MdOptions::default(),
+        );
+    }
+
+    pub fn run_md_nve_particles_with_options(
+        particles: &mut Vec<Particle>,
+        number_of_steps: i32,
+        dt: f64,
+        box_length: f64,
+        thermostat: &str,
+        cutoff: f64,
+        options: &MdOptions,
+    ) -> SimulationSummary {
+        let pool = WorkerPool::new(options.num_threads);
+        let _entered = pool.enter();
         let mut values: Vec<f32> = Vec::new();
+        let mut current: Vec<Vector3<f64>> = Vec::with_capacity(number_of_steps.max(0) as usize);
         let box_len = Vec3::new(box_length, box_length, box_length);
-        let mut cl = CellList::new(box_len, cutoff); // TODO CELL
-        let pme = PmeConfig::default();
-
-        // Create the subcells - we need to have a initial force list for each cell
-        cl.rebuild(&particles);
-        let mut initial_forces = vec![Vector3::<f64>::zeros(); particles.len()];
-        cl.for_each_neighbor_pair(particles, |i, j, dr, r2| {
-            let si = particles[i].lj_parameters.sigma;
-            let ei = particles[i].lj_parameters.epsilon;
-            let sj = particles[j].lj_parameters.sigma;
-            let ej = particles[j].lj_parameters.epsilon;
-            let sigma = 0.5 * (si + sj);
-            let epsilon = (ei * ej).sqrt();
-
-            let f_vec = compute_pair_forces_vector(dr, r2, sigma, epsilon);
-            let fv = Vector3::new(f_vec.x, f_vec.y, f_vec.z);
-
-            initial_forces[i] -= fv;
-            initial_forces[j] += fv;
-        });
-
-        for (p, f) in particles.iter_mut().zip(initial_forces.into_iter()) {
-            p.force = f;
-        }
+        let mut cl = CellList::new(box_len, cutoff);
+        let electrostatics = &options.electrostatics;
+        log_electrostatics(electrostatics, box_length, particles.iter());
+
+        // The store holds the state of the run; `particles` is a view of it that is
+        // synced for the terms that still work on `Particle`.
+        let mut store = ParticleStore::from_particles(particles);
+        store.clear_forces();
+        let mut lj_energy = store.compute_lj_forces_cell_list(&mut cl, box_length, cutoff);
+        store.write_back(particles);
         let electrostatic_init_energy =
-            add_electrostatic_forces_particles(particles, box_length, &pme);
+            add_electrostatic_forces_particles(particles, box_length, electrostatics);
+        if let Some(field) = &options.external_field {
+            field.apply(particles, 0.0);
+        }
+        let (mut bias_energy, _) = apply_biases(particles, &options.biases, box_length, 0.0);
+        store.load_forces(particles);
+        let mut colvars: Vec<Vec<f64>> = Vec::with_capacity(number_of_steps.max(0) as usize);
 
         let mut kinetic_energy = 0.0;
 
@@ -1471,8 +2195,7 @@ pub mod lennard_jones_simulations {
             kinetic_energy += 0.5 * p.mass * p.velocity.norm_squared();
         }
 
-        let mut potential_energy =
-            site_site_energy_calculation(particles, box_length) + electrostatic_init_energy;
+        let mut potential_energy = lj_energy + electrostatic_init_energy + bias_energy;
         let mut total_energy = kinetic_energy + potential_energy;
 
         info!(
@@ -1483,75 +2206,39 @@ pub mod lennard_jones_simulations {
         let mut xi_nose_hoover = 0.0;
 
         // --- time integration loop ---
-        for _step in 0..number_of_steps {
-            // 1) position updatelet mut forces = vec![Vector3::zeros(); particles.len()];
-            let mut forces = vec![Vector3::<f64>::zeros(); particles.len()];
-
-            let mut a_old: Vec<Vector3<f64>> = Vec::with_capacity(particles.len());
-
-            // current acceleration
-            for a in particles.iter() {
-                a_old.push(a.force / a.mass);
-            }
-
+        for step in 0..number_of_steps {
             // 1) velocity update (Verlet - half step)
-            for (atom, a_o) in particles.iter_mut().zip(a_old.iter()) {
-                atom.velocity += 0.5 * a_o * dt;
-            }
+            store.kick(0.5 * dt);
 
             // 2) position update - needs to use the velocity that has been
             // updated using the half step method
-            for atom in particles.iter_mut() {
-                atom.update_position_verlet(dt);
-            }
+            store.drift(dt);
 
             // 3) PBC
-            pbc_update(particles, box_length);
-
-            cl.rebuild(&particles);
-
-            let mut count = 0usize;
-
-            cl.for_each_neighbor_pair(&particles, |i, j, dr, r2| {
-                count += 1;
-                // Insert your force calc here (LJ etc.)
-                //println!(
-                //    "pair ({i},{j}) r2={r2:.4} dr=({:.3},{:.3},{:.3})",
-                //    dr.x, dr.y, dr.z
-                //);
-                //compute_forces_particles_index(&particles, box_length, i as u64, j as u64);
-
-                let si = particles[i as usize].lj_parameters.sigma;
-                let ei = particles[i as usize].lj_parameters.epsilon;
-                let sj = particles[j as usize].lj_parameters.sigma;
-                let ej = particles[j as usize].lj_parameters.epsilon;
-                let sigma = 0.5 * (si + sj);
-                let epsilon = (ei * ej).sqrt();
-
-                let f_vec = compute_pair_forces_vector(dr, r2, sigma, epsilon);
-                let fv = Vector3::new(f_vec.x, f_vec.y, f_vec.z);
+            store.wrap(box_length);
 
-                forces[i] -= fv;
-                forces[j] += fv;
-            });
-
-            for (p, f) in particles.iter_mut().zip(forces.into_iter()) {
-                p.force = f;
-            }
+            // 4) recompute forces: LJ on the store, the rest on the particle view
+            store.clear_forces();
+            lj_energy = store.compute_lj_forces_cell_list(&mut cl, box_length, cutoff);
+            store.write_back(particles);
             let electrostatic_energy =
-                add_electrostatic_forces_particles(particles, box_length, &pme);
-
-            //simulation_box.store_atoms_in_cells_particles(particles, &mut subcells, 10);
-
-            // 4) recompute forces (LJ)
-            //compute_forces_particles(particles, box_length, &mut subcells);
+                add_electrostatic_forces_particles(particles, box_length, electrostatics);
+            if let Some(field) = &options.external_field {
+                field.apply(particles, (step + 1) as f64 * dt);
+            }
+            let (energy, cv_values) = apply_biases(
+                particles,
+                &options.biases,
+                box_length,
+                (step + 1) as f64 * dt,
+            );
+            bias_energy = energy;
+            colvars.push(cv_values);
+            store.load_forces(particles);
 
             // 5) velocity update (Verlet - second half step)
-            for p in particles.iter_mut() {
-                let a_new = p.force / p.mass;
-                // where we do computing of the verlet
-                p.update_velocity_verlet(a_new, dt);
-            }
+            store.kick(0.5 * dt);
+            store.write_back(particles);
 
             // 6) measure temperature
             let dof = 3 * particles.len();
@@ -1573,25 +2260,47 @@ pub mod lennard_jones_simulations {
                     &mut xi_nose_hoover,
                 )
             }
+            store.load_velocities(particles);
 
             // 7) recompute energy
             kinetic_energy = 0.0;
             for p in particles.iter() {
                 kinetic_energy += 0.5 * p.mass * p.velocity.norm_squared();
             }
-            potential_energy =
-                site_site_energy_calculation(particles, box_length) + electrostatic_energy;
+            potential_energy = lj_energy + electrostatic_energy + bias_energy;
             total_energy = kinetic_energy + potential_energy;
 
             values.push(total_energy as f32);
+            current.push(charge_current(particles.iter()));
         }
 
         info!(
             "Init particle energy | E_kin={kinetic_energy:.6} E_pot={potential_energy:.6} E_tot={total_energy:.6}"
         );
+        log_mean_current(&current, options);
 
         // Optional: your running-average helper
         compute_average_val(&mut values, 2, number_of_steps as u64);
+
+        SimulationSummary {
+            energy: total_energy,
+            current,
+            bias_energy,
+            colvars,
+            pressure: compute_pressure_particles(particles, box_length),
+            ..Default::default()
+        }
+    }
+
+    fn log_mean_current(current: &[Vector3<f64>], options: &MdOptions) {
+        if options.external_field.is_none() || current.is_empty() {
+            return;
+        }
+        let mean = current.iter().sum::<Vector3<f64>>() / current.len() as f64;
+        info!(
+            "External field run | <J>=({:.6e}, {:.6e}, {:.6e})",
+            mean.x, mean.y, mean.z
+        );
     }
 
     fn single_particle_energy(particles: &[Particle], idx: usize, box_length: f64) -> f64 {
@@ -1886,133 +2595,407 @@ pub mod lennard_jones_simulations {
      */
 
     pub fn run_md_nve_systems(
-        systems: &mut Vec<System>,
+        systems: &mut [System],
         number_of_steps: i32,
         dt: f64,
         box_length: f64,
         thermostat: &str,
+        cutoff: f64,
     ) {
+        run_md_nve_systems_with_options(
+            systems,
+            number_of_steps,
+            dt,
+            box_length,
+            thermostat,
+            cutoff,
+            &MdOptions::default(),
+        );
+    }
+
+    pub fn run_md_nve_systems_with_options(
+        systems: &mut [System],
+        number_of_steps: i32,
+        dt: f64,
+        box_length: f64,
+        thermostat: &str,
+        cutoff: f64,
+        options: &MdOptions,
+    ) -> SimulationSummary {
+        let pool = WorkerPool::new(options.num_threads);
+        let _entered = pool.enter();
         let mut values: Vec<f32> = Vec::new();
+        let mut current: Vec<Vector3<f64>> = Vec::with_capacity(number_of_steps.max(0) as usize);
         let mut total_energy = 0.0;
         let mut kinetic_energy = 0.0;
         let mut potential_energy = 0.0;
-        let pme = PmeConfig::default();
+        let mut restraint_energy = 0.0;
+        let mut bias_energy = 0.0;
+        let mut colvars: Vec<Vec<f64>> = Vec::with_capacity(number_of_steps.max(0) as usize);
+        let electrostatics = &options.electrostatics;
+        log_electrostatics(
+            electrostatics,
+            box_length,
+            systems.iter().flat_map(|s| s.atoms.iter()),
+        );
+        if cutoff.max(electrostatics.cutoff()) > 0.5 * box_length {
+            warn!(
+                "cutoff exceeds half the box ({:.4}); nonbonded pairs are cut at L/2",
+                0.5 * box_length
+            );
+            if let Electrostatics::Ewald(fitted) = electrostatics.fitted_to_box(box_length) {
+                info!(
+                    "Ewald real-space cutoff capped at {:.4}: alpha {:.5}, kmax {}, grid spacing {:.4}",
+                    fitted.real_cutoff, fitted.alpha, fitted.kmax, fitted.grid_spacing
+                );
+            }
+        }
+        if let Electrostatics::Ewald(PmeConfig {
+            slab_vacuum_factor: Some(_),
+            ..
+        }) = electrostatics
+        {
+            // the real-space pairs see periodic slab images through the cubic box along z
+            let z = systems
+                .iter()
+                .flat_map(|s| s.atoms.iter().map(|a| a.position.z));
+            let (z_min, z_max) = z.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), z| {
+                (lo.min(z), hi.max(z))
+            });
+            if z_max - z_min + cutoff.max(electrostatics.cutoff()) > box_length {
+                warn!(
+                    "slab Ewald: the slab ({:.4} thick) leaves less than a cutoff of vacuum along z in the box ({:.4})",
+                    z_max - z_min,
+                    box_length
+                );
+            }
+        }
+
+        // bond constraints (SHAKE/RATTLE or LINCS), one list per molecule, plus rigid waters
+        let constraints: Vec<Vec<Constraint>> = systems
+            .iter()
+            .map(|sys| constraints_from_bonds(sys, options.constraints.bonds))
+            .collect();
+        for (sys, cons) in systems.iter_mut().zip(constraints.iter()) {
+            // project the starting structure and velocities onto the constraints
+            let reference: Vec<Vector3<f64>> = sys.atoms.iter().map(|a| a.position).collect();
+            let result = constrain_positions(
+                &mut sys.atoms,
+                &reference,
+                cons,
+                0.0,
+                box_length,
+                &options.constraints,
+            )
+            .and_then(|_| {
+                settle_positions(&mut sys.atoms, &reference, &sys.settles, 0.0, box_length)
+            })
+            .and_then(|_| {
+                constrain_velocities(&mut sys.atoms, cons, box_length, &options.constraints)
+            })
+            .and_then(|_| settle_velocities(&mut sys.atoms, &sys.settles, box_length));
+            if let Err(e) = result {
+                warn!("{e}");
+            }
+            construct_virtual_sites(&mut sys.atoms, &sys.virtual_sites, box_length);
+        }
 
-        // Create the subcells for the simulation box
-        let simulation_box = cell_subdivision::SimulationBox {
-            x_dimension: box_length,
-            y_dimension: box_length,
-            z_dimension: box_length,
-        };
-        // Create the subcells - here we have used a subdivision of 10 for the cells
-        let mut subcells = simulation_box.create_subcells(10);
-        // Store the coordinates in cells
-        simulation_box.store_atoms_in_cells_systems(systems, &mut subcells, 10);
         // --- initial forces and energy ---
 
         info!(
             "Init systems energy | E_kin={kinetic_energy:.6} E_pot={potential_energy:.6} E_tot={total_energy:.6}"
         );
 
-        for sys in systems.iter_mut() {
-            for a in sys.atoms.iter_mut() {
-                a.force = Vector3::zeros();
-            }
-            compute_bonded_forces(
-                &mut sys.atoms,
-                &sys.bonds,
-                &sys.angles,
-                &sys.dihedrals,
-                &sys.impropers,
-                box_length,
-            );
-        }
-        compute_intermolecular_forces_systems(systems, box_length);
-        let _ = add_electrostatic_forces_systems(systems, box_length, &pme);
+        let _ = compute_forces_systems_with_options(systems, box_length, cutoff, options, 0.0);
 
         // this is only used if we apply nose hoover
         let mut xi_nose_hoover = vec![0.0; systems.len()];
+        // and this one for the dual Drude thermostat
+        let mut drude_thermostat = vec![DrudeThermostatState::default(); systems.len()];
 
         // --- time integration loop ---
         for _step in 0..number_of_steps {
-            for sys in systems.iter_mut() {
+            for (sys, cons) in systems.iter_mut().zip(constraints.iter()) {
                 let mut a_old: Vec<Vector3<f64>> = Vec::with_capacity(sys.atoms.len());
 
                 for a in sys.atoms.iter() {
-                    a_old.push(a.force / a.mass);
+                    a_old.push(a.acceleration());
                 }
 
                 for (atom, a_o) in sys.atoms.iter_mut().zip(a_old.iter()) {
                     atom.velocity += 0.5 * a_o * dt;
                 }
 
+                let reference: Vec<Vector3<f64>> = sys.atoms.iter().map(|a| a.position).collect();
                 for atom in sys.atoms.iter_mut() {
                     atom.update_position_verlet(dt);
                 }
-
-                pbc_update(&mut sys.atoms, box_length);
-
-                for a in sys.atoms.iter_mut() {
-                    a.force = Vector3::zeros();
-                }
-                compute_bonded_forces(
+                if let Err(e) = constrain_positions(
                     &mut sys.atoms,
-                    &sys.bonds,
-                    &sys.angles,
-                    &sys.dihedrals,
-                    &sys.impropers,
+                    &reference,
+                    cons,
+                    dt,
                     box_length,
-                );
+                    &options.constraints,
+                ) {
+                    warn!("Step {_step}: {e}");
+                }
+                if let Err(e) =
+                    settle_positions(&mut sys.atoms, &reference, &sys.settles, dt, box_length)
+                {
+                    warn!("Step {_step}: {e}");
+                }
+                apply_drude_hard_wall(&mut sys.atoms, &sys.drudes, box_length);
+
+                pbc_update(&mut sys.atoms, box_length);
+                construct_virtual_sites(&mut sys.atoms, &sys.virtual_sites, box_length);
             }
 
-            compute_intermolecular_forces_systems(systems, box_length);
-            let electrostatic_energy = add_electrostatic_forces_systems(systems, box_length, &pme);
+            let evaluation = compute_forces_systems_with_options(
+                systems,
+                box_length,
+                cutoff,
+                options,
+                (_step + 1) as f64 * dt,
+            );
+            potential_energy = evaluation.potential;
+            restraint_energy = evaluation.restraint;
+            bias_energy = evaluation.bias;
+            colvars.push(evaluation.colvars);
 
             for (s, sys) in systems.iter_mut().enumerate() {
                 for a in sys.atoms.iter_mut() {
-                    let a_new = a.force / a.mass;
+                    let a_new = a.acceleration();
                     a.update_velocity_verlet(a_new, dt);
                 }
+                if let Err(e) = constrain_velocities(
+                    &mut sys.atoms,
+                    &constraints[s],
+                    box_length,
+                    &options.constraints,
+                ) {
+                    warn!("Step {_step}: {e}");
+                }
+                if let Err(e) = settle_velocities(&mut sys.atoms, &sys.settles, box_length) {
+                    warn!("Step {_step}: {e}");
+                }
 
-                let dof = 3 * sys.atoms.len();
+                // every constraint removes one degree of freedom, every rigid water three;
+                // virtual sites have none
+                let n_real = sys.atoms.len() - sys.virtual_sites.len();
+                let dof = constrained_dof(n_real, &constraints[s], &sys.settles);
                 let _system_temperature = compute_temperature_particles(&sys.atoms, dof);
                 if thermostat == "berendsen" {
-                    apply_thermostat_berendsen_particles(&mut sys.atoms, 300.0, 0.1, dt);
+                    apply_thermostat_berendsen_particles_with_dof(
+                        &mut sys.atoms,
+                        dof,
+                        300.0,
+                        0.1,
+                        dt,
+                    );
                 } else if thermostat == "nose_hoover" {
-                    apply_thermostat_nose_hoover_particles(
+                    apply_thermostat_nose_hoover_particles_with_dof(
                         &mut sys.atoms,
+                        dof,
                         300.0,
                         10.0,
                         dt,
                         &mut xi_nose_hoover[s],
                     )
+                } else if thermostat == "drude" {
+                    apply_dual_thermostat_drude(
+                        &mut sys.atoms,
+                        &sys.drudes,
+                        dof,
+                        &options.drude_thermostat,
+                        dt,
+                        &mut drude_thermostat[s],
+                    )
                 }
             }
 
             kinetic_energy = 0.0;
-            potential_energy = 0.0;
-            for sys in systems.iter_mut() {
+            for sys in systems.iter() {
                 for a in sys.atoms.iter() {
                     kinetic_energy += 0.5 * a.mass * a.velocity.norm_squared();
                 }
-                potential_energy += compute_bonded_energy(
-                    &mut sys.atoms,
-                    &sys.bonds,
-                    &sys.angles,
-                    &sys.dihedrals,
-                    &sys.impropers,
-                    box_length,
-                );
             }
-            potential_energy += intermolecular_site_site_energy_systems(systems, box_length);
-            potential_energy += electrostatic_energy;
 
             total_energy = kinetic_energy + potential_energy;
             values.push(total_energy as f32);
-            info!("Step {_step:>4} | E_tot={total_energy:.6} E_kin={kinetic_energy:.6} E_pot={potential_energy:.6}");
+            current.push(charge_current(systems.iter().flat_map(|s| s.atoms.iter())));
+            info!("Step {_step:>4} | E_tot={total_energy:.6} E_kin={kinetic_energy:.6} E_pot={potential_energy:.6} E_restr={restraint_energy:.6} E_bias={bias_energy:.6}");
         }
+        log_mean_current(&current, options);
 
         compute_average_val(&mut values, 2, number_of_steps as u64);
+
+        SimulationSummary {
+            energy: total_energy,
+            current,
+            restraint_energy,
+            bias_energy,
+            colvars,
+            pressure: compute_pressure_systems(systems, box_length, cutoff, electrostatics),
+        }
+    }
+
+    fn compute_forces_systems_with_options(
+        systems: &mut [System],
+        box_length: f64,
+        cutoff: f64,
+        options: &MdOptions,
+        time: f64,
+    ) -> ForceEvaluation {
+        /*
+        Forces on every atom of the systems at their current positions: bonded, Drude,
+        extended bonded, position restraints (when enabled), nonbonded, the external
+        field and the CV biases, with the virtual site forces spread onto their
+        constructing atoms.
+         */
+        let mut potential = 0.0;
+        let mut restraint = 0.0;
+        for sys in systems.iter_mut() {
+            for a in sys.atoms.iter_mut() {
+                a.force = Vector3::zeros();
+            }
+            potential += compute_bonded_forces(
+                &mut sys.atoms,
+                &sys.bonds,
+                &sys.angles,
+                &sys.dihedrals,
+                &sys.impropers,
+                box_length,
+            );
+            potential += compute_drude_forces(&mut sys.atoms, &sys.drudes, box_length);
+            potential +=
+                apply_extended_bonded_forces_and_energy(&mut sys.atoms, &sys.extended, box_length);
+            if options.position_restraints {
+                restraint +=
+                    apply_position_restraints(&mut sys.atoms, &sys.position_restraints, box_length);
+            }
+        }
+        // nonbonded forces (LJ + Ewald) through the cell list
+        potential +=
+            compute_nonbonded_forces_systems(systems, box_length, cutoff, &options.electrostatics);
+        if let Some(field) = &options.external_field {
+            for sys in systems.iter_mut() {
+                field.apply(&mut sys.atoms, time);
+            }
+        }
+        let (mut bias, mut colvars) = (0.0, Vec::new());
+        if !options.biases.is_empty() {
+            // CVs may span several systems, so they see one concatenated atom list
+            let mut all_atoms: Vec<Particle> = systems
+                .iter()
+                .flat_map(|s| s.atoms.iter().cloned())
+                .collect();
+            for a in all_atoms.iter_mut() {
+                a.force = Vector3::zeros();
+            }
+            (bias, colvars) = apply_biases(&mut all_atoms, &options.biases, box_length, time);
+            let mut bias_forces = all_atoms.iter().map(|a| a.force);
+            for a in systems.iter_mut().flat_map(|s| s.atoms.iter_mut()) {
+                a.force += bias_forces.next().unwrap_or_else(Vector3::zeros);
+            }
+        }
+        for sys in systems.iter_mut() {
+            spread_virtual_site_forces(&mut sys.atoms, &sys.virtual_sites, box_length);
+        }
+        ForceEvaluation {
+            potential: potential + restraint + bias,
+            restraint,
+            bias,
+            colvars,
+        }
+    }
+
+    pub fn minimize_steepest_descent_systems(
+        systems: &mut [System],
+        box_length: f64,
+        cutoff: f64,
+        options: &MdOptions,
+        max_step: f64,
+        max_iters: usize,
+        force_tol: f64,
+    ) -> MinimizationSummary {
+        /*
+        Steepest descent on the systems with the same force field as the MD driver,
+        position restraints included when `options.position_restraints` is set.
+
+        As in GROMACS, the atom feeling the largest force moves by the step size h and
+        every other atom proportionally, x <- x + h F / max|F|. A step that lowers the
+        energy is kept and h grows by 1.2; otherwise it is undone and h shrinks by 0.2.
+        Bond constraints are not applied; virtual sites follow their constructing atoms.
+        The external field is left out: its energy -sum_i q_i E.r_i is not defined for
+        wrapped coordinates, and its forces alone would not match the energy minimised.
+         */
+        let pool = WorkerPool::new(options.num_threads);
+        let _entered = pool.enter();
+        if options.external_field.is_some() {
+            info!("The external field is not applied during energy minimization");
+        }
+        let options = &MdOptions {
+            external_field: None,
+            ..options.clone()
+        };
+        let max_force = |systems: &[System]| {
+            systems
+                .iter()
+                .flat_map(|s| s.atoms.iter())
+                .map(|a| a.force.norm())
+                .fold(0.0, f64::max)
+        };
+
+        for sys in systems.iter_mut() {
+            construct_virtual_sites(&mut sys.atoms, &sys.virtual_sites, box_length);
+        }
+        let initial =
+            compute_forces_systems_with_options(systems, box_length, cutoff, options, 0.0);
+        let (mut energy, mut restraint_energy) = (initial.potential, initial.restraint);
+        let mut f_max = max_force(systems);
+        let mut step = max_step;
+        let mut iterations = 0;
+
+        for iter in 0..max_iters {
+            if f_max < force_tol {
+                break;
+            }
+            iterations = iter + 1;
+
+            let saved: Vec<Vec<Particle>> = systems.iter().map(|s| s.atoms.clone()).collect();
+            for sys in systems.iter_mut() {
+                for a in sys.atoms.iter_mut() {
+                    a.position += step * a.force / f_max;
+                }
+                pbc_update(&mut sys.atoms, box_length);
+                construct_virtual_sites(&mut sys.atoms, &sys.virtual_sites, box_length);
+            }
+            let trial =
+                compute_forces_systems_with_options(systems, box_length, cutoff, options, 0.0);
+
+            if trial.potential < energy {
+                energy = trial.potential;
+                restraint_energy = trial.restraint;
+                f_max = max_force(systems);
+                step *= 1.2;
+            } else {
+                // positions and forces go back to the last accepted structure
+                for (sys, atoms) in systems.iter_mut().zip(saved) {
+                    sys.atoms = atoms;
+                }
+                step *= 0.2;
+            }
+            info!("Minimization iter {iter:>4} | E_pot={energy:.6} E_restr={restraint_energy:.6} max |F| = {f_max:.6}");
+        }
+
+        MinimizationSummary {
+            energy,
+            restraint_energy,
+            max_force: f_max,
+            iterations,
+            converged: f_max < force_tol,
+        }
     }
 
     pub fn run_md_nve(
@@ -2023,6 +3006,26 @@ pub mod lennard_jones_simulations {
         thermostat: &str,
         cutoff: f64,
     ) {
+        run_md_nve_with_options(
+            state,
+            number_of_steps,
+            dt,
+            box_length,
+            thermostat,
+            cutoff,
+            &MdOptions::default(),
+        );
+    }
+
+    pub fn run_md_nve_with_options(
+        state: &mut InitOutput,
+        number_of_steps: i32,
+        dt: f64,
+        box_length: f64,
+        thermostat: &str,
+        cutoff: f64,
+        options: &MdOptions,
+    ) -> SimulationSummary {
         if thermostat == "monte_carlo" {
             match state {
                 InitOutput::Particles(particles) => {
@@ -2039,13 +3042,27 @@ pub mod lennard_jones_simulations {
         match state {
             InitOutput::Particles(particles) => {
                 if thermostat == "monte_carlo" {
-                    return;
+                    return SimulationSummary::default();
                 }
-                run_md_nve_particles(particles, number_of_steps, dt, box_length, thermostat, 30.0);
-            }
-            InitOutput::Systems(systems) => {
-                run_md_nve_systems(systems, number_of_steps, dt, box_length, thermostat);
+                run_md_nve_particles_with_options(
+                    particles,
+                    number_of_steps,
+                    dt,
+                    box_length,
+                    thermostat,
+                    cutoff,
+                    options,
+                )
             }
+            InitOutput::Systems(systems) => run_md_nve_systems_with_options(
+                systems,
+                number_of_steps,
+                dt,
+                box_length,
+                thermostat,
+                cutoff,
+                options,
+            ),
         }
     }
 
@@ -2056,6 +3073,7 @@ pub mod lennard_jones_simulations {
         dt: f64,
         box_length: f64,
         thermostat: &str,
+        cutoff: f64,
         world: &C,
     ) where
         C: mpi::topology::Communicator + mpi::traits::CommunicatorCollectives,
@@ -2077,7 +3095,7 @@ pub mod lennard_jones_simulations {
                         "MPI NVE currently supports particle systems; falling back to serial systems integration."
                     );
                 }
-                run_md_nve_systems(systems, number_of_steps, dt, box_length, thermostat);
+                run_md_nve_systems(systems, number_of_steps, dt, box_length, thermostat, cutoff);
             }
         }
     }
@@ -2113,6 +3131,499 @@ mod tests {
         };
     }
 
+    #[test]
+    #[allow(deprecated)]
+    fn intramolecular_terms_skip_excluded_pairs() {
+        use crate::molecule::molecule::{make_h2_system, Bond, BondForm, System};
+        use lennard_jones_simulations::{
+            compute_intramolecular_forces_systems, intramolecular_site_site_energy_systems,
+            LJParameters, Particle,
+        };
+        use nalgebra::Vector3;
+
+        // bonded H2 pair is excluded, so there is no intramolecular nonbonded energy
+        let mut h2 = vec![make_h2_system()];
+        let e_h2 = compute_intramolecular_forces_systems(&mut h2, 10.0);
+        assert!(e_h2.abs() < 1e-12);
+
+        let atoms: Vec<Particle> = (0..5)
+            .map(|i| Particle {
+                id: i,
+                position: Vector3::new(1.1 * i as f64, 0.1 * (i % 2) as f64, 0.0),
+                velocity: Vector3::zeros(),
+                force: Vector3::zeros(),
+                lj_parameters: LJParameters {
+                    epsilon: 1.0,
+                    sigma: 1.0,
+                    number_of_atoms: 1,
+                },
+                mass: 1.0,
+                energy: 0.0,
+                atom_type: 0.0,
+                charge: if i % 2 == 0 { 0.5 } else { -0.5 },
+            })
+            .collect();
+        let bonds = (0..4)
+            .map(|i| Bond {
+                atom1: i,
+                atom2: i + 1,
+                k: 100.0,
+                r0: 1.1,
+                form: BondForm::Harmonic,
+            })
+            .collect();
+        let mut chain = System {
+            atoms,
+            bonds,
+            ..Default::default()
+        };
+        chain.generate_exclusions(3, 0.5, 0.5);
+        let mut systems = vec![chain];
+
+        let energy = compute_intramolecular_forces_systems(&mut systems, 30.0);
+        let energy_only = intramolecular_site_site_energy_systems(&systems, 30.0);
+        assert!((energy - energy_only).abs() < 1e-10);
+        assert!(energy.abs() > 0.0);
+
+        // pair forces obey Newton's third law
+        let net: Vector3<f64> = systems[0].atoms.iter().map(|a| a.force).sum();
+        assert!(net.norm() < 1e-10);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn cell_list_nonbonded_matches_all_pairs_systems() {
+        use crate::molecule::molecule::{create_systems, make_h2_system};
+        use lennard_jones_simulations::{
+            add_electrostatic_forces_systems, compute_intermolecular_forces_systems,
+            compute_intramolecular_forces_systems, compute_nonbonded_forces_systems, InitOutput,
+        };
+
+        let box_length = 12.0;
+        let pme = PmeConfig {
+            alpha: 0.6,
+            real_cutoff: 5.9,
+            kmax: 3,
+            ..Default::default()
+        };
+        let mut systems = match create_systems(&make_h2_system(), 27) {
+            InitOutput::Systems(systems) => systems,
+            InitOutput::Particles(_) => panic!("expected systems output"),
+        };
+        let mut reference = systems.clone();
+
+        // cutoff just below L/2 so both paths see the same minimum-image pairs for LJ
+        let cutoff = 5.9;
+        let coulomb = Electrostatics::Ewald(pme);
+        let e_cells = compute_nonbonded_forces_systems(&mut systems, box_length, cutoff, &coulomb);
+
+        let mut e_ref = compute_intermolecular_forces_systems(&mut reference, box_length);
+        e_ref += compute_intramolecular_forces_systems(&mut reference, box_length);
+        e_ref += add_electrostatic_forces_systems(&mut reference, box_length, &coulomb);
+
+        // the all-pairs LJ has no cutoff; the tail beyond 5.9 sigma is ~1e-4 per pair
+        assert!((e_cells - e_ref).abs() < 0.05 * e_ref.abs().max(1.0));
+        for (sys, sys_ref) in systems.iter().zip(reference.iter()) {
+            for (a, b) in sys.atoms.iter().zip(sys_ref.atoms.iter()) {
+                assert!((a.force - b.force).norm() < 1e-2);
+            }
+        }
+
+        // cutoffs beyond half the box are capped at L/2
+        let mut capped = reference.clone();
+        let e_half = compute_nonbonded_forces_systems(&mut capped, box_length, 6.0, &coulomb);
+        let e_long = compute_nonbonded_forces_systems(&mut capped, box_length, 20.0, &coulomb);
+        assert!((e_half - e_long).abs() < 1e-12);
+    }
+
+    #[test]
+    fn spme_matches_direct_ewald_sum() {
+        use crate::molecule::molecule::{create_systems, make_h2_system};
+        use lennard_jones_simulations::{add_electrostatic_forces_systems, InitOutput};
+
+        let box_length = 10.0;
+        let mut systems = match create_systems(&make_h2_system(), 8) {
+            InitOutput::Systems(systems) => systems,
+            InitOutput::Particles(_) => panic!("expected systems output"),
+        };
+        // give the (neutral) H2 molecules a dipole
+        for sys in systems.iter_mut() {
+            sys.atoms[0].charge = 0.4;
+            sys.atoms[1].charge = -0.4;
+        }
+        let mut direct = systems.clone();
+
+        let spme = PmeConfig {
+            alpha: 0.6,
+            real_cutoff: 4.9,
+            grid_spacing: 0.25,
+            spline_order: 6,
+            ..Default::default()
+        };
+        let ewald = PmeConfig {
+            method: ReciprocalMethod::Ewald,
+            kmax: 8,
+            ..spme
+        };
+
+        let e_spme = add_electrostatic_forces_systems(
+            &mut systems,
+            box_length,
+            &Electrostatics::Ewald(spme),
+        );
+        let e_ewald = add_electrostatic_forces_systems(
+            &mut direct,
+            box_length,
+            &Electrostatics::Ewald(ewald),
+        );
+
+        assert!((e_spme - e_ewald).abs() < 1e-4 * e_ewald.abs().max(1.0));
+        for (sys, sys_ref) in systems.iter().zip(direct.iter()) {
+            for (a, b) in sys.atoms.iter().zip(sys_ref.atoms.iter()) {
+                assert!((a.force - b.force).norm() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn ewald_exclusion_correction_removes_intramolecular_coulomb() {
+        use crate::molecule::molecule::make_h2_system;
+        use lennard_jones_simulations::add_electrostatic_forces_systems;
+
+        // one excluded +q/-q pair in a large box: with the reciprocal part of the
+        // excluded pair removed, only negligible image interactions are left
+        let box_length = 30.0;
+        let mut h2 = make_h2_system();
+        h2.atoms[0].charge = 0.4;
+        h2.atoms[1].charge = -0.4;
+        assert!(h2.is_excluded(0, 1));
+
+        let (pme, _) =
+            PmeConfig::tune(box_length, &[0.4, -0.4], 1e-6, ReciprocalMethod::Ewald).unwrap();
+        let mut systems = vec![h2];
+        let energy =
+            add_electrostatic_forces_systems(&mut systems, box_length, &Electrostatics::Ewald(pme));
+
+        assert!(energy.abs() < 1e-4, "residual energy {energy}");
+        for a in systems[0].atoms.iter() {
+            assert!(a.force.norm() < 1e-4, "residual force {:?}", a.force);
+        }
+    }
+
+    #[test]
+    fn system_pressure_includes_slab_virial() {
+        use crate::electrostatics::slab::slab_correction;
+        use crate::molecule::molecule::{create_systems, make_h2_system};
+        use lennard_jones_simulations::{compute_pressure_systems, InitOutput};
+        use nalgebra::Vector3;
+
+        let box_length = 12.0;
+        let mut systems = match create_systems(&make_h2_system(), 8) {
+            InitOutput::Systems(systems) => systems,
+            InitOutput::Particles(_) => panic!("expected systems output"),
+        };
+        for sys in systems.iter_mut() {
+            sys.atoms[0].charge = 0.4;
+            sys.atoms[1].charge = -0.4;
+            sys.atoms[1].position.z += 0.3;
+        }
+        let bulk = PmeConfig::default();
+        let slab = PmeConfig {
+            slab_vacuum_factor: Some(3.0),
+            ..bulk
+        };
+
+        let p_bulk =
+            compute_pressure_systems(&systems, box_length, 5.0, &Electrostatics::Ewald(bulk));
+        let p_slab =
+            compute_pressure_systems(&systems, box_length, 5.0, &Electrostatics::Ewald(slab));
+
+        // W = diag(E, E, -E) adds E / 3V
+        let atoms: Vec<_> = systems.iter().flat_map(|s| s.atoms.clone()).collect();
+        let dims = Vector3::new(box_length, box_length, 3.0 * box_length);
+        let e_slab = slab_correction(&atoms, dims, 1.0).energy;
+        assert!(e_slab.abs() > 1e-6);
+        let expected = e_slab / (3.0 * box_length.powi(3));
+        assert!((p_slab - p_bulk - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn system_pressure_includes_bond_virial() {
+        use crate::molecule::molecule::{bond_virial, make_h2_system};
+        use lennard_jones_simulations::compute_pressure_systems;
+        use nalgebra::Vector3;
+
+        // a single stretched H2 at rest: the 1-2 pair is excluded, so only the bond acts
+        let box_length = 10.0;
+        let mut system = make_h2_system();
+        for a in system.atoms.iter_mut() {
+            a.velocity = Vector3::zeros();
+        }
+        system.atoms[1].position = system.atoms[0].position + Vector3::new(0.9, 0.0, 0.0);
+
+        let pressure = compute_pressure_systems(
+            std::slice::from_ref(&system),
+            box_length,
+            3.0,
+            &Electrostatics::default(),
+        );
+        let w = bond_virial(&system.atoms, &system.bonds, box_length);
+        // a stretched bond pulls inwards and lowers the pressure
+        assert!(w.trace() < 0.0);
+        assert!((pressure - w.trace() / (3.0 * box_length.powi(3))).abs() < 1e-12);
+    }
+
+    #[test]
+    fn constant_field_drives_linear_current() {
+        use crate::electrostatics::damped::DampedCoulomb;
+        use crate::electrostatics::external_field::ExternalField;
+        use crate::test_support::particle;
+        use lennard_jones_simulations::{run_md_nve_particles_with_options, MdOptions, Particle};
+        use nalgebra::Vector3;
+
+        // a lone ion under a constant field accelerates uniformly, so J = q^2 E t / m
+        let mut particles = vec![Particle {
+            charge: 2.0,
+            ..particle(Vector3::new(10.0, 10.0, 10.0), 1.0)
+        }];
+        let options = MdOptions {
+            electrostatics: Electrostatics::Wolf(DampedCoulomb::default()),
+            external_field: Some(ExternalField::constant(Vector3::new(0.5, 0.0, 0.0))),
+            ..Default::default()
+        };
+        let dt = 0.01;
+        let summary =
+            run_md_nve_particles_with_options(&mut particles, 10, dt, 20.0, "none", 9.0, &options);
+
+        assert_eq!(summary.current.len(), 10);
+        for (n, j) in summary.current.iter().enumerate() {
+            let expected = 2.0 * 2.0 * 0.5 * (n + 1) as f64 * dt;
+            assert!((j.x - expected).abs() < 1e-12, "step {n}: {j:?}");
+            assert!(j.y.abs() < 1e-12 && j.z.abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn constrained_h2_keeps_bond_length_in_md() {
+        use crate::constraints::constraint::{
+            ConstraintAlgorithm, ConstraintOptions, ConstraintSelection,
+        };
+        use crate::molecule::molecule::{create_systems, make_h2_system};
+        use lennard_jones_simulations::{run_md_nve_systems_with_options, InitOutput, MdOptions};
+
+        for algorithm in [ConstraintAlgorithm::Shake, ConstraintAlgorithm::Lincs] {
+            let mut systems = match create_systems(&make_h2_system(), 4) {
+                InitOutput::Systems(systems) => systems,
+                InitOutput::Particles(_) => panic!("expected systems output"),
+            };
+            let options = MdOptions {
+                constraints: ConstraintOptions {
+                    bonds: ConstraintSelection::AllBonds,
+                    algorithm,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            run_md_nve_systems_with_options(
+                &mut systems,
+                50,
+                0.001,
+                10.0,
+                "berendsen",
+                3.0,
+                &options,
+            );
+
+            for sys in systems.iter() {
+                let bond = &sys.bonds[0];
+                let r = lennard_jones_simulations::minimum_image_convention(
+                    sys.atoms[bond.atom2].position - sys.atoms[bond.atom1].position,
+                    10.0,
+                )
+                .norm();
+                assert!((r - bond.r0).abs() < 1e-6, "{algorithm:?}: bond length {r}");
+            }
+        }
+    }
+
+    #[test]
+    fn position_restraints_hold_atoms_in_minimisation_and_md() {
+        use crate::molecule::molecule::make_h2_system;
+        use crate::molecule::restraint::{parse_position_restraints, set_reference_positions};
+        use lennard_jones_simulations::{
+            minimize_steepest_descent_systems, run_md_nve_systems_with_options, MdOptions,
+        };
+
+        let box_length = 10.0;
+        let mut stretched = make_h2_system();
+        stretched.atoms[1].position.x += 0.3;
+        let start: Vec<nalgebra::Vector3<f64>> =
+            stretched.atoms.iter().map(|a| a.position).collect();
+        stretched.position_restraints =
+            parse_position_restraints("[ position_restraints ]\n1 1 1000 1000 1000\n")
+                .expect("restraint row should parse");
+        set_reference_positions(&mut stretched.position_restraints, &start)
+            .expect("reference in range");
+
+        let restrained = MdOptions {
+            position_restraints: true,
+            ..Default::default()
+        };
+        for (options, pinned) in [(&restrained, true), (&MdOptions::default(), false)] {
+            let mut systems = vec![stretched.clone()];
+            let summary = minimize_steepest_descent_systems(
+                &mut systems,
+                box_length,
+                3.0,
+                options,
+                0.01,
+                500,
+                1e-3,
+            );
+            assert!(summary.converged, "{summary:?}");
+
+            let atoms = &systems[0].atoms;
+            let image = |v| lennard_jones_simulations::minimum_image_convention(v, box_length);
+            let r = image(atoms[1].position - atoms[0].position).norm();
+            assert!((r - systems[0].bonds[0].r0).abs() < 1e-3, "bond length {r}");
+            let shift = image(atoms[0].position - start[0]).norm();
+            if pinned {
+                // the bond pulls with 100 * 0.35, the restraint holds with 1000 per unit length
+                assert!(shift < 0.05, "restrained atom moved by {shift}");
+            } else {
+                assert!((shift - 0.175).abs() < 1e-2, "free atom moved by {shift}");
+                assert_eq!(summary.restraint_energy, 0.0);
+            }
+        }
+
+        // restraint energy is only reported in a run phase that enables the restraints
+        for (options, active) in [(&restrained, true), (&MdOptions::default(), false)] {
+            let mut systems = vec![stretched.clone()];
+            let summary = run_md_nve_systems_with_options(
+                &mut systems,
+                20,
+                0.001,
+                box_length,
+                "none",
+                3.0,
+                options,
+            );
+            assert_eq!(summary.restraint_energy > 0.0, active);
+        }
+    }
+
+    #[test]
+    fn external_field_is_left_out_of_minimisation() {
+        use crate::electrostatics::external_field::ExternalField;
+        use crate::molecule::molecule::make_h2_system;
+        use lennard_jones_simulations::{minimize_steepest_descent_systems, MdOptions};
+
+        let mut h2 = make_h2_system();
+        h2.atoms[0].charge = 0.4;
+        h2.atoms[1].charge = -0.4;
+        h2.atoms[1].position.x += 0.3;
+
+        let field = MdOptions {
+            external_field: Some(ExternalField::constant(nalgebra::Vector3::new(
+                5.0, 0.0, 0.0,
+            ))),
+            ..Default::default()
+        };
+        let minimise = |options: &MdOptions| {
+            let mut systems = vec![h2.clone()];
+            let summary = minimize_steepest_descent_systems(
+                &mut systems,
+                10.0,
+                3.0,
+                options,
+                0.01,
+                200,
+                1e-3,
+            );
+            (systems, summary)
+        };
+        let (with_field, e_field) = minimise(&field);
+        let (without, e_plain) = minimise(&MdOptions::default());
+
+        assert_eq!(e_field.energy, e_plain.energy);
+        for (a, b) in with_field[0].atoms.iter().zip(without[0].atoms.iter()) {
+            assert_eq!(a.position, b.position);
+        }
+    }
+
+    #[test]
+    fn distance_bias_drives_particles_and_conserves_energy() {
+        use crate::colvar::bias::Bias;
+        use crate::colvar::colvar::Colvar;
+        use crate::test_support::particles;
+        use lennard_jones_simulations::{run_md_nve_particles_with_options, MdOptions};
+
+        let mut particles = particles(
+            &[
+                nalgebra::Vector3::new(4.25, 5.0, 5.0),
+                nalgebra::Vector3::new(5.75, 5.0, 5.0),
+            ],
+            1.0,
+        );
+        let options = MdOptions {
+            biases: vec![Bias::harmonic(
+                Colvar::Distance { atoms: [0, 1] },
+                50.0,
+                1.0,
+            )],
+            ..Default::default()
+        };
+        let summary = run_md_nve_particles_with_options(
+            &mut particles,
+            400,
+            0.002,
+            10.0,
+            "none",
+            3.0,
+            &options,
+        );
+
+        // the bias energy 1/2 k (1.5 - 1)^2 turns into kinetic energy and back
+        assert!(
+            (summary.energy - 6.25).abs() < 1e-2,
+            "E = {}",
+            summary.energy
+        );
+        assert_eq!(summary.colvars.len(), 400);
+        let closest = summary
+            .colvars
+            .iter()
+            .map(|v| v[0])
+            .fold(f64::INFINITY, f64::min);
+        assert!((closest - 0.5).abs() < 2e-2, "closest approach {closest}");
+    }
+
+    #[test]
+    fn kremer_grest_chains_stay_bonded_in_md() {
+        use crate::molecule::molecule::{create_systems, make_kremer_grest_chain, BondForm};
+        use lennard_jones_simulations::{run_md_nve_systems, InitOutput};
+
+        let mut systems = match create_systems(&make_kremer_grest_chain(10), 3) {
+            InitOutput::Systems(systems) => systems,
+            InitOutput::Particles(_) => panic!("expected systems output"),
+        };
+        assert!(systems
+            .iter()
+            .all(|s| s.bonds.len() == 9 && matches!(s.bonds[0].form, BondForm::Fene { .. })));
+
+        run_md_nve_systems(&mut systems, 200, 0.002, 20.0, "none", 1.122);
+        for sys in &systems {
+            for bond in &sys.bonds {
+                let r = lennard_jones_simulations::minimum_image_convention(
+                    sys.atoms[bond.atom2].position - sys.atoms[bond.atom1].position,
+                    20.0,
+                )
+                .norm();
+                assert!(r > 0.8 && r < 1.2, "bond length {r}");
+            }
+        }
+    }
+
     #[test]
     fn berenden_pull_towards_target() {
         /* mock velocities - T = 300K
@@ -79,6 +79,7 @@ fn main() {
             0.0005,
             10.0,
             md_mode,
+            30.0,
             &world,
         );
     }
@@ -124,6 +125,7 @@ fn main() {
             0.0005,
             10.0,
             md_mode,
+            30.0,
             &world,
         );
     }
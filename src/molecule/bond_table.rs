@@ -0,0 +1,98 @@
+/*
+Tabulated bond potentials (GROMACS bond types 8 and 9)
+
+A table_b<n>.xvg file lists r, V(r) and f(r) = -dV/dr on a grid; the bond energy is
+k V(r). Between grid points V is a cubic Hermite interpolant of the tabulated values
+and slopes, so the force is exactly the derivative of the interpolated energy. Outside
+the table V is continued linearly with the slope of the nearest end.
+
+Lines starting with '#' or '@' (xmgrace headers) are skipped.
+ */
+
+use std::fs;
+
+#[derive(Clone, Debug)]
+pub struct BondTable {
+    pub r: Vec<f64>,
+    pub v: Vec<f64>,
+    pub f: Vec<f64>, // -dV/dr
+}
+
+impl BondTable {
+    pub fn new(r: Vec<f64>, v: Vec<f64>, f: Vec<f64>) -> Result<Self, String> {
+        if r.len() < 2 || v.len() != r.len() || f.len() != r.len() {
+            return Err("a bond table needs at least two rows of r, V and f".to_string());
+        }
+        if r.windows(2).any(|w| w[1] <= w[0]) {
+            return Err("bond table distances must be strictly increasing".to_string());
+        }
+        Ok(BondTable { r, v, f })
+    }
+
+    pub fn parse_xvg(contents: &str) -> Result<Self, String> {
+        let (mut r, mut v, mut f) = (Vec::new(), Vec::new(), Vec::new());
+        for (line_number, raw_line) in contents.lines().enumerate() {
+            let line = raw_line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('@') {
+                continue;
+            }
+            let values = line
+                .split_whitespace()
+                .take(3)
+                .map(|t| t.parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|e| {
+                    format!("line {}: failed to parse table value: {e}", line_number + 1)
+                })?;
+            if values.len() < 3 {
+                return Err(format!(
+                    "line {}: table rows need r, V and f columns",
+                    line_number + 1
+                ));
+            }
+            r.push(values[0]);
+            v.push(values[1]);
+            f.push(values[2]);
+        }
+        BondTable::new(r, v, f)
+    }
+
+    pub fn read_xvg(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read bond table at '{path}': {e}"))?;
+        BondTable::parse_xvg(&contents)
+    }
+
+    pub fn evaluate(&self, r: f64) -> (f64, f64) {
+        /*
+        V(r) and dV/dr
+         */
+        let last = self.r.len() - 1;
+        if r <= self.r[0] {
+            return (self.v[0] - self.f[0] * (r - self.r[0]), -self.f[0]);
+        }
+        if r >= self.r[last] {
+            return (
+                self.v[last] - self.f[last] * (r - self.r[last]),
+                -self.f[last],
+            );
+        }
+        let n = self.r.partition_point(|&x| x <= r) - 1;
+        let h = self.r[n + 1] - self.r[n];
+        let t = (r - self.r[n]) / h;
+        let (t2, t3) = (t * t, t * t * t);
+        let (v0, v1) = (self.v[n], self.v[n + 1]);
+        let (m0, m1) = (-self.f[n] * h, -self.f[n + 1] * h);
+
+        let value = (2.0 * t3 - 3.0 * t2 + 1.0) * v0
+            + (t3 - 2.0 * t2 + t) * m0
+            + (-2.0 * t3 + 3.0 * t2) * v1
+            + (t3 - t2) * m1;
+        let slope = ((6.0 * t2 - 6.0 * t) * v0
+            + (3.0 * t2 - 4.0 * t + 1.0) * m0
+            + (-6.0 * t2 + 6.0 * t) * v1
+            + (3.0 * t2 - 2.0 * t) * m1)
+            / h;
+        (value, slope)
+    }
+}
@@ -1,9 +1,35 @@
+use crate::electrostatics::reaction_field::ONE_4PI_EPS0_KJ_NM;
 use crate::lennard_jones_simulations::{LJParameters, Particle};
-use crate::molecule::molecule::{Angle, Bond, Dihedral, Improper, System};
+use crate::molecule::cmap::{assign_backbone_cmaps, parse_cmap_type, CmapType};
+use crate::molecule::drude::DrudePair;
+use crate::molecule::martini::parse_atomtype_with_rule;
+use crate::molecule::molecule::{
+    atomic_number, element_from_atom_name, Angle, Bond, BondForm, Dihedral, Improper, System,
+    UreyBradley,
+};
+use crate::molecule::topology::{
+    parse_bonded_type, parse_dihedral_type, sigma_epsilon, BondedType, Preprocessor, SourceLine,
+};
 use nalgebra::Vector3;
 use std::collections::HashMap;
 use std::fs;
 
+// CHARMM Drude force field conventions: Drude atom type, default spring constant
+// (kcal/mol/A^2, E = K_D d^2), Drude mass taken from the core (amu), hard wall (A)
+// and the Coulomb constant (kcal/mol A/e^2) relating polarisability and charge
+const DRUDE_TYPE: &str = "DRUD";
+const DRUDE_FORCE_CONSTANT: f64 = 500.0;
+const DRUDE_MASS: f64 = 0.4;
+const DRUDE_HARD_WALL: f64 = 0.2;
+const CHARMM_COULOMB_CONSTANT: f64 = 332.0716;
+const KJ_PER_KCAL: f64 = 4.184;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParameterUnits {
+    Charmm,  // .prm sections: A, kcal/mol, E = K x^2 for bonds, angles and impropers
+    Gromacs, // GROMACS port (.itp): nm, kJ/mol, E = 1/2 k x^2
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct CharmmAtomType {
     pub name: String,
@@ -22,6 +48,8 @@ pub struct CharmmAtom {
     pub type_name: String,
     pub charge: f64,
     pub mass: Option<f64>,
+    pub alpha: Option<f64>, // Drude polarisability (A^3), from the ALPHA keyword
+    pub thole: Option<f64>, // Thole screening factor, from the THOLE keyword
 }
 
 #[derive(Clone, Debug)]
@@ -60,6 +88,7 @@ struct AngleParam {
     t3: String,
     k: f64,
     theta0_deg: f64,
+    urey_bradley: Option<(f64, f64)>, // optional Kub and S0 columns
 }
 
 #[derive(Clone, Debug)]
@@ -83,6 +112,14 @@ struct ImproperParam {
     psi0_deg: f64,
 }
 
+#[derive(Clone, Debug)]
+struct PairParam {
+    t1: String,
+    t2: String,
+    sigma: f64,
+    epsilon: f64,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct CharmmForceField {
     pub residue_name: Option<String>,
@@ -96,6 +133,11 @@ pub struct CharmmForceField {
     angle_params: Vec<AngleParam>,
     dihedral_params: Vec<DihedralParam>,
     improper_params: Vec<ImproperParam>,
+    pair_params: Vec<PairParam>, // 1-4 LJ per type pair, from [ pairtypes ]
+    // CMAP grids (e.g. from read_cmap_types), assigned to matching backbones by to_system
+    pub cmap_types: Vec<CmapType>,
+    // units of the parameter tables, None until some are read; the two are not mixed
+    pub parameter_units: Option<ParameterUnits>,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -186,6 +228,7 @@ impl CharmmForceField {
                 && tokens[2].parse::<f64>().is_ok()
             {
                 ff.bond_params.push(parse_bond_param(&tokens)?);
+                ff.parameter_units = Some(ParameterUnits::Charmm);
                 continue;
             }
 
@@ -194,6 +237,7 @@ impl CharmmForceField {
                 && tokens[3].parse::<f64>().is_ok()
             {
                 ff.angle_params.push(parse_angle_param(&tokens)?);
+                ff.parameter_units = Some(ParameterUnits::Charmm);
                 continue;
             }
 
@@ -202,6 +246,7 @@ impl CharmmForceField {
                 && tokens[4].parse::<f64>().is_ok()
             {
                 ff.dihedral_params.push(parse_dihedral_param(&tokens)?);
+                ff.parameter_units = Some(ParameterUnits::Charmm);
                 continue;
             }
 
@@ -210,11 +255,13 @@ impl CharmmForceField {
                 && tokens[4].parse::<f64>().is_ok()
             {
                 ff.improper_params.push(parse_improper_param(&tokens)?);
+                ff.parameter_units = Some(ParameterUnits::Charmm);
                 continue;
             }
 
             if matches!(section, ParamSection::Nonbonded) && tokens.len() >= 4 {
                 update_nonbonded_params(&tokens, &mut ff.atom_types)?;
+                ff.parameter_units = Some(ParameterUnits::Charmm);
             }
         }
 
@@ -233,6 +280,132 @@ impl CharmmForceField {
         Self::parse_str(&contents)
     }
 
+    pub fn read_gromacs_parameters(&mut self, path: &str, defines: &[&str]) -> Result<(), String> {
+        /*
+        Parameter tables from a GROMACS port of the force field, e.g. ffnonbonded.itp and
+        ffbonded.itp of charmm27.ff, or its forcefield.itp with the includes followed:
+
+            [ atomtypes ]       name at.num mass charge ptype sigma epsilon
+            [ pairtypes ]       i j 1 sigma14 epsilon14
+            [ bondtypes ]       i j 1 b0 kb
+            [ angletypes ]      i j k 5 th0 cth ub0 cub     (or funct 1 without UB)
+            [ dihedraltypes ]   i j k l 9 phi0 cp mult      (or funct 1)
+                                i j k l 2 q0 cq             (impropers)
+            [ cmaptypes ]       i j k l m 1 nx ny <grid>    (cmap.itp)
+
+        Consecutive funct 9 lines for the same types are the terms of one multiple-term
+        dihedral. [ defaults ] sets the comb-rule for the nonbonded columns (2 when
+        absent); other sections ([ constrainttypes ], ...) are skipped.
+
+        The tables keep GROMACS units (nm, kJ/mol, with E = 1/2 k x^2 for bonds, angles
+        and impropers), so systems built from them take coordinates in nm. They cannot be
+        added to a force field that already holds CHARMM .prm parameters. Periodic
+        impropers (funct 4) are rejected, CHARMM impropers are harmonic (funct 2).
+         */
+        let lines = Preprocessor::new(&[], defines).process_file(path)?;
+        self.add_gromacs_parameters(&lines)
+    }
+
+    pub fn parse_gromacs_parameters(
+        &mut self,
+        contents: &str,
+        defines: &[&str],
+    ) -> Result<(), String> {
+        let mut preprocessor = Preprocessor::new(&[], defines);
+        preprocessor.follow_includes = false;
+        let lines = preprocessor.process_str(contents, "", None)?;
+        self.add_gromacs_parameters(&lines)
+    }
+
+    fn add_gromacs_parameters(&mut self, lines: &[SourceLine]) -> Result<(), String> {
+        if self.parameter_units == Some(ParameterUnits::Charmm) {
+            return Err(
+                "the force field already holds CHARMM parameters (A, kcal/mol); GROMACS \
+                 parameters (nm, kJ/mol) cannot be added to it"
+                    .to_string(),
+            );
+        }
+        self.parameter_units = Some(ParameterUnits::Gromacs);
+        let mut section = String::new();
+        let mut comb_rule = 2;
+        for line in lines {
+            let text = line.text.as_str();
+            if text.starts_with('[') && text.ends_with(']') {
+                section = text[1..text.len() - 1].trim().to_ascii_lowercase();
+                continue;
+            }
+            let tokens: Vec<&str> = text.split_whitespace().collect();
+            let result = match section.as_str() {
+                "defaults" => parse_usize(tokens.get(1).unwrap_or(&""), "comb-rule").map(|rule| {
+                    comb_rule = rule;
+                }),
+                "atomtypes" => self.add_gromacs_atom_type(&tokens, comb_rule),
+                "pairtypes" => parse_gromacs_pair_type(&tokens, comb_rule)
+                    .map(|pair| self.pair_params.push(pair)),
+                "bondtypes" => {
+                    parse_gromacs_bond_type(&tokens).map(|bond| self.bond_params.push(bond))
+                }
+                "angletypes" => {
+                    parse_gromacs_angle_type(&tokens).map(|angle| self.angle_params.push(angle))
+                }
+                "dihedraltypes" => self.add_gromacs_dihedral_type(&tokens),
+                "cmaptypes" => parse_cmap_type(&tokens).map(|cmap| self.cmap_types.push(cmap)),
+                _ => Ok(()),
+            };
+            result.map_err(|e| format!("{}: {e}", line.location()))?;
+        }
+        Ok(())
+    }
+
+    fn add_gromacs_atom_type(&mut self, tokens: &[&str], comb_rule: usize) -> Result<(), String> {
+        let parsed = parse_atomtype_with_rule(tokens, Some(comb_rule))?;
+        let atom_type = self
+            .atom_types
+            .entry(parsed.name.clone())
+            .or_insert_with(|| CharmmAtomType {
+                name: parsed.name.clone(),
+                ..Default::default()
+            });
+        atom_type.mass = parsed.mass;
+        atom_type.sigma = parsed.sigma;
+        atom_type.epsilon = parsed.epsilon;
+        Ok(())
+    }
+
+    fn add_gromacs_dihedral_type(&mut self, tokens: &[&str]) -> Result<(), String> {
+        let entry = parse_dihedral_type(tokens)?;
+        let t = &entry.types;
+        match entry.funct {
+            1 | 9 => self.dihedral_params.push(DihedralParam {
+                t1: t[0].clone(),
+                t2: t[1].clone(),
+                t3: t[2].clone(),
+                t4: t[3].clone(),
+                k: gromacs_parameter(&entry, 1, "dihedral cp")?,
+                multiplicity: parse_usize(
+                    entry.parameters.get(2).map_or("", String::as_str),
+                    "dihedral multiplicity",
+                )?,
+                phase_deg: gromacs_parameter(&entry, 0, "dihedral phi0")?,
+            }),
+            2 => self.improper_params.push(ImproperParam {
+                t1: t[0].clone(),
+                t2: t[1].clone(),
+                t3: t[2].clone(),
+                t4: t[3].clone(),
+                k: gromacs_parameter(&entry, 1, "improper cq")?,
+                psi0_deg: gromacs_parameter(&entry, 0, "improper q0")?,
+            }),
+            4 => {
+                return Err(
+                    "periodic improper dihedral types (function 4) are not supported".to_string(),
+                )
+            }
+            funct => return Err(format!("unsupported dihedral type function {funct}")),
+        }
+        Ok(())
+    }
+
     pub fn to_system(&self, coordinates: &[Vector3<f64>]) -> Result<System, String> {
         if coordinates.len() != self.atoms.len() {
             return Err(format!(
@@ -243,6 +416,7 @@ impl CharmmForceField {
         }
 
         let mut particles = Vec::with_capacity(self.atoms.len());
+        let mut elements = Vec::with_capacity(self.atoms.len());
         for (idx, atom) in self.atoms.iter().enumerate() {
             let atom_type = self
                 .atom_types
@@ -255,6 +429,10 @@ impl CharmmForceField {
             let epsilon = atom_type
                 .epsilon
                 .ok_or_else(|| format!("missing epsilon for atom type '{}'", atom.type_name))?;
+            elements.push(match atom_type.element.as_deref().map(atomic_number) {
+                Some(z) if z > 0 => z,
+                _ => element_from_atom_name(&atom.name),
+            });
 
             particles.push(Particle {
                 id: atom.index,
@@ -287,10 +465,12 @@ impl CharmmForceField {
                     atom2: b.atom2,
                     k: p.k,
                     r0: p.r0,
+                    form: BondForm::Harmonic,
                 })
             })
             .collect::<Result<Vec<_>, String>>()?;
 
+        let mut urey_bradleys = Vec::new();
         let angles = self
             .angles
             .iter()
@@ -301,6 +481,14 @@ impl CharmmForceField {
                 let p = self
                     .find_angle_param(t1, t2, t3)
                     .ok_or_else(|| format!("missing angle parameter for {t1}-{t2}-{t3}"))?;
+                if let Some((k, r0)) = p.urey_bradley {
+                    urey_bradleys.push(UreyBradley {
+                        atom1: a.atom1,
+                        atom3: a.atom3,
+                        k,
+                        r0,
+                    });
+                }
                 Ok(Angle {
                     atom1: a.atom1,
                     atom2: a.atom2,
@@ -311,28 +499,29 @@ impl CharmmForceField {
             })
             .collect::<Result<Vec<_>, String>>()?;
 
-        let dihedrals = self
-            .dihedrals
-            .iter()
-            .map(|d| {
-                let t1 = &self.atoms[d.atom1].type_name;
-                let t2 = &self.atoms[d.atom2].type_name;
-                let t3 = &self.atoms[d.atom3].type_name;
-                let t4 = &self.atoms[d.atom4].type_name;
-                let p = self
-                    .find_dihedral_param(t1, t2, t3, t4)
-                    .ok_or_else(|| format!("missing dihedral parameter for {t1}-{t2}-{t3}-{t4}"))?;
-                Ok(Dihedral {
-                    atom1: d.atom1,
-                    atom2: d.atom2,
-                    atom3: d.atom3,
-                    atom4: d.atom4,
-                    k: p.k,
-                    multiplicity: p.multiplicity,
-                    phase: p.phase_deg.to_radians(),
-                })
-            })
-            .collect::<Result<Vec<_>, String>>()?;
+        // a multiple-term dihedral contributes one periodic term per parameter line
+        let mut dihedrals = Vec::new();
+        for d in &self.dihedrals {
+            let t1 = &self.atoms[d.atom1].type_name;
+            let t2 = &self.atoms[d.atom2].type_name;
+            let t3 = &self.atoms[d.atom3].type_name;
+            let t4 = &self.atoms[d.atom4].type_name;
+            let terms = self.find_dihedral_params(t1, t2, t3, t4);
+            if terms.is_empty() {
+                return Err(format!(
+                    "missing dihedral parameter for {t1}-{t2}-{t3}-{t4}"
+                ));
+            }
+            dihedrals.extend(terms.into_iter().map(|p| Dihedral {
+                atom1: d.atom1,
+                atom2: d.atom2,
+                atom3: d.atom3,
+                atom4: d.atom4,
+                k: p.k,
+                multiplicity: p.multiplicity,
+                phase: p.phase_deg.to_radians(),
+            }));
+        }
 
         let impropers = self
             .impropers
@@ -356,13 +545,130 @@ impl CharmmForceField {
             })
             .collect::<Result<Vec<_>, String>>()?;
 
-        Ok(System {
+        // the phi/psi correction map of every backbone C-N-CA-C-N with a matching type
+        let type_names: Vec<String> = self.atoms.iter().map(|a| a.type_name.clone()).collect();
+        let cmaps = assign_backbone_cmaps(&type_names, &bonds, &self.cmap_types);
+
+        let drudes = self.add_drude_particles(&mut particles);
+        elements.resize(particles.len(), 0);
+
+        let mut system = System {
             atoms: particles,
             bonds,
             angles,
             dihedrals,
             impropers,
-        })
+            drudes,
+            elements,
+            ..Default::default()
+        };
+        system.extended.urey_bradleys = urey_bradleys;
+        system.extended.cmaps = cmaps;
+
+        // CHARMM excludes 1-2 and 1-3 pairs and treats 1-4 pairs with unscaled Coulomb
+        // (E14FAC = 1) and the dedicated 1-4 LJ parameters: a [ pairtypes ] entry for
+        // the type pair, else the 1-4 values of the two types when they provide them.
+        // Drude 1-4 pairs (indices past the residue atoms) stay Coulomb only.
+        system.generate_exclusions(3, 1.0, 1.0);
+        let n_residue = self.atoms.len();
+        for pair in system
+            .pairs
+            .iter_mut()
+            .filter(|p| p.atom1 < n_residue && p.atom2 < n_residue)
+        {
+            let type1 = &self.atoms[pair.atom1].type_name;
+            let type2 = &self.atoms[pair.atom2].type_name;
+            if let Some(p) = self.find_pair_param(type1, type2) {
+                pair.sigma = p.sigma;
+                pair.epsilon = p.epsilon;
+                continue;
+            }
+            let (s1, e1) = self.lj_14_parameters(type1);
+            let (s2, e2) = self.lj_14_parameters(type2);
+            if let (Some(s1), Some(e1), Some(s2), Some(e2)) = (s1, e1, s2, e2) {
+                pair.sigma = 0.5 * (s1 + s2);
+                pair.epsilon = (e1 * e2).sqrt();
+            }
+        }
+
+        Ok(system)
+    }
+
+    fn add_drude_particles(&self, particles: &mut Vec<Particle>) -> Vec<DrudePair> {
+        /*
+        Every atom with an ALPHA entry gets a Drude particle, appended after the residue
+        atoms and placed on top of its core. The ATOM charge is the total charge of the
+        pair; the Drude carries
+
+            q_D = -sqrt(2 K_D |alpha| / k_e)
+
+        and the core the remainder. The Drude mass is taken from the core. THOLE factors
+        are kept on the atoms, but the Thole screening itself is not applied. ALPHA is
+        read in A^3; with GROMACS parameters alpha, K_D, the hard wall and k_e are taken
+        in nm and kJ/mol.
+         */
+        let gromacs = self.parameter_units == Some(ParameterUnits::Gromacs);
+        let (alpha_scale, hard_wall, coulomb_constant) = if gromacs {
+            (1e-3, 0.1 * DRUDE_HARD_WALL, ONE_4PI_EPS0_KJ_NM)
+        } else {
+            (1.0, DRUDE_HARD_WALL, CHARMM_COULOMB_CONSTANT)
+        };
+        let drude_mass = self
+            .atom_types
+            .get(DRUDE_TYPE)
+            .map(|t| t.mass)
+            .filter(|m| *m > 0.0)
+            .unwrap_or(DRUDE_MASS);
+
+        let mut drudes = Vec::new();
+        for (core, atom) in self.atoms.iter().enumerate() {
+            let Some(alpha) = atom.alpha else {
+                continue;
+            };
+            let k = self.drude_force_constant(&atom.type_name);
+            let q_drude = -(2.0 * k * alpha_scale * alpha.abs() / coulomb_constant).sqrt();
+
+            let mut drude = particles[core].clone();
+            drude.id = particles.len() + 1;
+            drude.mass = drude_mass;
+            drude.charge = q_drude;
+            drude.lj_parameters.epsilon = 0.0;
+            particles[core].mass -= drude_mass;
+            particles[core].charge -= q_drude;
+
+            drudes.push(DrudePair {
+                core,
+                drude: particles.len(),
+                k,
+                hard_wall,
+            });
+            particles.push(drude);
+        }
+        drudes
+    }
+
+    fn drude_force_constant(&self, core_type: &str) -> f64 {
+        /*
+        K_D of E = K_D d^2: an explicit "<core type> DRUD" (or "X DRUD") bond entry
+        overrides the default; GROMACS bond types hold 2 K_D (E = 1/2 k d^2)
+         */
+        let gromacs = self.parameter_units == Some(ParameterUnits::Gromacs);
+        let entry = self
+            .find_bond_param(core_type, DRUDE_TYPE)
+            .or_else(|| self.find_bond_param("X", DRUDE_TYPE));
+        match (entry, gromacs) {
+            (Some(p), false) => p.k,
+            (Some(p), true) => 0.5 * p.k,
+            (None, false) => DRUDE_FORCE_CONSTANT,
+            (None, true) => DRUDE_FORCE_CONSTANT * KJ_PER_KCAL * 100.0,
+        }
+    }
+
+    fn lj_14_parameters(&self, type_name: &str) -> (Option<f64>, Option<f64>) {
+        match self.atom_types.get(type_name) {
+            Some(t) => (t.sigma_14.or(t.sigma), t.epsilon_14.or(t.epsilon)),
+            None => (None, None),
+        }
     }
 
     fn find_bond_param(&self, t1: &str, t2: &str) -> Option<&BondParam> {
@@ -377,17 +683,24 @@ impl CharmmForceField {
         })
     }
 
-    fn find_dihedral_param(
-        &self,
-        t1: &str,
-        t2: &str,
-        t3: &str,
-        t4: &str,
-    ) -> Option<&DihedralParam> {
-        self.dihedral_params.iter().find(|p| {
-            matches_quad(t1, t2, t3, t4, &p.t1, &p.t2, &p.t3, &p.t4)
-                || matches_quad(t4, t3, t2, t1, &p.t1, &p.t2, &p.t3, &p.t4)
-        })
+    fn find_dihedral_params(&self, t1: &str, t2: &str, t3: &str, t4: &str) -> Vec<&DihedralParam> {
+        /*
+        The entry with the fewest X wildcards matching in either direction, followed by
+        the consecutive entries for the same types (the terms of a multiple-term dihedral)
+         */
+        let Some(first) = most_specific(&self.dihedral_params, |p| {
+            quad_specificity([t1, t2, t3, t4], [&p.t1, &p.t2, &p.t3, &p.t4])
+        }) else {
+            return Vec::new();
+        };
+        let same_types = |p: &DihedralParam| {
+            let q = &self.dihedral_params[first];
+            (&p.t1, &p.t2, &p.t3, &p.t4) == (&q.t1, &q.t2, &q.t3, &q.t4)
+        };
+        self.dihedral_params[first..]
+            .iter()
+            .take_while(|p| same_types(p))
+            .collect()
     }
 
     fn find_improper_param(
@@ -397,10 +710,16 @@ impl CharmmForceField {
         t3: &str,
         t4: &str,
     ) -> Option<&ImproperParam> {
-        self.improper_params.iter().find(|p| {
-            matches_quad(t1, t2, t3, t4, &p.t1, &p.t2, &p.t3, &p.t4)
-                || matches_quad(t4, t3, t2, t1, &p.t1, &p.t2, &p.t3, &p.t4)
+        most_specific(&self.improper_params, |p| {
+            quad_specificity([t1, t2, t3, t4], [&p.t1, &p.t2, &p.t3, &p.t4])
         })
+        .map(|n| &self.improper_params[n])
+    }
+
+    fn find_pair_param(&self, t1: &str, t2: &str) -> Option<&PairParam> {
+        self.pair_params
+            .iter()
+            .find(|p| (p.t1 == t1 && p.t2 == t2) || (p.t1 == t2 && p.t2 == t1))
     }
 }
 
@@ -424,6 +743,18 @@ fn parse_residue_atom(tokens: &[&str]) -> Result<CharmmAtom, String> {
         return Err("ATOM row requires: ATOM <name> <type> <charge> [mass]".to_string());
     }
 
+    // Drude topologies append keyword/value pairs: ATOM ... ALPHA <a> THOLE <t>
+    let keyword = |name: &str| -> Result<Option<f64>, String> {
+        match tokens.iter().position(|t| t.eq_ignore_ascii_case(name)) {
+            Some(i) => tokens
+                .get(i + 1)
+                .ok_or_else(|| format!("{name} keyword without a value"))
+                .and_then(|v| parse_f64(v, name))
+                .map(Some),
+            None => Ok(None),
+        }
+    };
+
     Ok(CharmmAtom {
         index: 0,
         name: tokens[1].to_string(),
@@ -432,6 +763,8 @@ fn parse_residue_atom(tokens: &[&str]) -> Result<CharmmAtom, String> {
             .parse::<f64>()
             .map_err(|e| format!("invalid ATOM charge: {e}"))?,
         mass: tokens.get(4).and_then(|v| v.parse::<f64>().ok()),
+        alpha: keyword("ALPHA")?,
+        thole: keyword("THOLE")?,
     })
 }
 
@@ -525,6 +858,13 @@ fn parse_angle_param(tokens: &[&str]) -> Result<AngleParam, String> {
         t3: tokens[2].to_string(),
         k: parse_f64(tokens[3], "angle k")?,
         theta0_deg: parse_f64(tokens[4], "angle theta0")?,
+        urey_bradley: match (tokens.get(5), tokens.get(6)) {
+            (Some(k), Some(s0)) => Some((
+                parse_f64(k, "Urey-Bradley k")?,
+                parse_f64(s0, "Urey-Bradley S0")?,
+            )),
+            _ => None,
+        },
     })
 }
 
@@ -551,6 +891,66 @@ fn parse_improper_param(tokens: &[&str]) -> Result<ImproperParam, String> {
     })
 }
 
+fn parse_gromacs_pair_type(tokens: &[&str], comb_rule: usize) -> Result<PairParam, String> {
+    let entry = parse_bonded_type(tokens, 2)?;
+    if entry.funct != 1 {
+        return Err(format!("unsupported pair type function {}", entry.funct));
+    }
+    let (sigma, epsilon) = sigma_epsilon(
+        comb_rule,
+        gromacs_parameter(&entry, 0, "pair V")?,
+        gromacs_parameter(&entry, 1, "pair W")?,
+    );
+    Ok(PairParam {
+        t1: entry.types[0].clone(),
+        t2: entry.types[1].clone(),
+        sigma,
+        epsilon,
+    })
+}
+
+fn parse_gromacs_bond_type(tokens: &[&str]) -> Result<BondParam, String> {
+    let entry = parse_bonded_type(tokens, 2)?;
+    if entry.funct != 1 {
+        return Err(format!("unsupported bond type function {}", entry.funct));
+    }
+    Ok(BondParam {
+        t1: entry.types[0].clone(),
+        t2: entry.types[1].clone(),
+        k: gromacs_parameter(&entry, 1, "bond kb")?,
+        r0: gromacs_parameter(&entry, 0, "bond b0")?,
+    })
+}
+
+fn parse_gromacs_angle_type(tokens: &[&str]) -> Result<AngleParam, String> {
+    let entry = parse_bonded_type(tokens, 3)?;
+    let urey_bradley = match entry.funct {
+        1 => None,
+        5 => {
+            let k_ub = gromacs_parameter(&entry, 3, "Urey-Bradley cub")?;
+            let r_ub = gromacs_parameter(&entry, 2, "Urey-Bradley ub0")?;
+            (k_ub != 0.0).then_some((k_ub, r_ub))
+        }
+        funct => return Err(format!("unsupported angle type function {funct}")),
+    };
+    Ok(AngleParam {
+        t1: entry.types[0].clone(),
+        t2: entry.types[1].clone(),
+        t3: entry.types[2].clone(),
+        k: gromacs_parameter(&entry, 1, "angle cth")?,
+        theta0_deg: gromacs_parameter(&entry, 0, "angle th0")?,
+        urey_bradley,
+    })
+}
+
+fn gromacs_parameter(entry: &BondedType, n: usize, what: &str) -> Result<f64, String> {
+    let token = entry
+        .parameters
+        .get(n)
+        .ok_or_else(|| format!("missing {what}"))?;
+    parse_f64(token, what)
+}
+
 fn update_nonbonded_params(
     tokens: &[&str],
     atom_types: &mut HashMap<String, CharmmAtomType>,
@@ -600,17 +1000,26 @@ fn rmin_half_to_sigma(rmin_half: f64) -> f64 {
     (2.0 * rmin_half) / 2f64.powf(1.0 / 6.0)
 }
 
-fn matches_quad(
-    a1: &str,
-    a2: &str,
-    a3: &str,
-    a4: &str,
-    p1: &str,
-    p2: &str,
-    p3: &str,
-    p4: &str,
-) -> bool {
-    matches_type(a1, p1) && matches_type(a2, p2) && matches_type(a3, p3) && matches_type(a4, p4)
+fn quad_specificity(types: [&str; 4], pattern: [&str; 4]) -> Option<usize> {
+    /*
+    Number of non-wildcard types when the pattern matches forwards or backwards
+     */
+    let forward = (0..4).all(|n| matches_type(types[n], pattern[n]));
+    let backward = (0..4).all(|n| matches_type(types[3 - n], pattern[n]));
+    (forward || backward).then(|| pattern.iter().filter(|t| **t != "X").count())
+}
+
+fn most_specific<T>(params: &[T], specificity: impl Fn(&T) -> Option<usize>) -> Option<usize> {
+    // index of the first entry with the highest specificity
+    let mut best: Option<(usize, usize)> = None; // (specificity, index)
+    for (index, param) in params.iter().enumerate() {
+        if let Some(s) = specificity(param) {
+            if best.is_none_or(|(b, _)| s > b) {
+                best = Some((s, index));
+            }
+        }
+    }
+    best.map(|(_, index)| index)
 }
 
 fn matches_type(value: &str, pattern: &str) -> bool {
@@ -632,6 +1041,7 @@ fn parse_usize(token: &str, what: &str) -> Result<usize, String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::molecule::cmap::parse_cmap_types;
 
     #[test]
     fn parses_charmm_stream_and_builds_system() {
@@ -655,7 +1065,7 @@ BONDS
 CT1 HC 340.0 1.09
 
 ANGLES
-HC CT1 HC 33.0 109.5
+HC CT1 HC 33.0 109.5 5.40 1.802
 
 DIHEDRALS
 X CT1 HC X 0.15 3 0.0
@@ -689,6 +1099,8 @@ HC  0.0 -0.020 1.34
         assert_eq!(system.atoms.len(), 4);
         assert_eq!(system.bonds.len(), 3);
         assert_eq!(system.angles.len(), 3);
+        assert_eq!(system.extended.urey_bradleys.len(), 3);
+        assert!((system.extended.urey_bradleys[0].r0 - 1.802).abs() < 1e-12);
         assert_eq!(system.dihedrals.len(), 1);
         assert_eq!(system.impropers.len(), 1);
 
@@ -697,6 +1109,266 @@ HC  0.0 -0.020 1.34
         assert!((system.atoms[0].lj_parameters.sigma - expected_sigma).abs() < 1e-12);
     }
 
+    #[test]
+    fn drude_atoms_get_bound_drude_particles() {
+        let input = r#"
+MASS 1 ODW 15.6 O
+MASS 2 HDW 1.008 H
+MASS 3 DRUD 0.4 H
+
+RESI SWM4 0.0
+ATOM OH2 ODW 0.0 ALPHA -0.97825258 THOLE 1.3
+ATOM H1 HDW 0.55733
+ATOM H2 HDW 0.55733
+BOND OH2 H1 OH2 H2
+
+BONDS
+ODW HDW 450.0 0.9572
+
+NONBONDED
+ODW 0.0 -0.21 1.79
+HDW 0.0 0.0 0.0
+DRUD 0.0 0.0 0.0
+"#;
+        let ff = CharmmForceField::parse_str(input).expect("drude topology should parse");
+        assert_eq!(ff.atoms[0].thole, Some(1.3));
+
+        let coords = vec![
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.9572, 0.0, 0.0),
+            Vector3::new(-0.24, 0.927, 0.0),
+        ];
+        let system = ff.to_system(&coords).expect("drude system should build");
+
+        assert_eq!(system.atoms.len(), 4);
+        assert_eq!(system.drudes.len(), 1);
+        let pair = &system.drudes[0];
+        assert_eq!((pair.core, pair.drude), (0, 3));
+
+        // SWM4-NDP: q_D = -1.71636 for alpha = 0.978 A^3 and K_D = 500
+        let q_drude = system.atoms[3].charge;
+        assert!((q_drude + 1.71636).abs() < 1e-4);
+        assert!((system.atoms[0].charge + q_drude).abs() < 1e-12);
+        assert!((system.atoms[0].mass + system.atoms[3].mass - 15.6).abs() < 1e-12);
+
+        // the Drude is excluded from its core and from the atoms bonded to it
+        assert!(system.is_excluded(0, 3));
+        assert!(system.is_excluded(1, 3));
+        assert!(system.is_excluded(2, 3));
+    }
+
+    #[test]
+    fn drudes_follow_the_units_of_the_parameters() {
+        let rtf = r#"
+MASS 1 ODW 15.6 O
+MASS 2 HDW 1.008 H
+MASS 3 DRUD 0.4 H
+
+RESI SWM4 0.0
+ATOM OH2 ODW 0.0 ALPHA -0.97825258 THOLE 1.3
+ATOM H1 HDW 0.55733
+ATOM H2 HDW 0.55733
+BOND OH2 H1 OH2 H2
+"#;
+        let gromacs_parameters = "\
+[ atomtypes ]
+ODW 8 15.6 0.0 A 0.318 0.8786
+HDW 1 1.008 0.0 A 0.0 0.0
+DRUD 0 0.4 0.0 A 0.0 0.0
+[ bondtypes ]
+ODW HDW 1 0.09572 376560.0
+";
+        let mut ff = CharmmForceField::parse_str(rtf).expect("drude topology should parse");
+        assert_eq!(ff.parameter_units, None);
+        ff.parse_gromacs_parameters(gromacs_parameters, &[])
+            .expect("GROMACS parameters should load");
+        assert_eq!(ff.parameter_units, Some(ParameterUnits::Gromacs));
+
+        let coords = vec![
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.09572, 0.0, 0.0),
+            Vector3::new(-0.024, 0.0927, 0.0),
+        ];
+        let system = ff.to_system(&coords).expect("drude system should build");
+        // the same SWM4-NDP Drude charge as in A and kcal/mol, with K_D in kJ/mol/nm^2
+        assert!((system.atoms[3].charge + 1.71636).abs() < 1e-4);
+        assert!((system.drudes[0].k - 209200.0).abs() < 1e-6);
+        assert!((system.drudes[0].hard_wall - 0.02).abs() < 1e-12);
+
+        // CHARMM .prm parameters and GROMACS ones are not mixed
+        let charmm = format!("{rtf}\nBONDS\nODW HDW 450.0 0.9572\n");
+        let mut ff = CharmmForceField::parse_str(&charmm).expect("drude topology should parse");
+        assert_eq!(ff.parameter_units, Some(ParameterUnits::Charmm));
+        let error = ff
+            .parse_gromacs_parameters(gromacs_parameters, &[])
+            .unwrap_err();
+        assert!(error.contains("CHARMM parameters"), "{error}");
+
+        // periodic impropers have no place in the proper dihedral table
+        let mut ff = CharmmForceField::default();
+        let error = ff
+            .parse_gromacs_parameters("[ dihedraltypes ]\nX CT1 NH1 X 4 180.0 4.6 2\n", &[])
+            .unwrap_err();
+        assert!(error.contains("function 4"), "{error}");
+    }
+
+    #[test]
+    fn assigns_cmaps_to_residue_backbones() {
+        let input = r#"
+MASS 1 C 12.011 C
+MASS 2 NH1 14.007 N
+MASS 3 CT1 12.011 C
+
+RESI BB 0.0
+ATOM C0 C 0.0
+ATOM N1 NH1 0.0
+ATOM CA CT1 0.0
+ATOM C1 C 0.0
+ATOM N2 NH1 0.0
+BOND C0 N1 N1 CA CA C1 C1 N2
+
+BONDS
+C NH1 370.0 1.345
+NH1 CT1 320.0 1.43
+CT1 C 250.0 1.49
+
+NONBONDED
+C 0.0 -0.11 2.0
+NH1 0.0 -0.2 1.85
+CT1 0.0 -0.02 2.275
+"#;
+        let mut ff = CharmmForceField::parse_str(input).expect("residue should parse");
+        ff.cmap_types = parse_cmap_types(
+            "[ cmaptypes ]
+C NH1 CT1 C NH1 1 3 3\\
+1.0 2.0 3.0\\
+4.0 5.0 6.0\\
+7.0 8.0 9.0
+",
+        )
+        .expect("cmap types should parse");
+
+        let coords: Vec<Vector3<f64>> = (0..5)
+            .map(|i| Vector3::new(1.4 * i as f64, 0.5 * (i % 2) as f64, 0.2 * i as f64))
+            .collect();
+        let system = ff.to_system(&coords).expect("system should build");
+        assert_eq!(system.extended.cmaps.len(), 1);
+        assert_eq!(system.extended.cmaps[0].atoms, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn loads_charmm27_parameter_tables_from_gromacs_itp_files() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/ff/Charmm27.ff/charmm27.ff");
+        let input = r#"
+RESI TEST 0.0
+ATOM H1 HA 0.09
+ATOM C1 CT3 -0.09
+ATOM C2 CT3 0.0
+ATOM C3 CT3 0.0
+BOND H1 C1 C1 C2 C2 C3
+ANGL H1 C1 C2
+DIHE H1 C1 C2 C3
+"#;
+        let mut ff = CharmmForceField::parse_str(input).expect("residue should parse");
+        ff.read_gromacs_parameters(&format!("{dir}/ffnonbonded.itp"), &[])
+            .expect("ffnonbonded.itp should load");
+        ff.read_gromacs_parameters(&format!("{dir}/ffbonded.itp"), &[])
+            .expect("ffbonded.itp should load");
+
+        let bond = ff.find_bond_param("CT3", "CT3").expect("CT3-CT3 bond");
+        assert_eq!((bond.r0, bond.k), (0.153, 186188.0));
+
+        // CT1 CT2 CPH1 CPH1 has three terms, found from either end
+        let terms = ff.find_dihedral_params("CPH1", "CPH1", "CT2", "CT1");
+        let multiplicities: Vec<usize> = terms.iter().map(|p| p.multiplicity).collect();
+        assert_eq!(multiplicities, vec![1, 2, 3]);
+        assert!((terms[1].k - 1.12968).abs() < 1e-12);
+
+        // a specific improper and one matched through "CPB X X CE1"
+        assert_eq!(
+            ff.find_improper_param("HE2", "HE2", "CE2", "CE2")
+                .unwrap()
+                .k,
+            25.104
+        );
+        assert_eq!(
+            ff.find_improper_param("CE1", "HA", "CT3", "CPB").unwrap().k,
+            753.12
+        );
+
+        let coords = vec![
+            Vector3::new(0.0, 0.1111, 0.0),
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.153, 0.0, 0.0),
+            Vector3::new(0.2, 0.14, 0.0),
+        ];
+        let system = ff.to_system(&coords).expect("system should build");
+        assert!((system.atoms[1].lj_parameters.sigma - 0.367050271874).abs() < 1e-12);
+        assert!((system.atoms[1].lj_parameters.epsilon - 0.33472).abs() < 1e-12);
+        assert_eq!(system.bonds.len(), 3);
+        assert_eq!(system.extended.urey_bradleys.len(), 1);
+        assert_eq!(system.extended.urey_bradleys[0].r0, 0.2179);
+        assert_eq!(system.dihedrals.len(), 1);
+        assert_eq!(system.dihedrals[0].k, 0.64852);
+
+        // the H1-C3 pair takes the CT3 HA pairtype, not the mixed HA/CT3 parameters
+        assert_eq!(system.pairs.len(), 1);
+        assert!((system.pairs[0].sigma - 0.286869387241).abs() < 1e-12);
+        assert!((system.pairs[0].epsilon - 0.06205874894).abs() < 1e-12);
+    }
+
+    #[test]
+    fn assigns_cmaps_from_charmm27_cmap_itp() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/ff/Charmm27.ff/charmm27.ff");
+        let input = r#"
+RESI BB 0.0
+ATOM C0 C 0.0
+ATOM N1 NH1 0.0
+ATOM CA CT1 0.0
+ATOM C1 C 0.0
+ATOM N2 NH1 0.0
+BOND C0 N1 N1 CA CA C1 C1 N2
+"#;
+        let mut ff = CharmmForceField::parse_str(input).expect("residue should parse");
+        for file in ["ffnonbonded.itp", "ffbonded.itp", "cmap.itp"] {
+            ff.read_gromacs_parameters(&format!("{dir}/{file}"), &[])
+                .unwrap_or_else(|e| panic!("{file} should load: {e}"));
+        }
+        assert!(!ff.cmap_types.is_empty());
+
+        let coords: Vec<Vector3<f64>> = (0..5)
+            .map(|i| Vector3::new(0.14 * i as f64, 0.05 * (i % 2) as f64, 0.02 * i as f64))
+            .collect();
+        let system = ff.to_system(&coords).expect("system should build");
+        assert_eq!(system.extended.cmaps.len(), 1);
+        assert_eq!(system.extended.cmaps[0].atoms, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn specific_dihedral_types_win_over_wildcards() {
+        let mut ff = CharmmForceField::default();
+        ff.parse_gromacs_parameters(
+            "[ dihedraltypes ]
+X   CT1 NH1 X    9  0.0    0.5  1
+CT2 CT1 NH1 C    9  0.0    1.6  1
+CT2 CT1 NH1 C    9  180.0  2.5  2
+#ifdef EXTRA
+CT2 CT1 NH1 H    9  0.0    1.0  3
+#endif
+",
+            &[],
+        )
+        .expect("dihedral types should parse");
+
+        let terms = ff.find_dihedral_params("C", "NH1", "CT1", "CT2");
+        assert_eq!(terms.len(), 2);
+        assert_eq!((terms[1].multiplicity, terms[1].phase_deg), (2, 180.0));
+
+        let terms = ff.find_dihedral_params("CT2", "CT1", "NH1", "H");
+        assert_eq!(terms.len(), 1);
+        assert_eq!(terms[0].k, 0.5);
+    }
+
     #[test]
     fn errors_when_no_residue_atoms_present() {
         let input = "MASS 1 CT1 12.011 C\n";
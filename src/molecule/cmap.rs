@@ -0,0 +1,393 @@
+/*
+CHARMM CMAP backbone correction (MacKerell, Feig and Brooks, J. Comput. Chem. 25,
+1400 (2004))
+
+A CMAP term couples the two backbone torsions of a residue, phi = C-N-CA-C and
+psi = N-CA-C-N, through a correction energy tabulated on a periodic n x n grid
+starting at -180 degrees (GROMACS `[ cmaptypes ]`, phi is the slow index).
+
+The grid is interpolated bicubically. The derivatives at the grid points come from
+periodic cubic splines, first along phi and psi and then along psi of the phi
+derivative for the cross term, so the surface is C1 continuous and reproduces the
+tabulated values exactly at the nodes. Forces follow from dE/dphi and dE/dpsi with
+the torsion gradients used by the dihedral terms.
+ */
+
+use crate::lennard_jones_simulations::Particle;
+use crate::molecule::molecule::{dihedral_gradient, Bond};
+use nalgebra::{DMatrix, DVector, Dyn, Vector3, LU};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct CmapGrid {
+    pub n: usize,
+    values: Vec<f64>,
+    d_phi: Vec<f64>,
+    d_psi: Vec<f64>,
+    d_phi_psi: Vec<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CmapType {
+    pub types: [String; 5],
+    pub grid: Arc<CmapGrid>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cmap {
+    // C(i-1), N, CA, C, N(i+1)
+    pub atoms: [usize; 5],
+    pub grid: Arc<CmapGrid>,
+}
+
+fn periodic_spline_slopes(lu: &LU<f64, Dyn, Dyn>, y: &[f64], h: f64) -> Vec<f64> {
+    /*
+    First derivatives of the periodic cubic spline through y on a uniform grid,
+
+        m_(i-1) + 4 m_i + m_(i+1) = 3 (y_(i+1) - y_(i-1)) / h
+     */
+    let n = y.len();
+    let rhs = DVector::from_fn(n, |i, _| 3.0 * (y[(i + 1) % n] - y[(i + n - 1) % n]) / h);
+    lu.solve(&rhs)
+        .map(|m| m.iter().copied().collect())
+        .unwrap_or_else(|| vec![0.0; n])
+}
+
+impl CmapGrid {
+    pub fn new(n: usize, values: Vec<f64>) -> Result<Self, String> {
+        if n < 3 || values.len() != n * n {
+            return Err(format!(
+                "CMAP grid needs n x n values with n >= 3, got n = {n} and {} values",
+                values.len()
+            ));
+        }
+        let h = 2.0 * PI / n as f64;
+        let cyclic = DMatrix::from_fn(n, n, |i, j| {
+            if i == j {
+                4.0
+            } else if (i + 1) % n == j || (j + 1) % n == i {
+                1.0
+            } else {
+                0.0
+            }
+        });
+        let lu = cyclic.lu();
+
+        let at = |g: &[f64], i: usize, j: usize| g[i * n + j];
+        let mut d_phi = vec![0.0; n * n];
+        let mut d_psi = vec![0.0; n * n];
+        let mut d_phi_psi = vec![0.0; n * n];
+        for j in 0..n {
+            let column: Vec<f64> = (0..n).map(|i| at(&values, i, j)).collect();
+            for (i, m) in periodic_spline_slopes(&lu, &column, h)
+                .into_iter()
+                .enumerate()
+            {
+                d_phi[i * n + j] = m;
+            }
+        }
+        for i in 0..n {
+            let row = &values[i * n..(i + 1) * n];
+            d_psi[i * n..(i + 1) * n].copy_from_slice(&periodic_spline_slopes(&lu, row, h));
+            let row = &d_phi[i * n..(i + 1) * n];
+            d_phi_psi[i * n..(i + 1) * n].copy_from_slice(&periodic_spline_slopes(&lu, row, h));
+        }
+
+        Ok(CmapGrid {
+            n,
+            values,
+            d_phi,
+            d_psi,
+            d_phi_psi,
+        })
+    }
+
+    pub fn energy(&self, phi: f64, psi: f64) -> (f64, f64, f64) {
+        /*
+        Bicubic (tensor product Hermite) interpolation of the cell containing
+        (phi, psi), in radians. Returns (E, dE/dphi, dE/dpsi).
+         */
+        let n = self.n;
+        let h = 2.0 * PI / n as f64;
+        let cell = |angle: f64| {
+            let x = (angle + PI).rem_euclid(2.0 * PI) / h;
+            let i = (x.floor() as usize).min(n - 1);
+            (i, x - i as f64)
+        };
+        let (i0, t) = cell(phi);
+        let (j0, u) = cell(psi);
+
+        // Hermite basis (value, slope) at both ends and their derivatives
+        let basis = |s: f64| {
+            let s2 = s * s;
+            let s3 = s2 * s;
+            (
+                [2.0 * s3 - 3.0 * s2 + 1.0, -2.0 * s3 + 3.0 * s2],
+                [s3 - 2.0 * s2 + s, s3 - s2],
+                [6.0 * s2 - 6.0 * s, -6.0 * s2 + 6.0 * s],
+                [3.0 * s2 - 4.0 * s + 1.0, 3.0 * s2 - 2.0 * s],
+            )
+        };
+        let (ht, gt, dht, dgt) = basis(t);
+        let (hu, gu, dhu, dgu) = basis(u);
+
+        let (mut e, mut de_dt, mut de_du) = (0.0, 0.0, 0.0);
+        for a in 0..2 {
+            for b in 0..2 {
+                let k = ((i0 + a) % n) * n + (j0 + b) % n;
+                // slopes per cell rather than per radian
+                let (f, fx, fy, fxy) = (
+                    self.values[k],
+                    self.d_phi[k] * h,
+                    self.d_psi[k] * h,
+                    self.d_phi_psi[k] * h * h,
+                );
+                e += f * ht[a] * hu[b]
+                    + fx * gt[a] * hu[b]
+                    + fy * ht[a] * gu[b]
+                    + fxy * gt[a] * gu[b];
+                de_dt += f * dht[a] * hu[b]
+                    + fx * dgt[a] * hu[b]
+                    + fy * dht[a] * gu[b]
+                    + fxy * dgt[a] * gu[b];
+                de_du += f * ht[a] * dhu[b]
+                    + fx * gt[a] * dhu[b]
+                    + fy * ht[a] * dgu[b]
+                    + fxy * gt[a] * dgu[b];
+            }
+        }
+        (e, de_dt / h, de_du / h)
+    }
+}
+
+pub fn cmap_term(atoms: &[Particle], cmap: &Cmap, box_length: f64) -> (f64, [Vector3<f64>; 5]) {
+    /*
+    CMAP energy and the forces on its five atoms
+     */
+    let r: Vec<Vector3<f64>> = cmap.atoms.iter().map(|&i| atoms[i].position).collect();
+    let phi_atoms = [r[0], r[1], r[2], r[3]];
+    let psi_atoms = [r[1], r[2], r[3], r[4]];
+    let (Some((phi, g_phi)), Some((psi, g_psi))) = (
+        dihedral_gradient(&phi_atoms, box_length),
+        dihedral_gradient(&psi_atoms, box_length),
+    ) else {
+        // collinear backbone: the torsions and their gradients are undefined
+        return (0.0, [Vector3::zeros(); 5]);
+    };
+
+    let (energy, de_dphi, de_dpsi) = cmap.grid.energy(phi, psi);
+    let mut forces = [Vector3::zeros(); 5];
+    for k in 0..4 {
+        forces[k] -= de_dphi * g_phi[k];
+        forces[k + 1] -= de_dpsi * g_psi[k];
+    }
+    (energy, forces)
+}
+
+pub fn parse_cmap_type(tokens: &[&str]) -> Result<CmapType, String> {
+    /*
+    One `[ cmaptypes ]` entry with its continuation lines already joined:
+
+        C NH1 CT1 C NH1  1  24 24  <24 x 24 values, kJ/mol>
+     */
+    if tokens.len() < 8 {
+        return Err("cmaptypes row requires 5 types, funct and the grid size".to_string());
+    }
+    if tokens[5] != "1" {
+        return Err(format!("unsupported cmap function type {}", tokens[5]));
+    }
+    let parse = |t: &str, label: &str| {
+        t.parse::<usize>()
+            .map_err(|e| format!("failed to parse cmap {label}: {e}"))
+    };
+    let (nx, ny) = (
+        parse(tokens[6], "grid size")?,
+        parse(tokens[7], "grid size")?,
+    );
+    if nx != ny {
+        return Err(format!("CMAP grid must be square, got {nx} x {ny}"));
+    }
+    let values = tokens[8..]
+        .iter()
+        .map(|t| {
+            t.parse::<f64>()
+                .map_err(|e| format!("failed to parse cmap value: {e}"))
+        })
+        .collect::<Result<Vec<f64>, String>>()?;
+
+    Ok(CmapType {
+        types: std::array::from_fn(|i| tokens[i].to_string()),
+        grid: Arc::new(CmapGrid::new(nx, values)?),
+    })
+}
+
+pub fn parse_cmap_types(contents: &str) -> Result<Vec<CmapType>, String> {
+    let mut types = Vec::new();
+    let mut in_section = false;
+    let mut entry = String::new();
+
+    for raw_line in contents.lines() {
+        let line = raw_line.split(';').next().unwrap_or("").trim();
+        if line.starts_with('[') && line.ends_with(']') {
+            in_section = line[1..line.len() - 1]
+                .trim()
+                .eq_ignore_ascii_case("cmaptypes");
+            continue;
+        }
+        if !in_section || line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // entries span several lines joined by a trailing backslash
+        match line.strip_suffix('\\') {
+            Some(part) => {
+                entry.push_str(part);
+                entry.push(' ');
+            }
+            None => {
+                entry.push_str(line);
+                let tokens: Vec<&str> = entry.split_whitespace().collect();
+                types.push(parse_cmap_type(&tokens)?);
+                entry.clear();
+            }
+        }
+    }
+    Ok(types)
+}
+
+pub fn read_cmap_types(path: &str) -> Result<Vec<CmapType>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("failed to read cmap file at '{path}': {e}"))?;
+    parse_cmap_types(&contents)
+}
+
+pub fn assign_backbone_cmaps(
+    type_names: &[String],
+    bonds: &[Bond],
+    cmap_types: &[CmapType],
+) -> Vec<Cmap> {
+    /*
+    Find every bonded chain C-N-CA-C-N whose atom types match a CMAP type, i.e. the
+    phi/psi pair of every residue that has neighbours on both sides
+     */
+    if cmap_types.is_empty() {
+        return Vec::new();
+    }
+    let mut neighbours: Vec<Vec<usize>> = vec![Vec::new(); type_names.len()];
+    for b in bonds {
+        neighbours[b.atom1].push(b.atom2);
+        neighbours[b.atom2].push(b.atom1);
+    }
+    let lookup: HashMap<[&str; 5], &Arc<CmapGrid>> = cmap_types
+        .iter()
+        .map(|t| (std::array::from_fn(|i| t.types[i].as_str()), &t.grid))
+        .collect();
+
+    let mut cmaps = Vec::new();
+    for ca in 0..type_names.len() {
+        for &n in &neighbours[ca] {
+            for &c in neighbours[ca].iter().filter(|&&c| c != n) {
+                for &c_prev in neighbours[n].iter().filter(|&&a| a != ca) {
+                    for &n_next in neighbours[c].iter().filter(|&&a| a != ca) {
+                        let atoms = [c_prev, n, ca, c, n_next];
+                        let key = atoms.map(|i| type_names[i].as_str());
+                        if let Some(grid) = lookup.get(&key) {
+                            cmaps.push(Cmap {
+                                atoms,
+                                grid: Arc::clone(grid),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+    cmaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::molecule::molecule::BondForm;
+    use crate::test_support::{assert_forces_match_energy, particles, Tolerance};
+
+    fn bundled_cmap_types() -> Vec<CmapType> {
+        read_cmap_types(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/ff/Charmm27.ff/charmm27.ff/cmap.itp"
+        ))
+        .expect("bundled cmap.itp should parse")
+    }
+
+    #[test]
+    fn bundled_cmap_reproduces_grid_values() {
+        let types = bundled_cmap_types();
+        assert_eq!(types.len(), 6);
+        let grid = &types[0].grid;
+        assert_eq!(grid.n, 24);
+        assert_eq!(types[0].types.join(" "), "C NH1 CT1 C NH1");
+
+        // first values of the C-NH1-CT1-C-NH1 map: phi = -180, psi = -180 and -165
+        let (e, _, _) = grid.energy(-PI, -PI);
+        assert!((e - 0.53048936).abs() < 1e-9);
+        let (e, _, _) = grid.energy(-PI, (-165.0f64).to_radians());
+        assert!((e - 3.2162408).abs() < 1e-9);
+        // periodic: +180 is the same node as -180
+        let (e, _, _) = grid.energy(PI - 1e-12, -PI);
+        assert!((e - 0.53048936).abs() < 1e-6);
+
+        // between the nodes the derivatives match the interpolated surface
+        let (phi, psi, d) = (-1.1, 2.3, 1e-6);
+        let (_, de_dphi, de_dpsi) = grid.energy(phi, psi);
+        let numeric_phi = (grid.energy(phi + d, psi).0 - grid.energy(phi - d, psi).0) / (2.0 * d);
+        let numeric_psi = (grid.energy(phi, psi + d).0 - grid.energy(phi, psi - d).0) / (2.0 * d);
+        assert!((de_dphi - numeric_phi).abs() < 1e-5);
+        assert!((de_dpsi - numeric_psi).abs() < 1e-5);
+    }
+
+    #[test]
+    fn backbone_cmap_is_assigned_and_forces_match_energy() {
+        let types = bundled_cmap_types();
+        let names: Vec<String> = ["C", "NH1", "CT1", "C", "NH1", "HA"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let bond = |atom1, atom2| Bond {
+            atom1,
+            atom2,
+            k: 0.0,
+            r0: 0.0,
+            form: BondForm::Harmonic,
+        };
+        let bonds = [bond(0, 1), bond(1, 2), bond(2, 3), bond(3, 4), bond(2, 5)];
+        let cmaps = assign_backbone_cmaps(&names, &bonds, &types);
+        assert_eq!(cmaps.len(), 1);
+        assert_eq!(cmaps[0].atoms, [0, 1, 2, 3, 4]);
+
+        let positions = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.13, 0.02, 0.0),
+            Vector3::new(0.2, 0.14, 0.03),
+            Vector3::new(0.35, 0.13, 0.07),
+            Vector3::new(0.41, 0.25, -0.02),
+            Vector3::new(0.17, 0.2, 0.12),
+        ];
+        let atoms = particles(&positions, 12.0);
+
+        let (_, term_forces) = cmap_term(&atoms, &cmaps[0], 10.0);
+        let mut forces = vec![Vector3::zeros(); atoms.len()];
+        for (k, &i) in cmaps[0].atoms.iter().enumerate() {
+            forces[i] = term_forces[k];
+        }
+        assert_forces_match_energy(
+            &atoms,
+            &forces,
+            |a| cmap_term(a, &cmaps[0], 10.0).0,
+            1e-7,
+            Tolerance::Relative(1e-4),
+            "cmap",
+        );
+    }
+}
@@ -0,0 +1,174 @@
+/*
+Drude polarisable particles (classical Drude oscillator, CHARMM Drude force field)
+
+Every polarisable atom is split into a core and an auxiliary Drude particle carrying
+the charge q_D, bound to the core by an isotropic harmonic spring
+
+    E = k_D |d|^2,    d = r_drude - r_core
+
+so that the atomic polarisability is alpha = k_e q_D^2 / (2 k_D). The Drude is
+excluded from its own core and inherits every exclusion and 1-4 pair of the core.
+
+To keep the extended Lagrangian dynamics stable a hard wall limits |d|: a Drude that
+has moved beyond the wall is reflected back inside it and its velocity relative to
+the core along d is reversed, leaving the core-Drude centre of mass untouched.
+ */
+
+use crate::lennard_jones_simulations::{minimum_image_convention, Particle};
+use crate::molecule::molecule::{pair_key, Pair14, System};
+use nalgebra::Vector3;
+
+#[derive(Clone, Debug)]
+pub struct DrudePair {
+    pub core: usize,
+    pub drude: usize,
+    pub k: f64,         // spring constant k_D, E = k_D d^2
+    pub hard_wall: f64, // maximum core-Drude separation, 0 = no wall
+}
+
+fn drude_term(atoms: &[Particle], pair: &DrudePair, box_length: f64) -> (f64, [Vector3<f64>; 2]) {
+    /*
+    Spring energy and the forces on (core, Drude)
+     */
+    let d = minimum_image_convention(
+        atoms[pair.drude].position - atoms[pair.core].position,
+        box_length,
+    );
+    let f_drude = -2.0 * pair.k * d;
+    (pair.k * d.norm_squared(), [-f_drude, f_drude])
+}
+
+pub fn compute_drude_forces(atoms: &mut [Particle], drudes: &[DrudePair], box_length: f64) -> f64 {
+    let mut energy = 0.0;
+    for pair in drudes {
+        let (en, f) = drude_term(atoms, pair, box_length);
+        atoms[pair.core].force += f[0];
+        atoms[pair.drude].force += f[1];
+        energy += en;
+    }
+    energy
+}
+
+pub fn drude_energy(atoms: &[Particle], drudes: &[DrudePair], box_length: f64) -> f64 {
+    drudes
+        .iter()
+        .map(|pair| drude_term(atoms, pair, box_length).0)
+        .sum()
+}
+
+pub fn apply_drude_hard_wall(atoms: &mut [Particle], drudes: &[DrudePair], box_length: f64) {
+    /*
+    Reflect every Drude that moved beyond its hard wall. Positions and velocities of
+    the core and Drude are changed in opposite directions, weighted by mass, so the
+    pair's centre of mass and total momentum are conserved.
+     */
+    for pair in drudes {
+        if pair.hard_wall <= 0.0 {
+            continue;
+        }
+        let (c, dr) = (pair.core, pair.drude);
+        let d = minimum_image_convention(atoms[dr].position - atoms[c].position, box_length);
+        let r = d.norm();
+        if r <= pair.hard_wall {
+            continue;
+        }
+
+        let d_hat = d / r;
+        let (m_c, m_d) = (atoms[c].mass, atoms[dr].mass);
+        let m_tot = m_c + m_d;
+
+        let r_new = (2.0 * pair.hard_wall - r).max(0.0);
+        let delta = d_hat * (r_new - r);
+        atoms[dr].position += (m_c / m_tot) * delta;
+        atoms[c].position -= (m_d / m_tot) * delta;
+
+        let v_out = (atoms[dr].velocity - atoms[c].velocity).dot(&d_hat);
+        if v_out > 0.0 {
+            let dv = -2.0 * v_out * d_hat;
+            atoms[dr].velocity += (m_c / m_tot) * dv;
+            atoms[c].velocity -= (m_d / m_tot) * dv;
+        }
+    }
+}
+
+pub fn inherit_drude_exclusions(system: &mut System) {
+    /*
+    Exclude every Drude from its core and give it copies of the core's exclusions and
+    1-4 pairs. Drudes carry no LJ, so their 1-4 pairs are Coulomb only.
+     */
+    if system.drudes.is_empty() {
+        return;
+    }
+    let mut drude_of: Vec<Option<usize>> = vec![None; system.atoms.len()];
+    for pair in &system.drudes {
+        drude_of[pair.core] = Some(pair.drude);
+        system.exclusions.insert(pair_key(pair.core, pair.drude));
+    }
+    // the particle itself and its Drude, if it has one
+    let with_drude = |i: usize| [Some(i), drude_of[i]].into_iter().flatten();
+
+    let core_exclusions: Vec<(usize, usize)> = system.exclusions.iter().copied().collect();
+    for (i, j) in core_exclusions {
+        for a in with_drude(i) {
+            for b in with_drude(j) {
+                system.exclusions.insert(pair_key(a, b));
+            }
+        }
+    }
+
+    let mut drude_pairs = Vec::new();
+    for pair in &system.pairs {
+        for a in with_drude(pair.atom1) {
+            for b in with_drude(pair.atom2) {
+                if a == pair.atom1 && b == pair.atom2 {
+                    continue;
+                }
+                drude_pairs.push(Pair14 {
+                    atom1: a,
+                    atom2: b,
+                    epsilon: 0.0,
+                    ..pair.clone()
+                });
+            }
+        }
+    }
+    system.pairs.extend(drude_pairs);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::particle;
+
+    #[test]
+    fn hard_wall_reflects_drude_and_conserves_momentum() {
+        let mut atoms = vec![
+            particle(Vector3::new(5.0, 5.0, 5.0), 15.6),
+            Particle {
+                velocity: Vector3::new(1.0, 0.5, 0.0),
+                ..particle(Vector3::new(5.25, 5.0, 5.0), 0.4)
+            },
+        ];
+        let drudes = vec![DrudePair {
+            core: 0,
+            drude: 1,
+            k: 500.0,
+            hard_wall: 0.2,
+        }];
+        let momentum = |a: &[Particle]| a.iter().map(|p| p.mass * p.velocity).sum::<Vector3<f64>>();
+        let com = |a: &[Particle]| a.iter().map(|p| p.mass * p.position).sum::<Vector3<f64>>();
+        let (p0, c0) = (momentum(&atoms), com(&atoms));
+
+        apply_drude_hard_wall(&mut atoms, &drudes, 20.0);
+
+        let d = atoms[1].position - atoms[0].position;
+        assert!((d.norm() - 0.15).abs() < 1e-12);
+        assert!((atoms[1].velocity - atoms[0].velocity).x < 0.0);
+        assert!((momentum(&atoms) - p0).norm() < 1e-12);
+        assert!((com(&atoms) - c0).norm() < 1e-12);
+
+        let (energy, f) = drude_term(&atoms, &drudes[0], 20.0);
+        assert!((energy - 500.0 * 0.15 * 0.15).abs() < 1e-9);
+        assert!((f[1] + 2.0 * 500.0 * d).norm() < 1e-9);
+    }
+}
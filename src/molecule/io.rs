@@ -78,6 +78,65 @@ pub fn read_pdb(path: &str) -> Result<Vec<Particle>, String> {
     read_pdb_from_str(&contents)
 }
 
+#[derive(Clone, Debug)]
+pub struct PdbAtom {
+    pub name: String,
+    pub residue_name: String,
+    pub chain: char,
+    pub residue_number: i64,
+    pub insertion_code: char,
+    pub hetero: bool,           // a HETATM record
+    pub position: Vector3<f64>, // Å, as in the file
+}
+
+pub fn read_pdb_atoms_from_str(contents: &str) -> Result<Vec<PdbAtom>, String> {
+    /*
+    ATOM/HETATM records with their residue and chain, for builders that need the
+    residue structure. Only the first model of a multi-model file is read.
+     */
+    let mut atoms = Vec::new();
+
+    for line in contents.lines() {
+        if line.starts_with("ENDMDL") {
+            break;
+        }
+        if !(line.starts_with("ATOM") || line.starts_with("HETATM")) {
+            continue;
+        }
+        let field = |start: usize, end: usize| line.get(start..end).unwrap_or("").trim();
+        let column = |index: usize| line.chars().nth(index).unwrap_or(' ');
+        let residue_number = field(22, 26)
+            .parse::<i64>()
+            .map_err(|e| format!("failed to parse residue number: {e}; line: {line}"))?;
+
+        atoms.push(PdbAtom {
+            name: field(12, 16).to_string(),
+            residue_name: field(17, 21).to_string(),
+            chain: column(21),
+            residue_number,
+            insertion_code: column(26),
+            hetero: line.starts_with("HETATM"),
+            position: Vector3::new(
+                parse_f64_slice(line, 30, 38, "x")?,
+                parse_f64_slice(line, 38, 46, "y")?,
+                parse_f64_slice(line, 46, 54, "z")?,
+            ),
+        });
+    }
+
+    if atoms.is_empty() {
+        return Err("no ATOM/HETATM records found in pdb input".to_string());
+    }
+
+    Ok(atoms)
+}
+
+pub fn read_pdb_atoms(path: &str) -> Result<Vec<PdbAtom>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("failed to read pdb file at '{path}': {e}"))?;
+    read_pdb_atoms_from_str(&contents)
+}
+
 pub fn read_gro_from_str(contents: &str) -> Result<(Vec<Particle>, Option<Vector3<f64>>), String> {
     let lines: Vec<&str> = contents.lines().collect();
     if lines.len() < 3 {
@@ -1,12 +1,27 @@
+use crate::electrostatics::reaction_field::ReactionField;
 use crate::lennard_jones_simulations::{LJParameters, Particle};
-use crate::molecule::molecule::{Angle, Bond, Dihedral, System};
+use crate::molecule::bond_table::BondTable;
+use crate::molecule::cmap::{assign_backbone_cmaps, parse_cmap_type, CmapType};
+use crate::molecule::molecule::{
+    element_from_atom_name, pair_key, Angle, Bond, BondForm, CosineAngle, CosineAngleForm,
+    Dihedral, ExtendedBonded, Improper, Pair14, RbDihedral, Settle, System, UreyBradley,
+};
+use crate::molecule::restraint::{
+    parse_position_restraint, set_reference_positions, PositionRestraint,
+};
+use crate::molecule::topology::{sigma_epsilon, Preprocessor};
+use crate::molecule::virtual_site::{VirtualSite, VirtualSiteKind};
+use crate::Electrostatics;
 use nalgebra::Vector3;
 use std::collections::HashMap;
 use std::fs;
+use std::sync::Arc;
 
 #[derive(Clone, Debug, Default)]
 pub struct MartiniAtomType {
     pub name: String,
+    pub bond_type: Option<String>, // the bond_type column bonded types are matched on
+    pub atomic_number: Option<u8>, // the at.num column, when the row has one
     pub mass: f64,
     pub charge: f64,
     pub sigma: Option<f64>,
@@ -19,6 +34,7 @@ pub struct MartiniAtomType {
 pub struct MartiniAtom {
     pub index: usize,
     pub type_name: String,
+    pub name: String,
     pub charge: f64,
     pub mass: Option<f64>,
 }
@@ -27,8 +43,10 @@ pub struct MartiniAtom {
 pub struct MartiniBond {
     pub atom1: usize,
     pub atom2: usize,
-    pub length: f64,
-    pub force_constant: f64,
+    pub funct: usize,
+    pub length: f64, // b0, the maximum extension bm (FENE) or the table number
+    pub force_constant: f64, // kb, or the well depth D (Morse)
+    pub beta: Option<f64>, // Morse steepness
 }
 
 #[derive(Clone, Debug)]
@@ -36,8 +54,10 @@ pub struct MartiniAngle {
     pub atom1: usize,
     pub atom2: usize,
     pub atom3: usize,
+    pub funct: usize,
     pub theta0_deg: f64,
     pub force_constant: f64,
+    pub urey_bradley: Option<(f64, f64)>, // (r13, k_UB) for funct 5
 }
 
 #[derive(Clone, Debug)]
@@ -46,80 +66,93 @@ pub struct MartiniDihedral {
     pub atom2: usize,
     pub atom3: usize,
     pub atom4: usize,
+    pub funct: usize,
+    // periodic (funct 1/9) and harmonic improper (funct 2, phase = xi0) parameters
     pub phase_deg: f64,
     pub force_constant: f64,
     pub multiplicity: usize,
+    // Ryckaert-Bellemans C0..C5, also used for Fourier (funct 5) torsions
+    pub rb_coefficients: Option<[f64; 6]>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MartiniPair {
+    pub atom1: usize,
+    pub atom2: usize,
+    pub lj: Option<(f64, f64)>, // (sigma, epsilon) given on the row
+}
+
+#[derive(Clone, Debug)]
+pub struct MartiniSettle {
+    pub atom: usize, // the oxygen; the two hydrogens follow it
+    pub d_oh: f64,
+    pub d_hh: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct MartiniVirtualSite {
+    pub site: usize,
+    pub atoms: Vec<usize>,
+    // number of constructing atoms of [ virtual_sites2/3/4 ], 0 for [ virtual_sitesn ]
+    pub section: usize,
+    pub funct: usize,
+    pub parameters: Vec<f64>, // a, b, c or the weights of a funct 3 [ virtual_sitesn ]
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct MartiniForceField {
     pub molecule_name: Option<String>,
+    pub nrexcl: Option<usize>,
     pub atom_types: HashMap<String, MartiniAtomType>,
     pub atoms: Vec<MartiniAtom>,
     pub bonds: Vec<MartiniBond>,
     pub angles: Vec<MartiniAngle>,
     pub dihedrals: Vec<MartiniDihedral>,
+    // [ pairs ]: explicit 1-4 pairs, used instead of the ones generated from the bonds
+    pub pairs: Vec<MartiniPair>,
+    pub settles: Vec<MartiniSettle>,
+    // CMAP grids, assigned to matching backbones by `to_system`
+    pub cmap_types: Vec<CmapType>,
+    pub virtual_sites: Vec<MartiniVirtualSite>,
+    // [ exclusions ]: the first atom of each row is excluded from the others
+    pub exclusions: Vec<Vec<usize>>,
+    // usually included under #ifdef POSRES, referenced to the coordinates of `to_system`
+    pub position_restraints: Vec<PositionRestraint>,
+    // tables for bond types 8 and 9, keyed by table number (table_b<n>.xvg)
+    pub bond_tables: HashMap<usize, Arc<BondTable>>,
+    // (fudgeLJ, fudgeQQ) of the generated 1-4 pairs, from a topology's [ defaults ]
+    pub pair_scales: Option<(f64, f64)>,
+    // comb-rule of a topology's [ defaults ]; 1-4 pairs without parameters mix sigma
+    // geometrically for rule 3 and arithmetically otherwise
+    pub comb_rule: Option<usize>,
 }
 
 impl MartiniForceField {
     pub fn parse_str(contents: &str) -> Result<Self, String> {
+        Self::parse_str_with_defines(contents, &[])
+    }
+
+    pub fn parse_str_with_defines(contents: &str, defines: &[&str]) -> Result<Self, String> {
+        /*
+        Parse a topology with the given preprocessor symbols defined (as with
+        `define = -DFLEXIBLE` in a GROMACS .mdp). `#define`, `#undef`, `#ifdef`,
+        `#ifndef`, `#else` and `#endif` are honoured; `#include` is skipped, use
+        `GromacsTopology` to read a topology together with its includes.
+         */
+        let mut preprocessor = Preprocessor::new(&[], defines);
+        preprocessor.follow_includes = false;
         let mut ff = MartiniForceField::default();
         let mut section = String::new();
 
-        for (line_number, raw_line) in contents.lines().enumerate() {
-            let line = raw_line.split(';').next().unwrap_or("").trim();
-
-            if line.is_empty() || line.starts_with('#') {
+        for line in preprocessor.process_str(contents, "", None)? {
+            let text = line.text.as_str();
+            if text.starts_with('[') && text.ends_with(']') {
+                section = text[1..text.len() - 1].trim().to_ascii_lowercase();
                 continue;
             }
-
-            if line.starts_with('[') && line.ends_with(']') {
-                section = line[1..line.len() - 1].trim().to_ascii_lowercase();
-                continue;
-            }
-
-            let tokens: Vec<&str> = line.split_whitespace().collect();
-            if tokens.is_empty() {
-                continue;
-            }
-
-            match section.as_str() {
-                "moleculetype" => {
-                    if ff.molecule_name.is_none() {
-                        ff.molecule_name = Some(tokens[0].to_string());
-                    }
-                }
-                "atomtypes" => {
-                    let atom_type = parse_atomtype(&tokens)
-                        .map_err(|e| format!("line {}: {e}", line_number + 1))?;
-                    ff.atom_types.insert(atom_type.name.clone(), atom_type);
-                }
-                "atoms" => {
-                    ff.atoms.push(
-                        parse_atom(&tokens)
-                            .map_err(|e| format!("line {}: {e}", line_number + 1))?,
-                    );
-                }
-                "bonds" => {
-                    ff.bonds.push(
-                        parse_bond(&tokens)
-                            .map_err(|e| format!("line {}: {e}", line_number + 1))?,
-                    );
-                }
-                "angles" => {
-                    ff.angles.push(
-                        parse_angle(&tokens)
-                            .map_err(|e| format!("line {}: {e}", line_number + 1))?,
-                    );
-                }
-                "dihedrals" => {
-                    ff.dihedrals.push(
-                        parse_dihedral(&tokens)
-                            .map_err(|e| format!("line {}: {e}", line_number + 1))?,
-                    );
-                }
-                _ => {}
-            }
+            let tokens: Vec<&str> = text.split_whitespace().collect();
+            ff.parse_row(&section, &tokens)
+                .map_err(|e| format!("{}: {e}", line.location()))?;
         }
 
         if ff.atoms.is_empty() {
@@ -129,12 +162,67 @@ impl MartiniForceField {
         Ok(ff)
     }
 
+    pub(crate) fn parse_row(&mut self, section: &str, tokens: &[&str]) -> Result<(), String> {
+        /*
+        One data row of `section`. A force field holds one molecule, so a second
+        [ moleculetype ] is an error; `GromacsTopology` reads files with several.
+         */
+        match section {
+            "moleculetype" => {
+                if let Some(name) = &self.molecule_name {
+                    return Err(format!(
+                        "[ moleculetype ] '{}' follows '{name}'; read files with several \
+                         molecules with GromacsTopology",
+                        tokens.first().unwrap_or(&"")
+                    ));
+                }
+                self.molecule_name = Some(tokens[0].to_string());
+                self.nrexcl = tokens.get(1).and_then(|v| v.parse::<usize>().ok());
+            }
+            "atomtypes" => {
+                let atom_type = parse_atomtype_with_rule(tokens, None)?;
+                self.atom_types.insert(atom_type.name.clone(), atom_type);
+            }
+            "atoms" => self.atoms.push(parse_atom(tokens)?),
+            "bonds" => self.bonds.push(parse_bond(tokens)?),
+            "angles" => self.angles.push(parse_angle(tokens)?),
+            "dihedrals" => self.dihedrals.push(parse_dihedral(tokens)?),
+            "pairs" => self.pairs.push(parse_pair(tokens, None)?),
+            "cmaptypes" => self.cmap_types.push(parse_cmap_type(tokens)?),
+            "settles" => self.settles.push(parse_settle(tokens)?),
+            "virtual_sites2" | "virtual_sites3" | "virtual_sites4" | "virtual_sitesn" => {
+                let section_size = section["virtual_sites".len()..].parse().unwrap_or(0);
+                self.virtual_sites
+                    .push(parse_virtual_site(tokens, section_size)?);
+            }
+            "position_restraints" => self
+                .position_restraints
+                .push(parse_position_restraint(tokens)?),
+            "exclusions" => {
+                let row = (0..tokens.len())
+                    .map(|i| parse_usize(tokens, i, "exclusion atom"))
+                    .collect::<Result<Vec<usize>, String>>()?;
+                self.exclusions.push(row);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
     pub fn read_itp(path: &str) -> Result<Self, String> {
         let contents = fs::read_to_string(path)
             .map_err(|e| format!("failed to read martini file at '{path}': {e}"))?;
         Self::parse_str(&contents)
     }
 
+    pub fn electrostatics(&self) -> Electrostatics {
+        /*
+        Martini is parameterised with reaction-field Coulomb (epsilon_r = 15,
+        epsilon_rf = infinity, rc = 1.1 nm); use this for runs of `to_system` outputs
+         */
+        Electrostatics::ReactionField(ReactionField::martini())
+    }
+
     pub fn to_system(&self, coordinates: &[Vector3<f64>]) -> Result<System, String> {
         if coordinates.len() != self.atoms.len() {
             return Err(format!(
@@ -145,6 +233,7 @@ impl MartiniForceField {
         }
 
         let mut particles = Vec::with_capacity(self.atoms.len());
+        let mut elements = Vec::with_capacity(self.atoms.len());
         for (idx, atom) in self.atoms.iter().enumerate() {
             let atom_type = self
                 .atom_types
@@ -153,6 +242,11 @@ impl MartiniForceField {
 
             let (sigma, epsilon) = infer_lj_parameters(atom_type)?;
             let mass = atom.mass.unwrap_or(atom_type.mass);
+            elements.push(
+                atom_type
+                    .atomic_number
+                    .unwrap_or_else(|| element_from_atom_name(&atom.name)),
+            );
 
             particles.push(Particle {
                 id: atom.index,
@@ -171,61 +265,179 @@ impl MartiniForceField {
             });
         }
 
-        let bonds = self
+        let all_bonds = self
             .bonds
             .iter()
-            .map(|b| Bond {
-                atom1: b.atom1 - 1,
-                atom2: b.atom2 - 1,
-                k: b.force_constant,
-                r0: b.length,
-            })
-            .collect();
+            .map(|b| self.to_bond(b))
+            .collect::<Result<Vec<Bond>, String>>()?;
 
-        let angles = self
-            .angles
-            .iter()
-            .map(|a| Angle {
-                atom1: a.atom1 - 1,
-                atom2: a.atom2 - 1,
-                atom3: a.atom3 - 1,
-                k: a.force_constant,
-                theta0: a.theta0_deg.to_radians(),
-            })
-            .collect();
+        // the GROMACS function type decides which bonded form each entry becomes
+        let mut angles = Vec::new();
+        let mut dihedrals = Vec::new();
+        let mut impropers = Vec::new();
+        let type_names: Vec<String> = self.atoms.iter().map(|a| a.type_name.clone()).collect();
+        let mut extended = ExtendedBonded {
+            cmaps: assign_backbone_cmaps(&type_names, &all_bonds, &self.cmap_types),
+            ..Default::default()
+        };
+        // funct 6 and 9 bonds act as springs only and stay out of the exclusion graph
+        let mut bonds = Vec::with_capacity(all_bonds.len());
+        for (bond, source) in all_bonds.into_iter().zip(&self.bonds) {
+            if matches!(source.funct, 6 | 9) {
+                extended.bonds_without_exclusions.push(bond);
+            } else {
+                bonds.push(bond);
+            }
+        }
+
+        for a in &self.angles {
+            let (atom1, atom2, atom3) = (a.atom1 - 1, a.atom2 - 1, a.atom3 - 1);
+            let (k, theta0) = (a.force_constant, a.theta0_deg.to_radians());
+            match a.funct {
+                1 | 5 => {
+                    angles.push(Angle {
+                        atom1,
+                        atom2,
+                        atom3,
+                        k,
+                        theta0,
+                    });
+                    if let Some((r0, k_ub)) = a.urey_bradley {
+                        extended.urey_bradleys.push(UreyBradley {
+                            atom1,
+                            atom3,
+                            k: k_ub,
+                            r0,
+                        });
+                    }
+                }
+                2 | 10 => extended.cosine_angles.push(CosineAngle {
+                    atom1,
+                    atom2,
+                    atom3,
+                    k,
+                    theta0,
+                    form: if a.funct == 2 {
+                        CosineAngleForm::G96
+                    } else {
+                        CosineAngleForm::Restricted
+                    },
+                }),
+                other => return Err(format!("unsupported angle function type {other}")),
+            }
+        }
 
-        let dihedrals = self
-            .dihedrals
+        for d in &self.dihedrals {
+            let idx = [d.atom1 - 1, d.atom2 - 1, d.atom3 - 1, d.atom4 - 1];
+            match (d.funct, d.rb_coefficients) {
+                (1 | 4 | 9, _) => dihedrals.push(Dihedral {
+                    atom1: idx[0],
+                    atom2: idx[1],
+                    atom3: idx[2],
+                    atom4: idx[3],
+                    k: d.force_constant,
+                    multiplicity: d.multiplicity,
+                    phase: d.phase_deg.to_radians(),
+                }),
+                (2, _) => impropers.push(Improper {
+                    atom1: idx[0],
+                    atom2: idx[1],
+                    atom3: idx[2],
+                    atom4: idx[3],
+                    k: d.force_constant,
+                    psi0: d.phase_deg.to_radians(),
+                }),
+                (3 | 5, Some(c)) => extended.rb_dihedrals.push(RbDihedral {
+                    atom1: idx[0],
+                    atom2: idx[1],
+                    atom3: idx[2],
+                    atom4: idx[3],
+                    c,
+                }),
+                (other, _) => return Err(format!("unsupported dihedral function type {other}")),
+            }
+        }
+
+        let settles = self
+            .settles
             .iter()
-            .map(|d| Dihedral {
-                atom1: d.atom1 - 1,
-                atom2: d.atom2 - 1,
-                atom3: d.atom3 - 1,
-                atom4: d.atom4 - 1,
-                k: d.force_constant,
-                multiplicity: d.multiplicity,
-                phase: d.phase_deg.to_radians(),
+            .map(|s| Settle {
+                oxygen: s.atom - 1,
+                d_oh: s.d_oh,
+                d_hh: s.d_hh,
             })
             .collect();
 
-        Ok(System {
+        let mut system = System {
             atoms: particles,
             bonds,
             angles,
             dihedrals,
-            impropers: Vec::new(),
-        })
+            impropers,
+            settles,
+            extended,
+            elements,
+            ..Default::default()
+        };
+        let masses: Vec<f64> = system.atoms.iter().map(|a| a.mass).collect();
+        system.virtual_sites = self
+            .virtual_sites
+            .iter()
+            .map(|v| v.to_virtual_site(&masses))
+            .collect::<Result<Vec<_>, String>>()?;
+        system.position_restraints = self.position_restraints.clone();
+        set_reference_positions(&mut system.position_restraints, coordinates)?;
+
+        // Martini topologies use nrexcl = 1 and have no 1-4 pair interactions.
+        let (lj_14_scale, coulomb_14_scale) = self.pair_scales.unwrap_or((1.0, 1.0));
+        system.generate_exclusions(self.nrexcl.unwrap_or(1), lj_14_scale, coulomb_14_scale);
+        if self.comb_rule == Some(3) {
+            for pair in &mut system.pairs {
+                pair.sigma = self.mixed_sigma(
+                    &system.atoms[pair.atom1].lj_parameters,
+                    &system.atoms[pair.atom2].lj_parameters,
+                );
+            }
+        }
+        if !self.pairs.is_empty() {
+            system.pairs = self.explicit_pairs(&system.atoms, lj_14_scale, coulomb_14_scale)?;
+        }
+        for row in &self.exclusions {
+            for &other in &row[1..] {
+                system.exclusions.insert(pair_key(row[0] - 1, other - 1));
+            }
+        }
+
+        Ok(system)
     }
 }
 
-fn parse_atomtype(tokens: &[&str]) -> Result<MartiniAtomType, String> {
+pub(crate) fn parse_atomtype_with_rule(
+    tokens: &[&str],
+    comb_rule: Option<usize>,
+) -> Result<MartiniAtomType, String> {
+    /*
+    One [ atomtypes ] row, `name [bond_type] [at.num] mass charge ptype V W`. The
+    ptype column (A, S, V or D) locates mass and charge; rows without one are read as
+    `name mass charge ... V W`. V and W are C6/C12 for comb-rule 1 and sigma/epsilon
+    for the other rules; without a rule they are told apart by their magnitude.
+     */
     if tokens.len() < 5 {
         return Err("atomtypes row requires at least 5 columns".to_string());
     }
 
     let name = tokens[0].to_string();
-    let mass = parse_f64(tokens, 1, "atomtype mass")?;
-    let charge = parse_f64(tokens, 2, "atomtype charge")?;
+    let ptype = (3..tokens.len() - 2).find(|&i| matches!(tokens[i], "A" | "S" | "V" | "D"));
+    let (mass, charge) = match ptype {
+        Some(p) => (
+            parse_f64(tokens, p - 2, "atomtype mass")?,
+            parse_f64(tokens, p - 1, "atomtype charge")?,
+        ),
+        None => (
+            parse_f64(tokens, 1, "atomtype mass")?,
+            parse_f64(tokens, 2, "atomtype charge")?,
+        ),
+    };
 
     // Martini files can encode either sigma/epsilon or C6/C12 in the last 2 columns.
     let maybe_a = tokens
@@ -240,15 +452,32 @@ fn parse_atomtype(tokens: &[&str]) -> Result<MartiniAtomType, String> {
         .parse::<f64>()
         .map_err(|e| format!("invalid atomtype nonbonded value: {e}"))?;
 
-    // Heuristic: sigma is usually ~0.3-0.8 nm, epsilon few kJ/mol; C12 is tiny.
-    let (sigma, epsilon, c6, c12) = if maybe_a > 0.0 && maybe_a < 2.0 && maybe_b < 20.0 {
+    let is_sigma_epsilon = match comb_rule {
+        Some(rule) => rule != 1,
+        None => looks_like_sigma_epsilon(maybe_a, maybe_b),
+    };
+    let (sigma, epsilon, c6, c12) = if is_sigma_epsilon {
         (Some(maybe_a), Some(maybe_b), None, None)
     } else {
         (None, None, Some(maybe_a), Some(maybe_b))
     };
 
+    // between the name and the mass: [bond_type] [at.num]; a lone column is the atomic
+    // number when it is an integer and the bond type otherwise, as in grompp
+    let atomic_number = match ptype {
+        Some(p) if p >= 4 => tokens[p - 3].parse::<u8>().ok(),
+        _ => None,
+    };
+    let bond_type = match ptype {
+        Some(p) if p >= 5 => Some(tokens[p - 4].to_string()),
+        Some(4) if atomic_number.is_none() => Some(tokens[1].to_string()),
+        _ => None,
+    };
+
     Ok(MartiniAtomType {
         name,
+        bond_type,
+        atomic_number,
         mass,
         charge,
         sigma,
@@ -258,6 +487,11 @@ fn parse_atomtype(tokens: &[&str]) -> Result<MartiniAtomType, String> {
     })
 }
 
+fn looks_like_sigma_epsilon(v: f64, w: f64) -> bool {
+    // Heuristic: sigma is usually ~0.3-0.8 nm, epsilon few kJ/mol; C12 is tiny.
+    v > 0.0 && v < 2.0 && w < 20.0
+}
+
 fn parse_atom(tokens: &[&str]) -> Result<MartiniAtom, String> {
     if tokens.len() < 7 {
         return Err("atoms row requires at least 7 columns".to_string());
@@ -265,6 +499,7 @@ fn parse_atom(tokens: &[&str]) -> Result<MartiniAtom, String> {
 
     let index = parse_usize(tokens, 0, "atom index")?;
     let type_name = tokens[1].to_string();
+    let name = tokens[4].to_string();
     let charge = parse_f64(tokens, 6, "atom charge")?;
     let mass = if tokens.len() > 7 {
         Some(parse_f64(tokens, 7, "atom mass")?)
@@ -275,6 +510,7 @@ fn parse_atom(tokens: &[&str]) -> Result<MartiniAtom, String> {
     Ok(MartiniAtom {
         index,
         type_name,
+        name,
         charge,
         mass,
     })
@@ -284,12 +520,24 @@ fn parse_bond(tokens: &[&str]) -> Result<MartiniBond, String> {
     if tokens.len() < 5 {
         return Err("bonds row requires at least 5 columns".to_string());
     }
+    // funct 1 and 6 harmonic, 2 G96 quartic, 3 Morse, 7 FENE, 8 and 9 tabulated (6 and 9
+    // without exclusions)
+    let funct = parse_usize(tokens, 2, "bond function type")?;
+    if !matches!(funct, 1 | 2 | 3 | 6 | 7 | 8 | 9) {
+        return Err(format!("unsupported bond function type {funct}"));
+    }
 
     Ok(MartiniBond {
         atom1: parse_usize(tokens, 0, "bond atom1")?,
         atom2: parse_usize(tokens, 1, "bond atom2")?,
+        funct,
         length: parse_f64(tokens, 3, "bond length")?,
         force_constant: parse_f64(tokens, 4, "bond force constant")?,
+        beta: if funct == 3 {
+            Some(parse_f64(tokens, 5, "Morse beta")?)
+        } else {
+            None
+        },
     })
 }
 
@@ -298,28 +546,351 @@ fn parse_angle(tokens: &[&str]) -> Result<MartiniAngle, String> {
         return Err("angles row requires at least 6 columns".to_string());
     }
 
+    // funct 1 harmonic, 2 G96 cosine-harmonic, 5 Urey-Bradley, 10 restricted bending
+    let funct = parse_usize(tokens, 3, "angle function type")?;
+    let urey_bradley = match funct {
+        1 | 2 | 10 => None,
+        5 => Some((
+            parse_f64(tokens, 6, "Urey-Bradley r13")?,
+            parse_f64(tokens, 7, "Urey-Bradley force constant")?,
+        )),
+        other => return Err(format!("unsupported angle function type {other}")),
+    };
+
     Ok(MartiniAngle {
         atom1: parse_usize(tokens, 0, "angle atom1")?,
         atom2: parse_usize(tokens, 1, "angle atom2")?,
         atom3: parse_usize(tokens, 2, "angle atom3")?,
+        funct,
         theta0_deg: parse_f64(tokens, 4, "angle theta0")?,
         force_constant: parse_f64(tokens, 5, "angle force constant")?,
+        urey_bradley,
     })
 }
 
 fn parse_dihedral(tokens: &[&str]) -> Result<MartiniDihedral, String> {
-    if tokens.len() < 8 {
-        return Err("dihedrals row requires at least 8 columns".to_string());
+    if tokens.len() < 7 {
+        return Err("dihedrals row requires at least 7 columns".to_string());
     }
 
-    Ok(MartiniDihedral {
+    let mut dihedral = MartiniDihedral {
         atom1: parse_usize(tokens, 0, "dihedral atom1")?,
         atom2: parse_usize(tokens, 1, "dihedral atom2")?,
         atom3: parse_usize(tokens, 2, "dihedral atom3")?,
         atom4: parse_usize(tokens, 3, "dihedral atom4")?,
-        phase_deg: parse_f64(tokens, 5, "dihedral phase")?,
-        force_constant: parse_f64(tokens, 6, "dihedral force constant")?,
-        multiplicity: parse_usize(tokens, 7, "dihedral multiplicity")?,
+        funct: parse_usize(tokens, 4, "dihedral function type")?,
+        phase_deg: 0.0,
+        force_constant: 0.0,
+        multiplicity: 0,
+        rb_coefficients: None,
+    };
+
+    match dihedral.funct {
+        // periodic (4: periodic improper): phase, k, multiplicity
+        1 | 4 | 9 => {
+            dihedral.phase_deg = parse_f64(tokens, 5, "dihedral phase")?;
+            dihedral.force_constant = parse_f64(tokens, 6, "dihedral force constant")?;
+            dihedral.multiplicity = parse_usize(tokens, 7, "dihedral multiplicity")?;
+        }
+        // harmonic improper: xi0, k
+        2 => {
+            dihedral.phase_deg = parse_f64(tokens, 5, "improper xi0")?;
+            dihedral.force_constant = parse_f64(tokens, 6, "improper force constant")?;
+        }
+        // Ryckaert-Bellemans: C0..C5
+        3 => {
+            let mut c = [0.0; 6];
+            for (n, cn) in c.iter_mut().enumerate() {
+                *cn = parse_f64(tokens, 5 + n, "Ryckaert-Bellemans coefficient")?;
+            }
+            dihedral.rb_coefficients = Some(c);
+        }
+        // Fourier: F1..F4
+        5 => {
+            let mut f = [0.0; 4];
+            for (n, fk) in f.iter_mut().enumerate() {
+                *fk = parse_f64(tokens, 5 + n, "Fourier coefficient")?;
+            }
+            dihedral.rb_coefficients = Some(RbDihedral::from_fourier([0; 4], f).c);
+        }
+        other => return Err(format!("unsupported dihedral function type {other}")),
+    }
+
+    Ok(dihedral)
+}
+
+impl MartiniForceField {
+    pub fn load_bond_table(&mut self, table: usize, path: &str) -> Result<(), String> {
+        self.bond_tables
+            .insert(table, Arc::new(BondTable::read_xvg(path)?));
+        Ok(())
+    }
+
+    fn explicit_pairs(
+        &self,
+        atoms: &[Particle],
+        lj_14_scale: f64,
+        coulomb_14_scale: f64,
+    ) -> Result<Vec<Pair14>, String> {
+        /*
+        The [ pairs ] rows as 1-4 pairs: LJ parameters from the row, else the mix of
+        the two atoms scaled by fudgeLJ as for generated pairs
+         */
+        self.pairs
+            .iter()
+            .map(|p| {
+                let (i, j) = (p.atom1.wrapping_sub(1), p.atom2.wrapping_sub(1));
+                if i >= atoms.len() || j >= atoms.len() {
+                    return Err(format!(
+                        "pair {}-{} refers to a missing atom",
+                        p.atom1, p.atom2
+                    ));
+                }
+                let (a, b) = (&atoms[i].lj_parameters, &atoms[j].lj_parameters);
+                let (sigma, epsilon) = p.lj.unwrap_or((
+                    self.mixed_sigma(a, b),
+                    lj_14_scale * (a.epsilon * b.epsilon).sqrt(),
+                ));
+                Ok(Pair14 {
+                    atom1: i,
+                    atom2: j,
+                    sigma,
+                    epsilon,
+                    coulomb_scale: coulomb_14_scale,
+                })
+            })
+            .collect()
+    }
+
+    fn mixed_sigma(&self, a: &LJParameters, b: &LJParameters) -> f64 {
+        /*
+        sigma of a 1-4 pair without parameters: geometric mean for comb-rule 3 (OPLS),
+        arithmetic mean otherwise
+         */
+        if self.comb_rule == Some(3) {
+            (a.sigma * b.sigma).sqrt()
+        } else {
+            0.5 * (a.sigma + b.sigma)
+        }
+    }
+
+    fn to_bond(&self, b: &MartiniBond) -> Result<Bond, String> {
+        let (k, r0, form) = match b.funct {
+            1 | 6 => (b.force_constant, b.length, BondForm::Harmonic),
+            2 => (b.force_constant, b.length, BondForm::Quartic),
+            3 => {
+                let beta = b.beta.unwrap_or(0.0);
+                (
+                    2.0 * b.force_constant * beta * beta,
+                    b.length,
+                    BondForm::Morse {
+                        depth: b.force_constant,
+                        beta,
+                    },
+                )
+            }
+            // the bare FENE spring has its minimum, and so its r0, at r = 0
+            7 => (
+                b.force_constant,
+                0.0,
+                BondForm::Fene {
+                    r_max: b.length,
+                    epsilon: 0.0,
+                    sigma: 0.0,
+                },
+            ),
+            _ => {
+                let number = b.length as usize;
+                let table = self
+                    .bond_tables
+                    .get(&number)
+                    .ok_or_else(|| format!("bond table {number} has not been loaded"))?;
+                (
+                    b.force_constant,
+                    0.0,
+                    BondForm::Tabulated {
+                        table: Arc::clone(table),
+                    },
+                )
+            }
+        };
+        Ok(Bond {
+            atom1: b.atom1 - 1,
+            atom2: b.atom2 - 1,
+            k,
+            r0,
+            form,
+        })
+    }
+}
+
+impl MartiniVirtualSite {
+    pub fn to_virtual_site(&self, masses: &[f64]) -> Result<VirtualSite, String> {
+        /*
+        Convert to 0-based indices and the construction selected by the section and
+        funct columns. Centre of mass sites take their weights from `masses`.
+         */
+        let idx: Vec<usize> = self.atoms.iter().map(|a| a - 1).collect();
+        let p = |n: usize| {
+            self.parameters
+                .get(n)
+                .copied()
+                .ok_or_else(|| format!("virtual site {} is missing parameters", self.site))
+        };
+        let kind = match (self.section, self.funct) {
+            (2, 1) => VirtualSiteKind::Linear2 {
+                atoms: [idx[0], idx[1]],
+                a: p(0)?,
+            },
+            (2, 2) => VirtualSiteKind::FixedDistance2 {
+                atoms: [idx[0], idx[1]],
+                a: p(0)?,
+            },
+            (3, 1) => VirtualSiteKind::Linear3 {
+                atoms: [idx[0], idx[1], idx[2]],
+                a: p(0)?,
+                b: p(1)?,
+            },
+            (3, 2) => VirtualSiteKind::FixedDistance3 {
+                atoms: [idx[0], idx[1], idx[2]],
+                a: p(0)?,
+                b: p(1)?,
+            },
+            (3, 4) => VirtualSiteKind::OutOfPlane3 {
+                atoms: [idx[0], idx[1], idx[2]],
+                a: p(0)?,
+                b: p(1)?,
+                c: p(2)?,
+            },
+            (4, 2) => VirtualSiteKind::FixedDistance4 {
+                atoms: [idx[0], idx[1], idx[2], idx[3]],
+                a: p(0)?,
+                b: p(1)?,
+                c: p(2)?,
+            },
+            (0, 1..=3) => {
+                let raw: Vec<f64> = match self.funct {
+                    1 => vec![1.0; idx.len()],
+                    2 => idx.iter().map(|&i| masses[i]).collect(),
+                    _ => self.parameters.clone(),
+                };
+                let total: f64 = raw.iter().sum();
+                if total <= 0.0 {
+                    return Err(format!("virtual site {} has no weight", self.site));
+                }
+                VirtualSiteKind::Weighted {
+                    atoms: idx,
+                    weights: raw.iter().map(|w| w / total).collect(),
+                }
+            }
+            (section, funct) => {
+                return Err(format!(
+                    "unsupported virtual site construction: section {section}, funct {funct}"
+                ))
+            }
+        };
+        Ok(VirtualSite {
+            site: self.site - 1,
+            kind,
+        })
+    }
+}
+
+fn parse_virtual_site(tokens: &[&str], section_size: usize) -> Result<MartiniVirtualSite, String> {
+    /*
+    [ virtual_sitesN ]: site, N constructing atoms, funct, parameters
+    [ virtual_sitesn ]: site, funct, constructing atoms (atom/weight pairs for funct 3)
+     */
+    let site = parse_usize(tokens, 0, "virtual site")?;
+    if section_size == 0 {
+        let funct = parse_usize(tokens, 1, "virtual site function type")?;
+        let rest = &tokens[2..];
+        let (atoms, parameters) = if funct == 3 {
+            let atoms = (0..rest.len() / 2)
+                .map(|n| parse_usize(rest, 2 * n, "virtual site atom"))
+                .collect::<Result<Vec<_>, String>>()?;
+            let weights = (0..rest.len() / 2)
+                .map(|n| parse_f64(rest, 2 * n + 1, "virtual site weight"))
+                .collect::<Result<Vec<_>, String>>()?;
+            (atoms, weights)
+        } else {
+            let atoms = (0..rest.len())
+                .map(|n| parse_usize(rest, n, "virtual site atom"))
+                .collect::<Result<Vec<_>, String>>()?;
+            (atoms, Vec::new())
+        };
+        if atoms.is_empty() {
+            return Err("virtual_sitesn row has no constructing atoms".to_string());
+        }
+        return Ok(MartiniVirtualSite {
+            site,
+            atoms,
+            section: 0,
+            funct,
+            parameters,
+        });
+    }
+
+    let atoms = (1..=section_size)
+        .map(|n| parse_usize(tokens, n, "virtual site atom"))
+        .collect::<Result<Vec<_>, String>>()?;
+    let funct = parse_usize(tokens, section_size + 1, "virtual site function type")?;
+    let parameters = tokens[section_size + 2..]
+        .iter()
+        .map(|t| {
+            t.parse::<f64>()
+                .map_err(|e| format!("failed to parse virtual site parameter: {e}"))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(MartiniVirtualSite {
+        site,
+        atoms,
+        section: section_size,
+        funct,
+        parameters,
+    })
+}
+
+pub(crate) fn parse_pair(tokens: &[&str], comb_rule: Option<usize>) -> Result<MartiniPair, String> {
+    /*
+    One [ pairs ] row, `ai aj 1 [V W]`; V and W are read as in [ atomtypes ]
+     */
+    if tokens.len() < 3 {
+        return Err("pairs row requires at least 3 columns".to_string());
+    }
+    if parse_usize(tokens, 2, "pair function type")? != 1 {
+        return Err("only pair function type 1 is supported".to_string());
+    }
+    let lj = if tokens.len() >= 5 {
+        let (v, w) = (
+            parse_f64(tokens, 3, "pair V")?,
+            parse_f64(tokens, 4, "pair W")?,
+        );
+        let rule = match comb_rule {
+            Some(rule) => rule,
+            None if looks_like_sigma_epsilon(v, w) => 2,
+            None => 1,
+        };
+        Some(sigma_epsilon(rule, v, w))
+    } else {
+        None
+    };
+    Ok(MartiniPair {
+        atom1: parse_usize(tokens, 0, "pair atom")?,
+        atom2: parse_usize(tokens, 1, "pair atom")?,
+        lj,
+    })
+}
+
+fn parse_settle(tokens: &[&str]) -> Result<MartiniSettle, String> {
+    if tokens.len() < 4 {
+        return Err("settles row requires at least 4 columns".to_string());
+    }
+
+    Ok(MartiniSettle {
+        atom: parse_usize(tokens, 0, "settle oxygen")?,
+        d_oh: parse_f64(tokens, 2, "settle O-H distance")?,
+        d_hh: parse_f64(tokens, 3, "settle H-H distance")?,
     })
 }
 
@@ -346,7 +917,7 @@ fn infer_lj_parameters(atom_type: &MartiniAtomType) -> Result<(f64, f64), String
     ))
 }
 
-fn parse_f64(tokens: &[&str], index: usize, label: &str) -> Result<f64, String> {
+pub(crate) fn parse_f64(tokens: &[&str], index: usize, label: &str) -> Result<f64, String> {
     tokens
         .get(index)
         .ok_or_else(|| format!("missing {label}"))?
@@ -354,7 +925,7 @@ fn parse_f64(tokens: &[&str], index: usize, label: &str) -> Result<f64, String>
         .map_err(|e| format!("failed to parse {label}: {e}"))
 }
 
-fn parse_usize(tokens: &[&str], index: usize, label: &str) -> Result<usize, String> {
+pub(crate) fn parse_usize(tokens: &[&str], index: usize, label: &str) -> Result<usize, String> {
     tokens
         .get(index)
         .ok_or_else(|| format!("missing {label}"))?
@@ -365,6 +936,7 @@ fn parse_usize(tokens: &[&str], index: usize, label: &str) -> Result<usize, Stri
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::molecule::molecule::extended_bonded_energy;
 
     #[test]
     fn parses_martini_itp_and_builds_system() {
@@ -421,4 +993,256 @@ C1   72.0 0.0 A 2.0 1.0
         assert!(sigma > 0.0);
         assert!(epsilon > 0.0);
     }
+
+    #[test]
+    fn bond_functs_select_polymer_forms() {
+        let itp = r#"
+[ atomtypes ]
+C1   72.0 0.0 A 0.47 3.5
+[ atoms ]
+1 C1 1 TST A 1 0.0
+2 C1 1 TST B 1 0.0
+3 C1 1 TST C 1 0.0
+4 C1 1 TST D 1 0.0
+5 C1 1 TST E 1 0.0
+[ bonds ]
+1 2 2 0.47 1250
+2 3 3 0.47 400.0 20.0
+3 4 7 1.5 30
+4 5 8 1 2.0
+"#;
+        let mut ff = MartiniForceField::parse_str(itp).expect("martini parsing should succeed");
+        let coords: Vec<Vector3<f64>> = (0..5)
+            .map(|i| Vector3::new(0.45 * i as f64, 0.0, 0.0))
+            .collect();
+        let missing = ff.to_system(&coords).unwrap_err();
+        assert!(missing.contains("bond table 1"), "{missing}");
+
+        let table = BondTable::new(vec![0.0, 1.0], vec![0.0, 1.0], vec![0.0, -2.0])
+            .expect("table should be valid");
+        ff.bond_tables.insert(1, Arc::new(table));
+        let system = ff.to_system(&coords).expect("system build should succeed");
+
+        let bonds = &system.bonds;
+        assert!(matches!(bonds[0].form, BondForm::Quartic));
+        assert!(matches!(
+            bonds[1].form,
+            BondForm::Morse { depth, beta } if depth == 400.0 && beta == 20.0
+        ));
+        // the harmonic limit of the Morse well
+        assert!((bonds[1].k - 2.0 * 400.0 * 20.0 * 20.0).abs() < 1e-9);
+        assert!(matches!(bonds[2].form, BondForm::Fene { r_max, .. } if r_max == 1.5));
+        assert!(matches!(bonds[3].form, BondForm::Tabulated { .. }));
+        // a tabulated V(r) = r^2 with k = 2
+        assert!((bonds[3].energy_and_derivative(0.45).0 - 2.0 * 0.45 * 0.45).abs() < 1e-12);
+        assert!(system.is_excluded(3, 4));
+    }
+
+    #[test]
+    fn funct_6_and_9_bonds_do_not_exclude() {
+        let itp = r#"
+[ atomtypes ]
+C1   72.0 0.0 A 0.47 3.5
+[ atoms ]
+1 C1 1 TST A 1 0.0
+2 C1 1 TST B 1 0.0
+3 C1 1 TST C 1 0.0
+4 C1 1 TST D 1 0.0
+[ bonds ]
+1 2 1 0.47 1250
+2 3 6 0.47 1250
+3 4 9 1 2.0
+"#;
+        let mut ff = MartiniForceField::parse_str(itp).expect("martini parsing should succeed");
+        let table = BondTable::new(vec![0.0, 1.0], vec![0.0, 1.0], vec![0.0, -2.0])
+            .expect("table should be valid");
+        ff.bond_tables.insert(1, Arc::new(table));
+        let coords: Vec<Vector3<f64>> = (0..4)
+            .map(|i| Vector3::new(0.5 * i as f64, 0.0, 0.0))
+            .collect();
+        let system = ff.to_system(&coords).expect("system build should succeed");
+
+        assert_eq!(system.bonds.len(), 1);
+        let springs = &system.extended.bonds_without_exclusions;
+        assert_eq!(springs.len(), 2);
+        assert!(matches!(springs[1].form, BondForm::Tabulated { .. }));
+        assert!(system.is_excluded(0, 1));
+        assert!(!system.is_excluded(1, 2));
+        assert!(!system.is_excluded(2, 3));
+        // the springs still act: 0.5 k (r - r0)^2 and the tabulated k V(r)
+        let expected =
+            0.5 * 1250.0 * (0.5f64 - 0.47).powi(2) + springs[1].energy_and_derivative(0.5).0;
+        let energy = extended_bonded_energy(&system.atoms, &system.extended, 100.0);
+        assert!((energy - expected).abs() < 1e-10);
+    }
+
+    #[test]
+    fn funct_column_selects_bonded_form() {
+        let itp = r#"
+[ atomtypes ]
+C1   72.0 0.0 A 0.47 3.5
+[ atoms ]
+1 C1 1 TST A 1 0.0
+2 C1 1 TST B 1 0.0
+3 C1 1 TST C 1 0.0
+4 C1 1 TST D 1 0.0
+[ bonds ]
+1 2 1 0.47 1250
+[ angles ]
+1 2 3 1  120.0 25.0
+1 2 3 2  130.0 25.0
+2 3 4 10 120.0 35.0
+1 2 3 5  110.0 30.0 0.25 5000.0
+[ dihedrals ]
+1 2 3 4 1 180.0 5.0 1
+1 2 3 4 2 0.0 40.0
+1 2 3 4 3 9.28 12.16 -13.12 -3.06 26.24 -31.5
+1 2 3 4 5 3.0 -1.0 2.0 0.0
+"#;
+        let ff = MartiniForceField::parse_str(itp).expect("martini parsing should succeed");
+        let coords = vec![
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.4, 0.1, 0.0),
+            Vector3::new(0.6, 0.5, 0.1),
+            Vector3::new(1.0, 0.6, 0.4),
+        ];
+        let system = ff.to_system(&coords).expect("system build should succeed");
+
+        assert_eq!(system.angles.len(), 2);
+        assert_eq!(system.extended.urey_bradleys.len(), 1);
+        assert_eq!(
+            (
+                system.extended.urey_bradleys[0].atom1,
+                system.extended.urey_bradleys[0].atom3
+            ),
+            (0, 2)
+        );
+        let forms: Vec<CosineAngleForm> = system
+            .extended
+            .cosine_angles
+            .iter()
+            .map(|a| a.form)
+            .collect();
+        assert_eq!(forms, [CosineAngleForm::G96, CosineAngleForm::Restricted]);
+        assert_eq!(system.dihedrals.len(), 1);
+        assert_eq!(system.impropers.len(), 1);
+        assert_eq!(system.extended.rb_dihedrals.len(), 2);
+        assert!((system.extended.rb_dihedrals[0].c[5] + 31.5).abs() < 1e-12);
+
+        // tabulated angles (funct 8) are not supported
+        let bad = itp.replace("2 3 4 10", "2 3 4 8");
+        assert!(MartiniForceField::parse_str(&bad).is_err());
+
+        let second = format!("[ moleculetype ]\nTST 1\n{itp}\n[ moleculetype ]\nOTHER 1\n");
+        let error = MartiniForceField::parse_str(&second).unwrap_err();
+        assert!(error.contains("GromacsTopology"), "{error}");
+    }
+
+    #[test]
+    fn cmaptypes_are_assigned_to_backbones() {
+        let itp = r#"
+[ cmaptypes ]
+C NH1 CT1 C NH1 1 3 3\
+1.0 2.0 3.0\
+4.0 5.0 6.0\
+7.0 8.0 9.0
+
+[ atomtypes ]
+C   12.0 0.0 A 0.35 0.3
+NH1 14.0 0.0 A 0.32 0.7
+CT1 12.0 0.0 A 0.38 0.1
+[ atoms ]
+1 C   1 ALA C   1 0.0
+2 NH1 2 ALA N   2 0.0
+3 CT1 2 ALA CA  2 0.0
+4 C   2 ALA C   2 0.0
+5 NH1 3 ALA N   3 0.0
+[ bonds ]
+1 2 1 0.133 300000
+2 3 1 0.145 300000
+3 4 1 0.152 300000
+4 5 1 0.133 300000
+"#;
+        let ff = MartiniForceField::parse_str(itp).expect("martini parsing should succeed");
+        assert_eq!(ff.cmap_types.len(), 1);
+        assert_eq!(ff.cmap_types[0].grid.n, 3);
+
+        let coords: Vec<Vector3<f64>> = (0..5)
+            .map(|i| Vector3::new(0.14 * i as f64, 0.05 * (i % 2) as f64, 0.02 * i as f64))
+            .collect();
+        let system = ff.to_system(&coords).expect("system build should succeed");
+        assert_eq!(system.extended.cmaps.len(), 1);
+        assert_eq!(system.extended.cmaps[0].atoms, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn reads_virtual_sites_from_bundled_tip4p_and_tip5p() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/ff/Charmm27.ff/charmm27.ff/");
+
+        let tip4p = fs::read_to_string(format!("{dir}tip4p.itp")).expect("tip4p.itp");
+        let mut ff = MartiniForceField::parse_str(&tip4p).expect("tip4p parsing should succeed");
+        assert_eq!(ff.virtual_sites.len(), 1);
+        assert_eq!(ff.exclusions.len(), 4);
+
+        for (name, mass) in [("OWT4", 15.9994), ("HWT4", 1.008), ("MWT4", 0.0)] {
+            ff.atom_types.insert(
+                name.to_string(),
+                MartiniAtomType {
+                    name: name.to_string(),
+                    mass,
+                    sigma: Some(0.3),
+                    epsilon: Some(0.0),
+                    ..Default::default()
+                },
+            );
+        }
+        let coords = vec![
+            Vector3::new(1.0, 1.0, 1.0),
+            Vector3::new(1.09572, 1.0, 1.0),
+            Vector3::new(0.976, 1.0927, 1.0),
+            Vector3::zeros(),
+        ];
+        let system = ff.to_system(&coords).expect("tip4p system should build");
+        assert_eq!(system.virtual_sites[0].site, 3);
+        assert_eq!(system.virtual_sites[0].constructing_atoms(), vec![0, 1, 2]);
+        // the M site is excluded from every atom of its water
+        for i in 0..3 {
+            assert!(system.is_excluded(i, 3));
+        }
+
+        let tip5p = fs::read_to_string(format!("{dir}tip5p.itp")).expect("tip5p.itp");
+        let ff = MartiniForceField::parse_str(&tip5p).expect("tip5p parsing should succeed");
+        let lone_pair = ff.virtual_sites[1]
+            .to_virtual_site(&[16.0, 1.0, 1.0, 0.0, 0.0])
+            .expect("3out site should convert");
+        assert!(matches!(
+            lone_pair.kind,
+            VirtualSiteKind::OutOfPlane3 { c, .. } if (c - 6.4437903493).abs() < 1e-12
+        ));
+    }
+
+    #[test]
+    fn reads_settles_from_bundled_tip3p() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/ff/Charmm27.ff/charmm27.ff/tip3p.itp"
+        );
+        let contents = fs::read_to_string(path).expect("bundled tip3p.itp should exist");
+
+        let rigid = MartiniForceField::parse_str_with_defines(&contents, &["_FF_CHARMM"])
+            .expect("tip3p parsing should succeed");
+        assert_eq!(rigid.atoms.len(), 3);
+        assert!(rigid.bonds.is_empty());
+        assert_eq!(rigid.settles.len(), 1);
+        assert_eq!(rigid.settles[0].atom, 1);
+        assert!((rigid.settles[0].d_oh - 0.09572).abs() < 1e-12);
+        assert!((rigid.settles[0].d_hh - 0.15139).abs() < 1e-12);
+
+        let flexible =
+            MartiniForceField::parse_str_with_defines(&contents, &["_FF_CHARMM", "FLEXIBLE"])
+                .expect("flexible tip3p parsing should succeed");
+        assert!(flexible.settles.is_empty());
+        assert_eq!(flexible.bonds.len(), 2);
+        assert_eq!(flexible.angles.len(), 1);
+    }
 }
@@ -1,4 +1,11 @@
+pub mod bond_table;
 pub mod charmm;
+pub mod cmap;
+pub mod drude;
 pub mod io;
 pub mod martini;
 pub mod molecule;
+pub mod pdb2gmx;
+pub mod restraint;
+pub mod topology;
+pub mod virtual_site;
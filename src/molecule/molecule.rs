@@ -10,8 +10,17 @@ use crate::lennard_jones_simulations::minimum_image_convention;
 use crate::lennard_jones_simulations::InitOutput;
 use crate::lennard_jones_simulations::LJParameters;
 use crate::lennard_jones_simulations::Particle;
-
-use nalgebra::Vector3;
+use crate::molecule::bond_table::BondTable;
+use crate::molecule::cmap::{cmap_term, Cmap};
+use crate::molecule::drude::{inherit_drude_exclusions, DrudePair};
+use crate::molecule::restraint::PositionRestraint;
+use crate::molecule::virtual_site::VirtualSite;
+use crate::parallel::threads::{chunk_bounds, parallel_force_reduce};
+
+use log::warn;
+use nalgebra::{Matrix3, Vector3};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
 #[derive(Copy, Clone)]
 pub struct SimpleBond {
@@ -39,7 +48,101 @@ pub struct Bond {
     pub atom1: usize,
     pub atom2: usize,
     pub k: f64,
-    pub r0: f64,
+    pub r0: f64, // equilibrium length (minimum of the full bond energy), also the constraint length
+    pub form: BondForm,
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum BondForm {
+    // E = 1/2 k (r - r0)^2
+    #[default]
+    Harmonic,
+    // E = -1/2 k r_max^2 ln(1 - (r / r_max)^2), plus a WCA repulsion of depth epsilon
+    // cut at 2^(1/6) sigma (Kremer-Grest); epsilon = 0 leaves the bare FENE spring
+    Fene {
+        r_max: f64,
+        epsilon: f64,
+        sigma: f64,
+    },
+    // E = D (1 - exp(-beta (r - r0)))^2, the curvature at r0 is k = 2 D beta^2
+    Morse {
+        depth: f64,
+        beta: f64,
+    },
+    // GROMOS-96: E = 1/4 k (r^2 - r0^2)^2
+    Quartic,
+    // E = k V(r), V and -dV/dr read from a GROMACS table_b<n>.xvg
+    Tabulated {
+        table: Arc<BondTable>,
+    },
+}
+
+// largest (r / r_max)^2 at which the FENE logarithm is evaluated
+const FENE_MAX_STRETCH: f64 = 0.99;
+
+impl Bond {
+    pub fn energy_and_derivative(&self, r: f64) -> (f64, f64) {
+        /*
+        Bond energy and dE/dr at length r
+         */
+        match &self.form {
+            BondForm::Harmonic => {
+                let dr = r - self.r0;
+                (0.5 * self.k * dr * dr, self.k * dr)
+            }
+            BondForm::Fene {
+                r_max,
+                epsilon,
+                sigma,
+            } => {
+                // the logarithm diverges at r_max; past FENE_MAX_STRETCH the energy goes on
+                // along its tangent, so a bond stretched that far keeps a very stiff
+                // restoring force and an energy that still agrees with it
+                let r_cap = r_max * FENE_MAX_STRETCH.sqrt();
+                let (mut energy, mut derivative) = if r < r_cap {
+                    let x2 = (r / r_max).powi(2);
+                    (
+                        -0.5 * self.k * r_max * r_max * (1.0 - x2).ln(),
+                        self.k * r / (1.0 - x2),
+                    )
+                } else {
+                    if r >= *r_max {
+                        warn!(
+                            "FENE bond {}-{} stretched to {r} past r_max = {r_max}",
+                            self.atom1, self.atom2
+                        );
+                    }
+                    let slope = self.k * r_cap / (1.0 - FENE_MAX_STRETCH);
+                    (
+                        -0.5 * self.k * r_max * r_max * (1.0 - FENE_MAX_STRETCH).ln()
+                            + slope * (r - r_cap),
+                        slope,
+                    )
+                };
+                if *epsilon > 0.0 && r < 2f64.powf(1.0 / 6.0) * sigma {
+                    let sr6 = (sigma / r).powi(6);
+                    energy += 4.0 * epsilon * (sr6 * sr6 - sr6) + epsilon;
+                    derivative -= 24.0 * epsilon * (2.0 * sr6 * sr6 - sr6) / r;
+                }
+                (energy, derivative)
+            }
+            BondForm::Morse { depth, beta } => {
+                let e = (-beta * (r - self.r0)).exp();
+                (
+                    depth * (1.0 - e).powi(2),
+                    2.0 * depth * beta * e * (1.0 - e),
+                )
+            }
+            BondForm::Quartic => {
+                let d = r * r - self.r0 * self.r0;
+                (0.25 * self.k * d * d, self.k * d * r)
+            }
+            BondForm::Tabulated { table } => {
+                let (v, dv) = table.evaluate(r);
+                (self.k * v, self.k * dv)
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -72,6 +175,125 @@ pub struct Improper {
     pub psi0: f64,
 }
 
+#[derive(Clone, Debug)]
+pub struct UreyBradley {
+    // CHARMM 1-3 spring across an angle, E = k/2 (r13 - r0)^2 (GROMACS angle funct 5)
+    pub atom1: usize,
+    pub atom3: usize,
+    pub k: f64,
+    pub r0: f64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CosineAngleForm {
+    // GROMOS-96, E = k/2 (cos(theta) - cos(theta0))^2 (funct 2)
+    G96,
+    // restricted bending, E = k/2 (cos(theta) - cos(theta0))^2 / sin^2(theta) (funct 10)
+    Restricted,
+}
+
+#[derive(Clone, Debug)]
+pub struct CosineAngle {
+    pub atom1: usize,
+    pub atom2: usize,
+    pub atom3: usize,
+    pub k: f64,
+    pub theta0: f64,
+    pub form: CosineAngleForm,
+}
+
+#[derive(Clone, Debug)]
+pub struct RbDihedral {
+    // Ryckaert-Bellemans torsion E = sum_n C_n cos^n(phi - 180 deg) (funct 3)
+    pub atom1: usize,
+    pub atom2: usize,
+    pub atom3: usize,
+    pub atom4: usize,
+    pub c: [f64; 6],
+}
+
+impl RbDihedral {
+    pub fn from_fourier(atoms: [usize; 4], f: [f64; 4]) -> Self {
+        /*
+        OPLS Fourier torsion (funct 5)
+
+            E = 1/2 [F1 (1 + cos(phi)) + F2 (1 - cos(2 phi)) + F3 (1 + cos(3 phi))
+                     + F4 (1 - cos(4 phi))]
+
+        rewritten as the equivalent Ryckaert-Bellemans series, as GROMACS does
+         */
+        let [f1, f2, f3, f4] = f;
+        RbDihedral {
+            atom1: atoms[0],
+            atom2: atoms[1],
+            atom3: atoms[2],
+            atom4: atoms[3],
+            c: [
+                f2 + 0.5 * (f1 + f3),
+                0.5 * (3.0 * f3 - f1),
+                4.0 * f4 - f2,
+                -2.0 * f3,
+                -4.0 * f4,
+                0.0,
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExtendedBonded {
+    // bonded forms beyond the harmonic bond/angle, cosine dihedral and harmonic
+    // improper, needed by CHARMM (Urey-Bradley, CMAP), OPLS (RB/Fourier) and Martini
+    // (G96 and restricted bending angles)
+    pub urey_bradleys: Vec<UreyBradley>,
+    pub cosine_angles: Vec<CosineAngle>,
+    pub rb_dihedrals: Vec<RbDihedral>,
+    pub cmaps: Vec<Cmap>,
+    // GROMACS bond types 6 and 9: bonded forces that do not enter the exclusion
+    // graph, so the pair keeps its nonbonded interaction
+    pub bonds_without_exclusions: Vec<Bond>,
+}
+
+impl ExtendedBonded {
+    pub fn len(&self) -> usize {
+        self.urey_bradleys.len()
+            + self.cosine_angles.len()
+            + self.rb_dihedrals.len()
+            + self.cmaps.len()
+            + self.bonds_without_exclusions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Pair14 {
+    // 1-4 pair that is excluded from the normal nonbonded loop but interacts
+    // through its own (scaled or force-field specific) LJ and Coulomb terms
+    pub atom1: usize,
+    pub atom2: usize,
+    pub sigma: f64,
+    pub epsilon: f64,
+    pub coulomb_scale: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Settle {
+    // rigid 3-site water (GROMACS [ settles ]): the oxygen is followed by its two
+    // hydrogens, held at the O-H and H-H distances by SETTLE
+    pub oxygen: usize,
+    pub d_oh: f64,
+    pub d_hh: f64,
+}
+
+impl Settle {
+    pub fn atoms(&self) -> [usize; 3] {
+        [self.oxygen, self.oxygen + 1, self.oxygen + 2]
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct NonBondedType {
     pub mass: f64,
@@ -94,10 +316,100 @@ pub fn bond_distance(a1: &Atom, a2: &Atom) -> f64 {
 #[derive(Default, Clone)]
 pub struct MoleculeTemplate {
     pub name: String,
-    pub atom_types: Vec<String>,      // len
-    pub positions: Vec<Vector3<f64>>, // x y z for each atom
-    pub bonds: Vec<(usize, usize, f64, f64)>,
-    pub exclusion_1_4_scale: Option<f64>, // (i, j, k, k_theta, theta_0)
+    pub atom_types: Vec<String>,              // len
+    pub positions: Vec<Vector3<f64>>,         // x y z for each atom
+    pub bonds: Vec<(usize, usize, f64, f64)>, // (i, j, k, r0), harmonic
+    pub exclusion_1_4_scale: Option<f64>,     // LJ and Coulomb scale of the 1-4 pairs
+}
+
+impl MoleculeTemplate {
+    pub fn to_system(
+        &self,
+        types: &HashMap<String, NonBondedType>,
+        nrexcl: usize,
+    ) -> Result<System, String> {
+        /*
+        One molecule of this template, with the nonbonded parameters of each atom type
+        looked up in `types` and the exclusions of apply_exclusions
+         */
+        if self.positions.len() != self.atom_types.len() {
+            return Err(format!(
+                "template '{}' has {} positions for {} atoms",
+                self.name,
+                self.positions.len(),
+                self.atom_types.len()
+            ));
+        }
+        let atoms = self
+            .atom_types
+            .iter()
+            .zip(&self.positions)
+            .enumerate()
+            .map(|(i, (type_name, position))| {
+                let t = types
+                    .get(type_name)
+                    .ok_or_else(|| format!("missing nonbonded type '{type_name}'"))?;
+                Ok(Particle {
+                    id: i,
+                    position: *position,
+                    velocity: Vector3::zeros(),
+                    force: Vector3::zeros(),
+                    lj_parameters: LJParameters {
+                        epsilon: t.epsilon,
+                        sigma: t.sigma,
+                        number_of_atoms: 1,
+                    },
+                    mass: t.mass,
+                    energy: 0.0,
+                    atom_type: i as f64,
+                    charge: t.charge,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let bonds = self
+            .bonds
+            .iter()
+            .map(|&(atom1, atom2, k, r0)| {
+                if atom1 >= atoms.len() || atom2 >= atoms.len() {
+                    return Err(format!("template bond {atom1}-{atom2} is out of range"));
+                }
+                Ok(Bond {
+                    atom1,
+                    atom2,
+                    k,
+                    r0,
+                    form: BondForm::Harmonic,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let mut system = System {
+            atoms,
+            bonds,
+            elements: self
+                .atom_types
+                .iter()
+                .map(|t| element_from_atom_name(t))
+                .collect(),
+            ..Default::default()
+        };
+        self.apply_exclusions(&mut system, nrexcl);
+        Ok(system)
+    }
+
+    pub fn apply_exclusions(&self, system: &mut System, nrexcl: usize) {
+        /*
+        Build the exclusions of a system made from this template. Without a 1-4 scale the
+        1-4 pairs are excluded outright, otherwise both LJ and Coulomb are scaled by it.
+         */
+        match self.exclusion_1_4_scale {
+            Some(scale) => system.generate_exclusions(nrexcl, scale, scale),
+            None => {
+                system.generate_exclusions(nrexcl, 1.0, 1.0);
+                system.pairs.clear();
+            }
+        }
+    }
 }
 
 #[derive(Clone, Default, Debug)]
@@ -107,26 +419,199 @@ pub struct System {
     pub angles: Vec<Angle>,
     pub dihedrals: Vec<Dihedral>,
     pub impropers: Vec<Improper>,
+    pub exclusions: HashSet<(usize, usize)>,
+    pub pairs: Vec<Pair14>,
+    pub drudes: Vec<DrudePair>,
+    pub settles: Vec<Settle>,
+    pub extended: ExtendedBonded,
+    pub virtual_sites: Vec<VirtualSite>,
+    // only applied in run phases that enable them (MdOptions::position_restraints)
+    pub position_restraints: Vec<PositionRestraint>,
+    // atomic number of each atom, 0 where unknown (beads, Drudes, virtual sites)
+    pub elements: Vec<u8>,
 }
 
 // System is all the atoms (global), bonded terms in global indices, and exclusion sets
 
-pub fn compute_bond_force(atoms: &mut Vec<Particle>, bond: &Bond, box_length: f64) -> f64 {
+pub fn atomic_number(symbol: &str) -> u8 {
     /*
-    Compute the bond energy,
+    Atomic number of an element symbol (any case), 0 if it is not one of H to Kr or I
+     */
+    const SYMBOLS: [&str; 36] = [
+        "H", "He", "Li", "Be", "B", "C", "N", "O", "F", "Ne", "Na", "Mg", "Al", "Si", "P", "S",
+        "Cl", "Ar", "K", "Ca", "Sc", "Ti", "V", "Cr", "Mn", "Fe", "Co", "Ni", "Cu", "Zn", "Ga",
+        "Ge", "As", "Se", "Br", "Kr",
+    ];
+    if symbol.eq_ignore_ascii_case("I") {
+        return 53;
+    }
+    SYMBOLS
+        .iter()
+        .position(|s| s.eq_ignore_ascii_case(symbol))
+        .map_or(0, |z| z as u8 + 1)
+}
+
+pub fn element_from_atom_name(name: &str) -> u8 {
+    /*
+    Fallback when a topology gives no element: like grompp, an atom whose name starts
+    with H (after leading digits, as in 1HB) is a hydrogen. Other names stay unknown.
+     */
+    match name
+        .trim_start_matches(|c: char| c.is_ascii_digit())
+        .chars()
+        .next()
+    {
+        Some('H' | 'h') => 1,
+        _ => 0,
+    }
+}
+
+#[inline]
+pub fn pair_key(i: usize, j: usize) -> (usize, usize) {
+    if i < j {
+        (i, j)
+    } else {
+        (j, i)
+    }
+}
+
+// (excluded pairs, 1-4 pairs), both keyed as (i, j) with i < j
+pub type ExclusionLists = (HashSet<(usize, usize)>, Vec<(usize, usize)>);
+
+pub fn exclusions_from_bonds(n_atoms: usize, bonds: &[Bond], nrexcl: usize) -> ExclusionLists {
+    /*
+    Walk the bond graph from every atom (breadth first) and exclude every pair that is
+    separated by at most `nrexcl` bonds, as GROMACS does with the nrexcl column.
+
+    Pairs exactly three bonds apart are also returned separately as the 1-4 pairs. They
+    are only reported when nrexcl >= 3, i.e. when they are actually excluded.
+     */
+    let mut neighbours: Vec<Vec<usize>> = vec![Vec::new(); n_atoms];
+    for b in bonds {
+        if b.atom1 < n_atoms && b.atom2 < n_atoms && b.atom1 != b.atom2 {
+            neighbours[b.atom1].push(b.atom2);
+            neighbours[b.atom2].push(b.atom1);
+        }
+    }
+
+    let mut excluded = HashSet::new();
+    let mut pairs_14 = HashSet::new();
+    let mut depth = vec![usize::MAX; n_atoms];
+
+    for start in 0..n_atoms {
+        depth.fill(usize::MAX);
+        depth[start] = 0;
+        let mut queue = VecDeque::from([start]);
+
+        while let Some(i) = queue.pop_front() {
+            if depth[i] >= nrexcl.max(3) {
+                continue;
+            }
+            for &j in &neighbours[i] {
+                if depth[j] != usize::MAX {
+                    continue;
+                }
+                depth[j] = depth[i] + 1;
+                queue.push_back(j);
+
+                if j > start {
+                    if depth[j] <= nrexcl {
+                        excluded.insert((start, j));
+                    }
+                    if depth[j] == 3 && nrexcl >= 3 {
+                        pairs_14.insert((start, j));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut pairs_14: Vec<(usize, usize)> = pairs_14.into_iter().collect();
+    pairs_14.sort_unstable();
+    (excluded, pairs_14)
+}
+
+impl System {
+    pub fn is_hydrogen(&self, i: usize) -> bool {
+        self.elements.get(i) == Some(&1)
+    }
+
+    pub fn generate_exclusions(&mut self, nrexcl: usize, lj_14_scale: f64, coulomb_14_scale: f64) {
+        /*
+        Generate the exclusion list from the bond graph and the 1-4 pair list.
+        1-4 pairs use Lorentz-Berthelot mixed parameters with epsilon scaled by
+        `lj_14_scale`; force fields with dedicated 1-4 parameters (CHARMM) overwrite
+        sigma/epsilon of the pairs afterwards. Drude particles inherit the exclusions
+        and 1-4 pairs of their cores. The atoms of a rigid (settled) water exclude each
+        other, as they have no bonds to build the graph from. Virtual sites are linked to
+        their constructing atoms in the graph, as grompp does.
+         */
+        let mut graph = self.bonds.clone();
+        for vsite in &self.virtual_sites {
+            graph.extend(vsite.constructing_atoms().into_iter().map(|atom| Bond {
+                atom1: vsite.site,
+                atom2: atom,
+                k: 0.0,
+                r0: 0.0,
+                form: BondForm::Harmonic,
+            }));
+        }
+        let (mut excluded, pairs_14) = exclusions_from_bonds(self.atoms.len(), &graph, nrexcl);
+        for settle in &self.settles {
+            let [o, h1, h2] = settle.atoms();
+            excluded.extend([pair_key(o, h1), pair_key(o, h2), pair_key(h1, h2)]);
+        }
+
+        self.exclusions = excluded;
+        self.pairs = pairs_14
+            .into_iter()
+            .map(|(i, j)| {
+                let (a, b) = (&self.atoms[i].lj_parameters, &self.atoms[j].lj_parameters);
+                Pair14 {
+                    atom1: i,
+                    atom2: j,
+                    sigma: 0.5 * (a.sigma + b.sigma),
+                    epsilon: lj_14_scale * (a.epsilon * b.epsilon).sqrt(),
+                    coulomb_scale: coulomb_14_scale,
+                }
+            })
+            .collect();
+        inherit_drude_exclusions(self);
+    }
+
+    #[inline]
+    pub fn is_excluded(&self, i: usize, j: usize) -> bool {
+        self.exclusions.contains(&pair_key(i, j))
+    }
+}
+
+fn bond_term(atoms: &[Particle], bond: &Bond, box_length: f64) -> (f64, [Vector3<f64>; 2]) {
+    /*
+    Bond energy and the forces on (atom1, atom2)
      */
     let (i, j) = (bond.atom1, bond.atom2); // get atoms#
     let r_vec = atoms[j].position - atoms[i].position; // get vector for position
     let rij_mic = minimum_image_convention(r_vec, box_length);
     let r = rij_mic.norm(); // get distance
-    let dr = r - bond.r0; // the difference between the current position and the equilibrium position
-    let f_mag = -bond.k * dr; // force magnitude
-    let f_vec = (r_vec / r) * f_mag;
+    let (energy, de_dr) = bond.energy_and_derivative(r);
+    if r <= 1e-12 {
+        return (energy, [Vector3::zeros(); 2]);
+    }
+    // -dE/dr_i = dE/dr (r_j - r_i) / r: a stretched bond pulls atom i towards atom j
+    let f_vec = (rij_mic / r) * de_dr;
+
+    (energy, [f_vec, -f_vec]) // bond energy and forces
+}
 
-    atoms[i].force += f_vec;
-    atoms[j].force -= f_vec;
+pub fn compute_bond_force(atoms: &mut [Particle], bond: &Bond, box_length: f64) -> f64 {
+    /*
+    Compute the bond energy,
+     */
+    let (energy, f) = bond_term(atoms, bond, box_length);
+    atoms[bond.atom1].force += f[0];
+    atoms[bond.atom2].force += f[1];
 
-    0.5 * bond.k * dr * dr // return the bond energy
+    energy // return the bond energy
 }
 
 pub fn compute_electostatic_bond_short_force(atoms: &mut Vec<Particle>, _box_length: f64) -> f64 {
@@ -151,39 +636,24 @@ pub fn compute_electostatic_bond_short_force(atoms: &mut Vec<Particle>, _box_len
     total_short_range_potential
 }
 
-fn angle_value(atoms: &[Particle], angle: &Angle, box_length: f64) -> f64 {
-    let r21 = minimum_image_convention(
-        atoms[angle.atom1].position - atoms[angle.atom2].position,
-        box_length,
-    );
-    let r23 = minimum_image_convention(
-        atoms[angle.atom3].position - atoms[angle.atom2].position,
-        box_length,
-    );
+fn angle_from_positions(r: &[Vector3<f64>; 3], theta0: f64, box_length: f64) -> f64 {
+    let r21 = minimum_image_convention(r[0] - r[1], box_length);
+    let r23 = minimum_image_convention(r[2] - r[1], box_length);
 
     let n1 = r21.norm();
     let n2 = r23.norm();
     if n1 <= 1e-12 || n2 <= 1e-12 {
-        return angle.theta0;
+        return theta0;
     }
 
     let cos_theta = (r21.dot(&r23) / (n1 * n2)).clamp(-1.0, 1.0);
     cos_theta.acos()
 }
 
-fn dihedral_value(atoms: &[Particle], dihedral: &Dihedral, box_length: f64) -> f64 {
-    let b1 = minimum_image_convention(
-        atoms[dihedral.atom2].position - atoms[dihedral.atom1].position,
-        box_length,
-    );
-    let b2 = minimum_image_convention(
-        atoms[dihedral.atom3].position - atoms[dihedral.atom2].position,
-        box_length,
-    );
-    let b3 = minimum_image_convention(
-        atoms[dihedral.atom4].position - atoms[dihedral.atom3].position,
-        box_length,
-    );
+fn dihedral_from_positions(r: &[Vector3<f64>; 4], box_length: f64) -> f64 {
+    let b1 = minimum_image_convention(r[1] - r[0], box_length);
+    let b2 = minimum_image_convention(r[2] - r[1], box_length);
+    let b3 = minimum_image_convention(r[3] - r[2], box_length);
 
     let n1 = b1.cross(&b2);
     let n2 = b2.cross(&b3);
@@ -200,143 +670,525 @@ fn dihedral_value(atoms: &[Particle], dihedral: &Dihedral, box_length: f64) -> f
     y.atan2(x)
 }
 
+fn angle_positions(atoms: &[Particle], angle: &Angle) -> [Vector3<f64>; 3] {
+    [
+        atoms[angle.atom1].position,
+        atoms[angle.atom2].position,
+        atoms[angle.atom3].position,
+    ]
+}
+
+fn dihedral_positions(atoms: &[Particle], a: [usize; 4]) -> [Vector3<f64>; 4] {
+    [
+        atoms[a[0]].position,
+        atoms[a[1]].position,
+        atoms[a[2]].position,
+        atoms[a[3]].position,
+    ]
+}
+
+fn angle_value(atoms: &[Particle], angle: &Angle, box_length: f64) -> f64 {
+    angle_from_positions(&angle_positions(atoms, angle), angle.theta0, box_length)
+}
+
+fn dihedral_value(atoms: &[Particle], dihedral: &Dihedral, box_length: f64) -> f64 {
+    let idx = [
+        dihedral.atom1,
+        dihedral.atom2,
+        dihedral.atom3,
+        dihedral.atom4,
+    ];
+    dihedral_from_positions(&dihedral_positions(atoms, idx), box_length)
+}
+
 fn improper_value(atoms: &[Particle], improper: &Improper, box_length: f64) -> f64 {
-    let as_dihedral = Dihedral {
-        atom1: improper.atom1,
-        atom2: improper.atom2,
-        atom3: improper.atom3,
-        atom4: improper.atom4,
-        k: improper.k,
-        multiplicity: 1,
-        phase: 0.0,
-    };
-    dihedral_value(atoms, &as_dihedral, box_length)
+    let idx = [
+        improper.atom1,
+        improper.atom2,
+        improper.atom3,
+        improper.atom4,
+    ];
+    dihedral_from_positions(&dihedral_positions(atoms, idx), box_length)
 }
 
-pub fn compute_angle_force(atoms: &mut [Particle], angle: &Angle, box_length: f64) -> f64 {
-    let theta = angle_value(atoms, angle, box_length);
-    let dtheta = theta - angle.theta0;
-    let energy = 0.5 * angle.k * dtheta * dtheta;
+fn angle_gradient(r: &[Vector3<f64>; 3], box_length: f64) -> Option<(f64, [Vector3<f64>; 3])> {
+    /*
+    Bending angle theta and d(theta)/dr for the three atoms, from
 
-    let atom_indices = [angle.atom1, angle.atom2, angle.atom3];
-    let h = 1e-6;
+        cos(theta) = r21 . r23 / (|r21| |r23|),  d(theta) = -d(cos(theta)) / sin(theta)
 
-    for &idx in &atom_indices {
-        for dim in 0..3 {
-            atoms[idx].position[dim] += h;
-            let e_plus =
-                0.5 * angle.k * (angle_value(atoms, angle, box_length) - angle.theta0).powi(2);
-            atoms[idx].position[dim] -= 2.0 * h;
-            let e_minus =
-                0.5 * angle.k * (angle_value(atoms, angle, box_length) - angle.theta0).powi(2);
-            atoms[idx].position[dim] += h;
+    None for degenerate geometries (zero length arm or a linear angle), where the
+    gradient is undefined.
+     */
+    let r21 = minimum_image_convention(r[0] - r[1], box_length);
+    let r23 = minimum_image_convention(r[2] - r[1], box_length);
+    let (n1, n2) = (r21.norm(), r23.norm());
+    if n1 <= 1e-12 || n2 <= 1e-12 {
+        return None;
+    }
+
+    let cos_theta = (r21.dot(&r23) / (n1 * n2)).clamp(-1.0, 1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    if sin_theta <= 1e-12 {
+        return None;
+    }
 
-            let d_e = (e_plus - e_minus) / (2.0 * h);
-            atoms[idx].force[dim] += -d_e;
+    let g1 = -(r23 / (n1 * n2) - cos_theta * r21 / (n1 * n1)) / sin_theta;
+    let g3 = -(r21 / (n1 * n2) - cos_theta * r23 / (n2 * n2)) / sin_theta;
+    Some((cos_theta.acos(), [g1, -(g1 + g3), g3]))
+}
+
+pub(crate) fn dihedral_gradient(
+    r: &[Vector3<f64>; 4],
+    box_length: f64,
+) -> Option<(f64, [Vector3<f64>; 4])> {
+    /*
+    Torsion angle phi (same convention as dihedral_from_positions) and d(phi)/dr for
+    the four atoms, after Blondel and Karplus (J. Comput. Chem. 17, 1132 (1996)). With
+    b1 = r2 - r1, b2 = r3 - r2, b3 = r4 - r3, n1 = b1 x b2 and n2 = b2 x b3
+
+        d(phi)/dr1 =  |b2| n1 / |n1|^2
+        d(phi)/dr4 = -|b2| n2 / |n2|^2
+
+    and the middle atoms take the remainder so that the gradient sums to zero. The
+    expressions have no 1/sin(phi) singularity. None when b2 is collinear with b1 or b3.
+     */
+    let b1 = minimum_image_convention(r[1] - r[0], box_length);
+    let b2 = minimum_image_convention(r[2] - r[1], box_length);
+    let b3 = minimum_image_convention(r[3] - r[2], box_length);
+
+    let n1 = b1.cross(&b2);
+    let n2 = b2.cross(&b3);
+    let (n1_sq, n2_sq) = (n1.norm_squared(), n2.norm_squared());
+    let b2_norm = b2.norm();
+    if n1_sq <= 1e-24 || n2_sq <= 1e-24 || b2_norm <= 1e-12 {
+        return None;
+    }
+
+    let m1 = n1.cross(&(b2 / b2_norm));
+    let phi = m1.dot(&n2).atan2(n1.dot(&n2));
+
+    let g1 = n1 * (b2_norm / n1_sq);
+    let g4 = -n2 * (b2_norm / n2_sq);
+    let s1 = b1.dot(&b2) / (b2_norm * b2_norm);
+    let s3 = b3.dot(&b2) / (b2_norm * b2_norm);
+    let g2 = -g1 - s1 * g1 + s3 * g4;
+    let g3 = -g4 + s1 * g1 - s3 * g4;
+    Some((phi, [g1, g2, g3, g4]))
+}
+
+fn angle_term(atoms: &[Particle], angle: &Angle, box_length: f64) -> (f64, [Vector3<f64>; 3]) {
+    /*
+    Harmonic angle E = k/2 (theta - theta0)^2 and its analytical forces
+     */
+    let r = angle_positions(atoms, angle);
+    match angle_gradient(&r, box_length) {
+        Some((theta, grad)) => {
+            let d_theta = theta - angle.theta0;
+            let de = angle.k * d_theta;
+            (0.5 * angle.k * d_theta * d_theta, grad.map(|g| -de * g))
+        }
+        None => {
+            let d_theta = angle_from_positions(&r, angle.theta0, box_length) - angle.theta0;
+            (0.5 * angle.k * d_theta * d_theta, [Vector3::zeros(); 3])
         }
     }
+}
 
-    energy
+fn torsion_term(
+    r: &[Vector3<f64>; 4],
+    box_length: f64,
+    energy_and_derivative: impl Fn(f64) -> (f64, f64),
+) -> (f64, [Vector3<f64>; 4]) {
+    // F_i = -dE/dphi * dphi/dr_i, shared by proper and improper dihedrals
+    match dihedral_gradient(r, box_length) {
+        Some((phi, grad)) => {
+            let (energy, de) = energy_and_derivative(phi);
+            (energy, grad.map(|g| -de * g))
+        }
+        None => (
+            energy_and_derivative(dihedral_from_positions(r, box_length)).0,
+            [Vector3::zeros(); 4],
+        ),
+    }
 }
 
-pub fn compute_dihedral_force(atoms: &mut [Particle], dihedral: &Dihedral, box_length: f64) -> f64 {
-    let phi = dihedral_value(atoms, dihedral, box_length);
+fn dihedral_term(
+    atoms: &[Particle],
+    dihedral: &Dihedral,
+    box_length: f64,
+) -> (f64, [Vector3<f64>; 4]) {
+    /*
+    Periodic torsion E = k (1 + cos(n phi - phase))
+     */
     let n = dihedral.multiplicity as f64;
-    let energy = dihedral.k * (1.0 + (n * phi - dihedral.phase).cos());
-
-    let atom_indices = [
+    let idx = [
         dihedral.atom1,
         dihedral.atom2,
         dihedral.atom3,
         dihedral.atom4,
     ];
-    let h = 1e-6;
+    torsion_term(&dihedral_positions(atoms, idx), box_length, |phi| {
+        let arg = n * phi - dihedral.phase;
+        (dihedral.k * (1.0 + arg.cos()), -dihedral.k * n * arg.sin())
+    })
+}
+
+fn improper_term(
+    atoms: &[Particle],
+    improper: &Improper,
+    box_length: f64,
+) -> (f64, [Vector3<f64>; 4]) {
+    /*
+    Harmonic improper E = k/2 (psi - psi0)^2
+     */
+    let idx = [
+        improper.atom1,
+        improper.atom2,
+        improper.atom3,
+        improper.atom4,
+    ];
+    torsion_term(&dihedral_positions(atoms, idx), box_length, |psi| {
+        let d_psi = psi - improper.psi0;
+        (0.5 * improper.k * d_psi * d_psi, improper.k * d_psi)
+    })
+}
+
+fn urey_bradley_term(
+    atoms: &[Particle],
+    ub: &UreyBradley,
+    box_length: f64,
+) -> (f64, [Vector3<f64>; 2]) {
+    // a harmonic spring between the outer atoms of the angle
+    let spring = Bond {
+        atom1: ub.atom1,
+        atom2: ub.atom3,
+        k: ub.k,
+        r0: ub.r0,
+        form: BondForm::Harmonic,
+    };
+    bond_term(atoms, &spring, box_length)
+}
+
+fn cosine_angle_energy(angle: &CosineAngle, theta: f64) -> (f64, f64) {
+    /*
+    Energy and dE/dtheta of the cosine based angle potentials. With
+    d = cos(theta) - cos(theta0) and s = sin(theta)
 
-    for &idx in &atom_indices {
-        for dim in 0..3 {
-            atoms[idx].position[dim] += h;
-            let e_plus = dihedral.k
-                * (1.0
-                    + ((n * dihedral_value(atoms, dihedral, box_length)) - dihedral.phase).cos());
-            atoms[idx].position[dim] -= 2.0 * h;
-            let e_minus = dihedral.k
-                * (1.0
-                    + ((n * dihedral_value(atoms, dihedral, box_length)) - dihedral.phase).cos());
-            atoms[idx].position[dim] += h;
+        G96:         dE/dtheta = -k d s
+        restricted:  dE/dtheta = -k d (s^2 + d cos(theta)) / s^3
+     */
+    let (s, c) = theta.sin_cos();
+    let d = c - angle.theta0.cos();
+    match angle.form {
+        CosineAngleForm::G96 => (0.5 * angle.k * d * d, -angle.k * d * s),
+        CosineAngleForm::Restricted => (
+            0.5 * angle.k * d * d / (s * s),
+            -angle.k * d * (s * s + d * c) / (s * s * s),
+        ),
+    }
+}
 
-            let d_e = (e_plus - e_minus) / (2.0 * h);
-            atoms[idx].force[dim] += -d_e;
+fn cosine_angle_term(
+    atoms: &[Particle],
+    angle: &CosineAngle,
+    box_length: f64,
+) -> (f64, [Vector3<f64>; 3]) {
+    let r = [
+        atoms[angle.atom1].position,
+        atoms[angle.atom2].position,
+        atoms[angle.atom3].position,
+    ];
+    match angle_gradient(&r, box_length) {
+        Some((theta, grad)) => {
+            let (energy, de) = cosine_angle_energy(angle, theta);
+            (energy, grad.map(|g| -de * g))
+        }
+        None => {
+            let theta = angle_from_positions(&r, angle.theta0, box_length);
+            (cosine_angle_energy(angle, theta).0, [Vector3::zeros(); 3])
         }
     }
+}
 
+fn rb_dihedral_term(
+    atoms: &[Particle],
+    dihedral: &RbDihedral,
+    box_length: f64,
+) -> (f64, [Vector3<f64>; 4]) {
+    /*
+    Ryckaert-Bellemans torsion with psi = phi - 180 deg (polymer convention), so
+    cos(psi) = -cos(phi) and dE/dphi = sin(phi) sum_n n C_n cos^(n-1)(psi)
+     */
+    let idx = [
+        dihedral.atom1,
+        dihedral.atom2,
+        dihedral.atom3,
+        dihedral.atom4,
+    ];
+    torsion_term(&dihedral_positions(atoms, idx), box_length, |phi| {
+        let cos_psi = -phi.cos();
+        let mut energy = 0.0;
+        let mut de = 0.0;
+        let mut power = 1.0; // cos^n(psi)
+        for (n, c) in dihedral.c.iter().enumerate() {
+            if n > 0 {
+                de += n as f64 * c * power;
+                power *= cos_psi;
+            }
+            energy += c * power;
+        }
+        (energy, phi.sin() * de)
+    })
+}
+
+pub fn compute_angle_force(atoms: &mut [Particle], angle: &Angle, box_length: f64) -> f64 {
+    let (energy, f) = angle_term(atoms, angle, box_length);
+    for (&idx, fi) in [angle.atom1, angle.atom2, angle.atom3].iter().zip(f) {
+        atoms[idx].force += fi;
+    }
     energy
 }
 
-pub fn compute_improper_force(atoms: &mut [Particle], improper: &Improper, box_length: f64) -> f64 {
-    let psi = improper_value(atoms, improper, box_length);
-    let dpsi = psi - improper.psi0;
-    let energy = 0.5 * improper.k * dpsi * dpsi;
+pub fn compute_dihedral_force(atoms: &mut [Particle], dihedral: &Dihedral, box_length: f64) -> f64 {
+    let (energy, f) = dihedral_term(atoms, dihedral, box_length);
+    let idx = [
+        dihedral.atom1,
+        dihedral.atom2,
+        dihedral.atom3,
+        dihedral.atom4,
+    ];
+    for (&i, fi) in idx.iter().zip(f) {
+        atoms[i].force += fi;
+    }
+    energy
+}
 
-    let atom_indices = [
+pub fn compute_improper_force(atoms: &mut [Particle], improper: &Improper, box_length: f64) -> f64 {
+    let (energy, f) = improper_term(atoms, improper, box_length);
+    let idx = [
         improper.atom1,
         improper.atom2,
         improper.atom3,
         improper.atom4,
     ];
-    let h = 1e-6;
-
-    for &idx in &atom_indices {
-        for dim in 0..3 {
-            atoms[idx].position[dim] += h;
-            let e_plus = 0.5
-                * improper.k
-                * (improper_value(atoms, improper, box_length) - improper.psi0).powi(2);
-            atoms[idx].position[dim] -= 2.0 * h;
-            let e_minus = 0.5
-                * improper.k
-                * (improper_value(atoms, improper, box_length) - improper.psi0).powi(2);
-            atoms[idx].position[dim] += h;
+    for (&i, fi) in idx.iter().zip(f) {
+        atoms[i].force += fi;
+    }
+    energy
+}
 
-            let d_e = (e_plus - e_minus) / (2.0 * h);
-            atoms[idx].force[dim] += -d_e;
+pub fn apply_all_bonded_forces_and_energy(
+    atoms: &mut [Particle],
+    bonds: &[Bond],
+    angles: &[Angle],
+    dihedrals: &[Dihedral],
+    impropers: &[Improper],
+    box_length: f64,
+) -> f64 {
+    /*
+    Bonded forces and energy. Every term list is split into one contiguous chunk per
+    worker thread; each worker accumulates into its own force buffer.
+     */
+    let n_terms = bonds.len() + angles.len() + dihedrals.len() + impropers.len();
+    let shared: &[Particle] = atoms;
+
+    let (forces, energy) = parallel_force_reduce(shared.len(), n_terms, |w, n, forces| {
+        let mut energy = 0.0;
+
+        let (s, e) = chunk_bounds(bonds.len(), w, n);
+        for b in &bonds[s..e] {
+            let (en, f) = bond_term(shared, b, box_length);
+            forces[b.atom1] += f[0];
+            forces[b.atom2] += f[1];
+            energy += en;
+        }
+        let (s, e) = chunk_bounds(angles.len(), w, n);
+        for angle in &angles[s..e] {
+            let (en, f) = angle_term(shared, angle, box_length);
+            forces[angle.atom1] += f[0];
+            forces[angle.atom2] += f[1];
+            forces[angle.atom3] += f[2];
+            energy += en;
+        }
+        let (s, e) = chunk_bounds(dihedrals.len(), w, n);
+        for d in &dihedrals[s..e] {
+            let (en, f) = dihedral_term(shared, d, box_length);
+            forces[d.atom1] += f[0];
+            forces[d.atom2] += f[1];
+            forces[d.atom3] += f[2];
+            forces[d.atom4] += f[3];
+            energy += en;
         }
+        let (s, e) = chunk_bounds(impropers.len(), w, n);
+        for imp in &impropers[s..e] {
+            let (en, f) = improper_term(shared, imp, box_length);
+            forces[imp.atom1] += f[0];
+            forces[imp.atom2] += f[1];
+            forces[imp.atom3] += f[2];
+            forces[imp.atom4] += f[3];
+            energy += en;
+        }
+
+        energy
+    });
+
+    for (a, f) in atoms.iter_mut().zip(forces) {
+        a.force += f;
     }
+    energy
+}
 
+pub fn apply_extended_bonded_forces_and_energy(
+    atoms: &mut [Particle],
+    extended: &ExtendedBonded,
+    box_length: f64,
+) -> f64 {
+    /*
+    Forces and energy of the extended bonded forms, threaded in the same way as
+    apply_all_bonded_forces_and_energy
+     */
+    if extended.is_empty() {
+        return 0.0;
+    }
+    let shared: &[Particle] = atoms;
+    let ExtendedBonded {
+        urey_bradleys,
+        cosine_angles,
+        rb_dihedrals,
+        cmaps,
+        bonds_without_exclusions,
+    } = extended;
+
+    let (forces, energy) = parallel_force_reduce(shared.len(), extended.len(), |w, n, forces| {
+        let mut energy = 0.0;
+
+        let (s, e) = chunk_bounds(urey_bradleys.len(), w, n);
+        for ub in &urey_bradleys[s..e] {
+            let (en, f) = urey_bradley_term(shared, ub, box_length);
+            forces[ub.atom1] += f[0];
+            forces[ub.atom3] += f[1];
+            energy += en;
+        }
+        let (s, e) = chunk_bounds(cosine_angles.len(), w, n);
+        for angle in &cosine_angles[s..e] {
+            let (en, f) = cosine_angle_term(shared, angle, box_length);
+            forces[angle.atom1] += f[0];
+            forces[angle.atom2] += f[1];
+            forces[angle.atom3] += f[2];
+            energy += en;
+        }
+        let (s, e) = chunk_bounds(rb_dihedrals.len(), w, n);
+        for d in &rb_dihedrals[s..e] {
+            let (en, f) = rb_dihedral_term(shared, d, box_length);
+            forces[d.atom1] += f[0];
+            forces[d.atom2] += f[1];
+            forces[d.atom3] += f[2];
+            forces[d.atom4] += f[3];
+            energy += en;
+        }
+        let (s, e) = chunk_bounds(cmaps.len(), w, n);
+        for cmap in &cmaps[s..e] {
+            let (en, f) = cmap_term(shared, cmap, box_length);
+            for (&i, fi) in cmap.atoms.iter().zip(f) {
+                forces[i] += fi;
+            }
+            energy += en;
+        }
+        let (s, e) = chunk_bounds(bonds_without_exclusions.len(), w, n);
+        for b in &bonds_without_exclusions[s..e] {
+            let (en, f) = bond_term(shared, b, box_length);
+            forces[b.atom1] += f[0];
+            forces[b.atom2] += f[1];
+            energy += en;
+        }
+
+        energy
+    });
+
+    for (a, f) in atoms.iter_mut().zip(forces) {
+        a.force += f;
+    }
     energy
 }
 
-pub fn apply_all_bonded_forces_and_energy(
-    atoms: &mut Vec<Particle>,
+pub fn apply_bonded_forces_and_energy(
+    atoms: &mut [Particle],
+    bonds: &[Bond],
+    box_length: f64,
+) -> f64 {
+    apply_all_bonded_forces_and_energy(atoms, bonds, &[], &[], &[], box_length)
+}
+
+pub fn bonded_energy(
+    atoms: &[Particle],
     bonds: &[Bond],
     angles: &[Angle],
     dihedrals: &[Dihedral],
     impropers: &[Improper],
     box_length: f64,
 ) -> f64 {
+    /*
+    Bonded energy only - no forces are touched, so the atoms can be borrowed
+    immutably (used for energy reporting during MD)
+     */
     let mut energy = 0.0;
 
     for b in bonds {
-        energy += compute_bond_force(atoms, b, box_length);
+        let r = minimum_image_convention(
+            atoms[b.atom2].position - atoms[b.atom1].position,
+            box_length,
+        )
+        .norm();
+        energy += b.energy_and_derivative(r).0;
     }
     for angle in angles {
-        energy += compute_angle_force(atoms, angle, box_length);
+        energy += 0.5 * angle.k * (angle_value(atoms, angle, box_length) - angle.theta0).powi(2);
     }
     for dihedral in dihedrals {
-        energy += compute_dihedral_force(atoms, dihedral, box_length);
+        let phi = dihedral_value(atoms, dihedral, box_length);
+        energy += dihedral.k * (1.0 + (dihedral.multiplicity as f64 * phi - dihedral.phase).cos());
     }
     for improper in impropers {
-        energy += compute_improper_force(atoms, improper, box_length);
+        energy += 0.5
+            * improper.k
+            * (improper_value(atoms, improper, box_length) - improper.psi0).powi(2);
     }
 
     energy
 }
 
-pub fn apply_bonded_forces_and_energy(
-    atoms: &mut Vec<Particle>,
-    bonds: &[Bond],
+pub fn extended_bonded_energy(
+    atoms: &[Particle],
+    extended: &ExtendedBonded,
     box_length: f64,
 ) -> f64 {
-    apply_all_bonded_forces_and_energy(atoms, bonds, &[], &[], &[], box_length)
+    let ub: f64 = extended
+        .urey_bradleys
+        .iter()
+        .map(|ub| urey_bradley_term(atoms, ub, box_length).0)
+        .sum();
+    let angles: f64 = extended
+        .cosine_angles
+        .iter()
+        .map(|a| cosine_angle_term(atoms, a, box_length).0)
+        .sum();
+    let torsions: f64 = extended
+        .rb_dihedrals
+        .iter()
+        .map(|d| rb_dihedral_term(atoms, d, box_length).0)
+        .sum();
+    let cmaps: f64 = extended
+        .cmaps
+        .iter()
+        .map(|c| cmap_term(atoms, c, box_length).0)
+        .sum();
+    let bonds: f64 = extended
+        .bonds_without_exclusions
+        .iter()
+        .map(|b| bond_term(atoms, b, box_length).0)
+        .sum();
+    ub + angles + torsions + cmaps + bonds
 }
 
 pub fn make_h2_system() -> System {
@@ -395,15 +1247,112 @@ pub fn make_h2_system() -> System {
         atom2: 1,
         k,
         r0,
+        form: BondForm::Harmonic,
     }];
 
-    System {
+    let mut system = System {
         atoms,
         bonds,
         angles: vec![],
         dihedrals: vec![],
         impropers: vec![],
+        elements: vec![1, 1],
+        ..Default::default()
+    };
+    system.generate_exclusions(3, 1.0, 1.0);
+    system
+}
+
+pub fn make_kremer_grest_chain(n_beads: usize) -> System {
+    /*
+    Bead-spring polymer of Kremer and Grest (J. Chem. Phys. 92, 5057 (1990)) in LJ units:
+    FENE bonds with k = 30, r_max = 1.5 and the WCA repulsion (epsilon = sigma = 1)
+    between bonded beads. As for every bond form, r0 is the minimum of the full bond
+    energy, here FENE + WCA at about 0.961, found by bisection on dE/dr; the chain starts
+    as a zig-zag with bonds of that length. Bonded neighbours are excluded from the
+    nonbonded LJ, which should be run with a cutoff of 2^(1/6) for the purely repulsive
+    model.
+     */
+    let spring = Bond {
+        atom1: 0,
+        atom2: 0,
+        k: 30.0,
+        r0: 0.0,
+        form: BondForm::Fene {
+            r_max: 1.5,
+            epsilon: 1.0,
+            sigma: 1.0,
+        },
+    };
+    let (mut lo, mut hi) = (0.9, 1.2);
+    for _ in 0..60 {
+        let mid = 0.5 * (lo + hi);
+        if spring.energy_and_derivative(mid).1 < 0.0 {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
     }
+    let bond_length = 0.5 * (lo + hi);
+    let atoms = (0..n_beads)
+        .map(|i| Particle {
+            id: i,
+            position: Vector3::new(
+                0.9 * bond_length * i as f64,
+                if i % 2 == 0 { 0.0 } else { 0.4 * bond_length },
+                0.0,
+            ),
+            velocity: Vector3::zeros(),
+            force: Vector3::zeros(),
+            lj_parameters: LJParameters {
+                epsilon: 1.0,
+                sigma: 1.0,
+                number_of_atoms: n_beads as i32,
+            },
+            mass: 1.0,
+            energy: 0.0,
+            atom_type: 0.0,
+            charge: 0.0,
+        })
+        .collect();
+    let bonds = (1..n_beads)
+        .map(|i| Bond {
+            atom1: i - 1,
+            atom2: i,
+            r0: bond_length,
+            ..spring.clone()
+        })
+        .collect();
+
+    let mut system = System {
+        atoms,
+        bonds,
+        ..Default::default()
+    };
+    system.generate_exclusions(1, 1.0, 1.0);
+    system
+}
+
+pub fn bond_virial(atoms: &[Particle], bonds: &[Bond], box_length: f64) -> Matrix3<f64> {
+    /*
+    Bond contribution to the virial tensor, W = sum r_ij (x) F_j with r_ij = x_j - x_i
+    (minimum image) and F_j = -dE/dr r_ij / r the bond force on atom j. tr(W) / 3V adds
+    to the pressure as in compute_pressure_particles.
+     */
+    let mut virial = Matrix3::zeros();
+    for b in bonds {
+        let r_vec = minimum_image_convention(
+            atoms[b.atom2].position - atoms[b.atom1].position,
+            box_length,
+        );
+        let r = r_vec.norm();
+        if r <= 1e-12 {
+            continue;
+        }
+        let f_j = -b.energy_and_derivative(r).1 * r_vec / r;
+        virial += r_vec * f_j.transpose();
+    }
+    virial
 }
 
 pub fn create_systems(system: &System, number_of_molecules: i32) -> InitOutput {
@@ -457,9 +1406,23 @@ pub fn create_systems(system: &System, number_of_molecules: i32) -> InitOutput {
     InitOutput::Systems(molecules)
 }
 
+pub fn create_systems_from_template(
+    template: &MoleculeTemplate,
+    types: &HashMap<String, NonBondedType>,
+    nrexcl: usize,
+    number_of_molecules: i32,
+) -> Result<InitOutput, String> {
+    /*
+    create_systems for a molecule template, whose 1-4 scale sets the pairs of every copy
+     */
+    let system = template.to_system(types, nrexcl)?;
+    Ok(create_systems(&system, number_of_molecules))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::{assert_forces_match_energy, central_difference, Tolerance};
     use nalgebra::Vector3;
 
     #[test]
@@ -476,6 +1439,43 @@ mod tests {
         assert_eq!(test_atom.id, 1);
     }
 
+    #[test]
+    fn template_1_4_scale_reaches_system_pairs() {
+        let bead = NonBondedType {
+            mass: 1.0,
+            charge: 0.5,
+            sigma: 1.0,
+            epsilon: 2.0,
+        };
+        let types = HashMap::from([("B".to_string(), bead)]);
+        let mut template = MoleculeTemplate {
+            name: "chain".to_string(),
+            atom_types: vec!["B".to_string(); 4],
+            positions: (0..4).map(|i| Vector3::new(i as f64, 0.0, 0.0)).collect(),
+            bonds: vec![(0, 1, 100.0, 1.0), (1, 2, 100.0, 1.0), (2, 3, 100.0, 1.0)],
+            exclusion_1_4_scale: Some(0.5),
+        };
+
+        let systems = match create_systems_from_template(&template, &types, 3, 2).unwrap() {
+            InitOutput::Systems(systems) => systems,
+            _ => panic!("expected systems"),
+        };
+        assert_eq!(systems.len(), 2);
+        for system in &systems {
+            assert_eq!(system.pairs.len(), 1);
+            let pair = &system.pairs[0];
+            assert_eq!((pair.atom1, pair.atom2), (0, 3));
+            assert_eq!(pair.coulomb_scale, 0.5);
+            assert_eq!(pair.epsilon, 1.0);
+            assert!(system.is_excluded(0, 3));
+        }
+
+        template.exclusion_1_4_scale = None;
+        let system = template.to_system(&types, 3).unwrap();
+        assert!(system.pairs.is_empty());
+        assert!(system.is_excluded(0, 3));
+    }
+
     #[test]
     fn test_create_systems_offsets_molecules() {
         let h2 = make_h2_system();
@@ -502,6 +1502,59 @@ mod tests {
         );
     }
 
+    fn chain_system(n: usize) -> System {
+        let atoms = (0..n)
+            .map(|i| Particle {
+                id: i,
+                position: Vector3::new(1.5 * i as f64, 0.0, 0.0),
+                velocity: Vector3::zeros(),
+                force: Vector3::zeros(),
+                atom_type: 0.0,
+                mass: 1.0,
+                charge: 0.0,
+                energy: 0.0,
+                lj_parameters: LJParameters {
+                    epsilon: 1.0,
+                    sigma: 1.0,
+                    number_of_atoms: 1,
+                },
+            })
+            .collect();
+        let bonds = (0..n - 1)
+            .map(|i| Bond {
+                atom1: i,
+                atom2: i + 1,
+                k: 100.0,
+                r0: 1.5,
+                form: BondForm::Harmonic,
+            })
+            .collect();
+
+        System {
+            atoms,
+            bonds,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_exclusions_follow_nrexcl() {
+        let mut chain = chain_system(5);
+
+        chain.generate_exclusions(3, 0.5, 0.8333);
+        // 4 bonded (1-2) + 3 (1-3) + 2 (1-4) pairs
+        assert_eq!(chain.exclusions.len(), 9);
+        assert_eq!(chain.pairs.len(), 2);
+        assert!(chain.is_excluded(3, 0));
+        assert!(!chain.is_excluded(0, 4));
+        assert!((chain.pairs[0].epsilon - 0.5).abs() < 1e-12);
+        assert!((chain.pairs[0].coulomb_scale - 0.8333).abs() < 1e-12);
+
+        chain.generate_exclusions(1, 1.0, 1.0);
+        assert_eq!(chain.exclusions.len(), 4);
+        assert!(chain.pairs.is_empty());
+    }
+
     #[test]
     fn test_bond_distance_happy() {
         let atom1 = Atom {
@@ -664,4 +1717,289 @@ mod tests {
         let e = dih.k * (1.0 + (phi - dih.phase).cos());
         assert!(e.is_finite());
     }
+
+    #[test]
+    fn threaded_bonded_forces_match_single_thread() {
+        use crate::parallel::threads::WorkerPool;
+
+        let mut chain = chain_system(400);
+        for (i, a) in chain.atoms.iter_mut().enumerate() {
+            a.position.y = 0.4 * (i % 2) as f64;
+            a.position.z = 0.3 * ((i / 3) % 2) as f64;
+        }
+        chain.angles = (0..398)
+            .map(|i| Angle {
+                atom1: i,
+                atom2: i + 1,
+                atom3: i + 2,
+                k: 50.0,
+                theta0: 2.0,
+            })
+            .collect();
+        chain.dihedrals = (0..397)
+            .map(|i| Dihedral {
+                atom1: i,
+                atom2: i + 1,
+                atom3: i + 2,
+                atom4: i + 3,
+                k: 1.5,
+                multiplicity: 3,
+                phase: 0.0,
+            })
+            .collect();
+
+        let run = |n_threads: usize| {
+            let pool = WorkerPool::new(n_threads);
+            let _entered = pool.enter();
+            let mut atoms = chain.atoms.clone();
+            let e = apply_all_bonded_forces_and_energy(
+                &mut atoms,
+                &chain.bonds,
+                &chain.angles,
+                &chain.dihedrals,
+                &[],
+                1000.0,
+            );
+            (atoms, e)
+        };
+        let (serial, e1) = run(1);
+        let (threaded, e4) = run(4);
+
+        assert!((e1 - e4).abs() < 1e-9 * e1.abs().max(1.0));
+        for (a, b) in serial.iter().zip(threaded.iter()) {
+            assert!((a.force - b.force).norm() < 1e-8);
+        }
+    }
+
+    #[test]
+    fn analytical_bonded_forces_match_finite_differences() {
+        let box_length = 10.0;
+        let mut system = chain_system(4);
+        // a skewed geometry, with the last atom wrapped across the box boundary
+        let positions = [
+            Vector3::new(0.3, 0.2, 0.1),
+            Vector3::new(1.3, 0.4, -0.2),
+            Vector3::new(1.8, 1.3, 0.3),
+            Vector3::new(12.6, 1.1, 1.2),
+        ];
+        for (a, r) in system.atoms.iter_mut().zip(positions) {
+            a.position = r;
+        }
+        let bonds = [Bond {
+            atom1: 0,
+            atom2: 1,
+            k: 300.0,
+            r0: 0.9,
+            form: BondForm::Harmonic,
+        }];
+        let angles = [Angle {
+            atom1: 0,
+            atom2: 1,
+            atom3: 2,
+            k: 60.0,
+            theta0: 1.9,
+        }];
+        let dihedrals = [Dihedral {
+            atom1: 0,
+            atom2: 1,
+            atom3: 2,
+            atom4: 3,
+            k: 2.5,
+            multiplicity: 3,
+            phase: 0.3,
+        }];
+        let impropers = [Improper {
+            atom1: 0,
+            atom2: 1,
+            atom3: 2,
+            atom4: 3,
+            k: 40.0,
+            psi0: 0.2,
+        }];
+
+        let check =
+            |bonds: &[Bond], angles: &[Angle], dihedrals: &[Dihedral], impropers: &[Improper]| {
+                let mut atoms = system.atoms.clone();
+                let energy = apply_all_bonded_forces_and_energy(
+                    &mut atoms, bonds, angles, dihedrals, impropers, box_length,
+                );
+                assert!(
+                    (energy
+                        - bonded_energy(&atoms, bonds, angles, dihedrals, impropers, box_length))
+                    .abs()
+                        < 1e-10
+                );
+
+                let forces: Vec<Vector3<f64>> = atoms.iter().map(|a| a.force).collect();
+                assert_forces_match_energy(
+                    &system.atoms,
+                    &forces,
+                    |a| bonded_energy(a, bonds, angles, dihedrals, impropers, box_length),
+                    1e-6,
+                    Tolerance::Relative(1e-5),
+                    "bonded",
+                );
+            };
+
+        check(&bonds, &[], &[], &[]);
+        check(&[], &angles, &[], &[]);
+        check(&[], &[], &dihedrals, &[]);
+        check(&[], &[], &[], &impropers);
+    }
+
+    #[test]
+    fn overstretched_fene_bond_keeps_a_consistent_restoring_force() {
+        let chain = make_kremer_grest_chain(2);
+        let bond = &chain.bonds[0];
+        let (energy_at_r0, force_at_r0) = bond.energy_and_derivative(bond.r0);
+        assert!((bond.r0 - 0.961).abs() < 1e-3, "r0 = {}", bond.r0);
+        assert!(force_at_r0.abs() < 1e-9);
+
+        let mut last_energy = energy_at_r0;
+        for r in [1.45, 1.495, 1.5, 1.6, 2.0] {
+            let (energy, derivative) = bond.energy_and_derivative(r);
+            assert!(energy.is_finite() && energy > last_energy, "{r}: {energy}");
+            assert!(derivative > 0.0);
+            let numerical = central_difference(r, 1e-6, |x| bond.energy_and_derivative(x).0);
+            assert!(
+                (derivative - numerical).abs() < 1e-5 * derivative,
+                "{r}: {derivative} vs {numerical}"
+            );
+            last_energy = energy;
+        }
+    }
+
+    #[test]
+    fn polymer_bond_forms_match_finite_differences_and_virial() {
+        let box_length = 10.0;
+        let mut system = chain_system(2);
+        system.atoms[0].position = Vector3::new(0.2, 0.3, 0.1);
+        system.atoms[1].position = Vector3::new(9.4, 0.9, 0.6); // bonded across the boundary
+
+        // V(r) = (r - 1)^2 on a grid, so the table reproduces a harmonic k = 2 bond
+        let grid: Vec<f64> = (0..=40).map(|n| 0.05 * n as f64).collect();
+        let table = BondTable::new(
+            grid.clone(),
+            grid.iter().map(|r| (r - 1.0).powi(2)).collect(),
+            grid.iter().map(|r| -2.0 * (r - 1.0)).collect(),
+        )
+        .expect("table should be valid");
+        let forms = [
+            BondForm::Harmonic,
+            BondForm::Fene {
+                r_max: 1.5,
+                epsilon: 1.0,
+                sigma: 1.0,
+            },
+            BondForm::Morse {
+                depth: 5.0,
+                beta: 2.0,
+            },
+            BondForm::Quartic,
+            BondForm::Tabulated {
+                table: Arc::new(table),
+            },
+        ];
+
+        for form in forms {
+            let bond = Bond {
+                atom1: 0,
+                atom2: 1,
+                k: 30.0,
+                r0: 1.0,
+                form,
+            };
+            let mut atoms = system.atoms.clone();
+            let energy = compute_bond_force(&mut atoms, &bond, box_length);
+            let forces: Vec<Vector3<f64>> = atoms.iter().map(|a| a.force).collect();
+            assert_forces_match_energy(
+                &system.atoms,
+                &forces,
+                |a| bond_term(a, &bond, box_length).0,
+                1e-6,
+                Tolerance::Relative(1e-5),
+                &format!("{:?}", bond.form),
+            );
+
+            let r = minimum_image_convention(atoms[1].position - atoms[0].position, box_length);
+            let virial = bond_virial(&atoms, std::slice::from_ref(&bond), box_length);
+            assert!((virial.trace() - r.dot(&atoms[1].force)).abs() < 1e-9);
+            if let BondForm::Tabulated { .. } = bond.form {
+                let exact = 30.0 * (r.norm() - 1.0).powi(2);
+                assert!((energy - exact).abs() < 1e-12, "{energy} vs {exact}");
+            }
+        }
+    }
+
+    #[test]
+    fn extended_bonded_forces_match_finite_differences() {
+        let box_length = 10.0;
+        let mut system = chain_system(4);
+        let positions = [
+            Vector3::new(0.3, 0.2, 0.1),
+            Vector3::new(1.3, 0.4, -0.2),
+            Vector3::new(1.8, 1.3, 0.3),
+            Vector3::new(12.6, 1.1, 1.2),
+        ];
+        for (a, r) in system.atoms.iter_mut().zip(positions) {
+            a.position = r;
+        }
+        let cosine = |form| CosineAngle {
+            atom1: 0,
+            atom2: 1,
+            atom3: 2,
+            k: 25.0,
+            theta0: 2.1,
+            form,
+        };
+        let fourier = RbDihedral::from_fourier([0, 1, 2, 3], [3.0, -1.0, 2.0, 0.5]);
+        let terms = [
+            ExtendedBonded {
+                urey_bradleys: vec![UreyBradley {
+                    atom1: 0,
+                    atom3: 2,
+                    k: 200.0,
+                    r0: 1.2,
+                }],
+                ..Default::default()
+            },
+            ExtendedBonded {
+                cosine_angles: vec![cosine(CosineAngleForm::G96)],
+                ..Default::default()
+            },
+            ExtendedBonded {
+                cosine_angles: vec![cosine(CosineAngleForm::Restricted)],
+                ..Default::default()
+            },
+            ExtendedBonded {
+                rb_dihedrals: vec![fourier.clone()],
+                ..Default::default()
+            },
+        ];
+
+        for extended in &terms {
+            let mut atoms = system.atoms.clone();
+            let energy = apply_extended_bonded_forces_and_energy(&mut atoms, extended, box_length);
+            assert!((energy - extended_bonded_energy(&atoms, extended, box_length)).abs() < 1e-10);
+
+            let forces: Vec<Vector3<f64>> = atoms.iter().map(|a| a.force).collect();
+            assert_forces_match_energy(
+                &system.atoms,
+                &forces,
+                |a| extended_bonded_energy(a, extended, box_length),
+                1e-6,
+                Tolerance::Relative(1e-5),
+                "extended bonded",
+            );
+        }
+
+        // the Ryckaert-Bellemans form reproduces the Fourier series it was built from
+        let phi = dihedral_from_positions(&positions, box_length);
+        let fourier_energy = 0.5
+            * (3.0 * (1.0 + phi.cos()) - (1.0 - (2.0 * phi).cos())
+                + 2.0 * (1.0 + (3.0 * phi).cos())
+                + 0.5 * (1.0 - (4.0 * phi).cos()));
+        let rb_energy = rb_dihedral_term(&system.atoms, &fourier, box_length).0;
+        assert!((fourier_energy - rb_energy).abs() < 1e-10);
+    }
 }
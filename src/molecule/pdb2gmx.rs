@@ -0,0 +1,1227 @@
+/*
+pdb2gmx-style protein builder from GROMACS residue databases
+
+A force field directory describes residues in a few files sharing a base name
+(aminoacids.* in charmm27.ff):
+
+    .rtp     residue topologies: atom names, types and charges, bonds, impropers
+             and the default bonded function types ([ bondedtypes ])
+    .hdb     how to build the hydrogens of each residue
+    .n.tdb   N-terminus patches (NH3+, ...), .c.tdb C-terminus patches (COO-, ...):
+             atoms to replace (rename, retype, recharge), add or delete
+    .r2b     residue names of the PDB mapped to .rtp residues (HISD -> HSD)
+    .arn     atom renaming (H -> HN)
+
+The PDB residues of each chain are matched to their .rtp entries, the first and
+last residue of a chain get their terminus patches (by default NH3+, GLY-NH3+ or
+PRO-NH2+, and COO-), and every template atom missing from the PDB is built from the
+hdb rule or the patch that names it. Hydrogens present in the PDB under other names
+are dropped and rebuilt. Residues that the .rtp does not link to a neighbour (waters,
+ions) and HETATM residues without an .rtp entry (ligands) are left out with a
+warning, as pdb2gmx puts them in their own molecules; they need their own topology
+(tip3p.itp, ions.itp, ...). An hdb line reads
+
+    n  type  name  i j k [l]
+
+and builds n atoms (name, or name1..namen) bonded to i with the other control atoms
+fixing the geometry (-C and +N refer to the neighbouring residues):
+
+    1  one planar H, on the bisector of j-i-k (peptide H)
+    2  one H at the tetrahedral angle to j, trans to k (hydroxyl)
+    3  two planar H at 120 degrees to j (amide NH2)
+    4  two or three tetrahedral H, staggered with respect to k (methyl)
+    5  one tetrahedral H opposite j, k and l (CH with three heavy neighbours)
+    6  two tetrahedral H bisecting j-i-k (CH2)
+    8  two carboxyl oxygens, as type 3 at the C-O distance
+
+All angles and proper dihedrals follow from the bonds (all_dih = 1); impropers come
+from the .rtp and the patches. `Protein::to_itp` writes the molecule as a GROMACS
+[ moleculetype ] without parameters, and `Protein::to_system` reads it on top of a
+force field (e.g. charmm27.ff/forcefield.itp) so the bonded parameters come from its
+type tables and the CMAP terms from its [ cmaptypes ].
+
+Protein coordinates are kept in nm, the PDB is read in Angstrom.
+
+Reference: GROMACS reference manual, "Residue database", "Hydrogen database" and
+"Termini database".
+ */
+
+use crate::molecule::io::{read_pdb_atoms, PdbAtom};
+use crate::molecule::molecule::System;
+use crate::molecule::topology::{GromacsTopology, Preprocessor, SourceLine};
+use log::warn;
+use nalgebra::Vector3;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+const TETRAHEDRAL_DEG: f64 = 109.4712206; // acos(-1/3)
+const HYDROGEN_BOND_LENGTH: f64 = 1.0; // Å
+const CARBOXYL_BOND_LENGTH: f64 = 1.25; // Å
+                                        // the neutral histidine used for a PDB HIS, as pdb2gmx does without -his
+const DEFAULT_HISTIDINE: &str = "HISD";
+// PDB atom names that differ from the GROMACS ones (GROMACS xlateat.dat)
+const PDB_ATOM_ALIASES: [(&str, &str, &str); 3] =
+    [("ILE", "CD1", "CD"), ("*", "OT1", "O"), ("*", "OT2", "OXT")];
+
+#[derive(Clone, Debug)]
+pub struct BondedTypes {
+    pub bond: usize,
+    pub angle: usize,
+    pub dihedral: usize,
+    pub improper: usize,
+    pub nrexcl: usize,
+}
+
+impl Default for BondedTypes {
+    fn default() -> Self {
+        BondedTypes {
+            bond: 1,
+            angle: 1,
+            dihedral: 1,
+            improper: 2,
+            nrexcl: 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RtpAtom {
+    pub name: String,
+    pub type_name: String,
+    pub charge: f64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RtpResidue {
+    pub name: String,
+    pub atoms: Vec<RtpAtom>,
+    pub bonds: Vec<[String; 2]>,
+    pub impropers: Vec<[String; 4]>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HydrogenRule {
+    pub count: usize,
+    pub kind: usize,
+    pub name: String,
+    pub control: Vec<String>, // i j k [l], '-' and '+' for the neighbouring residues
+}
+
+impl HydrogenRule {
+    pub fn names(&self) -> Vec<String> {
+        if self.count == 1 {
+            vec![self.name.clone()]
+        } else {
+            (1..=self.count)
+                .map(|n| format!("{}{n}", self.name))
+                .collect()
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TdbReplace {
+    pub old: String,
+    pub new: String,
+    pub type_name: String,
+    pub mass: f64,
+    pub charge: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct TdbAdd {
+    pub rule: HydrogenRule,
+    pub type_name: String,
+    pub mass: f64,
+    pub charge: f64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TerminusPatch {
+    pub name: String,
+    pub replace: Vec<TdbReplace>,
+    pub add: Vec<TdbAdd>,
+    pub delete: Vec<String>,
+    pub bonds: Vec<[String; 2]>,
+    pub impropers: Vec<[String; 4]>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProteinAtom {
+    pub name: String,
+    pub residue_name: String,
+    pub residue_number: i64,
+    pub type_name: String,
+    pub charge: f64,
+    pub mass: Option<f64>, // only for patch atoms, otherwise the atom type's
+    pub position: Vector3<f64>, // nm
+}
+
+#[derive(Clone, Debug)]
+pub struct Protein {
+    pub atoms: Vec<ProteinAtom>,
+    pub bonds: Vec<[usize; 2]>,
+    pub impropers: Vec<[usize; 4]>,
+    pub bonded_types: BondedTypes,
+}
+
+// a residue while it is being built: template atoms with the coordinates found so far
+#[derive(Clone, Debug)]
+struct ResidueBuild {
+    name: String,
+    number: i64,
+    atoms: Vec<(ProteinAtom, Option<Vector3<f64>>)>,
+    bonds: Vec<[String; 2]>,
+    impropers: Vec<[String; 4]>,
+    rules: Vec<HydrogenRule>,
+}
+
+impl ResidueBuild {
+    fn index(&self, name: &str) -> Option<usize> {
+        self.atoms.iter().position(|(a, _)| a.name == name)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ProteinBuilder {
+    pub bonded_types: BondedTypes,
+    pub residues: HashMap<String, RtpResidue>,
+    pub hydrogens: HashMap<String, Vec<HydrogenRule>>,
+    pub n_termini: Vec<TerminusPatch>,
+    pub c_termini: Vec<TerminusPatch>,
+    pub residue_names: HashMap<String, String>,    // .r2b
+    pub atom_names: Vec<(String, String, String)>, // .arn: residue ("*" for any), from, to
+    // terminus patch names for every chain ("None" for none); defaults to NH3+ and COO-
+    pub n_terminus: Option<String>,
+    pub c_terminus: Option<String>,
+}
+
+impl ProteinBuilder {
+    pub fn read(force_field_dir: &str, database: &str) -> Result<Self, String> {
+        /*
+        Read <database>.rtp and, where present, the .hdb, .n.tdb, .c.tdb, .r2b and
+        .arn files next to it, e.g. ("ff/Charmm27.ff/charmm27.ff", "aminoacids")
+         */
+        let dir = Path::new(force_field_dir);
+        let read = |extension: &str| -> Result<Option<String>, String> {
+            let path = dir.join(format!("{database}.{extension}"));
+            if !path.is_file() {
+                return Ok(None);
+            }
+            fs::read_to_string(&path)
+                .map(Some)
+                .map_err(|e| format!("failed to read '{}': {e}", path.display()))
+        };
+
+        let rtp = read("rtp")?.ok_or_else(|| {
+            format!("no {database}.rtp in force field directory '{force_field_dir}'")
+        })?;
+        let (bonded_types, residues) = parse_rtp(&rtp)?;
+        Ok(ProteinBuilder {
+            bonded_types,
+            residues,
+            hydrogens: read("hdb")?.map_or(Ok(HashMap::new()), |c| parse_hdb(&c))?,
+            n_termini: read("n.tdb")?.map_or(Ok(Vec::new()), |c| parse_tdb(&c))?,
+            c_termini: read("c.tdb")?.map_or(Ok(Vec::new()), |c| parse_tdb(&c))?,
+            residue_names: read("r2b")?.map_or(Ok(HashMap::new()), |c| parse_r2b(&c))?,
+            atom_names: read("arn")?.map_or(Ok(Vec::new()), |c| parse_arn(&c))?,
+            n_terminus: None,
+            c_terminus: None,
+        })
+    }
+
+    pub fn build_pdb(&self, path: &str) -> Result<Protein, String> {
+        self.build(&read_pdb_atoms(path)?)
+    }
+
+    pub fn build(&self, pdb: &[PdbAtom]) -> Result<Protein, String> {
+        // residues are runs of records with the same chain, number and name
+        let residue_key = |a: &PdbAtom| {
+            (
+                a.chain,
+                a.residue_number,
+                a.insertion_code,
+                a.residue_name.clone(),
+            )
+        };
+        // the first record of every residue left out
+        let mut left_out: Vec<&PdbAtom> = Vec::new();
+        let pdb: Vec<&PdbAtom> = pdb
+            .iter()
+            .filter(|a| {
+                let in_chain = match self.residues.get(&self.rtp_name(&a.residue_name)) {
+                    Some(template) => template.is_linked(),
+                    None => !a.hetero,
+                };
+                if !in_chain
+                    && left_out
+                        .last()
+                        .is_none_or(|b| residue_key(b) != residue_key(a))
+                {
+                    left_out.push(a);
+                }
+                in_chain
+            })
+            .collect();
+        if !left_out.is_empty() {
+            let mut counts: Vec<(&str, usize)> = Vec::new();
+            for atom in &left_out {
+                match counts
+                    .iter_mut()
+                    .find(|(name, _)| *name == atom.residue_name)
+                {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((&atom.residue_name, 1)),
+                }
+            }
+            let counts: Vec<String> = counts
+                .iter()
+                .map(|(name, count)| format!("{count} {name}"))
+                .collect();
+            warn!(
+                "left out residues that are not part of a chain: {}",
+                counts.join(", ")
+            );
+        }
+        if pdb.is_empty() {
+            return Err("the pdb input has no chain residues".to_string());
+        }
+
+        let mut chains: Vec<Vec<Vec<&PdbAtom>>> = Vec::new();
+        for (n, atom) in pdb.iter().enumerate() {
+            let same_residue = n > 0 && residue_key(pdb[n - 1]) == residue_key(atom);
+            if same_residue {
+                chains
+                    .last_mut()
+                    .and_then(|c| c.last_mut())
+                    .unwrap()
+                    .push(atom);
+            } else if n > 0 && pdb[n - 1].chain == atom.chain {
+                chains.last_mut().unwrap().push(vec![atom]);
+            } else {
+                chains.push(vec![vec![atom]]);
+            }
+        }
+
+        let mut protein = Protein {
+            atoms: Vec::new(),
+            bonds: Vec::new(),
+            impropers: Vec::new(),
+            bonded_types: self.bonded_types.clone(),
+        };
+        for chain in &chains {
+            let residues = self.build_chain(chain)?;
+            protein.append_chain(&residues);
+        }
+        Ok(protein)
+    }
+
+    fn rtp_name(&self, pdb_name: &str) -> String {
+        let name = if pdb_name == "HIS" {
+            DEFAULT_HISTIDINE
+        } else {
+            pdb_name
+        };
+        self.residue_names
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    fn terminus(
+        &self,
+        patches: &[TerminusPatch],
+        chosen: &Option<String>,
+        default: String,
+    ) -> Result<TerminusPatch, String> {
+        let name = chosen.clone().unwrap_or(default);
+        patches
+            .iter()
+            .find(|p| p.name == name)
+            .cloned()
+            .ok_or_else(|| format!("terminus database has no entry '{name}'"))
+    }
+
+    fn build_chain(&self, chain: &[Vec<&PdbAtom>]) -> Result<Vec<ResidueBuild>, String> {
+        let last = chain.len() - 1;
+        let mut residues = Vec::with_capacity(chain.len());
+
+        for (r, pdb_residue) in chain.iter().enumerate() {
+            let pdb_name = &pdb_residue[0].residue_name;
+            let number = pdb_residue[0].residue_number;
+            let rtp_name = self.rtp_name(pdb_name);
+            let template = self.residues.get(&rtp_name).ok_or_else(|| {
+                format!("residue {pdb_name}{number} ('{rtp_name}') is not in the .rtp")
+            })?;
+
+            let mut residue = ResidueBuild {
+                name: rtp_name.clone(),
+                number,
+                atoms: template
+                    .atoms
+                    .iter()
+                    .map(|a| {
+                        let atom = ProteinAtom {
+                            name: a.name.clone(),
+                            residue_name: rtp_name.clone(),
+                            residue_number: number,
+                            type_name: a.type_name.clone(),
+                            charge: a.charge,
+                            mass: None,
+                            position: Vector3::zeros(),
+                        };
+                        (atom, None)
+                    })
+                    .collect(),
+                bonds: template.bonds.clone(),
+                impropers: template.impropers.clone(),
+                rules: self.hydrogens.get(&rtp_name).cloned().unwrap_or_default(),
+            };
+            let mut pdb_atoms: Vec<(String, Vector3<f64>)> = pdb_residue
+                .iter()
+                .map(|a| (self.translate_atom_name(&rtp_name, &a.name), a.position))
+                .collect();
+
+            let mut patches = Vec::new();
+            if r == 0 {
+                let default = match rtp_name.as_str() {
+                    "GLY" => "GLY-NH3+",
+                    "PRO" => "PRO-NH2+",
+                    _ => "NH3+",
+                };
+                patches.push(self.terminus(
+                    &self.n_termini,
+                    &self.n_terminus,
+                    default.to_string(),
+                )?);
+            }
+            if r == last {
+                patches.push(self.terminus(
+                    &self.c_termini,
+                    &self.c_terminus,
+                    "COO-".to_string(),
+                )?);
+            }
+            for patch in &patches {
+                apply_patch(&mut residue, &mut pdb_atoms, patch);
+            }
+
+            for (name, position) in pdb_atoms {
+                match residue.index(&name) {
+                    Some(i) => residue.atoms[i].1 = Some(position),
+                    // hydrogens are rebuilt from the database whatever their PDB name
+                    None if is_hydrogen(&name) => {}
+                    None => {
+                        return Err(format!(
+                            "atom {name} of residue {rtp_name}{number} is not in its template"
+                        ))
+                    }
+                }
+            }
+            residues.push(residue);
+        }
+
+        build_missing_atoms(&mut residues)?;
+        Ok(residues)
+    }
+
+    fn translate_atom_name(&self, residue: &str, name: &str) -> String {
+        let mut name = name.to_string();
+        let aliases = PDB_ATOM_ALIASES.iter().copied().chain(
+            self.atom_names
+                .iter()
+                .map(|(r, from, to)| (r.as_str(), from.as_str(), to.as_str())),
+        );
+        for (r, from, to) in aliases {
+            if (r == "*" || r == residue) && name == from {
+                name = to.to_string();
+            }
+        }
+        name
+    }
+}
+
+impl RtpResidue {
+    pub fn is_linked(&self) -> bool {
+        /*
+        Whether the residue bonds to its neighbours (-C, +N), i.e. belongs to a chain
+         */
+        self.bonds
+            .iter()
+            .flatten()
+            .any(|name| name.starts_with('-') || name.starts_with('+'))
+    }
+}
+
+fn is_hydrogen(name: &str) -> bool {
+    name.chars().find(|c| c.is_ascii_alphabetic()) == Some('H')
+}
+
+fn apply_patch(
+    residue: &mut ResidueBuild,
+    pdb_atoms: &mut [(String, Vector3<f64>)],
+    patch: &TerminusPatch,
+) {
+    /*
+    Replace, add and delete the patch atoms. Replacements that name an atom the patch
+    adds are applied after the additions.
+     */
+    let replace =
+        |residue: &mut ResidueBuild, pdb_atoms: &mut [(String, Vector3<f64>)], r: &TdbReplace| {
+            for (name, _) in pdb_atoms.iter_mut().filter(|(n, _)| *n == r.old) {
+                *name = r.new.clone();
+            }
+            let Some(i) = residue.index(&r.old) else {
+                return false;
+            };
+            let atom = &mut residue.atoms[i].0;
+            atom.name = r.new.clone();
+            atom.type_name = r.type_name.clone();
+            atom.mass = Some(r.mass);
+            atom.charge = r.charge;
+            true
+        };
+
+    let pending: Vec<&TdbReplace> = patch
+        .replace
+        .iter()
+        .filter(|r| !replace(residue, pdb_atoms, r))
+        .collect();
+
+    for add in &patch.add {
+        let anchor = &add.rule.control[0];
+        let Some(at) = residue.index(anchor) else {
+            continue;
+        };
+        let new_atoms: Vec<(ProteinAtom, Option<Vector3<f64>>)> = add
+            .rule
+            .names()
+            .into_iter()
+            .filter(|name| residue.index(name).is_none())
+            .map(|name| {
+                let atom = ProteinAtom {
+                    name,
+                    residue_name: residue.name.clone(),
+                    residue_number: residue.number,
+                    type_name: add.type_name.clone(),
+                    charge: add.charge,
+                    mass: Some(add.mass),
+                    position: Vector3::zeros(),
+                };
+                (atom, None)
+            })
+            .collect();
+        for (atom, _) in &new_atoms {
+            residue.bonds.push([anchor.clone(), atom.name.clone()]);
+        }
+        residue.atoms.splice(at + 1..at + 1, new_atoms);
+        residue.rules.push(add.rule.clone());
+    }
+
+    for r in pending {
+        replace(residue, pdb_atoms, r);
+    }
+
+    let deleted: HashSet<&String> = patch.delete.iter().collect();
+    residue.atoms.retain(|(a, _)| !deleted.contains(&a.name));
+    residue
+        .bonds
+        .retain(|b| b.iter().all(|n| !deleted.contains(n)));
+    residue
+        .impropers
+        .retain(|d| d.iter().all(|n| !deleted.contains(n)));
+    residue.bonds.extend(patch.bonds.iter().cloned());
+    residue.impropers.extend(patch.impropers.iter().cloned());
+}
+
+fn resolve(residues: &[ResidueBuild], r: usize, name: &str) -> Option<(usize, usize)> {
+    /*
+    (residue, atom) of a name in residue r; -X and +X refer to the previous and next
+    residue of the chain
+     */
+    let (r, name) = if let Some(name) = name.strip_prefix('-') {
+        (r.checked_sub(1)?, name)
+    } else if let Some(name) = name.strip_prefix('+') {
+        (r + 1, name)
+    } else {
+        (r, name)
+    };
+    Some((r, residues.get(r)?.index(name)?))
+}
+
+fn build_missing_atoms(residues: &mut [ResidueBuild]) -> Result<(), String> {
+    /*
+    Apply the hdb and patch rules until no more atoms can be placed; rules whose
+    control atoms are themselves built wait for a later pass
+     */
+    loop {
+        let mut progress = false;
+        for r in 0..residues.len() {
+            for rule in residues[r].rules.clone() {
+                let targets: Vec<(usize, usize)> = rule
+                    .names()
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(k, name)| residues[r].index(name).map(|i| (k, i)))
+                    .filter(|&(_, i)| residues[r].atoms[i].1.is_none())
+                    .collect();
+                if targets.is_empty() {
+                    continue;
+                }
+                let control: Option<Vec<Vector3<f64>>> = rule
+                    .control
+                    .iter()
+                    .map(|name| {
+                        let (cr, ci) = resolve(residues, r, name)?;
+                        residues[cr].atoms[ci].1
+                    })
+                    .collect();
+                let Some(control) = control else {
+                    continue;
+                };
+                let positions = place_atoms(&rule, &control).map_err(|e| {
+                    format!("residue {}{}: {e}", residues[r].name, residues[r].number)
+                })?;
+                for (k, i) in targets {
+                    residues[r].atoms[i].1 = Some(positions[k]);
+                }
+                progress = true;
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+
+    // an atom without a rule is the cause of the rules that wait for it
+    let unplaced = |buildable: bool| {
+        residues.iter().find_map(|residue| {
+            let built: HashSet<String> = residue.rules.iter().flat_map(|r| r.names()).collect();
+            residue
+                .atoms
+                .iter()
+                .find(|(a, x)| x.is_none() && built.contains(&a.name) == buildable)
+                .map(|(a, _)| {
+                    format!(
+                        "atom {} of residue {}{}",
+                        a.name, residue.name, residue.number
+                    )
+                })
+        })
+    };
+    if let Some(atom) = unplaced(false) {
+        return Err(format!(
+            "{atom} is missing from the PDB and has no rule to build it"
+        ));
+    }
+    if let Some(atom) = unplaced(true) {
+        return Err(format!(
+            "{atom} cannot be built, its control atoms are missing"
+        ));
+    }
+    Ok(())
+}
+
+fn place(
+    i: Vector3<f64>,
+    j: Vector3<f64>,
+    k: Vector3<f64>,
+    length: f64,
+    angle_deg: f64,
+    dihedral_deg: f64,
+) -> Vector3<f64> {
+    /*
+    The atom bonded to i with the angle (new, i, j) and the dihedral (new, i, j, k)
+     */
+    let ji = (i - j).normalize();
+    let normal = (j - k).cross(&ji);
+    let normal = if normal.norm() > 1e-8 {
+        normal.normalize()
+    } else {
+        // k on the i-j axis: any plane through it will do
+        let axis = if ji.x.abs() < 0.9 {
+            Vector3::x()
+        } else {
+            Vector3::y()
+        };
+        ji.cross(&axis).normalize()
+    };
+    let in_plane = normal.cross(&ji);
+    let (a, t) = (angle_deg.to_radians(), dihedral_deg.to_radians());
+    i + length * (-a.cos() * ji + a.sin() * t.cos() * in_plane + a.sin() * t.sin() * normal)
+}
+
+fn place_atoms(rule: &HydrogenRule, control: &[Vector3<f64>]) -> Result<Vec<Vector3<f64>>, String> {
+    let needed = if rule.kind == 5 { 4 } else { 3 };
+    if control.len() < needed {
+        return Err(format!(
+            "hydrogen type {} of {} needs {needed} control atoms",
+            rule.kind, rule.name
+        ));
+    }
+    let (i, j, k) = (control[0], control[1], control[2]);
+    let length = if rule.kind == 8 {
+        CARBOXYL_BOND_LENGTH
+    } else {
+        HYDROGEN_BOND_LENGTH
+    };
+    let bisector = ((i - j).normalize() + (i - k).normalize()).normalize();
+
+    let mut positions = match rule.kind {
+        1 => vec![i + length * bisector],
+        2 => vec![place(i, j, k, length, TETRAHEDRAL_DEG, 180.0)],
+        3 | 8 => vec![
+            place(i, j, k, length, 120.0, 180.0),
+            place(i, j, k, length, 120.0, 0.0),
+        ],
+        4 => (0..3)
+            .map(|n| place(i, j, k, length, TETRAHEDRAL_DEG, 180.0 + 120.0 * n as f64))
+            .collect(),
+        5 => vec![i + length * (3.0 * i - j - k - control[3]).normalize()],
+        6 => {
+            let normal = (j - i).cross(&(k - i)).normalize();
+            let half = 0.5 * TETRAHEDRAL_DEG.to_radians();
+            vec![
+                i + length * (half.cos() * bisector + half.sin() * normal),
+                i + length * (half.cos() * bisector - half.sin() * normal),
+            ]
+        }
+        other => {
+            return Err(format!(
+                "unsupported hydrogen type {other} for {}",
+                rule.name
+            ))
+        }
+    };
+    if rule.count > positions.len() {
+        return Err(format!(
+            "hydrogen type {} builds at most {} atoms, {} asked for {}",
+            rule.kind,
+            positions.len(),
+            rule.name,
+            rule.count
+        ));
+    }
+    positions.truncate(rule.count);
+    Ok(positions)
+}
+
+impl Protein {
+    fn append_chain(&mut self, residues: &[ResidueBuild]) {
+        let mut offsets = Vec::with_capacity(residues.len());
+        for residue in residues {
+            offsets.push(self.atoms.len());
+            self.atoms
+                .extend(residue.atoms.iter().map(|(atom, position)| ProteinAtom {
+                    position: 0.1 * position.unwrap_or_default(),
+                    ..atom.clone()
+                }));
+        }
+
+        // bonded terms that reach past the chain ends or name deleted atoms are dropped
+        let index =
+            |r: usize, name: &String| resolve(residues, r, name).map(|(cr, ci)| offsets[cr] + ci);
+        let mut seen: HashSet<[usize; 2]> = self
+            .bonds
+            .iter()
+            .map(|&[a, b]| [a.min(b), a.max(b)])
+            .collect();
+        for (r, residue) in residues.iter().enumerate() {
+            for bond in &residue.bonds {
+                if let (Some(a), Some(b)) = (index(r, &bond[0]), index(r, &bond[1])) {
+                    if a != b && seen.insert([a.min(b), a.max(b)]) {
+                        self.bonds.push([a, b]);
+                    }
+                }
+            }
+            for improper in &residue.impropers {
+                let atoms: Option<Vec<usize>> = improper.iter().map(|n| index(r, n)).collect();
+                if let Some(atoms) = atoms {
+                    self.impropers
+                        .push([atoms[0], atoms[1], atoms[2], atoms[3]]);
+                }
+            }
+        }
+    }
+
+    pub fn positions(&self) -> Vec<Vector3<f64>> {
+        self.atoms.iter().map(|a| a.position).collect()
+    }
+
+    pub fn total_charge(&self) -> f64 {
+        self.atoms.iter().map(|a| a.charge).sum()
+    }
+
+    fn neighbours(&self) -> Vec<Vec<usize>> {
+        let mut neighbours = vec![Vec::new(); self.atoms.len()];
+        for &[a, b] in &self.bonds {
+            neighbours[a].push(b);
+            neighbours[b].push(a);
+        }
+        neighbours
+    }
+
+    pub fn angles(&self) -> Vec<[usize; 3]> {
+        let neighbours = self.neighbours();
+        let mut angles = Vec::new();
+        for (j, around) in neighbours.iter().enumerate() {
+            for (n, &i) in around.iter().enumerate() {
+                for &k in &around[n + 1..] {
+                    angles.push([i, j, k]);
+                }
+            }
+        }
+        angles
+    }
+
+    pub fn dihedrals(&self) -> Vec<[usize; 4]> {
+        /*
+        Every proper dihedral i-j-k-l over a bond j-k
+         */
+        let neighbours = self.neighbours();
+        let mut dihedrals = Vec::new();
+        for &[j, k] in &self.bonds {
+            for &i in neighbours[j].iter().filter(|&&i| i != k) {
+                for &l in neighbours[k].iter().filter(|&&l| l != j && l != i) {
+                    dihedrals.push([i, j, k, l]);
+                }
+            }
+        }
+        dihedrals
+    }
+
+    pub fn to_itp(&self, name: &str) -> String {
+        /*
+        The molecule as a GROMACS [ moleculetype ]; bonded rows carry only the
+        function types of [ bondedtypes ], grompp-style, for the force field to fill in
+         */
+        let types = &self.bonded_types;
+        let mut itp = String::new();
+        let _ = writeln!(
+            itp,
+            "[ moleculetype ]\n; name  nrexcl\n{name}  {}\n",
+            types.nrexcl
+        );
+        let _ = writeln!(
+            itp,
+            "[ atoms ]\n;  nr  type  resnr  residue  atom  cgnr  charge  mass"
+        );
+        for (n, atom) in self.atoms.iter().enumerate() {
+            let _ = write!(
+                itp,
+                "{} {} {} {} {} {} {}",
+                n + 1,
+                atom.type_name,
+                atom.residue_number,
+                atom.residue_name,
+                atom.name,
+                n + 1,
+                atom.charge
+            );
+            if let Some(mass) = atom.mass {
+                let _ = write!(itp, " {mass}");
+            }
+            itp.push('\n');
+        }
+
+        let _ = writeln!(itp, "\n[ bonds ]");
+        for [a, b] in &self.bonds {
+            let _ = writeln!(itp, "{} {} {}", a + 1, b + 1, types.bond);
+        }
+        let _ = writeln!(itp, "\n[ angles ]");
+        for [a, b, c] in self.angles() {
+            let _ = writeln!(itp, "{} {} {} {}", a + 1, b + 1, c + 1, types.angle);
+        }
+        let _ = writeln!(itp, "\n[ dihedrals ]");
+        for [a, b, c, d] in self.dihedrals() {
+            let _ = writeln!(
+                itp,
+                "{} {} {} {} {}",
+                a + 1,
+                b + 1,
+                c + 1,
+                d + 1,
+                types.dihedral
+            );
+        }
+        for [a, b, c, d] in &self.impropers {
+            let _ = writeln!(
+                itp,
+                "{} {} {} {} {}",
+                a + 1,
+                b + 1,
+                c + 1,
+                d + 1,
+                types.improper
+            );
+        }
+        itp
+    }
+
+    pub fn to_system(&self, force_field: &GromacsTopology) -> Result<System, String> {
+        const NAME: &str = "Protein";
+        let mut topology = force_field.clone();
+        topology
+            .molecule_types
+            .retain(|m| m.molecule_name.as_deref() != Some(NAME));
+        topology.add_str(&self.to_itp(NAME), &[])?;
+        topology.molecules = vec![(NAME.to_string(), 1)];
+        topology
+            .to_systems(&self.positions())?
+            .pop()
+            .ok_or_else(|| "the protein has no atoms".to_string())
+    }
+}
+
+fn data_lines(contents: &str) -> Result<Vec<SourceLine>, String> {
+    let mut preprocessor = Preprocessor::new(&[], &[]);
+    preprocessor.follow_includes = false;
+    preprocessor.process_str(contents, "", None)
+}
+
+fn section_name(text: &str) -> Option<String> {
+    (text.starts_with('[') && text.ends_with(']'))
+        .then(|| text[1..text.len() - 1].trim().to_string())
+}
+
+fn names<const N: usize>(tokens: &[&str], what: &str) -> Result<[String; N], String> {
+    let row: Vec<String> = tokens.iter().take(N).map(|t| t.to_string()).collect();
+    row.try_into()
+        .map_err(|_| format!("{what} row requires {N} atom names"))
+}
+
+fn number<T: std::str::FromStr>(tokens: &[&str], index: usize, label: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    tokens
+        .get(index)
+        .ok_or_else(|| format!("missing {label}"))?
+        .parse::<T>()
+        .map_err(|e| format!("failed to parse {label}: {e}"))
+}
+
+fn parse_hydrogen_rule(tokens: &[&str]) -> Result<HydrogenRule, String> {
+    if tokens.len() < 4 {
+        return Err("hydrogen rule requires a count, type, name and control atoms".to_string());
+    }
+    Ok(HydrogenRule {
+        count: number(tokens, 0, "hydrogen count")?,
+        kind: number(tokens, 1, "hydrogen type")?,
+        name: tokens[2].to_string(),
+        control: tokens[3..].iter().map(|t| t.to_string()).collect(),
+    })
+}
+
+pub fn parse_rtp(contents: &str) -> Result<(BondedTypes, HashMap<String, RtpResidue>), String> {
+    let mut bonded_types = BondedTypes::default();
+    let mut residues: HashMap<String, RtpResidue> = HashMap::new();
+    let mut current: Option<RtpResidue> = None;
+    let mut section = String::new();
+
+    for line in data_lines(contents)? {
+        let tokens: Vec<&str> = line.text.split_whitespace().collect();
+        if let Some(name) = section_name(&line.text) {
+            match name.to_ascii_lowercase().as_str() {
+                s @ ("atoms" | "bonds" | "angles" | "dihedrals" | "impropers" | "cmap"
+                | "exclusions" | "bondedtypes") => section = s.to_string(),
+                _ => {
+                    if let Some(residue) = current.take() {
+                        residues.insert(residue.name.clone(), residue);
+                    }
+                    current = Some(RtpResidue {
+                        name,
+                        ..Default::default()
+                    });
+                    section.clear();
+                }
+            }
+            continue;
+        }
+
+        let parsed: Result<(), String> = (|| {
+            if section == "bondedtypes" {
+                bonded_types = BondedTypes {
+                    bond: number(&tokens, 0, "bond type")?,
+                    angle: number(&tokens, 1, "angle type")?,
+                    dihedral: number(&tokens, 2, "dihedral type")?,
+                    improper: number(&tokens, 3, "improper type")?,
+                    nrexcl: number(&tokens, 5, "nrexcl").unwrap_or(3),
+                };
+                return Ok(());
+            }
+            let Some(residue) = current.as_mut() else {
+                return Ok(());
+            };
+            match section.as_str() {
+                "atoms" => residue.atoms.push(RtpAtom {
+                    name: tokens[0].to_string(),
+                    type_name: tokens.get(1).ok_or("atom row requires a type")?.to_string(),
+                    charge: number(&tokens, 2, "atom charge")?,
+                }),
+                "bonds" => residue.bonds.push(names(&tokens, "bond")?),
+                "impropers" => residue.impropers.push(names(&tokens, "improper")?),
+                // angles and dihedrals follow from the bonds, CMAPs from the backbone
+                _ => {}
+            }
+            Ok(())
+        })();
+        parsed.map_err(|e| format!("{}: {e}", line.location()))?;
+    }
+    if let Some(residue) = current {
+        residues.insert(residue.name.clone(), residue);
+    }
+    Ok((bonded_types, residues))
+}
+
+pub fn parse_hdb(contents: &str) -> Result<HashMap<String, Vec<HydrogenRule>>, String> {
+    /*
+    Residue headers `name n` each followed by n hydrogen rules
+     */
+    let mut rules: HashMap<String, Vec<HydrogenRule>> = HashMap::new();
+    let mut current = String::new();
+    for line in data_lines(contents)? {
+        let tokens: Vec<&str> = line.text.split_whitespace().collect();
+        if tokens.len() == 2 && tokens[0].parse::<usize>().is_err() {
+            current = tokens[0].to_string();
+            rules.entry(current.clone()).or_default();
+            continue;
+        }
+        let rule = parse_hydrogen_rule(&tokens).map_err(|e| format!("{}: {e}", line.location()))?;
+        rules
+            .get_mut(&current)
+            .ok_or_else(|| format!("{}: hydrogen rule before a residue header", line.location()))?
+            .push(rule);
+    }
+    Ok(rules)
+}
+
+pub fn parse_tdb(contents: &str) -> Result<Vec<TerminusPatch>, String> {
+    let mut patches: Vec<TerminusPatch> = Vec::new();
+    let mut section = String::new();
+    // an [ add ] rule waits for its line of atom properties
+    let mut pending_rule: Option<HydrogenRule> = None;
+
+    for line in data_lines(contents)? {
+        if let Some(name) = section_name(&line.text) {
+            let lower = name.to_ascii_lowercase();
+            match lower.as_str() {
+                "replace" | "add" | "delete" | "bonds" | "impropers" | "angles" | "dihedrals"
+                | "cmap" => section = lower,
+                _ => {
+                    patches.push(TerminusPatch {
+                        name,
+                        ..Default::default()
+                    });
+                    section.clear();
+                }
+            }
+            continue;
+        }
+
+        let tokens: Vec<&str> = line.text.split_whitespace().collect();
+        let parsed: Result<(), String> = (|| {
+            let Some(patch) = patches.last_mut() else {
+                return Err("terminus data before the first entry".to_string());
+            };
+            match section.as_str() {
+                // old [new] type mass charge
+                "replace" => {
+                    let new_name = tokens.len() >= 5;
+                    let at = if new_name { 2 } else { 1 };
+                    patch.replace.push(TdbReplace {
+                        old: tokens[0].to_string(),
+                        new: tokens[at - 1].to_string(),
+                        type_name: tokens
+                            .get(at)
+                            .ok_or("replace row requires a type")?
+                            .to_string(),
+                        mass: number(&tokens, at + 1, "replacement mass")?,
+                        charge: number(&tokens, at + 2, "replacement charge")?,
+                    });
+                }
+                "add" => match pending_rule.take() {
+                    None => pending_rule = Some(parse_hydrogen_rule(&tokens)?),
+                    Some(rule) => patch.add.push(TdbAdd {
+                        rule,
+                        type_name: tokens[0].to_string(),
+                        mass: number(&tokens, 1, "added atom mass")?,
+                        charge: number(&tokens, 2, "added atom charge")?,
+                    }),
+                },
+                "delete" => patch.delete.push(tokens[0].to_string()),
+                "bonds" => patch.bonds.push(names(&tokens, "bond")?),
+                "impropers" => patch.impropers.push(names(&tokens, "improper")?),
+                _ => {}
+            }
+            Ok(())
+        })();
+        parsed.map_err(|e| format!("{}: {e}", line.location()))?;
+    }
+    if pending_rule.is_some() {
+        return Err("terminus [ add ] rule without its atom properties".to_string());
+    }
+    Ok(patches)
+}
+
+pub fn parse_r2b(contents: &str) -> Result<HashMap<String, String>, String> {
+    /*
+    `name rtp_name [nter cter both]`: the first rtp column is used, termini are patched
+    from the .tdb
+     */
+    let mut names = HashMap::new();
+    for line in data_lines(contents)? {
+        let tokens: Vec<&str> = line.text.split_whitespace().collect();
+        let rtp = tokens
+            .get(1)
+            .ok_or_else(|| format!("{}: .r2b row requires two names", line.location()))?;
+        names.insert(tokens[0].to_string(), rtp.to_string());
+    }
+    Ok(names)
+}
+
+pub fn parse_arn(contents: &str) -> Result<Vec<(String, String, String)>, String> {
+    let mut renames = Vec::new();
+    for line in data_lines(contents)? {
+        let tokens: Vec<&str> = line.text.split_whitespace().collect();
+        let [residue, from, to] = names::<3>(&tokens, "atom renaming")
+            .map_err(|e| format!("{}: {e}", line.location()))?;
+        renames.push((residue, from, to));
+    }
+    Ok(renames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::molecule::io::read_pdb_atoms_from_str;
+
+    const CHARMM_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/ff/Charmm27.ff/charmm27.ff");
+
+    // heavy atoms of an Ala-Ala dipeptide, plus the amide H of the second residue
+    const DIALANINE: &str = "\
+ATOM      1  N   ALA A   1       0.000   1.000   0.000  1.00  0.00
+ATOM      2  CA  ALA A   1       1.200   0.200   0.000  1.00  0.00
+ATOM      3  C   ALA A   1       1.928   0.307   1.336  1.00  0.00
+ATOM      4  O   ALA A   1       2.367  -0.701   1.890  1.00  0.00
+ATOM      5  CB  ALA A   1       2.127   0.625  -1.141  1.00  0.00
+ATOM      6  N   ALA A   2       2.050   1.530   1.842  1.00  0.00
+ATOM      7  H   ALA A   2       1.700   2.300   1.350  1.00  0.00
+ATOM      8  CA  ALA A   2       2.724   1.770   3.112  1.00  0.00
+ATOM      9  C   ALA A   2       2.036   1.025   4.251  1.00  0.00
+ATOM     10  O   ALA A   2       2.698   0.400   5.079  1.00  0.00
+ATOM     11  CB  ALA A   2       2.772   3.268   3.419  1.00  0.00
+";
+
+    fn builder() -> ProteinBuilder {
+        ProteinBuilder::read(CHARMM_DIR, "aminoacids").expect("the CHARMM27 databases should read")
+    }
+
+    #[test]
+    fn builds_hydrogens_and_termini_of_a_dipeptide() {
+        let builder = builder();
+        assert!(builder.residues.contains_key("HSD"));
+        assert_eq!(builder.bonded_types.angle, 5);
+        assert_eq!(builder.residue_names["HISE"], "HSE");
+
+        let pdb = read_pdb_atoms_from_str(DIALANINE).expect("pdb should parse");
+        let protein = builder.build(&pdb).expect("the dipeptide should build");
+
+        // ALA has 10 atoms; NH3+ swaps HN for H1-H3, COO- adds the second oxygen
+        assert_eq!(protein.atoms.len(), 23);
+        assert!(protein.total_charge().abs() < 1e-9);
+        let atom = |residue: i64, name: &str| {
+            protein
+                .atoms
+                .iter()
+                .find(|a| a.residue_number == residue && a.name == name)
+                .unwrap_or_else(|| panic!("{name} of residue {residue}"))
+        };
+        assert_eq!(atom(1, "N").type_name, "NH3");
+        assert_eq!(atom(1, "H3").type_name, "HC");
+        assert_eq!(atom(2, "OT2").type_name, "OC");
+        assert!(protein
+            .atoms
+            .iter()
+            .all(|a| a.name != "HN" || a.residue_number == 2));
+        // a PDB "H" is the CHARMM HN and keeps its coordinates (nm)
+        assert!((atom(2, "HN").position - Vector3::new(0.17, 0.23, 0.135)).norm() < 1e-12);
+
+        // every built atom sits at a bond length from its partner and clashes with nothing
+        let positions = protein.positions();
+        for [a, b] in &protein.bonds {
+            let d = (positions[*a] - positions[*b]).norm();
+            assert!(d > 0.095 && d < 0.16, "bond {a}-{b}: {d}");
+        }
+        for a in 0..positions.len() {
+            for b in a + 1..positions.len() {
+                assert!((positions[a] - positions[b]).norm() > 0.09);
+            }
+        }
+        let methyl = [atom(1, "HB1"), atom(1, "HB2"), atom(1, "HB3")];
+        let hh = (methyl[0].position - methyl[1].position).norm();
+        assert!((hh - 2.0 * 0.1 * (0.5 * TETRAHEDRAL_DEG.to_radians()).sin()).abs() < 1e-9);
+
+        let missing = DIALANINE.replace("ATOM     11  CB  ALA A   2", "REMARK");
+        let pdb = read_pdb_atoms_from_str(&missing).expect("pdb should parse");
+        let error = builder.build(&pdb).unwrap_err();
+        assert!(error.contains("CB") && error.contains("ALA2"), "{error}");
+    }
+
+    #[test]
+    fn waters_ions_and_ligands_are_left_out_of_the_chains() {
+        let builder = builder();
+        let solvated = format!(
+            "{DIALANINE}\
+HETATM   12  OW  HOH A 101       6.000   6.000   6.000  1.00  0.00
+HETATM   13  HW1 HOH A 101       6.957   6.000   6.000  1.00  0.00
+HETATM   14  HW2 HOH A 101       5.760   6.927   6.000  1.00  0.00
+HETATM   15  OW  HOH A 102       9.000   9.000   9.000  1.00  0.00
+HETATM   16 NA    NA A 103       3.000   8.000   8.000  1.00  0.00
+HETATM   17  C1  LIG A 104       8.000   3.000   8.000  1.00  0.00
+"
+        );
+        let pdb = read_pdb_atoms_from_str(&solvated).expect("pdb should parse");
+        assert!(pdb[11].hetero && !pdb[10].hetero);
+        let protein = builder
+            .build(&pdb)
+            .expect("the solvated dipeptide should build");
+        assert_eq!(protein.atoms.len(), 23);
+        assert!(protein.atoms.iter().all(|a| a.residue_name == "ALA"));
+
+        // an ATOM record of an unknown residue is still an error
+        let unknown = DIALANINE.replace("CB  ALA A   2", "CB  ALX A   2");
+        let pdb = read_pdb_atoms_from_str(&unknown).expect("pdb should parse");
+        let error = builder.build(&pdb).unwrap_err();
+        assert!(error.contains("ALX"), "{error}");
+    }
+
+    #[test]
+    fn built_protein_is_parameterised_by_charmm27() {
+        let mut builder = builder();
+        builder.c_terminus = Some("COOH".to_string());
+        let pdb = read_pdb_atoms_from_str(DIALANINE).expect("pdb should parse");
+        let protein = builder.build(&pdb).expect("the dipeptide should build");
+        assert!((protein.total_charge() - 1.0).abs() < 1e-9);
+
+        let force_field = GromacsTopology::read(&format!("{CHARMM_DIR}/forcefield.itp"), &[], &[])
+            .expect("forcefield.itp should read");
+        let system = protein
+            .to_system(&force_field)
+            .expect("CHARMM27 should parameterise the dipeptide");
+
+        assert_eq!(system.atoms.len(), protein.atoms.len());
+        assert_eq!(system.bonds.len(), protein.bonds.len());
+        assert!(system.bonds.iter().all(|b| b.k > 0.0 && b.r0 > 0.09));
+        assert_eq!(system.angles.len(), protein.angles().len());
+        assert!(system.dihedrals.len() >= protein.dihedrals().len());
+        assert_eq!(system.impropers.len(), protein.impropers.len());
+        assert!(!system.extended.urey_bradleys.is_empty());
+        assert!(!system.pairs.is_empty());
+        let n = protein.atoms.iter().position(|a| a.name == "N").unwrap();
+        assert!((system.atoms[n].mass - 14.007).abs() < 1e-9);
+        assert!((system.atoms[n].charge + 0.3).abs() < 1e-12);
+    }
+}
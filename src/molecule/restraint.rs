@@ -0,0 +1,324 @@
+/*
+Position restraints (GROMACS [ position_restraints ], e.g. the posre.itp written by
+pdb2gmx)
+
+Every restrained atom is tied to a reference position x_0 (the starting structure),
+with dx = x - x_0 under the minimum image:
+
+    funct 1, harmonic         E = 1/2 sum_a k_a dx_a^2          (per-axis constants)
+    funct 2, flat-bottomed    E = 1/2 k (d - r)^2   for d > r,  0 otherwise
+
+where d is the length of dx projected on the flat-bottom geometry g: 1 sphere,
+2 and 8 cylinder along z, 6 and 7 cylinders along x and y, 3-5 a layer normal to
+x, y or z. A negative radius inverts the potential and keeps the atom at least |r|
+away from x_0.
+
+Restraints only act when switched on for a run phase (MdOptions::position_restraints);
+their energy is part of the potential and is also reported on its own.
+
+References: GROMACS reference manual, "Position restraints" and "Flat-bottomed
+position restraints".
+ */
+
+use crate::lennard_jones_simulations::{minimum_image_convention, Particle};
+use nalgebra::Vector3;
+use std::fs;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlatBottomGeometry {
+    Sphere,
+    Cylinder(usize), // axis of the cylinder
+    Layer(usize),    // normal of the layer
+}
+
+#[derive(Clone, Debug)]
+pub enum RestraintKind {
+    Harmonic {
+        k: Vector3<f64>,
+    },
+    FlatBottomed {
+        geometry: FlatBottomGeometry,
+        radius: f64, // negative = inverted, keep the atom outside
+        k: f64,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct PositionRestraint {
+    pub atom: usize,
+    pub reference: Vector3<f64>,
+    pub kind: RestraintKind,
+}
+
+fn restraint_term(
+    atoms: &[Particle],
+    restraint: &PositionRestraint,
+    box_length: f64,
+) -> (f64, Vector3<f64>) {
+    /*
+    Restraint energy and the force on the restrained atom
+     */
+    let dx = minimum_image_convention(
+        atoms[restraint.atom].position - restraint.reference,
+        box_length,
+    );
+    match &restraint.kind {
+        RestraintKind::Harmonic { k } => {
+            let f = -k.component_mul(&dx);
+            (-0.5 * f.dot(&dx), f)
+        }
+        RestraintKind::FlatBottomed {
+            geometry,
+            radius,
+            k,
+        } => {
+            let mut projected = dx;
+            match *geometry {
+                FlatBottomGeometry::Sphere => {}
+                FlatBottomGeometry::Cylinder(axis) => projected[axis] = 0.0,
+                FlatBottomGeometry::Layer(axis) => {
+                    projected = Vector3::zeros();
+                    projected[axis] = dx[axis];
+                }
+            }
+            let d = projected.norm();
+            // distance past the wall, positive when the restraint is active
+            let excess = if *radius >= 0.0 {
+                d - radius
+            } else {
+                -radius - d
+            };
+            if excess <= 0.0 || d == 0.0 {
+                return (0.0, Vector3::zeros());
+            }
+            let direction = if *radius >= 0.0 { 1.0 } else { -1.0 };
+            (
+                0.5 * k * excess * excess,
+                -direction * k * excess * projected / d,
+            )
+        }
+    }
+}
+
+pub fn apply_position_restraints(
+    atoms: &mut [Particle],
+    restraints: &[PositionRestraint],
+    box_length: f64,
+) -> f64 {
+    let mut energy = 0.0;
+    for restraint in restraints {
+        let (en, f) = restraint_term(atoms, restraint, box_length);
+        atoms[restraint.atom].force += f;
+        energy += en;
+    }
+    energy
+}
+
+pub fn position_restraint_energy(
+    atoms: &[Particle],
+    restraints: &[PositionRestraint],
+    box_length: f64,
+) -> f64 {
+    restraints
+        .iter()
+        .map(|r| restraint_term(atoms, r, box_length).0)
+        .sum()
+}
+
+pub fn parse_position_restraint(tokens: &[&str]) -> Result<PositionRestraint, String> {
+    /*
+    One [ position_restraints ] row, atom index 1-based:
+
+        ai  1  fcx fcy fcz
+        ai  2  g   r   k
+
+    The reference position is left at the origin until `set_reference_positions`.
+     */
+    let field = |idx: usize, label: &str| -> Result<f64, String> {
+        tokens
+            .get(idx)
+            .ok_or_else(|| format!("missing {label}"))?
+            .parse::<f64>()
+            .map_err(|e| format!("failed to parse {label}: {e}"))
+    };
+    let atom = tokens
+        .first()
+        .ok_or("missing restrained atom")?
+        .parse::<usize>()
+        .map_err(|e| format!("failed to parse restrained atom: {e}"))?;
+    if atom == 0 {
+        return Err("restrained atom indices start at 1".to_string());
+    }
+    let kind = match tokens.get(1).copied() {
+        Some("1") => RestraintKind::Harmonic {
+            k: Vector3::new(field(2, "fcx")?, field(3, "fcy")?, field(4, "fcz")?),
+        },
+        Some("2") => {
+            let geometry = match tokens.get(2).copied() {
+                Some("1") => FlatBottomGeometry::Sphere,
+                Some("2") | Some("8") => FlatBottomGeometry::Cylinder(2),
+                Some("6") => FlatBottomGeometry::Cylinder(0),
+                Some("7") => FlatBottomGeometry::Cylinder(1),
+                Some("3") => FlatBottomGeometry::Layer(0),
+                Some("4") => FlatBottomGeometry::Layer(1),
+                Some("5") => FlatBottomGeometry::Layer(2),
+                other => {
+                    return Err(format!(
+                        "unsupported flat-bottom geometry {}",
+                        other.unwrap_or("(missing)")
+                    ))
+                }
+            };
+            RestraintKind::FlatBottomed {
+                geometry,
+                radius: field(3, "flat-bottom radius")?,
+                k: field(4, "flat-bottom force constant")?,
+            }
+        }
+        other => {
+            return Err(format!(
+                "unsupported position restraint function type {}",
+                other.unwrap_or("(missing)")
+            ))
+        }
+    };
+    Ok(PositionRestraint {
+        atom: atom - 1,
+        reference: Vector3::zeros(),
+        kind,
+    })
+}
+
+pub fn parse_position_restraints(contents: &str) -> Result<Vec<PositionRestraint>, String> {
+    let mut restraints = Vec::new();
+    let mut in_section = false;
+    for (line_number, raw_line) in contents.lines().enumerate() {
+        let line = raw_line.split(';').next().unwrap_or("").trim();
+        if line.starts_with('[') && line.ends_with(']') {
+            in_section = line[1..line.len() - 1]
+                .trim()
+                .eq_ignore_ascii_case("position_restraints");
+            continue;
+        }
+        if !in_section || line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        restraints.push(
+            parse_position_restraint(&tokens)
+                .map_err(|e| format!("line {}: {e}", line_number + 1))?,
+        );
+    }
+    Ok(restraints)
+}
+
+pub fn read_position_restraints(path: &str) -> Result<Vec<PositionRestraint>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("failed to read position restraint file at '{path}': {e}"))?;
+    parse_position_restraints(&contents)
+}
+
+pub fn set_reference_positions(
+    restraints: &mut [PositionRestraint],
+    reference: &[Vector3<f64>],
+) -> Result<(), String> {
+    /*
+    Restrain every atom to its position in `reference`, usually the starting structure
+     */
+    for r in restraints.iter_mut() {
+        r.reference = *reference.get(r.atom).ok_or_else(|| {
+            format!(
+                "restrained atom {} is outside the {} reference coordinates",
+                r.atom + 1,
+                reference.len()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_forces_match_energy, particle, Tolerance};
+
+    #[test]
+    fn posre_rows_are_parsed_and_referenced() {
+        let contents = "\
+; position restraints for Protein
+[ position_restraints ]
+;  i funct       fcx        fcy        fcz
+   1    1       1000       1000       1000
+   3    1          0          0        500
+   2    2          1        0.5        200   ; flat-bottomed sphere
+";
+        let mut restraints = parse_position_restraints(contents).expect("posre should parse");
+        assert_eq!(restraints.len(), 3);
+        assert_eq!(restraints[1].atom, 2);
+        assert!(matches!(
+            restraints[2].kind,
+            RestraintKind::FlatBottomed {
+                geometry: FlatBottomGeometry::Sphere,
+                ..
+            }
+        ));
+
+        let reference = vec![
+            Vector3::new(1.0, 1.0, 1.0),
+            Vector3::new(2.0, 1.0, 1.0),
+            Vector3::new(3.0, 1.0, 1.0),
+        ];
+        set_reference_positions(&mut restraints, &reference).expect("references in range");
+        assert!(set_reference_positions(&mut restraints, &reference[..2]).is_err());
+
+        // only z is restrained for atom 3; atom 2 sits inside its flat bottom
+        let mut atoms: Vec<Particle> = reference
+            .iter()
+            .map(|&r| particle(r + Vector3::new(0.1, 0.1, 0.1), 1.0))
+            .collect();
+        let energy = apply_position_restraints(&mut atoms, &restraints, 10.0);
+        let expected = 0.5 * 1000.0 * 3.0 * 0.01 + 0.5 * 500.0 * 0.01;
+        assert!((energy - expected).abs() < 1e-9);
+        assert!((atoms[2].force - Vector3::new(0.0, 0.0, -50.0)).norm() < 1e-9);
+        assert_eq!(atoms[1].force, Vector3::zeros());
+    }
+
+    #[test]
+    fn flat_bottomed_forces_match_finite_differences() {
+        let reference = Vector3::new(2.0, 2.0, 2.0);
+        let mut restraints = Vec::new();
+        for geometry in [
+            FlatBottomGeometry::Sphere,
+            FlatBottomGeometry::Cylinder(2),
+            FlatBottomGeometry::Layer(0),
+        ] {
+            for radius in [0.3, -1.5] {
+                restraints.push(PositionRestraint {
+                    atom: 0,
+                    reference,
+                    kind: RestraintKind::FlatBottomed {
+                        geometry,
+                        radius,
+                        k: 150.0,
+                    },
+                });
+            }
+        }
+
+        let start = reference + Vector3::new(0.4, -0.5, 0.3);
+        for restraint in &restraints {
+            let mut atoms = vec![particle(start, 1.0)];
+            let energy =
+                apply_position_restraints(&mut atoms, std::slice::from_ref(restraint), 10.0);
+            assert!(energy > 0.0);
+            assert_forces_match_energy(
+                &atoms,
+                &[atoms[0].force],
+                |a| position_restraint_energy(a, std::slice::from_ref(restraint), 10.0),
+                1e-6,
+                Tolerance::Absolute(1e-5),
+                &format!("{:?}", restraint.kind),
+            );
+        }
+    }
+}
@@ -0,0 +1,1053 @@
+/*
+GROMACS topologies (.top/.itp) with the grompp preprocessor
+
+The preprocessor runs first and hands the parser plain data lines:
+
+    #include "file.itp"       searched next to the including file, then in the
+                              include directories (like grompp's -I / include = )
+    #define NAME [value]      NAME is defined; a value replaces NAME in data rows
+    #undef NAME
+    #ifdef/#ifndef/#else/#endif
+
+Comments (';') are stripped and rows ending in '\' are joined with the next line.
+
+A topology is a force field followed by molecules:
+
+    [ defaults ]         nbfunc, comb-rule, gen-pairs, fudgeLJ, fudgeQQ
+    [ atomtypes ]        V and W are C6/C12 for comb-rule 1 and sigma/epsilon otherwise
+    [ bondtypes ], [ angletypes ], [ dihedraltypes ]
+                         parameters for bonded rows that only list atoms and a funct,
+                         matched on the bond_type column of the atom types;
+                         X is a wildcard in dihedral types, the most specific match
+                         wins and a funct 9 match brings all its consecutive lines
+                         (multiple periodic terms)
+    [ pairtypes ]        1-4 LJ parameters
+    [ cmaptypes ]
+    [ moleculetype ]     each with its own [ atoms ], [ bonds ], ... sections
+    [ system ], [ molecules ]
+
+Bonded rows are parsed by the molecule-level readers of `MartiniForceField`, which
+also builds the System of each molecule. The 1-4 pairs are the [ pairs ] rows of a
+molecule, or are generated from the bond graph up to nrexcl when it has none; Coulomb
+is scaled by fudgeQQ. A pair takes its LJ parameters from the row, else from
+[ pairtypes ], else (gen-pairs = yes) from the mixed atom types scaled by fudgeLJ;
+with gen-pairs = no a pair without either is an error, as in grompp. Mixed sigma is
+the geometric mean for comb-rule 3 and the arithmetic mean otherwise. [ cmap ] rows
+are not read, CMAP terms are assigned to backbones from the [ cmaptypes ]. Sections
+without a counterpart in System ([ constrainttypes ], [ nonbond_params ],
+[ implicit_genborn_params ], ...) are skipped.
+
+Topologies are in GROMACS units (nm, kJ/mol, amu, e).
+
+Reference: GROMACS reference manual, "Topology file" and "Topology includes".
+ */
+
+use crate::molecule::cmap::{parse_cmap_type, CmapType};
+use crate::molecule::io::read_gro;
+use crate::molecule::martini::{
+    parse_atomtype_with_rule, parse_f64, parse_pair, parse_usize, MartiniAtomType,
+    MartiniForceField,
+};
+use crate::molecule::molecule::System;
+use nalgebra::Vector3;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// guards against a file that (indirectly) includes itself
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+#[derive(Clone, Debug)]
+pub struct SourceLine {
+    pub file: String, // empty for in-memory input
+    pub line: usize,
+    pub text: String,
+}
+
+impl SourceLine {
+    pub fn location(&self) -> String {
+        location(&self.file, self.line)
+    }
+}
+
+fn location(file: &str, line: usize) -> String {
+    if file.is_empty() {
+        format!("line {line}")
+    } else {
+        format!("{file}:{line}")
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Preprocessor {
+    pub include_dirs: Vec<PathBuf>,
+    pub defines: HashMap<String, String>,
+    // single-file readers skip #include lines instead of following them
+    pub follow_includes: bool,
+}
+
+impl Preprocessor {
+    pub fn new(include_dirs: &[&str], defines: &[&str]) -> Self {
+        /*
+        Defines are given as NAME or NAME=value, as with `define = -DPOSRES` in an .mdp
+         */
+        Preprocessor {
+            include_dirs: include_dirs.iter().map(PathBuf::from).collect(),
+            defines: defines
+                .iter()
+                .map(|d| match d.split_once('=') {
+                    Some((name, value)) => (name.to_string(), value.to_string()),
+                    None => (d.to_string(), String::new()),
+                })
+                .collect(),
+            follow_includes: true,
+        }
+    }
+
+    pub fn process_file(&mut self, path: &str) -> Result<Vec<SourceLine>, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read topology file at '{path}': {e}"))?;
+        let dir = Path::new(path).parent().map(Path::to_path_buf);
+        self.process_str(&contents, path, dir.as_deref())
+    }
+
+    pub fn process_str(
+        &mut self,
+        contents: &str,
+        origin: &str,
+        dir: Option<&Path>,
+    ) -> Result<Vec<SourceLine>, String> {
+        let mut out = Vec::new();
+        self.process_into(contents, origin, dir, 0, &mut out)?;
+        Ok(out)
+    }
+
+    fn process_into(
+        &mut self,
+        contents: &str,
+        origin: &str,
+        dir: Option<&Path>,
+        depth: usize,
+        out: &mut Vec<SourceLine>,
+    ) -> Result<(), String> {
+        // one entry per open #ifdef/#ifndef: is this branch active?
+        let mut branches: Vec<bool> = Vec::new();
+        // rows continued onto the next line with a trailing backslash
+        let mut continued = String::new();
+
+        for (n, raw_line) in contents.lines().enumerate() {
+            let line_number = n + 1;
+            let line = raw_line.split(';').next().unwrap_or("").trim();
+            if let Some(part) = line.strip_suffix('\\') {
+                continued.push_str(part);
+                continued.push(' ');
+                continue;
+            }
+            let joined = std::mem::take(&mut continued) + line;
+            let line = joined.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let active = branches.iter().all(|&a| a);
+            if let Some(directive) = line.strip_prefix('#') {
+                let directive = directive.trim_start();
+                let (keyword, rest) = directive
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((directive, ""));
+                let rest = rest.trim();
+                let symbol = rest.split_whitespace().next().unwrap_or("");
+                match keyword {
+                    "ifdef" | "ifndef" => {
+                        let is_defined = self.defines.contains_key(symbol);
+                        branches.push(is_defined == (keyword == "ifdef"));
+                    }
+                    "else" => {
+                        let branch = branches.last_mut().ok_or_else(|| {
+                            format!("{}: #else without #ifdef", location(origin, line_number))
+                        })?;
+                        *branch = !*branch;
+                    }
+                    "endif" => {
+                        branches.pop().ok_or_else(|| {
+                            format!("{}: #endif without #ifdef", location(origin, line_number))
+                        })?;
+                    }
+                    _ if !active => {}
+                    "define" if !symbol.is_empty() => {
+                        let value = rest[symbol.len()..].trim().to_string();
+                        self.defines.insert(symbol.to_string(), value);
+                    }
+                    "undef" => {
+                        self.defines.remove(symbol);
+                    }
+                    "include" if self.follow_includes => {
+                        let here = location(origin, line_number);
+                        if depth >= MAX_INCLUDE_DEPTH {
+                            return Err(format!("{here}: #include nested too deeply"));
+                        }
+                        let name = rest.trim_matches(|c| c == '"' || c == '<' || c == '>');
+                        let path = self
+                            .resolve_include(name, dir)
+                            .map_err(|e| format!("{here}: {e}"))?;
+                        let included = fs::read_to_string(&path).map_err(|e| {
+                            format!("{here}: failed to read '{}': {e}", path.display())
+                        })?;
+                        let label = path.display().to_string();
+                        self.process_into(&included, &label, path.parent(), depth + 1, out)?;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            if !active {
+                continue;
+            }
+
+            out.push(SourceLine {
+                file: origin.to_string(),
+                line: line_number,
+                text: self.substitute(line),
+            });
+        }
+
+        if !branches.is_empty() {
+            return Err(if origin.is_empty() {
+                "unterminated #ifdef/#ifndef block".to_string()
+            } else {
+                format!("{origin}: unterminated #ifdef/#ifndef block")
+            });
+        }
+        Ok(())
+    }
+
+    fn resolve_include(&self, name: &str, dir: Option<&Path>) -> Result<PathBuf, String> {
+        let candidates = dir
+            .into_iter()
+            .map(Path::to_path_buf)
+            .chain(self.include_dirs.iter().cloned())
+            .map(|d| d.join(name));
+        let mut candidates: Vec<PathBuf> = candidates.collect();
+        if Path::new(name).is_absolute() {
+            candidates = vec![PathBuf::from(name)];
+        }
+        candidates
+            .into_iter()
+            .find(|p| p.is_file())
+            .ok_or_else(|| format!("include file '{name}' not found"))
+    }
+
+    fn substitute(&self, line: &str) -> String {
+        /*
+        Replace the defined names that carry a value, token by token
+         */
+        if self.defines.values().all(String::is_empty) {
+            return line.to_string();
+        }
+        line.split_whitespace()
+            .map(|token| match self.defines.get(token) {
+                Some(value) if !value.is_empty() => value.as_str(),
+                _ => token,
+            })
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TopologyDefaults {
+    pub nbfunc: usize,
+    pub comb_rule: usize,
+    pub gen_pairs: bool,
+    pub fudge_lj: f64,
+    pub fudge_qq: f64,
+}
+
+impl Default for TopologyDefaults {
+    fn default() -> Self {
+        TopologyDefaults {
+            nbfunc: 1,
+            comb_rule: 1,
+            gen_pairs: false,
+            fudge_lj: 1.0,
+            fudge_qq: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BondedType {
+    pub types: Vec<String>, // "X" matches any type
+    pub funct: usize,
+    pub parameters: Vec<String>, // appended to bonded rows without parameters
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GromacsTopology {
+    pub defaults: TopologyDefaults,
+    pub atom_types: HashMap<String, MartiniAtomType>,
+    pub bond_types: Vec<BondedType>,
+    pub angle_types: Vec<BondedType>,
+    pub dihedral_types: Vec<BondedType>,
+    // 1-4 (sigma, epsilon), keyed by the two type names in sorted order
+    pub pair_types: HashMap<(String, String), (f64, f64)>,
+    pub cmap_types: Vec<CmapType>,
+    pub molecule_types: Vec<MartiniForceField>,
+    pub system_name: String,
+    pub molecules: Vec<(String, usize)>,
+}
+
+impl GromacsTopology {
+    pub fn parse_str(
+        contents: &str,
+        include_dirs: &[&str],
+        defines: &[&str],
+    ) -> Result<Self, String> {
+        let mut preprocessor = Preprocessor::new(include_dirs, defines);
+        let lines = preprocessor.process_str(contents, "", None)?;
+        Self::from_lines(&lines)
+    }
+
+    pub fn read(path: &str, include_dirs: &[&str], defines: &[&str]) -> Result<Self, String> {
+        let mut preprocessor = Preprocessor::new(include_dirs, defines);
+        let lines = preprocessor.process_file(path)?;
+        Self::from_lines(&lines)
+    }
+
+    pub fn add_str(&mut self, contents: &str, defines: &[&str]) -> Result<(), String> {
+        /*
+        Read more topology text, e.g. the .itp of a molecule, on top of the force field
+        and molecules already read
+         */
+        let mut preprocessor = Preprocessor::new(&[], defines);
+        let lines = preprocessor.process_str(contents, "", None)?;
+        self.extend(&lines)
+    }
+
+    fn from_lines(lines: &[SourceLine]) -> Result<Self, String> {
+        let mut top = GromacsTopology::default();
+        top.extend(lines)?;
+        Ok(top)
+    }
+
+    fn extend(&mut self, lines: &[SourceLine]) -> Result<(), String> {
+        let mut section = String::new();
+        for line in lines {
+            let text = line.text.as_str();
+            if text.starts_with('[') && text.ends_with(']') {
+                section = text[1..text.len() - 1].trim().to_ascii_lowercase();
+                continue;
+            }
+            let tokens: Vec<&str> = text.split_whitespace().collect();
+            self.parse_row(&section, &tokens)
+                .map_err(|e| format!("{}: {e}", line.location()))?;
+        }
+
+        // every molecule sees the force field of the whole topology
+        let scales = (self.defaults.fudge_lj, self.defaults.fudge_qq);
+        for molecule in &mut self.molecule_types {
+            molecule.atom_types = self.atom_types.clone();
+            molecule.cmap_types = self.cmap_types.clone();
+            molecule.pair_scales = Some(scales);
+            molecule.comb_rule = Some(self.defaults.comb_rule);
+        }
+        Ok(())
+    }
+
+    fn parse_row(&mut self, section: &str, tokens: &[&str]) -> Result<(), String> {
+        match section {
+            "defaults" => {
+                self.defaults = TopologyDefaults {
+                    nbfunc: parse_usize(tokens, 0, "nbfunc")?,
+                    comb_rule: parse_usize(tokens, 1, "comb-rule")?,
+                    gen_pairs: tokens.get(2).is_some_and(|g| g.eq_ignore_ascii_case("yes")),
+                    fudge_lj: optional_f64(tokens, 3, "fudgeLJ", 1.0)?,
+                    fudge_qq: optional_f64(tokens, 4, "fudgeQQ", 1.0)?,
+                };
+            }
+            "atomtypes" => {
+                let atom_type = parse_atomtype_with_rule(tokens, Some(self.defaults.comb_rule))?;
+                self.atom_types.insert(atom_type.name.clone(), atom_type);
+            }
+            "bondtypes" => self.bond_types.push(parse_bonded_type(tokens, 2)?),
+            "angletypes" => self.angle_types.push(parse_bonded_type(tokens, 3)?),
+            "dihedraltypes" => self.dihedral_types.push(parse_dihedral_type(tokens)?),
+            "pairtypes" => {
+                if parse_usize(tokens, 2, "pairtype function type")? != 1 {
+                    return Err("only pairtype function type 1 is supported".to_string());
+                }
+                let (v, w) = (
+                    parse_f64(tokens, 3, "pairtype V")?,
+                    parse_f64(tokens, 4, "pairtype W")?,
+                );
+                self.pair_types.insert(
+                    type_pair(tokens[0], tokens[1]),
+                    sigma_epsilon(self.defaults.comb_rule, v, w),
+                );
+            }
+            "cmaptypes" => self.cmap_types.push(parse_cmap_type(tokens)?),
+            "moleculetype" => self.molecule_types.push(MartiniForceField {
+                molecule_name: Some(tokens[0].to_string()),
+                nrexcl: Some(parse_usize(tokens, 1, "nrexcl")?),
+                ..Default::default()
+            }),
+            "system" => {
+                if !self.system_name.is_empty() {
+                    self.system_name.push(' ');
+                }
+                self.system_name.push_str(&tokens.join(" "));
+            }
+            "molecules" => {
+                let count = parse_usize(tokens, 1, "molecule count")?;
+                self.molecules.push((tokens[0].to_string(), count));
+            }
+            "bonds" | "angles" | "dihedrals" => {
+                let rows = self.complete_bonded_row(section, tokens)?;
+                let molecule = self.current_molecule(section)?;
+                for row in &rows {
+                    let row: Vec<&str> = row.iter().map(String::as_str).collect();
+                    molecule.parse_row(section, &row)?;
+                }
+            }
+            "pairs" => {
+                let pair = parse_pair(tokens, Some(self.defaults.comb_rule))?;
+                self.current_molecule(section)?.pairs.push(pair);
+            }
+            "atoms"
+            | "settles"
+            | "exclusions"
+            | "position_restraints"
+            | "virtual_sites2"
+            | "virtual_sites3"
+            | "virtual_sites4"
+            | "virtual_sitesn" => {
+                self.current_molecule(section)?.parse_row(section, tokens)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn current_molecule(&mut self, section: &str) -> Result<&mut MartiniForceField, String> {
+        self.molecule_types
+            .last_mut()
+            .ok_or_else(|| format!("[ {section} ] before any [ moleculetype ]"))
+    }
+
+    fn complete_bonded_row(
+        &self,
+        section: &str,
+        tokens: &[&str],
+    ) -> Result<Vec<Vec<String>>, String> {
+        /*
+        A bonded row that stops after the function type takes its parameters from the
+        type tables, looked up by the bond types of the atom types of the current molecule
+        (opls_135 is matched as CT); an atom type without a bond_type column stands for
+        itself
+         */
+        let n_atoms = match section {
+            "bonds" => 2,
+            "angles" => 3,
+            _ => 4,
+        };
+        let row: Vec<String> = tokens.iter().map(|t| t.to_string()).collect();
+        if tokens.len() != n_atoms + 1 {
+            return Ok(vec![row]);
+        }
+
+        let molecule = self
+            .molecule_types
+            .last()
+            .ok_or_else(|| format!("[ {section} ] before any [ moleculetype ]"))?;
+        let funct = parse_usize(tokens, n_atoms, "function type")?;
+        let types = (0..n_atoms)
+            .map(|n| {
+                let index = parse_usize(tokens, n, "atom index")?;
+                index
+                    .checked_sub(1)
+                    .and_then(|i| molecule.atoms.get(i))
+                    .map(|a| {
+                        self.atom_types
+                            .get(&a.type_name)
+                            .and_then(|t| t.bond_type.as_deref())
+                            .unwrap_or(&a.type_name)
+                    })
+                    .ok_or_else(|| format!("atom {index} is not in [ atoms ]"))
+            })
+            .collect::<Result<Vec<&str>, String>>()?;
+
+        let table = match section {
+            "bonds" => &self.bond_types,
+            "angles" => &self.angle_types,
+            _ => &self.dihedral_types,
+        };
+        let mut matched = lookup_bonded_type(table, &types, funct);
+        if matched.is_empty() {
+            return Err(format!(
+                "no {section} parameters for {} with function type {funct}",
+                types.join("-")
+            ));
+        }
+        if funct != 9 {
+            matched.truncate(1);
+        }
+        Ok(matched
+            .into_iter()
+            .map(|t| row.iter().chain(&t.parameters).cloned().collect())
+            .collect())
+    }
+
+    pub fn molecule_type(&self, name: &str) -> Option<&MartiniForceField> {
+        self.molecule_types
+            .iter()
+            .find(|m| m.molecule_name.as_deref() == Some(name))
+    }
+
+    pub fn atom_count(&self) -> Result<usize, String> {
+        self.molecules.iter().try_fold(0, |total, (name, count)| {
+            let molecule = self
+                .molecule_type(name)
+                .ok_or_else(|| format!("[ molecules ] lists unknown molecule '{name}'"))?;
+            Ok(total + count * molecule.atoms.len())
+        })
+    }
+
+    pub fn to_systems(&self, coordinates: &[Vector3<f64>]) -> Result<Vec<System>, String> {
+        /*
+        One System per molecule of [ molecules ], in order, taking consecutive
+        coordinates (nm)
+         */
+        let expected = self.atom_count()?;
+        if coordinates.len() != expected {
+            return Err(format!(
+                "coordinate count mismatch: got {}, topology has {expected} atoms",
+                coordinates.len()
+            ));
+        }
+
+        let mut systems = Vec::new();
+        let mut offset = 0;
+        for (name, count) in &self.molecules {
+            let molecule = self
+                .molecule_type(name)
+                .ok_or_else(|| format!("[ molecules ] lists unknown molecule '{name}'"))?;
+            let n = molecule.atoms.len();
+            for _ in 0..*count {
+                let mut system = molecule
+                    .to_system(&coordinates[offset..offset + n])
+                    .map_err(|e| format!("molecule '{name}': {e}"))?;
+                self.apply_pair_types(molecule, &mut system)
+                    .map_err(|e| format!("molecule '{name}': {e}"))?;
+                systems.push(system);
+                offset += n;
+            }
+        }
+        Ok(systems)
+    }
+
+    pub fn systems_from_gro(
+        &self,
+        gro_path: &str,
+    ) -> Result<(Vec<System>, Option<Vector3<f64>>), String> {
+        /*
+        `read_gro` converts to Angstrom; positions and box go back to the nm of the
+        topology parameters
+         */
+        let (particles, box_dims) = read_gro(gro_path)?;
+        let coordinates: Vec<Vector3<f64>> = particles.iter().map(|p| 0.1 * p.position).collect();
+        let systems = self.to_systems(&coordinates)?;
+        Ok((systems, box_dims.map(|b| 0.1 * b)))
+    }
+
+    fn apply_pair_types(
+        &self,
+        molecule: &MartiniForceField,
+        system: &mut System,
+    ) -> Result<(), String> {
+        /*
+        [ pairtypes ] for the 1-4 pairs without parameters on their [ pairs ] row; the
+        mixed parameters set by `to_system` are only kept with gen-pairs = yes
+         */
+        let n_atoms = molecule.atoms.len();
+        for (k, pair) in system
+            .pairs
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, p)| p.atom1 < n_atoms && p.atom2 < n_atoms)
+        {
+            if molecule.pairs.get(k).is_some_and(|p| p.lj.is_some()) {
+                continue;
+            }
+            let key = type_pair(
+                &molecule.atoms[pair.atom1].type_name,
+                &molecule.atoms[pair.atom2].type_name,
+            );
+            match self.pair_types.get(&key) {
+                Some(&(sigma, epsilon)) => {
+                    pair.sigma = sigma;
+                    pair.epsilon = epsilon;
+                }
+                None if self.defaults.gen_pairs => {}
+                None => {
+                    return Err(format!(
+                        "no [ pairtypes ] entry for the {}-{} pair {}-{} and gen-pairs is no",
+                        key.0,
+                        key.1,
+                        pair.atom1 + 1,
+                        pair.atom2 + 1
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn type_pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+fn optional_f64(tokens: &[&str], index: usize, name: &str, default: f64) -> Result<f64, String> {
+    /*
+    An optional trailing column: `default` when the row stops before it
+     */
+    if index < tokens.len() {
+        parse_f64(tokens, index, name)
+    } else {
+        Ok(default)
+    }
+}
+
+pub(crate) fn sigma_epsilon(comb_rule: usize, v: f64, w: f64) -> (f64, f64) {
+    /*
+    (sigma, epsilon) from the V and W columns; comb-rule 1 gives C6 and C12
+     */
+    if comb_rule != 1 {
+        return (v, w);
+    }
+    if v <= 0.0 || w <= 0.0 {
+        return (0.0, 0.0);
+    }
+    ((w / v).powf(1.0 / 6.0), v * v / (4.0 * w))
+}
+
+pub(crate) fn parse_bonded_type(tokens: &[&str], n_types: usize) -> Result<BondedType, String> {
+    Ok(BondedType {
+        types: tokens
+            .get(..n_types)
+            .ok_or("bonded type row is missing atom types")?
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+        funct: parse_usize(tokens, n_types, "function type")?,
+        parameters: tokens[n_types + 1..]
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+    })
+}
+
+pub(crate) fn parse_dihedral_type(tokens: &[&str]) -> Result<BondedType, String> {
+    /*
+    Dihedral types list four atom types, or in the older form only the two central
+    ones (proper) or the two outer ones (funct 2 and 4 impropers)
+     */
+    if let Some(funct) = tokens.get(2).and_then(|t| t.parse::<usize>().ok()) {
+        let (a, b) = (tokens[0].to_string(), tokens[1].to_string());
+        let x = || "X".to_string();
+        return Ok(BondedType {
+            types: if matches!(funct, 2 | 4) {
+                vec![a, x(), x(), b]
+            } else {
+                vec![x(), a, b, x()]
+            },
+            funct,
+            parameters: tokens[3..].iter().map(|t| t.to_string()).collect(),
+        });
+    }
+    parse_bonded_type(tokens, 4)
+}
+
+fn lookup_bonded_type<'a>(
+    table: &'a [BondedType],
+    types: &[&str],
+    funct: usize,
+) -> Vec<&'a BondedType> {
+    /*
+    The entry matching the atom types in either direction with the fewest wildcards,
+    followed by the consecutive entries for the same types
+     */
+    let score = |entry: &BondedType| -> Option<usize> {
+        if entry.funct != funct || entry.types.len() != types.len() {
+            return None;
+        }
+        let fits = |reversed: bool| {
+            entry.types.iter().enumerate().all(|(n, t)| {
+                let atom = if reversed {
+                    types[types.len() - 1 - n]
+                } else {
+                    types[n]
+                };
+                t == "X" || t == atom
+            })
+        };
+        (fits(false) || fits(true)).then(|| entry.types.iter().filter(|t| *t != "X").count())
+    };
+
+    let mut best: Option<(usize, usize)> = None; // (score, index)
+    for (index, entry) in table.iter().enumerate() {
+        if let Some(s) = score(entry) {
+            if best.is_none_or(|(b, _)| s > b) {
+                best = Some((s, index));
+            }
+        }
+    }
+    let Some((_, first)) = best else {
+        return Vec::new();
+    };
+    table[first..]
+        .iter()
+        .take_while(|e| e.funct == funct && e.types == table[first].types)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::molecule::molecule::BondForm;
+
+    const CHARMM_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/ff/Charmm27.ff/charmm27.ff");
+
+    #[test]
+    fn preprocessor_handles_defines_and_nested_branches() {
+        let contents = "\
+#define SCALE 2.5
+#ifdef OUTER
+outer
+#ifndef INNER
+outer-only SCALE
+#else
+both
+#endif
+#else
+#undef SCALE
+neither SCALE
+#endif
+#include \"never-read.itp\"
+";
+        let mut preprocessor = Preprocessor::new(&[], &["OUTER"]);
+        preprocessor.follow_includes = false;
+        let text = |p: &mut Preprocessor| -> Vec<String> {
+            p.process_str(contents, "", None)
+                .expect("input should preprocess")
+                .into_iter()
+                .map(|l| l.text)
+                .collect()
+        };
+        assert_eq!(text(&mut preprocessor), ["outer", "outer-only 2.5"]);
+
+        let mut preprocessor = Preprocessor::new(&[], &["OUTER", "INNER=1"]);
+        preprocessor.follow_includes = false;
+        assert_eq!(text(&mut preprocessor), ["outer", "both"]);
+
+        let mut preprocessor = Preprocessor::new(&[], &[]);
+        preprocessor.follow_includes = false;
+        assert_eq!(text(&mut preprocessor), ["neither SCALE"]);
+
+        // a followed include has to exist
+        let mut preprocessor = Preprocessor::new(&[], &["OUTER"]);
+        let missing = preprocessor.process_str(contents, "", None).unwrap_err();
+        assert!(missing.contains("line 13"), "{missing}");
+        assert!(Preprocessor::new(&[], &[])
+            .process_str("#ifdef A\n", "", None)
+            .is_err());
+    }
+
+    #[test]
+    fn bonded_types_fill_in_missing_parameters() {
+        let top = "\
+[ defaults ]
+1 2 yes 0.5 0.8333
+[ atomtypes ]
+CT 6 12.011 0.0 A 0.35 0.28
+HC 1 1.008 0.0 A 0.25 0.12
+[ pairtypes ]
+HC HC 1 0.2 0.05
+[ bondtypes ]
+CT HC 1 0.109 284512.0
+CT CT 1 0.153 224262.4
+[ angletypes ]
+HC CT CT 5 110.1 289.5 0.218 22530.0
+[ dihedraltypes ]
+X  CT CT X  9 0.0 0.65 3
+HC CT CT HC 9 0.0 0.5 3
+HC CT CT HC 9 180.0 0.1 1
+[ moleculetype ]
+ETH 3
+[ atoms ]
+1 HC 1 ETH H1 1 0.0
+2 CT 1 ETH C1 1 0.0
+3 CT 1 ETH C2 1 0.0
+4 HC 1 ETH H2 1 0.0
+[ bonds ]
+1 2 1
+2 3 1
+3 4 1 0.11 300000.0
+[ angles ]
+1 2 3 5
+[ dihedrals ]
+1 2 3 4 9
+[ system ]
+two stretched ethane fragments
+[ molecules ]
+ETH 2
+";
+        let topology = GromacsTopology::parse_str(top, &[], &[]).expect("topology should parse");
+        assert_eq!(topology.system_name, "two stretched ethane fragments");
+        assert_eq!(topology.atom_count(), Ok(8));
+
+        let molecule = topology.molecule_type("ETH").expect("ETH is defined");
+        assert!((molecule.bonds[0].length - 0.109).abs() < 1e-12);
+        assert!((molecule.bonds[1].force_constant - 224262.4).abs() < 1e-9);
+        // explicit parameters are kept
+        assert!((molecule.bonds[2].length - 0.11).abs() < 1e-12);
+        assert_eq!(molecule.angles[0].urey_bradley, Some((0.218, 22530.0)));
+        // the specific dihedral type wins over the wildcard and brings both terms
+        let terms: Vec<(f64, usize)> = molecule
+            .dihedrals
+            .iter()
+            .map(|d| (d.force_constant, d.multiplicity))
+            .collect();
+        assert_eq!(terms, [(0.5, 3), (0.1, 1)]);
+
+        let coordinates: Vec<Vector3<f64>> = (0..8)
+            .map(|i| Vector3::new(0.12 * (i % 4) as f64, 0.05 * (i % 2) as f64, i as f64))
+            .collect();
+        let systems = topology
+            .to_systems(&coordinates)
+            .expect("systems should build");
+        assert_eq!(systems.len(), 2);
+        assert!((systems[1].atoms[0].position.z - 4.0).abs() < 1e-12);
+        assert_eq!(systems[0].dihedrals.len(), 2);
+        assert!(matches!(systems[0].bonds[0].form, BondForm::Harmonic));
+        // elements come from the at.num column
+        assert_eq!(systems[0].elements, [1, 6, 6, 1]);
+        // the H-H 1-4 pair uses its pairtype, Coulomb is scaled by fudgeQQ
+        let pair = &systems[0].pairs[0];
+        assert_eq!((pair.atom1, pair.atom2), (0, 3));
+        assert_eq!((pair.sigma, pair.epsilon), (0.2, 0.05));
+        assert!((pair.coulomb_scale - 0.8333).abs() < 1e-12);
+
+        assert!(topology.to_systems(&coordinates[..7]).is_err());
+        let unknown = top.replace("ETH 2\n", "ETH 2\nPROP 1\n");
+        let unknown = GromacsTopology::parse_str(&unknown, &[], &[]).expect("still parses");
+        assert!(unknown.atom_count().unwrap_err().contains("PROP"));
+    }
+
+    #[test]
+    fn explicit_pairs_replace_generated_ones() {
+        let top = "\
+[ defaults ]
+1 2 no 0.5 0.8333
+[ atomtypes ]
+CT 6 12.011 0.0 A 0.35 0.28
+HC 1 1.008 0.0 A 0.25 0.12
+[ pairtypes ]
+HC CT 1 0.3 0.1
+[ moleculetype ]
+PRP 3
+[ atoms ]
+1 HC 1 PRP H1 1 0.0
+2 CT 1 PRP C1 1 0.0
+3 CT 1 PRP C2 1 0.0
+4 CT 1 PRP C3 1 0.0
+5 HC 1 PRP H2 1 0.0
+[ bonds ]
+1 2 1 0.109 284512.0
+2 3 1 0.153 224262.4
+3 4 1 0.153 224262.4
+4 5 1 0.109 284512.0
+[ pairs ]
+1 4 1
+2 5 1 0.32 0.15
+[ system ]
+chain
+[ molecules ]
+PRP 1
+";
+        let coordinates: Vec<Vector3<f64>> = (0..5)
+            .map(|i| Vector3::new(0.13 * i as f64, 0.05 * (i % 2) as f64, 0.0))
+            .collect();
+        let build = |top: &str| {
+            GromacsTopology::parse_str(top, &[], &[])
+                .expect("topology should parse")
+                .to_systems(&coordinates)
+        };
+
+        // the pairtype fills in the first row, the second carries its own parameters
+        let systems = build(top).expect("systems should build");
+        let pairs = &systems[0].pairs;
+        assert_eq!(pairs.len(), 2);
+        assert_eq!((pairs[0].atom1, pairs[0].atom2), (0, 3));
+        assert_eq!((pairs[0].sigma, pairs[0].epsilon), (0.3, 0.1));
+        assert_eq!((pairs[1].sigma, pairs[1].epsilon), (0.32, 0.15));
+        assert!((pairs[1].coulomb_scale - 0.8333).abs() < 1e-12);
+        assert!(systems[0].is_excluded(0, 2));
+
+        // the rows replace the pairs generated from the bond graph
+        let single = top.replace("2 5 1 0.32 0.15\n", "");
+        assert_eq!(
+            build(&single).expect("systems should build")[0].pairs.len(),
+            1
+        );
+
+        // a pair without a pairtype is an error unless gen-pairs mixes the atom types
+        let unmatched = single.replace("1 4 1\n", "1 5 1\n");
+        let missing = build(&unmatched).unwrap_err();
+        assert!(missing.contains("gen-pairs"), "{missing}");
+        let generated = build(&unmatched.replace("1 2 no", "1 2 yes")).expect("gen-pairs");
+        let pair = &generated[0].pairs[0];
+        assert!((pair.sigma - 0.25).abs() < 1e-12);
+        assert!((pair.epsilon - 0.5 * 0.12).abs() < 1e-12);
+    }
+
+    #[test]
+    fn opls_types_match_on_bond_type_and_mix_geometrically() {
+        let top = "\
+[ defaults ]
+1 3 yes 0.5 0.5
+[ atomtypes ]
+opls_135 CT 6 12.011 -0.18 A 0.35 0.276
+opls_140 HC 1 1.008 0.06 A 0.25 0.125
+[ bondtypes ]
+CT HC 1 0.109 284512.0
+CT CT 1 0.1529 224262.4
+[ moleculetype ]
+PRP 3
+[ atoms ]
+1 opls_140 1 PRP H1 1 0.06
+2 opls_135 1 PRP C1 1 -0.18
+3 opls_135 1 PRP C2 1 -0.12
+4 opls_135 1 PRP C3 1 -0.18
+[ bonds ]
+1 2 1
+2 3 1
+3 4 1
+[ system ]
+propane fragment
+[ molecules ]
+PRP 1
+";
+        let topology = GromacsTopology::parse_str(top, &[], &[]).expect("topology should parse");
+        assert_eq!(
+            topology.atom_types["opls_135"].bond_type.as_deref(),
+            Some("CT")
+        );
+        assert_eq!(topology.atom_types["opls_140"].atomic_number, Some(1));
+        let molecule = topology.molecule_type("PRP").expect("PRP is defined");
+        assert!((molecule.bonds[0].length - 0.109).abs() < 1e-12);
+        assert!((molecule.bonds[2].length - 0.1529).abs() < 1e-12);
+
+        let coordinates: Vec<Vector3<f64>> = (0..4)
+            .map(|i| Vector3::new(0.13 * i as f64, 0.05 * (i % 2) as f64, 0.0))
+            .collect();
+        let systems = topology
+            .to_systems(&coordinates)
+            .expect("systems should build");
+        let pair = &systems[0].pairs[0];
+        assert_eq!((pair.atom1, pair.atom2), (0, 3));
+        assert!((pair.sigma - (0.25f64 * 0.35).sqrt()).abs() < 1e-12);
+        assert!((pair.epsilon - 0.5 * (0.125f64 * 0.276).sqrt()).abs() < 1e-12);
+
+        // the fudge factors default to 1 only when their columns are absent
+        let short =
+            GromacsTopology::parse_str(&top.replace("1 3 yes 0.5 0.5", "1 3 yes"), &[], &[])
+                .expect("defaults without fudge columns");
+        assert_eq!(
+            (short.defaults.fudge_lj, short.defaults.fudge_qq),
+            (1.0, 1.0)
+        );
+        let bad = GromacsTopology::parse_str(&top.replace("yes 0.5 0.5", "yes 0.5 half"), &[], &[]);
+        assert!(bad.unwrap_err().contains("fudgeQQ"));
+    }
+
+    #[test]
+    fn reads_charmm27_topology_with_includes_and_gro() {
+        let dir = std::env::temp_dir().join(format!("sang_md_top_{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("temporary directory");
+        let top_path = dir.join("water_ions.top");
+        let gro_path = dir.join("water_ions.gro");
+        fs::write(
+            &top_path,
+            "\
+#include \"forcefield.itp\"
+#include \"tip3p.itp\"
+#include \"ions.itp\"
+
+[ system ]
+Water and salt
+
+[ molecules ]
+SOL 2
+NA 1
+CL 1
+",
+        )
+        .expect("write topology");
+        fs::write(
+            &gro_path,
+            "\
+water and salt
+    8
+    1SOL     OW    1   1.000   1.000   1.000
+    1SOL    HW1    2   1.096   1.000   1.000
+    1SOL    HW2    3   0.976   1.093   1.000
+    2SOL     OW    4   2.000   2.000   2.000
+    2SOL    HW1    5   2.096   2.000   2.000
+    2SOL    HW2    6   1.976   2.093   2.000
+    3NA      NA    7   0.500   0.500   0.500
+    4CL      CL    8   1.500   1.500   1.500
+   3.00000   3.00000   3.00000
+",
+        )
+        .expect("write coordinates");
+
+        let top_path = top_path.to_str().expect("utf-8 path");
+        let rigid = GromacsTopology::read(top_path, &[CHARMM_DIR], &[])
+            .expect("the CHARMM27 topology should read");
+        assert_eq!(rigid.defaults.comb_rule, 2);
+        assert!(rigid.defaults.gen_pairs);
+        // columns are name, atomic number, mass, charge, ptype, sigma, epsilon
+        let carbon = &rigid.atom_types["C"];
+        assert!((carbon.mass - 12.011).abs() < 1e-9);
+        assert_eq!(carbon.epsilon, Some(0.46024));
+        assert!(!rigid.bond_types.is_empty() && !rigid.cmap_types.is_empty());
+        assert!(rigid.dihedral_types.iter().any(|t| t.types[0] == "X"));
+
+        let (systems, box_dims) = rigid
+            .systems_from_gro(gro_path.to_str().expect("utf-8 path"))
+            .expect("systems should build from the .gro");
+        assert_eq!(systems.len(), 4);
+        assert_eq!(box_dims, Some(Vector3::new(3.0, 3.0, 3.0)));
+        assert_eq!(systems[0].settles.len(), 1);
+        assert!((systems[1].atoms[0].position - Vector3::new(2.0, 2.0, 2.0)).norm() < 1e-12);
+        assert!((systems[0].atoms[0].mass - 15.9994).abs() < 1e-9);
+        assert_eq!(systems[2].atoms[0].charge, 1.0);
+        assert_eq!(systems[3].atoms[0].charge, -1.0);
+
+        let flexible = GromacsTopology::read(top_path, &[CHARMM_DIR], &["FLEXIBLE"])
+            .expect("flexible water should read");
+        let (systems, _) = flexible
+            .systems_from_gro(gro_path.to_str().expect("utf-8 path"))
+            .expect("flexible systems should build");
+        assert!(systems[0].settles.is_empty());
+        assert_eq!(systems[0].bonds.len(), 2);
+        assert!((systems[0].bonds[0].k - 502416.0).abs() < 1e-9);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,355 @@
+/*
+Virtual interaction sites (GROMACS [ virtual_sites2 ], [ virtual_sites3 ],
+[ virtual_sites4 ] and [ virtual_sitesn ])
+
+A virtual site is a massless particle whose position is a function of a few real
+constructing atoms i, j, k, l (r_ij = x_j - x_i, minimum image):
+
+    2      x = (1 - a) x_i + a x_j
+    2fd    x = x_i + a r_ij / |r_ij|
+    3      x = x_i + a r_ij + b r_ik                       (TIP4P M site)
+    3fd    x = x_i + b t / |t|,  t = r_ij + a r_jk
+    3out   x = x_i + a r_ij + b r_ik + c (r_ij x r_ik)     (TIP5P lone pairs)
+    4fdn   x = x_i + c m / |m|,  m = (a r_ik - r_ij) x (b r_il - r_ij)
+    n      x = sum_n w_n x_n (centre of geometry, mass or weights)
+
+The sites are rebuilt after every drift, before forces are computed. The force
+acting on a site is then passed on to its constructing atoms through the chain rule,
+
+    F_n += (dx / dx_n)^T F_site
+
+which conserves the total force and torque, and the site's own force is cleared.
+ */
+
+use crate::lennard_jones_simulations::{minimum_image_convention, Particle};
+use nalgebra::{Matrix3, Vector3};
+
+#[derive(Clone, Debug)]
+pub enum VirtualSiteKind {
+    Linear2 {
+        atoms: [usize; 2],
+        a: f64,
+    },
+    FixedDistance2 {
+        atoms: [usize; 2],
+        a: f64,
+    },
+    Linear3 {
+        atoms: [usize; 3],
+        a: f64,
+        b: f64,
+    },
+    FixedDistance3 {
+        atoms: [usize; 3],
+        a: f64,
+        b: f64,
+    },
+    OutOfPlane3 {
+        atoms: [usize; 3],
+        a: f64,
+        b: f64,
+        c: f64,
+    },
+    FixedDistance4 {
+        atoms: [usize; 4],
+        a: f64,
+        b: f64,
+        c: f64,
+    },
+    // normalised weights; centre of geometry and centre of mass are special cases
+    Weighted {
+        atoms: Vec<usize>,
+        weights: Vec<f64>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct VirtualSite {
+    pub site: usize,
+    pub kind: VirtualSiteKind,
+}
+
+impl VirtualSite {
+    pub fn constructing_atoms(&self) -> Vec<usize> {
+        match &self.kind {
+            VirtualSiteKind::Linear2 { atoms, .. }
+            | VirtualSiteKind::FixedDistance2 { atoms, .. } => atoms.to_vec(),
+            VirtualSiteKind::Linear3 { atoms, .. }
+            | VirtualSiteKind::FixedDistance3 { atoms, .. }
+            | VirtualSiteKind::OutOfPlane3 { atoms, .. } => atoms.to_vec(),
+            VirtualSiteKind::FixedDistance4 { atoms, .. } => atoms.to_vec(),
+            VirtualSiteKind::Weighted { atoms, .. } => atoms.clone(),
+        }
+    }
+}
+
+fn cross_matrix(v: &Vector3<f64>) -> Matrix3<f64> {
+    // [v]_x, so that [v]_x w = v x w
+    Matrix3::new(0.0, -v.z, v.y, v.z, 0.0, -v.x, -v.y, v.x, 0.0)
+}
+
+fn unit_jacobian(v: &Vector3<f64>) -> Matrix3<f64> {
+    // d(v / |v|) / dv
+    let n = v.norm();
+    let u = v / n;
+    (Matrix3::identity() - u * u.transpose()) / n
+}
+
+fn construct(
+    atoms: &[Particle],
+    site: &VirtualSite,
+    box_length: f64,
+) -> (Vector3<f64>, Vec<(usize, Matrix3<f64>)>) {
+    /*
+    Position of the site and the Jacobian dx / dx_n for every constructing atom
+     */
+    let identity = Matrix3::<f64>::identity();
+    let origin = |i: usize| atoms[i].position;
+    let rel = |i: usize, j: usize| {
+        minimum_image_convention(atoms[j].position - atoms[i].position, box_length)
+    };
+
+    // the first atom takes whatever the others do not, so the rows sum to I
+    let with_origin = |i: usize, others: Vec<(usize, Matrix3<f64>)>| {
+        let rest: Matrix3<f64> = others.iter().map(|(_, m)| m).sum();
+        let mut jac = vec![(i, identity - rest)];
+        jac.extend(others);
+        jac
+    };
+
+    match site.kind {
+        VirtualSiteKind::Linear2 { atoms: [i, j], a } => (
+            origin(i) + a * rel(i, j),
+            with_origin(i, vec![(j, identity * a)]),
+        ),
+        VirtualSiteKind::FixedDistance2 { atoms: [i, j], a } => {
+            let r_ij = rel(i, j);
+            (
+                origin(i) + a * r_ij.normalize(),
+                with_origin(i, vec![(j, a * unit_jacobian(&r_ij))]),
+            )
+        }
+        VirtualSiteKind::Linear3 {
+            atoms: [i, j, k],
+            a,
+            b,
+        } => (
+            origin(i) + a * rel(i, j) + b * rel(i, k),
+            with_origin(i, vec![(j, identity * a), (k, identity * b)]),
+        ),
+        VirtualSiteKind::FixedDistance3 {
+            atoms: [i, j, k],
+            a,
+            b,
+        } => {
+            let t = rel(i, j) + a * rel(j, k);
+            let p = b * unit_jacobian(&t);
+            (
+                origin(i) + b * t.normalize(),
+                with_origin(i, vec![(j, (1.0 - a) * p), (k, a * p)]),
+            )
+        }
+        VirtualSiteKind::OutOfPlane3 {
+            atoms: [i, j, k],
+            a,
+            b,
+            c,
+        } => {
+            let (r_ij, r_ik) = (rel(i, j), rel(i, k));
+            (
+                origin(i) + a * r_ij + b * r_ik + c * r_ij.cross(&r_ik),
+                with_origin(
+                    i,
+                    vec![
+                        (j, identity * a - c * cross_matrix(&r_ik)),
+                        (k, identity * b + c * cross_matrix(&r_ij)),
+                    ],
+                ),
+            )
+        }
+        VirtualSiteKind::FixedDistance4 {
+            atoms: [i, j, k, l],
+            a,
+            b,
+            c,
+        } => {
+            let (r_ij, r_ik, r_il) = (rel(i, j), rel(i, k), rel(i, l));
+            let r_ja = a * r_ik - r_ij;
+            let r_jb = b * r_il - r_ij;
+            let m = r_ja.cross(&r_jb);
+            // d m = -[r_jb]_x d r_ja + [r_ja]_x d r_jb
+            let p = c * unit_jacobian(&m);
+            (
+                origin(i) + c * m.normalize(),
+                with_origin(
+                    i,
+                    vec![
+                        (j, p * (cross_matrix(&r_jb) - cross_matrix(&r_ja))),
+                        (k, -a * p * cross_matrix(&r_jb)),
+                        (l, b * p * cross_matrix(&r_ja)),
+                    ],
+                ),
+            )
+        }
+        VirtualSiteKind::Weighted {
+            atoms: ref from,
+            ref weights,
+        } => {
+            // unwrap around the first atom so the average does not straddle the box
+            let first = from[0];
+            let position = origin(first)
+                + from
+                    .iter()
+                    .zip(weights)
+                    .map(|(&n, &w)| w * rel(first, n))
+                    .sum::<Vector3<f64>>();
+            let jac = from
+                .iter()
+                .zip(weights)
+                .map(|(&n, &w)| (n, identity * w))
+                .collect();
+            (position, jac)
+        }
+    }
+}
+
+pub fn construct_virtual_sites(atoms: &mut [Particle], sites: &[VirtualSite], box_length: f64) {
+    /*
+    Place every site from its constructing atoms. Sites are built in order, so a site
+    may be constructed from an earlier one.
+     */
+    for site in sites {
+        let (position, _) = construct(atoms, site, box_length);
+        let p = &mut atoms[site.site];
+        p.position = position;
+        p.velocity = Vector3::zeros();
+    }
+}
+
+pub fn spread_virtual_site_forces(atoms: &mut [Particle], sites: &[VirtualSite], box_length: f64) {
+    /*
+    Hand the forces on the sites to their constructing atoms, last site first so that
+    forces on sites built from other sites reach the real atoms
+     */
+    for site in sites.iter().rev() {
+        let force = std::mem::replace(&mut atoms[site.site].force, Vector3::zeros());
+        let (_, jacobians) = construct(atoms, site, box_length);
+        for (n, jac) in jacobians {
+            atoms[n].force += jac.transpose() * force;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_forces_match_energy, particle, Tolerance};
+
+    #[test]
+    fn tip4p_m_site_sits_on_the_bisector() {
+        // bundled tip4p.itp: x4 = x1 + a r12 + b r13 with a = b = 0.128012065
+        let mut atoms = vec![
+            particle(Vector3::new(1.0, 1.0, 1.0), 16.0),
+            particle(Vector3::new(1.0 + 0.09572, 1.0, 1.0), 1.008),
+            particle(
+                Vector3::new(
+                    1.0 + 0.09572 * 104.52f64.to_radians().cos(),
+                    1.0 + 0.09572 * 104.52f64.to_radians().sin(),
+                    1.0,
+                ),
+                1.008,
+            ),
+            particle(Vector3::zeros(), 0.0),
+        ];
+        let sites = [VirtualSite {
+            site: 3,
+            kind: VirtualSiteKind::Linear3 {
+                atoms: [0, 1, 2],
+                a: 0.128012065,
+                b: 0.128012065,
+            },
+        }];
+        construct_virtual_sites(&mut atoms, &sites, 10.0);
+        let d_om = (atoms[3].position - atoms[0].position).norm();
+        assert!((d_om - 0.015).abs() < 1e-6);
+    }
+
+    #[test]
+    fn spread_forces_conserve_force_and_torque_and_match_chain_rule() {
+        let positions = [
+            Vector3::new(1.0, 1.0, 1.0),
+            Vector3::new(1.1, 1.02, 0.97),
+            Vector3::new(0.96, 1.09, 1.03),
+            Vector3::new(1.04, 0.95, 1.08),
+        ];
+        let kinds = [
+            VirtualSiteKind::Linear2 {
+                atoms: [0, 1],
+                a: 0.3,
+            },
+            VirtualSiteKind::FixedDistance2 {
+                atoms: [0, 1],
+                a: 0.05,
+            },
+            VirtualSiteKind::Linear3 {
+                atoms: [0, 1, 2],
+                a: 0.2,
+                b: 0.4,
+            },
+            VirtualSiteKind::FixedDistance3 {
+                atoms: [0, 1, 2],
+                a: 0.4,
+                b: 0.06,
+            },
+            VirtualSiteKind::OutOfPlane3 {
+                atoms: [0, 1, 2],
+                a: -0.34,
+                b: -0.34,
+                c: 6.44,
+            },
+            VirtualSiteKind::FixedDistance4 {
+                atoms: [0, 1, 2, 3],
+                a: 0.8,
+                b: 1.2,
+                c: 0.07,
+            },
+            VirtualSiteKind::Weighted {
+                atoms: vec![0, 1, 2, 3],
+                weights: vec![0.4, 0.1, 0.2, 0.3],
+            },
+        ];
+        let f_site = Vector3::new(3.0, -2.0, 1.5);
+
+        for kind in kinds {
+            let site = VirtualSite { site: 4, kind };
+            let mut atoms: Vec<Particle> = positions.iter().map(|&r| particle(r, 12.0)).collect();
+            atoms.push(particle(Vector3::zeros(), 0.0));
+            construct_virtual_sites(&mut atoms, std::slice::from_ref(&site), 10.0);
+            let x_site = atoms[4].position;
+
+            atoms[4].force = f_site;
+            spread_virtual_site_forces(&mut atoms, std::slice::from_ref(&site), 10.0);
+            assert_eq!(atoms[4].force, Vector3::zeros());
+
+            let total: Vector3<f64> = atoms.iter().map(|a| a.force).sum();
+            let torque: Vector3<f64> = atoms.iter().map(|a| a.position.cross(&a.force)).sum();
+            assert!((total - f_site).norm() < 1e-9, "{:?}", site.kind);
+            assert!(
+                (torque - x_site.cross(&f_site)).norm() < 1e-9,
+                "{:?}",
+                site.kind
+            );
+
+            // the spread forces derive from the energy -F_site . x_site
+            let forces: Vec<Vector3<f64>> = atoms.iter().map(|a| a.force).collect();
+            assert_forces_match_energy(
+                &atoms,
+                &forces,
+                |a| -f_site.dot(&construct(a, &site, 10.0).0),
+                1e-7,
+                Tolerance::Absolute(1e-5),
+                &format!("{:?}", site.kind),
+            );
+        }
+    }
+}
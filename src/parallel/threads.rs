@@ -0,0 +1,354 @@
+/*
+Shared-memory parallelism for the force kernels
+
+Every kernel follows the same replicated-buffer scheme as the `mpi` path: each worker
+gets its own zeroed force buffer, accumulates the share of the work it owns into it,
+and the buffers are then summed into the result. Workers never write to shared memory,
+so no locking is needed, and the reduction runs in worker order, so for a given thread
+count the result is deterministic.
+
+The MD drivers start one `WorkerPool` per run, sized by `MdOptions::num_threads`, and
+enter it for the duration of the run; every `parallel_force_reduce` call on that thread
+then hands its kernel to the pool's persistent workers instead of spawning threads.
+Outside an entered pool a call spawns scoped threads, as many as SANG_MD_NUM_THREADS
+or else the number of available cores.
+ */
+
+use nalgebra::Vector3;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+// below this amount of work the spawn overhead outweighs the gain
+const MIN_WORK_PER_THREAD: usize = 64;
+
+type ForceKernel<'a> = dyn Fn(usize, usize, &mut [Vector3<f64>]) -> f64 + Sync + 'a;
+type Job = Box<dyn FnOnce() + Send>;
+
+thread_local! {
+    // pool entered on this thread, see `WorkerPool::enter`
+    static CURRENT_POOL: RefCell<Option<Arc<PoolWorkers>>> = const { RefCell::new(None) };
+}
+
+pub fn num_threads() -> usize {
+    /*
+    Default thread count: SANG_MD_NUM_THREADS, or else the number of available cores
+     */
+    std::env::var("SANG_MD_NUM_THREADS")
+        .ok()
+        .and_then(|s| s.trim().parse::<usize>().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+}
+
+pub struct WorkerPool {
+    workers: Arc<PoolWorkers>,
+}
+
+struct PoolWorkers {
+    // the calling thread acts as worker 0, so a pool of n threads has n - 1 of these
+    senders: Vec<Mutex<mpsc::Sender<Job>>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Drop for PoolWorkers {
+    fn drop(&mut self) {
+        // closing the channels ends the worker loops
+        self.senders.clear();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Restores the previously entered pool when dropped
+pub struct PoolGuard {
+    previous: Option<Arc<PoolWorkers>>,
+    // the guard resets a thread-local, so it must stay on the entering thread
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for PoolGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT_POOL.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+impl WorkerPool {
+    pub fn new(n_threads: usize) -> Self {
+        /*
+        Start a pool of `n_threads` workers (counting the calling thread);
+        0 takes the default from `num_threads`
+         */
+        let n_threads = if n_threads == 0 {
+            num_threads()
+        } else {
+            n_threads
+        };
+        let mut senders = Vec::with_capacity(n_threads - 1);
+        let mut handles = Vec::with_capacity(n_threads - 1);
+        for _ in 1..n_threads {
+            let (tx, rx) = mpsc::channel::<Job>();
+            senders.push(Mutex::new(tx));
+            handles.push(std::thread::spawn(move || {
+                for job in rx {
+                    job();
+                }
+            }));
+        }
+        WorkerPool {
+            workers: Arc::new(PoolWorkers { senders, handles }),
+        }
+    }
+
+    pub fn n_threads(&self) -> usize {
+        self.workers.n_threads()
+    }
+
+    pub fn enter(&self) -> PoolGuard {
+        /*
+        Make this pool the one `parallel_force_reduce` uses on the current thread until
+        the returned guard is dropped
+         */
+        let previous =
+            CURRENT_POOL.with(|current| current.borrow_mut().replace(self.workers.clone()));
+        PoolGuard {
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn force_reduce<F>(
+        &self,
+        n_workers: usize,
+        n_atoms: usize,
+        kernel: F,
+    ) -> (Vec<Vector3<f64>>, f64)
+    where
+        F: Fn(usize, usize, &mut [Vector3<f64>]) -> f64 + Sync,
+    {
+        /*
+        `force_reduce_over_workers` on the pool's threads; `n_workers` is capped at the
+        pool size
+         */
+        self.workers.force_reduce(n_workers, n_atoms, &kernel)
+    }
+}
+
+impl PoolWorkers {
+    fn n_threads(&self) -> usize {
+        self.senders.len() + 1
+    }
+
+    fn force_reduce(
+        &self,
+        n_workers: usize,
+        n_atoms: usize,
+        kernel: &ForceKernel<'_>,
+    ) -> (Vec<Vector3<f64>>, f64) {
+        let n_workers = n_workers.clamp(1, self.n_threads());
+        let mut forces = vec![Vector3::<f64>::zeros(); n_atoms];
+        if n_workers == 1 {
+            let energy = kernel(0, 1, &mut forces);
+            return (forces, energy);
+        }
+
+        // SAFETY: the jobs below are the only holders of this reference, and they hold
+        // it only until they finish or are dropped. Every job owns a clone of
+        // `result_tx`, and this function does not return before `result_rx` reports
+        // that all of those clones are gone, so `kernel` outlives every use.
+        let kernel: &'static ForceKernel<'static> = unsafe {
+            std::mem::transmute::<&ForceKernel<'_>, &'static ForceKernel<'static>>(kernel)
+        };
+
+        let (result_tx, result_rx) = mpsc::channel();
+        for (w, sender) in self.senders.iter().enumerate().take(n_workers - 1) {
+            let worker = w + 1;
+            let result_tx = result_tx.clone();
+            let job: Job = Box::new(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let mut local = vec![Vector3::<f64>::zeros(); n_atoms];
+                    let energy = kernel(worker, n_workers, &mut local);
+                    (local, energy)
+                }));
+                let _ = result_tx.send((worker, result));
+            });
+            // a worker that has gone away drops the job, which the receive loop sees
+            let _ = sender.lock().map(|sender| sender.send(job));
+        }
+        drop(result_tx);
+
+        let own = panic::catch_unwind(AssertUnwindSafe(|| kernel(0, n_workers, &mut forces)));
+
+        let mut results: Vec<Option<(Vec<Vector3<f64>>, f64)>> = vec![None; n_workers];
+        let mut worker_panicked = false;
+        for (worker, result) in result_rx.iter() {
+            match result {
+                Ok(r) => results[worker] = Some(r),
+                Err(_) => worker_panicked = true,
+            }
+        }
+        let own_energy = match own {
+            Ok(e) => e,
+            Err(payload) => panic::resume_unwind(payload),
+        };
+        if worker_panicked || results.iter().skip(1).any(|r| r.is_none()) {
+            panic!("force worker panicked");
+        }
+
+        let mut energy = own_energy;
+        for (local, e) in results.into_iter().flatten() {
+            for (f, lf) in forces.iter_mut().zip(local) {
+                *f += lf;
+            }
+            energy += e;
+        }
+        (forces, energy)
+    }
+}
+
+pub fn chunk_bounds(len: usize, chunk: usize, n_chunks: usize) -> (usize, usize) {
+    /*
+    Contiguous [start, end) share of `len` items for chunk `chunk` of `n_chunks`
+     */
+    let base = len / n_chunks;
+    let rem = len % n_chunks;
+    let start = chunk * base + chunk.min(rem);
+    let end = start + base + usize::from(chunk < rem);
+    (start, end)
+}
+
+pub fn parallel_force_reduce<F>(
+    n_atoms: usize,
+    work_items: usize,
+    kernel: F,
+) -> (Vec<Vector3<f64>>, f64)
+where
+    F: Fn(usize, usize, &mut [Vector3<f64>]) -> f64 + Sync,
+{
+    /*
+    Run `kernel(worker, n_workers, forces)` on every worker and reduce the per-worker
+    force buffers and energies. The kernel decides which share of the work belongs to
+    `worker`; `work_items` is only used to decide how many workers are worth starting.
+    Uses the pool entered on this thread, if any.
+     */
+    let max_workers = (work_items / MIN_WORK_PER_THREAD).max(1);
+    match CURRENT_POOL.with(|current| current.borrow().clone()) {
+        Some(pool) => pool.force_reduce(max_workers, n_atoms, &kernel),
+        None => force_reduce_over_workers(num_threads().min(max_workers), n_atoms, kernel),
+    }
+}
+
+pub fn force_reduce_over_workers<F>(
+    n_workers: usize,
+    n_atoms: usize,
+    kernel: F,
+) -> (Vec<Vector3<f64>>, f64)
+where
+    F: Fn(usize, usize, &mut [Vector3<f64>]) -> f64 + Sync,
+{
+    /*
+    Same reduction on `n_workers` scoped threads spawned for this call
+     */
+    let n_workers = n_workers.max(1);
+    let mut forces = vec![Vector3::<f64>::zeros(); n_atoms];
+    if n_workers == 1 {
+        let energy = kernel(0, 1, &mut forces);
+        return (forces, energy);
+    }
+
+    let results: Vec<(Vec<Vector3<f64>>, f64)> = std::thread::scope(|scope| {
+        let kernel = &kernel;
+        let handles: Vec<_> = (0..n_workers)
+            .map(|w| {
+                scope.spawn(move || {
+                    let mut local = vec![Vector3::<f64>::zeros(); n_atoms];
+                    let energy = kernel(w, n_workers, &mut local);
+                    (local, energy)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("force worker panicked"))
+            .collect()
+    });
+
+    let mut energy = 0.0;
+    for (local, e) in results {
+        for (f, lf) in forces.iter_mut().zip(local) {
+            *f += lf;
+        }
+        energy += e;
+    }
+    (forces, energy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_cover_the_range_once() {
+        let mut covered = Vec::new();
+        for c in 0..4 {
+            let (s, e) = chunk_bounds(10, c, 4);
+            covered.extend(s..e);
+        }
+        assert_eq!(covered, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn worker_buffers_reduce_to_the_serial_result() {
+        // every worker adds its strided share of i to atom i % 5
+        let kernel = |w: usize, n: usize, forces: &mut [Vector3<f64>]| {
+            let mut e = 0.0;
+            for i in (w..1000).step_by(n) {
+                forces[i % 5].x += i as f64;
+                e += 1.0;
+            }
+            e
+        };
+        let (serial, e1) = force_reduce_over_workers(1, 5, kernel);
+        let (threaded, e4) = force_reduce_over_workers(4, 5, kernel);
+
+        assert_eq!(e1, e4);
+        for (a, b) in serial.iter().zip(threaded.iter()) {
+            assert!((a - b).norm() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn entered_pool_runs_every_call_on_its_workers() {
+        let kernel = |w: usize, n: usize, forces: &mut [Vector3<f64>]| {
+            for i in (w..1000).step_by(n) {
+                forces[i % 5].x += i as f64;
+            }
+            n as f64
+        };
+        let (serial, _) = force_reduce_over_workers(1, 5, kernel);
+
+        let pool = WorkerPool::new(3);
+        assert_eq!(pool.n_threads(), 3);
+        {
+            let _entered = pool.enter();
+            for _ in 0..10 {
+                // 64 work items per worker are needed to use all three
+                let (forces, n_workers) = parallel_force_reduce(5, 1000, kernel);
+                assert_eq!(n_workers, 9.0);
+                for (a, b) in serial.iter().zip(forces.iter()) {
+                    assert!((a - b).norm() < 1e-9);
+                }
+            }
+        }
+        assert!(CURRENT_POOL.with(|current| current.borrow().is_none()));
+    }
+}
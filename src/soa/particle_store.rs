@@ -0,0 +1,524 @@
+/*
+Structure-of-arrays (SoA) particle storage
+
+`Particle` is an array-of-structs record: each particle carries its LJ parameters, an
+energy and a charge next to its position, so a force loop strides over a lot of memory
+it never uses. `ParticleStore` keeps every quantity in its own contiguous array and
+refers to the LJ parameters through a small per-type table, so the inner pair loops run
+over plain `f64` slices that the compiler can vectorise.
+
+`Particle` remains the public currency of the rest of the code: a store is built from a
+particle slice, `particle(i)` hands out a `Particle` view of one entry, and `write_back`
+copies positions, velocities and forces into the original particles. The particle NVE
+driver keeps one store as its state for the whole run: the velocity Verlet steps and the
+threaded cell-list LJ kernel (`compute_lj_forces_cell_list`) work on the store, and the
+particles are only a view that is synced for the terms that still take `Particle`
+(electrostatics, fields, biases, thermostats).
+ */
+
+use crate::cell::cell::CellList;
+use crate::coulomb_prefactor;
+use crate::lennard_jones_simulations::{erfc_approx, LJParameters, Particle};
+use crate::parallel::threads::{chunk_bounds, parallel_force_reduce};
+use nalgebra::Vector3;
+
+#[derive(Clone, Debug, Default)]
+pub struct ParticleStore {
+    pub ids: Vec<usize>,
+    pub x: Vec<f64>,
+    pub y: Vec<f64>,
+    pub z: Vec<f64>,
+    pub vx: Vec<f64>,
+    pub vy: Vec<f64>,
+    pub vz: Vec<f64>,
+    pub fx: Vec<f64>,
+    pub fy: Vec<f64>,
+    pub fz: Vec<f64>,
+    pub mass: Vec<f64>,
+    pub charge: Vec<f64>,
+    pub atom_type: Vec<f64>,
+    pub type_index: Vec<usize>,
+    // distinct LJ parameter sets, indexed by `type_index`
+    pub types: Vec<LJParameters>,
+    // mixed 4*eps*sigma^6 and 4*eps*sigma^12 for every type pair (row major, n_types^2)
+    c6: Vec<f64>,
+    c12: Vec<f64>,
+}
+
+impl ParticleStore {
+    pub fn from_particles(particles: &[Particle]) -> Self {
+        let n = particles.len();
+        let mut store = ParticleStore {
+            ids: Vec::with_capacity(n),
+            x: Vec::with_capacity(n),
+            y: Vec::with_capacity(n),
+            z: Vec::with_capacity(n),
+            vx: Vec::with_capacity(n),
+            vy: Vec::with_capacity(n),
+            vz: Vec::with_capacity(n),
+            fx: Vec::with_capacity(n),
+            fy: Vec::with_capacity(n),
+            fz: Vec::with_capacity(n),
+            mass: Vec::with_capacity(n),
+            charge: Vec::with_capacity(n),
+            atom_type: Vec::with_capacity(n),
+            type_index: Vec::with_capacity(n),
+            ..Default::default()
+        };
+
+        for p in particles {
+            let t = match store.types.iter().position(|t| {
+                t.sigma == p.lj_parameters.sigma && t.epsilon == p.lj_parameters.epsilon
+            }) {
+                Some(t) => t,
+                None => {
+                    store.types.push(p.lj_parameters.clone());
+                    store.types.len() - 1
+                }
+            };
+
+            store.ids.push(p.id);
+            store.x.push(p.position.x);
+            store.y.push(p.position.y);
+            store.z.push(p.position.z);
+            store.vx.push(p.velocity.x);
+            store.vy.push(p.velocity.y);
+            store.vz.push(p.velocity.z);
+            store.fx.push(p.force.x);
+            store.fy.push(p.force.y);
+            store.fz.push(p.force.z);
+            store.mass.push(p.mass);
+            store.charge.push(p.charge);
+            store.atom_type.push(p.atom_type);
+            store.type_index.push(t);
+        }
+
+        store.build_pair_tables();
+        store
+    }
+
+    fn build_pair_tables(&mut self) {
+        // Lorentz-Berthelot mixing, as everywhere else in the code
+        let nt = self.types.len();
+        self.c6 = vec![0.0; nt * nt];
+        self.c12 = vec![0.0; nt * nt];
+        for a in 0..nt {
+            for b in 0..nt {
+                let sigma = 0.5 * (self.types[a].sigma + self.types[b].sigma);
+                let epsilon = (self.types[a].epsilon * self.types[b].epsilon).sqrt();
+                let s6 = sigma.powi(6);
+                self.c6[a * nt + b] = 4.0 * epsilon * s6;
+                self.c12[a * nt + b] = 4.0 * epsilon * s6 * s6;
+            }
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.x.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.x.is_empty()
+    }
+
+    #[inline]
+    pub fn position(&self, i: usize) -> Vector3<f64> {
+        Vector3::new(self.x[i], self.y[i], self.z[i])
+    }
+
+    #[inline]
+    pub fn velocity(&self, i: usize) -> Vector3<f64> {
+        Vector3::new(self.vx[i], self.vy[i], self.vz[i])
+    }
+
+    #[inline]
+    pub fn force(&self, i: usize) -> Vector3<f64> {
+        Vector3::new(self.fx[i], self.fy[i], self.fz[i])
+    }
+
+    pub fn particle(&self, i: usize) -> Particle {
+        /*
+        AoS view of entry i, for code that still works with `Particle`
+         */
+        Particle {
+            id: self.ids[i],
+            position: self.position(i),
+            velocity: self.velocity(i),
+            force: self.force(i),
+            lj_parameters: self.types[self.type_index[i]].clone(),
+            mass: self.mass[i],
+            energy: 0.0,
+            atom_type: self.atom_type[i],
+            charge: self.charge[i],
+        }
+    }
+
+    pub fn to_particles(&self) -> Vec<Particle> {
+        (0..self.len()).map(|i| self.particle(i)).collect()
+    }
+
+    pub fn write_back(&self, particles: &mut [Particle]) {
+        /*
+        Copy the dynamic state (positions, velocities, forces) back into the particles
+        the store was built from
+         */
+        for (i, p) in particles.iter_mut().enumerate().take(self.len()) {
+            p.position = self.position(i);
+            p.velocity = self.velocity(i);
+            p.force = self.force(i);
+        }
+    }
+
+    pub fn load_forces(&mut self, particles: &[Particle]) {
+        /*
+        Take the forces back from the particle view, after terms evaluated on `Particle`
+        have added to them
+         */
+        for (i, p) in particles.iter().enumerate().take(self.len()) {
+            self.fx[i] = p.force.x;
+            self.fy[i] = p.force.y;
+            self.fz[i] = p.force.z;
+        }
+    }
+
+    pub fn load_velocities(&mut self, particles: &[Particle]) {
+        /*
+        Take the velocities back from the particle view, e.g. after a thermostat
+         */
+        for (i, p) in particles.iter().enumerate().take(self.len()) {
+            self.vx[i] = p.velocity.x;
+            self.vy[i] = p.velocity.y;
+            self.vz[i] = p.velocity.z;
+        }
+    }
+
+    pub fn kick(&mut self, dt: f64) {
+        /*
+        v += f / m * dt
+         */
+        for i in 0..self.len() {
+            let inv_m = dt / self.mass[i];
+            self.vx[i] += self.fx[i] * inv_m;
+            self.vy[i] += self.fy[i] * inv_m;
+            self.vz[i] += self.fz[i] * inv_m;
+        }
+    }
+
+    pub fn drift(&mut self, dt: f64) {
+        /*
+        x += v * dt
+         */
+        for i in 0..self.len() {
+            self.x[i] += self.vx[i] * dt;
+            self.y[i] += self.vy[i] * dt;
+            self.z[i] += self.vz[i] * dt;
+        }
+    }
+
+    pub fn wrap(&mut self, box_length: f64) {
+        /*
+        Put every position back into [0, L)
+         */
+        for c in [&mut self.x, &mut self.y, &mut self.z] {
+            for x in c.iter_mut() {
+                *x = x.rem_euclid(box_length);
+            }
+        }
+    }
+
+    pub fn clear_forces(&mut self) {
+        self.fx.fill(0.0);
+        self.fy.fill(0.0);
+        self.fz.fill(0.0);
+    }
+
+    pub fn compute_lj_forces(&mut self, box_length: f64, cutoff: f64) -> f64 {
+        /*
+        Truncated Lennard-Jones forces, accumulated into fx/fy/fz. Returns the energy.
+
+        The j loop only touches contiguous slices and a branch-free cutoff mask, so it
+        is a straight candidate for auto-vectorisation.
+         */
+        let n = self.len();
+        let nt = self.types.len();
+        let rc2 = cutoff * cutoff;
+        let inv_l = 1.0 / box_length;
+        let mut energy = 0.0;
+
+        for i in 0..n {
+            let (xi, yi, zi) = (self.x[i], self.y[i], self.z[i]);
+            let row = self.type_index[i] * nt;
+            let (mut fxi, mut fyi, mut fzi) = (0.0, 0.0, 0.0);
+
+            for j in (i + 1)..n {
+                let mut dx = self.x[j] - xi;
+                let mut dy = self.y[j] - yi;
+                let mut dz = self.z[j] - zi;
+                dx -= box_length * (dx * inv_l).round();
+                dy -= box_length * (dy * inv_l).round();
+                dz -= box_length * (dz * inv_l).round();
+
+                let r2 = dx * dx + dy * dy + dz * dz;
+                let mask = if r2 <= rc2 && r2 > 1e-24 { 1.0 } else { 0.0 };
+                let inv_r2 = mask / r2.max(1e-24);
+                let inv_r6 = inv_r2 * inv_r2 * inv_r2;
+
+                let c6 = self.c6[row + self.type_index[j]];
+                let c12 = self.c12[row + self.type_index[j]];
+
+                energy += inv_r6 * (c12 * inv_r6 - c6);
+                // F_ij / r, positive = repulsive
+                let f_over_r = inv_r6 * (12.0 * c12 * inv_r6 - 6.0 * c6) * inv_r2;
+
+                fxi -= f_over_r * dx;
+                fyi -= f_over_r * dy;
+                fzi -= f_over_r * dz;
+                self.fx[j] += f_over_r * dx;
+                self.fy[j] += f_over_r * dy;
+                self.fz[j] += f_over_r * dz;
+            }
+
+            self.fx[i] += fxi;
+            self.fy[i] += fyi;
+            self.fz[i] += fzi;
+        }
+
+        energy
+    }
+
+    pub fn compute_lj_forces_cell_list(
+        &mut self,
+        cl: &mut CellList,
+        box_length: f64,
+        cutoff: f64,
+    ) -> f64 {
+        /*
+        Same truncated Lennard-Jones as `compute_lj_forces`, but over the neighbour pairs
+        of a cell list built for `cutoff`. The list is rebuilt from the current
+        positions, and the cells are split into one contiguous block per worker thread,
+        each with its own force buffer. Forces are accumulated into fx/fy/fz; returns
+        the energy.
+         */
+        cl.rebuild_from_coordinates(&self.x, &self.y, &self.z);
+
+        let n = self.len();
+        let nt = self.types.len();
+        let rc2 = cutoff * cutoff;
+        let inv_l = 1.0 / box_length;
+        let cl = &*cl;
+        let store = &*self;
+
+        let (forces, energy) = parallel_force_reduce(n, n, |w, n_workers, forces| {
+            let (start, end) = chunk_bounds(cl.n_cells(), w, n_workers);
+            let mut energy = 0.0;
+            cl.for_each_candidate_pair_in_cells(start..end, |i, j| {
+                let mut dx = store.x[j] - store.x[i];
+                let mut dy = store.y[j] - store.y[i];
+                let mut dz = store.z[j] - store.z[i];
+                dx -= box_length * (dx * inv_l).round();
+                dy -= box_length * (dy * inv_l).round();
+                dz -= box_length * (dz * inv_l).round();
+
+                let r2 = dx * dx + dy * dy + dz * dz;
+                if r2 > rc2 || r2 <= 1e-24 {
+                    return;
+                }
+                let inv_r2 = 1.0 / r2;
+                let inv_r6 = inv_r2 * inv_r2 * inv_r2;
+
+                let t = store.type_index[i] * nt + store.type_index[j];
+                let (c6, c12) = (store.c6[t], store.c12[t]);
+
+                energy += inv_r6 * (c12 * inv_r6 - c6);
+                // F_ij / r, positive = repulsive
+                let f =
+                    inv_r6 * (12.0 * c12 * inv_r6 - 6.0 * c6) * inv_r2 * Vector3::new(dx, dy, dz);
+                forces[i] -= f;
+                forces[j] += f;
+            });
+            energy
+        });
+
+        for (i, f) in forces.iter().enumerate() {
+            self.fx[i] += f.x;
+            self.fy[i] += f.y;
+            self.fz[i] += f.z;
+        }
+        energy
+    }
+
+    pub fn compute_coulomb_forces(&mut self, box_length: f64, cutoff: f64, alpha: f64) -> f64 {
+        /*
+        Real-space Coulomb, k_e q_i q_j erfc(alpha r) / r, truncated at `cutoff`.
+        With alpha = 0 this is plain cutoff Coulomb. Returns the energy.
+         */
+        let n = self.len();
+        let rc2 = cutoff * cutoff;
+        let inv_l = 1.0 / box_length;
+        let two_alpha_over_sqrt_pi = 2.0 * alpha / std::f64::consts::PI.sqrt();
+        let k_e = coulomb_prefactor();
+        let mut energy = 0.0;
+
+        for i in 0..n {
+            let qi = self.charge[i];
+            if qi == 0.0 {
+                continue;
+            }
+            let (xi, yi, zi) = (self.x[i], self.y[i], self.z[i]);
+            let (mut fxi, mut fyi, mut fzi) = (0.0, 0.0, 0.0);
+
+            for j in (i + 1)..n {
+                let mut dx = self.x[j] - xi;
+                let mut dy = self.y[j] - yi;
+                let mut dz = self.z[j] - zi;
+                dx -= box_length * (dx * inv_l).round();
+                dy -= box_length * (dy * inv_l).round();
+                dz -= box_length * (dz * inv_l).round();
+
+                let r2 = dx * dx + dy * dy + dz * dz;
+                let mask = if r2 <= rc2 && r2 > 1e-24 { 1.0 } else { 0.0 };
+                let r = r2.max(1e-24).sqrt();
+                let qq = mask * k_e * qi * self.charge[j];
+
+                let ar = alpha * r;
+                let erfc_ar = erfc_approx(ar);
+                energy += qq * erfc_ar / r;
+                let f_over_r = qq * (erfc_ar / r + two_alpha_over_sqrt_pi * (-(ar * ar)).exp())
+                    / r2.max(1e-24);
+
+                fxi -= f_over_r * dx;
+                fyi -= f_over_r * dy;
+                fzi -= f_over_r * dz;
+                self.fx[j] += f_over_r * dx;
+                self.fy[j] += f_over_r * dy;
+                self.fz[j] += f_over_r * dz;
+            }
+
+            self.fx[i] += fxi;
+            self.fy[i] += fyi;
+            self.fz[i] += fzi;
+        }
+
+        energy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lennard_jones_simulations::{
+        minimum_image_convention, site_site_energy_calculation, Particle,
+    };
+    use crate::test_support::{assert_forces_match_energy, particle, Tolerance};
+    use crate::{Electrostatics, PmeConfig};
+
+    fn particles() -> Vec<Particle> {
+        // two LJ types and alternating charges
+        (0..12)
+            .map(|i| {
+                let f = i as f64;
+                let position =
+                    Vector3::new((1.3 * f) % 6.0, (0.7 * f * f) % 6.0, (2.1 * f + 0.4) % 6.0);
+                Particle {
+                    id: i,
+                    velocity: Vector3::new(f, -f, 0.5),
+                    lj_parameters: LJParameters {
+                        epsilon: if i % 3 == 0 { 0.5 } else { 1.0 },
+                        sigma: if i % 3 == 0 { 0.9 } else { 1.0 },
+                        number_of_atoms: 1,
+                    },
+                    charge: if i % 2 == 0 { 0.4 } else { -0.4 },
+                    ..particle(position, 1.0)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_particles_through_the_store() {
+        let ps = particles();
+        let store = ParticleStore::from_particles(&ps);
+
+        assert_eq!(store.len(), ps.len());
+        assert_eq!(store.types.len(), 2);
+        let view = store.particle(3);
+        assert_eq!(view.id, 3);
+        assert!((view.position - ps[3].position).norm() < 1e-12);
+        assert!((view.lj_parameters.sigma - 0.9).abs() < 1e-12);
+    }
+
+    #[test]
+    fn lj_kernel_matches_site_site_energy() {
+        let mut ps = particles();
+        let box_length = 6.0;
+        let mut store = ParticleStore::from_particles(&ps);
+
+        // cutoff covering the whole minimum-image cell
+        let e_store = store.compute_lj_forces(box_length, box_length);
+        let e_ref = site_site_energy_calculation(&mut ps, box_length);
+        assert!((e_store - e_ref).abs() < 1e-8 * e_ref.abs().max(1.0));
+
+        let net: f64 = store.fx.iter().sum::<f64>() + store.fy.iter().sum::<f64>();
+        assert!(net.abs() < 1e-6);
+    }
+
+    #[test]
+    fn cell_list_lj_kernel_matches_all_pairs() {
+        let ps = particles();
+        let (box_length, cutoff) = (6.0, 2.5);
+
+        let mut all_pairs = ParticleStore::from_particles(&ps);
+        all_pairs.clear_forces();
+        let e_ref = all_pairs.compute_lj_forces(box_length, cutoff);
+
+        let mut store = ParticleStore::from_particles(&ps);
+        store.clear_forces();
+        let mut cl = CellList::new(
+            crate::cell::cell::Vec3::new(box_length, box_length, box_length),
+            cutoff,
+        );
+        let e = store.compute_lj_forces_cell_list(&mut cl, box_length, cutoff);
+
+        assert!((e - e_ref).abs() < 1e-10 * e_ref.abs().max(1.0));
+        for i in 0..ps.len() {
+            assert!((store.force(i) - all_pairs.force(i)).norm() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn coulomb_kernel_forces_are_energy_gradients() {
+        let ps = particles();
+        let (box_length, cutoff, alpha) = (6.0, 2.9, 0.8);
+        let mut store = ParticleStore::from_particles(&ps);
+        store.compute_coulomb_forces(box_length, cutoff, alpha);
+
+        let forces: Vec<Vector3<f64>> = (0..store.len()).map(|i| store.force(i)).collect();
+        assert_forces_match_energy(
+            &ps,
+            &forces,
+            |a| ParticleStore::from_particles(a).compute_coulomb_forces(box_length, cutoff, alpha),
+            1e-6,
+            Tolerance::Absolute(1e-3),
+            "real-space Coulomb",
+        );
+
+        // same energy as the real-space Ewald pair term used by the System path
+        let ewald = Electrostatics::Ewald(PmeConfig {
+            alpha,
+            real_cutoff: cutoff,
+            ..Default::default()
+        });
+        let mut e_ref = 0.0;
+        for i in 0..ps.len() {
+            for j in (i + 1)..ps.len() {
+                let dr = minimum_image_convention(ps[j].position - ps[i].position, box_length);
+                e_ref += ewald.pair(ps[i].charge, ps[j].charge, dr.norm()).0;
+            }
+        }
+        let e_store =
+            ParticleStore::from_particles(&ps).compute_coulomb_forces(box_length, cutoff, alpha);
+        assert!((e_store - e_ref).abs() < 1e-10);
+    }
+}
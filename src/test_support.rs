@@ -0,0 +1,93 @@
+/*
+Fixtures and checks shared by the unit tests
+
+`particle` is the neutral test particle every module builds its small systems from;
+tests that need a velocity, charge or LJ parameters set them with struct update
+syntax. `assert_forces_match_energy` compares analytical forces with central
+differences of the energy, `central_difference` does the same for a scalar function.
+ */
+
+use crate::lennard_jones_simulations::{LJParameters, Particle};
+use nalgebra::Vector3;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Tolerance {
+    Absolute(f64),
+    Relative(f64), // of the numerical value, or absolute below 1
+}
+
+impl Tolerance {
+    fn bound(self, numerical: f64) -> f64 {
+        match self {
+            Tolerance::Absolute(tol) => tol,
+            Tolerance::Relative(tol) => tol * numerical.abs().max(1.0),
+        }
+    }
+}
+
+pub fn particle(position: Vector3<f64>, mass: f64) -> Particle {
+    /*
+    A neutral particle at rest without LJ interactions (epsilon = 0)
+     */
+    Particle {
+        id: 0,
+        position,
+        velocity: Vector3::zeros(),
+        force: Vector3::zeros(),
+        lj_parameters: LJParameters {
+            epsilon: 0.0,
+            sigma: 1.0,
+            number_of_atoms: 1,
+        },
+        mass,
+        energy: 0.0,
+        atom_type: 0.0,
+        charge: 0.0,
+    }
+}
+
+pub fn particles(positions: &[Vector3<f64>], mass: f64) -> Vec<Particle> {
+    /*
+    One `particle` per position, with ids in order
+     */
+    positions
+        .iter()
+        .enumerate()
+        .map(|(id, &position)| Particle {
+            id,
+            ..particle(position, mass)
+        })
+        .collect()
+}
+
+pub fn central_difference(x: f64, h: f64, f: impl Fn(f64) -> f64) -> f64 {
+    (f(x + h) - f(x - h)) / (2.0 * h)
+}
+
+pub fn assert_forces_match_energy(
+    atoms: &[Particle],
+    forces: &[Vector3<f64>],
+    energy: impl Fn(&[Particle]) -> f64,
+    h: f64,
+    tolerance: Tolerance,
+    label: &str,
+) {
+    /*
+    forces[i] = -dE/dx_i for every atom and axis, with dE/dx_i by central differences
+    of step h around the positions of `atoms`
+     */
+    for (i, force) in forces.iter().enumerate() {
+        for dim in 0..3 {
+            let numerical = -central_difference(atoms[i].position[dim], h, |x| {
+                let mut shifted = atoms.to_vec();
+                shifted[i].position[dim] = x;
+                energy(&shifted)
+            });
+            assert!(
+                (force[dim] - numerical).abs() < tolerance.bound(numerical),
+                "{label} atom {i} dim {dim}: analytical {} vs numerical {numerical}",
+                force[dim]
+            );
+        }
+    }
+}
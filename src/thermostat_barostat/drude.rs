@@ -0,0 +1,131 @@
+/*
+Dual thermostat for Drude polarisable systems (Lamoureux and Roux, J. Chem. Phys. 119,
+3025 (2003))
+
+Each core-Drude pair is split into its centre of mass motion V and the relative
+motion v_r = v_drude - v_core with reduced mass mu. The centre of mass motion and
+the atoms without a Drude are coupled to the warm bath T_warm, while the relative
+motion is kept close to the cold bath T_cold, so the Drudes stay near the
+self-consistent field minimum. Each bath has its own Nose-Hoover friction
+
+    dxi/dt = (T / T0 - 1) / tau^2,    v' = v exp(-xi dt)
+
+Temperatures use the same reduced units as compute_temperature_particles. The warm
+bath owns the degrees of freedom of the system (after constraints, without virtual
+sites) less the 3 relative ones of every pair, which belong to the cold bath.
+ */
+
+use crate::lennard_jones_simulations::Particle;
+use crate::molecule::drude::DrudePair;
+use nalgebra::Vector3;
+
+#[derive(Copy, Clone, Debug)]
+pub struct DrudeThermostat {
+    pub t_warm: f64,
+    pub t_cold: f64,
+    pub tau_warm: f64,
+    pub tau_cold: f64,
+}
+
+impl Default for DrudeThermostat {
+    fn default() -> Self {
+        DrudeThermostat {
+            t_warm: 300.0,
+            t_cold: 1.0,
+            tau_warm: 0.1,
+            tau_cold: 0.02,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DrudeThermostatState {
+    pub xi_warm: f64,
+    pub xi_cold: f64,
+}
+
+fn friction_scale(temperature: f64, target: f64, tau: f64, dt: f64, xi: &mut f64) -> f64 {
+    if temperature <= 0.0 || target <= 0.0 || tau <= 0.0 {
+        return 1.0;
+    }
+    *xi += (temperature / target - 1.0) / (tau * tau) * dt;
+    (-*xi * dt).exp()
+}
+
+pub fn apply_dual_thermostat_drude(
+    atoms: &mut [Particle],
+    drudes: &[DrudePair],
+    dof: usize,
+    params: &DrudeThermostat,
+    dt: f64,
+    state: &mut DrudeThermostatState,
+) {
+    if atoms.is_empty() || dt <= 0.0 {
+        return;
+    }
+
+    let mut in_pair = vec![false; atoms.len()];
+    // (centre of mass velocity, relative velocity, total mass) per pair
+    let mut pair_motion: Vec<(Vector3<f64>, Vector3<f64>, f64)> = Vec::with_capacity(drudes.len());
+    let mut ke_warm = 0.0;
+    let mut ke_cold = 0.0;
+
+    for pair in drudes {
+        let (core, drude) = (&atoms[pair.core], &atoms[pair.drude]);
+        let m_tot = core.mass + drude.mass;
+        let mu = core.mass * drude.mass / m_tot;
+        let v_com = (core.mass * core.velocity + drude.mass * drude.velocity) / m_tot;
+        let v_rel = drude.velocity - core.velocity;
+        ke_warm += 0.5 * m_tot * v_com.norm_squared();
+        ke_cold += 0.5 * mu * v_rel.norm_squared();
+        in_pair[pair.core] = true;
+        in_pair[pair.drude] = true;
+        pair_motion.push((v_com, v_rel, m_tot));
+    }
+    for (a, _) in atoms.iter().zip(&in_pair).filter(|(_, &paired)| !paired) {
+        ke_warm += 0.5 * a.mass * a.velocity.norm_squared();
+    }
+
+    let dof_warm = dof.saturating_sub(3 * drudes.len());
+    let dof_cold = 3 * drudes.len();
+    let t_warm = if dof_warm > 0 {
+        2.0 * ke_warm / dof_warm as f64
+    } else {
+        0.0
+    };
+    let t_cold = if dof_cold > 0 {
+        2.0 * ke_cold / dof_cold as f64
+    } else {
+        0.0
+    };
+
+    let s_warm = friction_scale(
+        t_warm,
+        params.t_warm,
+        params.tau_warm,
+        dt,
+        &mut state.xi_warm,
+    );
+    let s_cold = friction_scale(
+        t_cold,
+        params.t_cold,
+        params.tau_cold,
+        dt,
+        &mut state.xi_cold,
+    );
+
+    for (a, _) in atoms
+        .iter_mut()
+        .zip(&in_pair)
+        .filter(|(_, &paired)| !paired)
+    {
+        a.velocity *= s_warm;
+    }
+    for (pair, (v_com, v_rel, m_tot)) in drudes.iter().zip(pair_motion) {
+        let (v_com, v_rel) = (v_com * s_warm, v_rel * s_cold);
+        let m_core = atoms[pair.core].mass;
+        let m_drude = atoms[pair.drude].mass;
+        atoms[pair.core].velocity = v_com - (m_drude / m_tot) * v_rel;
+        atoms[pair.drude].velocity = v_com + (m_core / m_tot) * v_rel;
+    }
+}
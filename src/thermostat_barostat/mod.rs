@@ -1,3 +1,4 @@
 pub mod andersen; // declare the submodule andersen
 pub mod berendsen; // declare the submodule berendsen
+pub mod drude; // declare the dual (warm atoms / cold Drudes) thermostat
 pub mod nose_hoover; // declare the nose_hoover module
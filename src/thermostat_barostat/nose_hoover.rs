@@ -4,18 +4,37 @@ pub mod nose_hoover {
     };
 
     pub fn apply_thermostat_nose_hoover_particles(
-        particles: &mut Vec<Particle>,
+        particles: &mut [Particle],
         target_temperature: f64,
         thermostat_mass: f64,
         dt: f64,
         xi: &mut f64,
     ) -> () {
+        let dof = 3 * particles.len();
+        apply_thermostat_nose_hoover_particles_with_dof(
+            particles,
+            dof,
+            target_temperature,
+            thermostat_mass,
+            dt,
+            xi,
+        );
+    }
+
+    pub fn apply_thermostat_nose_hoover_particles_with_dof(
+        particles: &mut [Particle],
+        dof: usize,
+        target_temperature: f64,
+        thermostat_mass: f64,
+        dt: f64,
+        xi: &mut f64,
+    ) {
+        // as above, with the degrees of freedom reduced by e.g. constraints
         if particles.is_empty() || target_temperature <= 0.0 || thermostat_mass <= 0.0 || dt <= 0.0
         {
             return;
         }
 
-        let dof = 3 * particles.len();
         if dof == 0 {
             return;
         }